@@ -1,23 +1,33 @@
-use crate::inv::{InvChange, LocalInv};
-use crate::ui::{HomePage, Page, TextFieldInfo, UiOutput, UiTheme};
+use crate::inv::{to_inv_pic, Id, Item, LocalInv, Picture, PictureId};
+use crate::ui::{ConflictsPage, HomePage, Page, TextFieldInfo, UiOutput, UiTheme};
 use crate::SaveDirs;
 
 use jano::android_activity::input::{TextInputState, TextSpan};
 use jano::egui_app::Egui;
-use jano::TcpStream;
 use jano::{android, egui, log, set_keyboard_visibility, FrameStats};
 use serde::{Deserialize, Serialize};
 
-use inv_common::{ServerConn, ServerErr};
+use inv_common::audit::ItemVersion;
+use inv_common::auth::Role;
+use inv_common::inv::Trashed;
+use inv_common::merge::{merge_items, Merged};
+use inv_common::picture::{PictureCache, Size};
+use inv_common::save;
+use inv_common::tls::{self, Stream};
+use inv_common::{ClientInfo, InvEvent, ServerConn, ServerErr};
 
-type Server = ServerConn<TcpStream>;
+type Server = ServerConn<Stream>;
 
 #[derive(Serialize, Deserialize)]
 pub struct Settings {
     pub server_address: String,
     pub server_port: u32,
+    /// SHA-256 fingerprint of the server's TLS certificate. Empty to connect without TLS.
+    pub server_fingerprint: String,
     pub theme: UiTheme,
     pub name: String,
+    /// Password or token for the `name` account on the server.
+    pub password: String,
     pub scale: f32,
 }
 impl Default for Settings {
@@ -25,29 +35,48 @@ impl Default for Settings {
         Self {
             server_address: "192.168.1.239".into(),
             server_port: 25552,
+            server_fingerprint: String::new(),
             theme: UiTheme::default(),
-            name: fastrand::u32(0..1000).to_string(),
+            name: String::new(),
+            password: String::new(),
             scale: 3.0,
         }
     }
 }
 
+/// An item we edited that someone else also edited, and that couldn't be merged automatically.
+pub struct ItemConflict {
+    pub id: Id,
+    pub local: Item,
+    pub remote: Item,
+    pub merged: Merged,
+}
+
 pub struct App {
     save_dirs: SaveDirs,
     server: Option<Server>,
     pub focused_text_field: Option<TextFieldInfo>,
     pub settings: Settings,
     pub inv: LocalInv,
+    /// The saved inv couldn't be read, so it is left alone instead of being saved over.
+    inv_unreadable: bool,
+    pub pictures: PictureCache,
+    pub conflicts: Vec<ItemConflict>,
     pages: Option<Vec<Box<dyn Page>>>,
 }
 impl Default for App {
     fn default() -> Self {
+        let save_dirs = SaveDirs::new();
+        let pictures = PictureCache::new(&save_dirs.pictures);
         Self {
-            save_dirs: SaveDirs::new(),
+            save_dirs,
             server: None,
             focused_text_field: None,
             settings: Default::default(),
             inv: Default::default(),
+            inv_unreadable: false,
+            pictures,
+            conflicts: vec![],
             pages: Some(vec![Box::<HomePage>::default()]),
         }
     }
@@ -70,31 +99,158 @@ impl App {
             self.msg_popup("Successfully synced with server");
         }
     }
-    pub fn try_sync_server(&mut self, download: bool) -> Result<(), ServerErr> {
+    /// Uploads local changes, returning the server's version of each item that was rejected
+    /// because someone else changed it first.
+    fn upload_changes(&mut self) -> Result<Vec<(Id, Item)>, ServerErr> {
         let Some(server) = &mut self.server else {
-            return Ok(());
+            return Ok(vec![]);
+        };
+        let upload = self.inv.upload_changes(server, &self.pictures)?;
+        if upload.denied {
+            self.msg_popup("Your account can't change the inventory, so your changes were undone");
+        }
+        if !upload.dropped_photos.is_empty() {
+            self.msg_popup(format!(
+                "{} photos were missing everywhere, so they were taken out of your changes",
+                upload.dropped_photos.len()
+            ));
+        }
+        Ok(upload.conflicts)
+    }
+
+    /// Merges our edit of an item with the server's. Returns false if the user has to choose.
+    fn handle_conflict(&mut self, id: Id, remote: Item) -> bool {
+        let Some(local) = self.inv.get_item(&id) else {
+            return true;
         };
+        let base = self.inv.base_item(&id).unwrap_or(&remote);
+        let merged = merge_items(base, local, &remote);
+        if merged.conflicts.is_empty() {
+            self.inv.resolve_conflict(id, remote, Some(merged.item));
+            return true;
+        }
+        let mut local = local.clone();
+        local.creation_date = remote.creation_date;
+
+        // Keep our edit queued, so it isn't lost before the user gets to it.
+        self.inv.requeue(id);
+        self.conflicts.retain(|c| c.id != id);
+        self.conflicts.push(ItemConflict {
+            id,
+            local,
+            remote,
+            merged,
+        });
+        false
+    }
+
+    pub fn try_sync_server(&mut self, download: bool) -> Result<(), ServerErr> {
+        if self.server.is_none() {
+            return Ok(());
+        }
 
         // before obtaining inv data from server, make sure we have updated the server of any changes we have made locally
-        for change in self.inv.consume_changes() {
-            match change {
-                InvChange::AddedItem(id) | InvChange::ModifiedItem(id) => {
-                    let item = self.inv.get_item(&id).unwrap();
-                    server.insert_item(id, item).map_err(ServerErr::OtherIo)?
-                }
-                InvChange::DeletedItem(id) => server.remove_item(id).map_err(ServerErr::OtherIo)?,
-            };
+        let mut retry = false;
+        for (id, remote) in self.upload_changes()? {
+            retry |= self.handle_conflict(id, remote);
+        }
+        if retry {
+            // Upload what we merged. Anything that conflicts again is left to the user.
+            for (id, remote) in self.upload_changes()? {
+                self.handle_conflict(id, remote);
+            }
         }
 
+        let server = self.server.as_mut().unwrap();
         if download {
-            let server_inv = server.get_inv()?;
-            self.inv.r#override(server_inv);
+            let delta = server.get_changes(self.inv.revision)?;
+            self.inv.apply_delta(delta);
+            // Anything pushed before the download is already part of it.
+            server.take_events().for_each(drop);
         }
         Ok(())
     }
 
+    /// Applies the changes other clients have made since the last frame.
+    pub fn poll_server_events(&mut self) {
+        let Some(server) = &mut self.server else {
+            return;
+        };
+        match server.poll_events() {
+            Ok(events) => {
+                for event in events {
+                    if let InvEvent::ServerShutdown = event {
+                        self.msg_popup("The server shut down");
+                        self.server = None;
+                        return;
+                    }
+                    self.inv.apply_event(event);
+                }
+            }
+            Err(err) => {
+                self.msg_popup(format!("Lost connection to server : {err}"));
+                self.server = None;
+            }
+        }
+    }
+
+    fn connected_server(&mut self) -> Result<&mut Server, ServerErr> {
+        self.server
+            .as_mut()
+            .ok_or_else(|| ServerErr::OperationFailed("Not connected to a server".into()))
+    }
+
+    pub fn get_clients(&mut self) -> Result<Vec<ClientInfo>, ServerErr> {
+        self.connected_server()?.get_clients()
+    }
+
+    pub fn get_trash(&mut self) -> Result<Vec<(Id, Trashed)>, ServerErr> {
+        self.connected_server()?.get_trash()
+    }
+
+    /// Moves `item` out of the server's trash and back into our inv.
+    pub fn restore_trashed(&mut self, id: Id, mut item: Item) -> Result<(), ServerErr> {
+        item.revision = self.connected_server()?.restore_item(id)?;
+        self.inv
+            .apply_event(InvEvent::ItemInserted(id, Box::new(item)));
+        Ok(())
+    }
+
+    pub fn get_item_history(&mut self, id: Id) -> Result<Vec<ItemVersion>, ServerErr> {
+        self.connected_server()?.get_item_history(id)
+    }
+
+    pub fn purge_trashed(&mut self, id: Id) -> Result<(), ServerErr> {
+        self.connected_server()?.purge_item(id)
+    }
+
+    /// Stores a picture we took, to be uploaded with the first item using it.
+    pub fn add_picture(&mut self, pic: &Picture) -> std::io::Result<PictureId> {
+        let id = self.pictures.add(pic)?;
+        self.inv.queue_picture(id);
+        Ok(id)
+    }
+
+    /// Whether we or the server have picture `id`. Asks the server for its thumbnail if we don't.
+    pub fn has_picture(&mut self, id: &PictureId) -> bool {
+        if self.pictures.contains(id, Size::Full) || self.pictures.contains(id, Size::Thumbnail) {
+            return true;
+        }
+        if let Some(server) = &mut self.server {
+            if let Err(err) = self.pictures.fetch(id, Size::Thumbnail, server) {
+                log::warn!("Failed to fetch thumbnail of picture {id} : {err}");
+            }
+        }
+        self.pictures.contains(id, Size::Thumbnail)
+    }
+
+    /// What our account is allowed to do, if we are connected.
+    pub fn role(&self) -> Option<Role> {
+        self.server.as_ref().map(Server::role)
+    }
+
     pub fn try_connect_to_server(&self) -> Result<Server, ServerErr> {
-        let stream = TcpStream::connect_timeout(
+        let tcp = std::net::TcpStream::connect_timeout(
             &std::net::SocketAddr::from((
                 self.settings
                     .server_address
@@ -104,8 +260,13 @@ impl App {
             )),
             std::time::Duration::from_secs(5),
         )?;
-        stream.set_read_timeout(Some(std::time::Duration::from_secs(3)))?;
-        Server::connect(stream, &self.settings.name)
+        tcp.set_read_timeout(Some(std::time::Duration::from_secs(3)))?;
+        let stream = match self.settings.server_fingerprint.trim() {
+            "" => Stream::Plain(tcp),
+            fingerprint => tls::connect(tcp, fingerprint)
+                .map_err(|err| ServerErr::OperationFailed(format!("TLS failed : {err}")))?,
+        };
+        Server::connect(stream, &self.settings.name, &self.settings.password)
     }
 
     pub fn connect_to_server(&mut self) {
@@ -123,7 +284,10 @@ impl App {
 }
 impl jano::egui_app::EguiApp for App {
     fn on_picture_taken(&mut self, _egui: &Option<Egui>, pic: jano::Picture) {
-        self.curr_page_mut().on_picture_taken(pic);
+        match self.add_picture(&to_inv_pic(pic)) {
+            Ok(id) => self.curr_page_mut().on_picture_taken(id),
+            Err(err) => self.msg_popup(format!("Failed to store picture : {err}")),
+        }
     }
 
     fn on_resume(&mut self) {
@@ -137,9 +301,24 @@ impl jano::egui_app::EguiApp for App {
             }
         }
         if let Ok(bytes) = std::fs::read(&self.save_dirs.inv) {
-            match bincode::deserialize(&bytes) {
-                Ok(inv) => self.inv = inv,
-                Err(err) => log::error!("Failed to parse inv: {err:?}"),
+            match save::decode_local(&bytes) {
+                Ok(decoded) => {
+                    // An inv saved by an older release may still hold its pictures.
+                    for encoded in decoded.pictures {
+                        if let Err(err) = self.pictures.add_encoded(&encoded) {
+                            log::warn!("Failed to store a picture from the saved inv : {err}");
+                        }
+                    }
+                    self.inv = decoded.inv;
+                    self.inv_unreadable = false;
+                }
+                Err(err) => {
+                    log::error!("Failed to parse inv: {err}");
+                    self.inv_unreadable = true;
+                    self.msg_popup(format!(
+                        "Failed to read the saved inventory, so changes won't be saved : {err}"
+                    ));
+                }
             }
         }
 
@@ -161,14 +340,23 @@ impl jano::egui_app::EguiApp for App {
             ),
         }
 
-        let inv = bincode::serialize(&self.inv).unwrap();
-        match std::fs::write(&self.save_dirs.inv, inv) {
+        if self.inv_unreadable {
+            log::warn!(
+                "Not saving inv over {:?}, which couldn't be read",
+                self.save_dirs.inv
+            );
+            return;
+        }
+        let inv = save::encode_local(&self.inv);
+        match save::write_atomic(&self.save_dirs.inv, &inv) {
             Ok(_) => log::info!("Saved inv to {:?}", self.save_dirs.inv),
             Err(err) => log::warn!("Failed to save inv to {:?} : {err:?}", self.save_dirs.inv),
         }
     }
 
     fn draw_frame(&mut self, egui: &mut Egui, ctx: &egui::Context, _stats: FrameStats) {
+        self.poll_server_events();
+
         let mut out = UiOutput::default();
         self.show(egui, ctx, &mut out);
 
@@ -211,6 +399,19 @@ impl jano::egui_app::EguiApp for App {
         }
         self.focused_text_field = out.focused_text_field;
 
+        if let Some(server) = &mut self.server {
+            for (id, size) in &out.fetch_pictures {
+                if let Err(err) = self.pictures.fetch(id, *size, server) {
+                    log::warn!("Failed to fetch picture {id} : {err}");
+                }
+            }
+        }
+        // A request that timed out part way leaves the connection unusable.
+        if self.server.as_ref().is_some_and(Server::is_lost) {
+            self.msg_popup("Lost connection to server");
+            self.server = None;
+        }
+
         if let Some(text) = out.copy_text {
             if let Err(err) = jano::set_clipboard_content(&text) {
                 log::warn!("Error copying text: {err:?}");
@@ -248,6 +449,13 @@ impl App {
             });
         });
         egui::TopBottomPanel::bottom("bottom").show(ctx, |ui| {
+            if !self.conflicts.is_empty()
+                && ui
+                    .button(format!("{} conflicting edits", self.conflicts.len()))
+                    .clicked
+            {
+                out.push_page = Some(Box::<ConflictsPage>::default());
+            }
             if self.server.is_none() {
                 ui.horizontal(|ui| {
                     ui.label("Failed to connect to server");
@@ -15,6 +15,7 @@ fn android_main(android: AndroidApp) {
 struct SaveDirs {
     settings: PathBuf,
     inv: PathBuf,
+    pictures: PathBuf,
 }
 impl SaveDirs {
     fn new() -> Self {
@@ -22,6 +23,7 @@ impl SaveDirs {
         Self {
             settings: dir.join("settings.data"),
             inv: dir.join("inv.data"),
+            pictures: dir.join("pictures"),
         }
     }
 }
@@ -1,5 +1,13 @@
 use crate::app::App;
-use crate::inv::{to_jano_pic, Id, Inv, Item, Listing, Listings, Platform, Usd};
+use crate::inv::{
+    to_jano_pic, Gallery, Id, Inv, InvStats, Item, Listing, Listings, Photo, PictureId, Platform,
+};
+use inv_common::audit::{Action, ItemVersion};
+use inv_common::auth::Role;
+use inv_common::inv::Trashed;
+use inv_common::picture::{PictureCache, Size};
+use inv_common::spreadsheet::{self, Import};
+use inv_common::ClientInfo;
 
 use jano::egui::{self, Response, ScrollArea, Ui};
 use jano::egui_app::Egui;
@@ -26,6 +34,26 @@ pub fn text_edit(ui: &mut Ui, output: &mut UiOutput, text: &mut String) -> Respo
     rs
 }
 
+/// The texture of the `size` version of picture `id`, at its own size, once it is cached.
+/// Until then it is asked for in `output`.
+pub fn picture_texture(
+    egui: &mut Egui,
+    output: &mut UiOutput,
+    pictures: &mut PictureCache,
+    id: &PictureId,
+    size: Size,
+) -> Option<egui::load::SizedTexture> {
+    if pictures.is_missing(id, size) {
+        output.fetch_pictures.push((*id, size));
+        return None;
+    }
+    let pic = pictures.get(id, size)?;
+    let pic_size = egui::vec2(pic.size[0] as f32, pic.size[1] as f32);
+    let handle =
+        egui.obtain_tex_handle_for_pic(egui::Id::new((id, size)), &to_jano_pic(pic.clone()));
+    Some(egui::load::SizedTexture::new(handle.id(), pic_size))
+}
+
 #[derive(Default, Clone, Copy, Debug)]
 pub enum ItemSort {
     #[default]
@@ -100,6 +128,8 @@ pub struct UiOutput {
     pub focused_text_field: Option<TextFieldInfo>,
     pub pop_page: bool,
     pub push_page: Option<Box<dyn Page>>,
+    /// Pictures to fetch from the server, as they are needed but not cached.
+    pub fetch_pictures: Vec<(PictureId, Size)>,
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, Default)]
@@ -189,7 +219,7 @@ impl UiTheme {
 }
 
 pub trait Page {
-    fn on_picture_taken(&mut self, _pic: jano::Picture) {}
+    fn on_picture_taken(&mut self, _pic: PictureId) {}
     fn title(&self) -> String;
     #[rustfmt::skip]
     fn has_back_button(&self) -> bool { true }
@@ -218,6 +248,15 @@ impl Page for HomePage {
         if ui.button("Stats").clicked {
             out.push_page = Some(Box::<StatsPage>::default());
         }
+        if ui.button("Spreadsheets").clicked {
+            out.push_page = Some(Box::<SpreadsheetPage>::default());
+        }
+        if ui.button("Connected Users").clicked {
+            out.push_page = Some(Box::<ClientsPage>::default());
+        }
+        if ui.button("Trash").clicked {
+            out.push_page = Some(Box::<TrashPage>::default());
+        }
     }
 }
 
@@ -357,12 +396,14 @@ impl Page for ItemListPage {
                     ui.painter()
                         .rect_stroke(rect, 10.0, egui::Stroke::new(1.0, color));
 
-                    if let Some(pic) = &item.picture {
-                        let handle = egui.obtain_tex_handle_for_pic(
-                            egui::Id::new(id.0),
-                            &to_jano_pic(pic.clone()),
-                        );
-                        let sized_image = egui::load::SizedTexture::new(handle.id(), pic_size2);
+                    let texture = (item.gallery.cover())
+                        .filter(|_| ui.is_rect_visible(rect))
+                        .and_then(|photo| {
+                            let pic = &photo.picture;
+                            picture_texture(egui, out, &mut app.pictures, pic, Size::Thumbnail)
+                        });
+                    if let Some(texture) = texture {
+                        let sized_image = egui::load::SizedTexture::new(texture.id, pic_size2);
                         let image = egui::Image::from_texture(sized_image).rounding(pic_rounding);
                         image.paint_at(ui, egui::Rect::from_min_size(rect.min, pic_size2));
                     }
@@ -397,7 +438,7 @@ impl Page for ItemListPage {
                     ui2.label(&format!("${}", item.est_cost));
 
                     if rs.clicked {
-                        out.push_page = Some(Box::new(ItemDetailsPage(*id)));
+                        out.push_page = Some(Box::new(ItemDetailsPage::new(*id)));
                     }
                     ui.add_space(1.0);
                 }
@@ -406,13 +447,31 @@ impl Page for ItemListPage {
     }
 }
 
-pub struct ItemDetailsPage(Id);
+/// How far a photo has to be dragged sideways to swipe to the next one.
+const SWIPE_DISTANCE: f32 = 40.0;
+
+pub struct ItemDetailsPage {
+    id: Id,
+    /// Index of the photo being shown in the item's gallery.
+    photo: usize,
+    /// How far the photo has been dragged sideways so far.
+    swipe: f32,
+}
+impl ItemDetailsPage {
+    pub fn new(id: Id) -> Self {
+        Self {
+            id,
+            photo: 0,
+            swipe: 0.0,
+        }
+    }
+}
 impl Page for ItemDetailsPage {
     #[rustfmt::skip]
-    fn title(&self) -> String { format!("Item Details - {}", self.0.0) }
+    fn title(&self) -> String { format!("Item Details - {}", self.id.0) }
 
     fn show2(&mut self, ui: &mut Ui, out: &mut UiOutput, app: &mut App, egui: &mut Egui) {
-        let id = self.0;
+        let id = self.id;
         let pic_size = egui::vec2(50.0, 50.0);
         let pic_rounding = 10.0;
         ScrollArea::vertical().show(ui, |ui| {
@@ -421,16 +480,25 @@ impl Page for ItemDetailsPage {
                 return;
             };
 
-            let _pic_rs = match &item.picture {
-                Some(pic) => {
-                    let handle = egui
-                        .obtain_tex_handle_for_pic(egui::Id::new(id.0), &to_jano_pic(pic.clone()));
-                    let sized_image = egui::load::SizedTexture::new(handle.id(), pic_size);
+            let photos = item.gallery.photos();
+            let last = photos.len().saturating_sub(1);
+            let photo_idx = self.photo.min(last);
+            // The thumbnail stands in until the whole picture has been fetched.
+            let texture = photos.get(photo_idx).and_then(|photo| {
+                let pic = &photo.picture;
+                picture_texture(egui, out, &mut app.pictures, pic, Size::Full)
+                    .or_else(|| picture_texture(egui, out, &mut app.pictures, pic, Size::Thumbnail))
+            });
+            let pic_rs = match texture {
+                Some(texture) => {
+                    let w = ui.available_width();
+                    let h = w * texture.size.y / texture.size.x;
+                    let sized_image = egui::load::SizedTexture::new(texture.id, egui::vec2(w, h));
                     let image = egui::Image::from_texture(sized_image);
-                    ui.add(image.sense(egui::Sense::click()).rounding(pic_rounding))
+                    ui.add(image.sense(egui::Sense::drag()).rounding(pic_rounding))
                 }
                 None => {
-                    let (rect, response) = ui.allocate_exact_size(pic_size, egui::Sense::click());
+                    let (rect, response) = ui.allocate_exact_size(pic_size, egui::Sense::drag());
                     if ui.is_rect_visible(rect) {
                         ui.painter().rect_stroke(
                             rect,
@@ -441,6 +509,31 @@ impl Page for ItemDetailsPage {
                     response
                 }
             };
+            // Swiping left shows the next photo, swiping right the one before.
+            if pic_rs.dragged() {
+                self.swipe += pic_rs.drag_delta().x;
+            } else {
+                if self.swipe <= -SWIPE_DISTANCE {
+                    self.photo = (photo_idx + 1).min(last);
+                } else if self.swipe >= SWIPE_DISTANCE {
+                    self.photo = photo_idx.saturating_sub(1);
+                }
+                self.swipe = 0.0;
+            }
+            if photos.len() > 1 {
+                ui.horizontal(|ui| {
+                    if ui.button("<").clicked {
+                        self.photo = photo_idx.saturating_sub(1);
+                    }
+                    ui.label(format!("{} / {}", photo_idx + 1, photos.len()));
+                    if ui.button(">").clicked {
+                        self.photo = (photo_idx + 1).min(last);
+                    }
+                });
+            }
+            if let Some(photo) = photos.get(photo_idx).filter(|p| !p.caption.is_empty()) {
+                ui.label(photo.caption.as_str());
+            }
 
             ui.horizontal(|ui| {
                 if ui.button("📋").clicked() {
@@ -507,15 +600,88 @@ impl Page for ItemDetailsPage {
                 if ui.button("clone").clicked {
                     out.push_page = Some(Box::new(EditItemPage::new(Id::new())));
                 }
+                if ui.button("history").clicked {
+                    out.push_page = Some(Box::new(ItemHistoryPage::new(id)));
+                }
             });
         });
     }
 }
 
+pub struct ItemHistoryPage {
+    id: Id,
+    /// Newest first.
+    versions: Option<Vec<ItemVersion>>,
+}
+impl ItemHistoryPage {
+    pub fn new(id: Id) -> Self {
+        Self { id, versions: None }
+    }
+}
+impl Page for ItemHistoryPage {
+    #[rustfmt::skip]
+    fn title(&self) -> String { format!("Item History - {}", self.id.0) }
+
+    fn show(&mut self, ui: &mut Ui, out: &mut UiOutput, app: &mut App) {
+        if ui.button("refresh").clicked {
+            self.versions = None;
+        }
+        if self.versions.is_none() {
+            match app.get_item_history(self.id) {
+                Ok(mut versions) => {
+                    versions.reverse();
+                    self.versions = Some(versions);
+                }
+                Err(err) => {
+                    app.msg_popup(format!("Failed to get item history : {err}"));
+                    self.versions = Some(vec![]);
+                }
+            }
+        }
+        ui.separator();
+
+        let mut revert = None;
+        ScrollArea::vertical().show(ui, |ui| {
+            for (idx, version) in self.versions.iter().flatten().enumerate() {
+                ui.group(|ui| {
+                    ui.label(format!(
+                        "{} by {}",
+                        display_date(version.time),
+                        version.user
+                    ));
+                    ui.label(match version.action {
+                        Action::RemoveItem => String::from("Moved to the trash"),
+                        Action::PurgeItem => String::from("Deleted forever"),
+                        Action::RestoreItem => String::from("Restored from the trash"),
+                        Action::Undo(seq) => format!("Undid change #{seq}"),
+                        Action::InsertItem if version.changed.is_empty() => String::from("Created"),
+                        Action::InsertItem => format!("Changed: {}", version.changed.join(", ")),
+                    });
+                    let Some(item) = &version.item else {
+                        return;
+                    };
+                    ui.label(format!("{} - ${}", item.name, item.est_cost));
+                    // The newest version is what the item already is.
+                    if idx > 0 && ui.button("Revert to this").clicked {
+                        revert = Some(item.copy());
+                    }
+                });
+            }
+        });
+
+        if let Some(item) = revert {
+            app.inv.insert_item(self.id, item);
+            app.msg_popup("Reverted item");
+            out.sync_server = true;
+            out.pop_page = true;
+        }
+    }
+}
+
 pub struct ItemTemplate {
     location: String,
     listings: Listings,
-    picture: Option<jano::Picture>,
+    gallery: Gallery,
     name: String,
     desc: String,
     count: String,
@@ -534,7 +700,7 @@ impl ItemTemplate {
         Self {
             location: item.location,
             listings: item.listings,
-            picture: item.picture.clone().map(to_jano_pic),
+            gallery: item.gallery,
             name: item.name,
             desc: item.desc,
             count: item.count.to_string(),
@@ -568,7 +734,7 @@ impl ItemTemplate {
 
         item.location = self.location.clone();
         item.listings = self.listings.clone();
-        item.picture = self.picture.clone().map(crate::inv::to_inv_pic);
+        item.gallery = self.gallery.clone();
         item.name = self.name.clone();
         item.desc = self.desc.clone();
         item.condition = self.condition.clone();
@@ -615,27 +781,29 @@ pub fn display_date(mut date: SystemTime) -> String {
 pub struct EditItemPage {
     pub id: Id,
     pub template: ItemTemplate,
-    pub pic_options: bool,
+    /// The photo whose options are shown.
+    pub selected_photo: Option<usize>,
 }
 impl EditItemPage {
     pub fn new_w_item(id: Id, item: Item) -> Self {
         Self {
             id,
             template: ItemTemplate::from_item(item),
-            pic_options: false,
+            selected_photo: None,
         }
     }
     pub fn new(id: Id) -> Self {
         Self {
             id,
             template: ItemTemplate::from_item(Item::default()),
-            pic_options: false,
+            selected_photo: None,
         }
     }
 }
 impl Page for EditItemPage {
-    fn on_picture_taken(&mut self, pic: jano::Picture) {
-        self.template.picture = Some(pic)
+    fn on_picture_taken(&mut self, pic: PictureId) {
+        self.template.gallery.push(Photo::new(pic));
+        self.selected_photo = Some(self.template.gallery.len() - 1);
     }
 
     #[rustfmt::skip]
@@ -645,7 +813,7 @@ impl Page for EditItemPage {
     fn has_back_button(&self) -> bool { false }
     fn show2(&mut self, ui: &mut Ui, out: &mut UiOutput, app: &mut App, egui: &mut Egui) {
         #[rustfmt::skip]
-        let Self { id, template: item, .. } = self;
+        let Self { id, template: item, selected_photo } = self;
         let id = *id;
 
         fn add_field(ui: &mut Ui, out: &mut UiOutput, label: &str, value: &mut String, w: f32) {
@@ -685,45 +853,102 @@ impl Page for EditItemPage {
         }
 
         egui::ScrollArea::vertical().show(ui, |ui| {
-            ui.horizontal(|ui| {
+            ui.horizontal_wrapped(|ui| {
                 let pic_size = egui::vec2(85.0, 85.0);
                 let pic_rounding = 20.0;
-                let pic_rs = match &item.picture {
-                    Some(pic) => {
-                        let handle = egui.obtain_tex_handle_for_pic(egui::Id::new(id.0), pic);
-                        let sized_image = egui::load::SizedTexture::new(handle.id(), pic_size);
-                        let image = egui::Image::from_texture(sized_image);
-                        ui.add(image.sense(egui::Sense::click()).rounding(pic_rounding))
-                    }
-                    None => {
-                        let (rect, response) =
-                            ui.allocate_exact_size(pic_size, egui::Sense::click());
-                        if ui.is_rect_visible(rect) {
-                            ui.painter().rect_stroke(
-                                rect,
-                                pic_rounding,
-                                egui::Stroke::new(2.0, egui::Color32::GRAY),
-                            );
+                for (idx, photo) in item.gallery.photos().iter().enumerate() {
+                    let pic = &photo.picture;
+                    let texture =
+                        picture_texture(egui, out, &mut app.pictures, pic, Size::Thumbnail);
+                    let pic_rs = match texture {
+                        Some(texture) => {
+                            let sized_image = egui::load::SizedTexture::new(texture.id, pic_size);
+                            let image = egui::Image::from_texture(sized_image);
+                            ui.add(image.sense(egui::Sense::click()).rounding(pic_rounding))
+                        }
+                        None => {
+                            let (rect, response) =
+                                ui.allocate_exact_size(pic_size, egui::Sense::click());
+                            if ui.is_rect_visible(rect) {
+                                ui.painter().rect_stroke(
+                                    rect,
+                                    pic_rounding,
+                                    egui::Stroke::new(2.0, egui::Color32::GRAY),
+                                );
+                            }
+                            response
                         }
-                        response
+                    };
+                    if *selected_photo == Some(idx) {
+                        let color = ui.visuals().selection.bg_fill;
+                        ui.painter().rect_stroke(
+                            pic_rs.rect,
+                            pic_rounding,
+                            egui::Stroke::new(3.0, color),
+                        );
                     }
-                };
-                self.pic_options ^= pic_rs.clicked;
+                    if idx == item.gallery.cover_idx() {
+                        ui.painter().text(
+                            pic_rs.rect.left_top() + egui::vec2(6.0, 4.0),
+                            egui::Align2::LEFT_TOP,
+                            "★",
+                            egui::FontId::proportional(16.0),
+                            egui::Color32::YELLOW,
+                        );
+                    }
+                    if pic_rs.clicked {
+                        let selected = *selected_photo == Some(idx);
+                        *selected_photo = (!selected).then_some(idx);
+                    }
+                }
+
+                let (rect, add_rs) = ui.allocate_exact_size(pic_size, egui::Sense::click());
+                if ui.is_rect_visible(rect) {
+                    let stroke = egui::Stroke::new(2.0, egui::Color32::GRAY);
+                    ui.painter().rect_stroke(rect, pic_rounding, stroke);
+                    ui.painter().text(
+                        rect.center(),
+                        egui::Align2::CENTER_CENTER,
+                        "+",
+                        egui::FontId::proportional(32.0),
+                        egui::Color32::GRAY,
+                    );
+                }
+                if add_rs.clicked {
+                    if let Err(err) = jano::take_picture() {
+                        app.msg_popup(format!("{err:?}"));
+                    }
+                }
             });
 
-            if self.pic_options {
+            if let Some(idx) = (*selected_photo).filter(|idx| *idx < item.gallery.len()) {
                 ui.horizontal(|ui| {
                     ui.label("|");
-                    if ui.button("retake").clicked {
-                        if let Err(err) = jano::take_picture() {
-                            app.msg_popup(format!("{err:?}"));
-                        }
-                        self.pic_options = false;
+                    if ui.button("<").clicked && idx > 0 {
+                        item.gallery.move_photo(idx, idx - 1);
+                        *selected_photo = Some(idx - 1);
+                    }
+                    if ui.button(">").clicked && idx + 1 < item.gallery.len() {
+                        item.gallery.move_photo(idx, idx + 1);
+                        *selected_photo = Some(idx + 1);
+                    }
+                    if ui.button("cover").clicked {
+                        item.gallery.set_cover(idx);
                     }
                     if ui.button("remove").clicked {
-                        item.picture = None;
+                        item.gallery.remove(idx);
+                        *selected_photo = None;
                     }
                 });
+                if let Some(photo) = item.gallery.photos().get(idx) {
+                    let mut caption = photo.caption.clone();
+                    ui.horizontal(|ui| {
+                        if text_edit(ui, out, &mut caption).changed() {
+                            item.gallery.set_caption(idx, caption);
+                        }
+                        ui.label("caption");
+                    });
+                }
             }
 
             add_field2(ui, out, "name", &mut item.name, 500.0);
@@ -786,7 +1011,7 @@ impl Page for EditItemPage {
             ui.separator();
             ui.horizontal(|ui| {
                 ui.menu_button("Delete", |ui| {
-                    ui.label("Are you sure? ");
+                    ui.label("Move it to the trash? ");
                     if ui.button("Yes").clicked() {
                         ui.close_menu();
                         app.inv.remove_item(&id);
@@ -853,10 +1078,30 @@ impl Page for SettingsPage {
             ui.label("Server port: ");
             ui.add(egui::Slider::new(&mut app.settings.server_port, 0..=26000));
         });
+        ui.horizontal(|ui| {
+            ui.label("Server fingerprint: ");
+            text_edit(ui, out, &mut app.settings.server_fingerprint);
+        });
+        ui.label("Leave the fingerprint empty if the server doesn't use TLS.");
         ui.horizontal(|ui| {
             ui.label("Username: ");
             text_edit(ui, out, &mut app.settings.name);
         });
+        ui.horizontal(|ui| {
+            ui.label("Password: ");
+            let rs = ui.add(egui::TextEdit::singleline(&mut app.settings.password).password(true));
+            if rs.has_focus() {
+                out.focused_text_field =
+                    Some(TextFieldInfo::new(app.settings.password.clone(), [0, 0]));
+            }
+        });
+        match app.role() {
+            Some(role) => ui.label(format!("Logged in as {} ({role})", app.settings.name)),
+            None => ui.label("Not logged in"),
+        };
+        if ui.button("Log in").clicked {
+            out.reconnect_to_server = true;
+        }
     }
 }
 
@@ -867,26 +1112,270 @@ impl Page for StatsPage {
     fn title(&self) -> String { String::from("Stats") }
 
     fn show(&mut self, ui: &mut Ui, _out: &mut UiOutput, app: &mut App) {
-        let mut unsold_count = 0;
-        let mut sold_count = 0;
-        let mut unsold_cost = Usd(0);
-        let mut sold_cost = Usd(0);
+        let stats = InvStats::of(app.inv.items().map(|(_, item)| item));
+        ui.label(format!("Total listings: {}", stats.listings));
 
-        for (_, item) in app.inv.items() {
-            unsold_count += item.count - item.sold_count();
-            sold_count += item.sold_count();
-            unsold_cost.0 += item.est_cost.0 * item.count - item.est_cost.0 * item.sold_count();
-            sold_cost.0 += item.est_cost.0 * item.sold_count();
-        }
+        ui.label(format!(
+            "Total items: {} ({})",
+            stats.total_count(),
+            stats.total_cost()
+        ));
+        ui.label(format!("Unsold items: {} ({})", stats.unsold_count, stats.unsold_cost));
+        ui.label(format!("Sold items: {} ({})", stats.sold_count, stats.sold_cost));
+    }
+}
 
-        ui.label(format!("Total listings: {}", app.inv.item_count()));
+/// Moves items in and out of spreadsheets as CSV, through the clipboard.
+#[derive(Default)]
+pub struct SpreadsheetPage {
+    /// What importing the last copied cells would do, or why it couldn't be read.
+    import: Option<Result<Import, String>>,
+    /// Photos of the import, by row, whose picture neither we nor the server have.
+    missing_pictures: Vec<(usize, PictureId)>,
+}
+impl Page for SpreadsheetPage {
+    #[rustfmt::skip]
+    fn title(&self) -> String { String::from("Spreadsheets") }
+
+    fn show(&mut self, ui: &mut Ui, out: &mut UiOutput, app: &mut App) {
+        if ui.button("Copy items as CSV").clicked {
+            let mut csv = vec![];
+            match spreadsheet::export(&app.inv, &mut csv) {
+                Ok(()) => out.copy_text = Some(String::from_utf8_lossy(&csv).into_owned()),
+                Err(err) => app.msg_popup(format!("Failed to export items : {err}")),
+            }
+        }
+        ui.label("Copy spreadsheet cells with their headers, then check them.");
+        if ui.button("Check copied cells").clicked {
+            let csv = jano::get_clipboard_content();
+            let import = spreadsheet::import(&app.inv, csv.as_bytes());
+            // The server refuses items using pictures it doesn't have, so they are caught here.
+            self.missing_pictures = (import.iter().flat_map(|import| &import.items))
+                .flat_map(|imported| {
+                    let pictures = imported.item.gallery.pictures();
+                    pictures.map(move |picture| (imported.row, *picture))
+                })
+                .filter(|(_, picture)| !app.has_picture(picture))
+                .collect();
+            self.import = Some(import.map_err(|err| err.to_string()));
+        }
 
+        let import = match &self.import {
+            None => return,
+            Some(Err(err)) => {
+                ui.label(format!("Couldn't read the cells : {err}"));
+                return;
+            }
+            Some(Ok(import)) => import,
+        };
         ui.label(format!(
-            "Total items: {} ({})",
-            unsold_count + sold_count,
-            Usd(unsold_cost.0 + sold_cost.0)
+            "{} new items, {} changed, {} unchanged",
+            import.new_count(),
+            import.changed_count(),
+            import.unchanged
         ));
-        ui.label(format!("Unsold items: {unsold_count} ({unsold_cost})"));
-        ui.label(format!("Sold items: {sold_count} ({sold_cost})"));
+        let can_import = import.errors.is_empty()
+            && self.missing_pictures.is_empty()
+            && !import.items.is_empty();
+        let import_clicked = can_import && ui.button("Import").clicked;
+        ScrollArea::vertical().show(ui, |ui| {
+            for err in &import.errors {
+                ui.colored_label(egui::Color32::RED, err.to_string());
+            }
+            for (row, picture) in &self.missing_pictures {
+                ui.colored_label(
+                    egui::Color32::RED,
+                    format!("row {row} : picture {picture} isn't on this phone or the server"),
+                );
+            }
+            for header in &import.ignored_columns {
+                ui.label(format!("Ignored column {header:?}"));
+            }
+            for imported in &import.items {
+                match &imported.changed {
+                    Some(fields) => ui.label(format!(
+                        "row {} : change {:?} ({})",
+                        imported.row,
+                        imported.item.name,
+                        fields.join(", ")
+                    )),
+                    None => ui.label(format!(
+                        "row {} : add {:?}",
+                        imported.row, imported.item.name
+                    )),
+                };
+            }
+        });
+
+        if import_clicked {
+            let Some(Ok(import)) = self.import.take() else {
+                return;
+            };
+            let count = import.items.len();
+            for imported in import.items {
+                app.inv.insert_item(imported.id, imported.item);
+            }
+            out.sync_server = true;
+            app.msg_popup(format!("Imported {count} items"));
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ClientsPage {
+    clients: Option<Vec<ClientInfo>>,
+}
+impl Page for ClientsPage {
+    #[rustfmt::skip]
+    fn title(&self) -> String { String::from("Connected Users") }
+
+    fn show(&mut self, ui: &mut Ui, _out: &mut UiOutput, app: &mut App) {
+        if ui.button("refresh").clicked {
+            self.clients = None;
+        }
+        if self.clients.is_none() {
+            match app.get_clients() {
+                Ok(clients) => self.clients = Some(clients),
+                Err(err) => {
+                    app.msg_popup(format!("Failed to get users : {err}"));
+                    self.clients = Some(vec![]);
+                }
+            }
+        }
+        ui.separator();
+
+        for client in self.clients.iter().flatten() {
+            ui.group(|ui| {
+                ui.label(&client.name);
+                ui.label(format!("Role: {}", client.role));
+                ui.label(format!("Release: {}", client.release));
+                ui.label(format!("Connected: {}", display_date(client.connected)));
+            });
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct TrashPage {
+    trash: Option<Vec<(Id, Trashed)>>,
+}
+impl Page for TrashPage {
+    #[rustfmt::skip]
+    fn title(&self) -> String { String::from("Trash") }
+
+    fn show(&mut self, ui: &mut Ui, _out: &mut UiOutput, app: &mut App) {
+        if ui.button("refresh").clicked {
+            self.trash = None;
+        }
+        if self.trash.is_none() {
+            match app.get_trash() {
+                Ok(mut trash) => {
+                    trash.sort_by_key(|(_, trashed)| std::cmp::Reverse(trashed.deleted_at));
+                    self.trash = Some(trash);
+                }
+                Err(err) => {
+                    app.msg_popup(format!("Failed to get trash : {err}"));
+                    self.trash = Some(vec![]);
+                }
+            }
+        }
+        ui.separator();
+
+        let can_purge = app.role() == Some(Role::Admin);
+        let mut restore = None;
+        let mut purge = None;
+        ScrollArea::vertical().show(ui, |ui| {
+            for (idx, (_, trashed)) in self.trash.iter().flatten().enumerate() {
+                ui.group(|ui| {
+                    ui.heading(&trashed.item.name);
+                    ui.label(format!(
+                        "Removed {} by {}",
+                        display_date(trashed.deleted_at),
+                        trashed.deleted_by
+                    ));
+                    ui.horizontal(|ui| {
+                        if ui.button("Restore").clicked {
+                            restore = Some(idx);
+                        }
+                        if can_purge {
+                            ui.menu_button("Delete forever", |ui| {
+                                ui.label("This can't be undone. ");
+                                if ui.button("Yes").clicked() {
+                                    ui.close_menu();
+                                    purge = Some(idx);
+                                }
+                                if ui.button("Cancel").clicked() {
+                                    ui.close_menu();
+                                }
+                            });
+                        }
+                    });
+                });
+            }
+        });
+
+        let trash = self.trash.as_mut().unwrap();
+        if let Some(idx) = restore {
+            let (id, trashed) = trash.remove(idx);
+            if let Err(err) = app.restore_trashed(id, trashed.item) {
+                app.msg_popup(format!("Failed to restore item : {err}"));
+                self.trash = None;
+            }
+        } else if let Some(idx) = purge {
+            let (id, _) = trash.remove(idx);
+            if let Err(err) = app.purge_trashed(id) {
+                app.msg_popup(format!("Failed to delete item : {err}"));
+                self.trash = None;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ConflictsPage {}
+impl Page for ConflictsPage {
+    #[rustfmt::skip]
+    fn title(&self) -> String { String::from("Conflicting Edits") }
+
+    fn show(&mut self, ui: &mut Ui, out: &mut UiOutput, app: &mut App) {
+        if app.conflicts.is_empty() {
+            out.pop_page = true;
+            return;
+        }
+        ui.label("These items were also changed on another device.");
+
+        let mut resolved = None;
+        ScrollArea::vertical().show(ui, |ui| {
+            for (idx, conflict) in app.conflicts.iter().enumerate() {
+                ui.group(|ui| {
+                    ui.heading(&conflict.local.name);
+                    ui.label(format!(
+                        "Changed on both: {}",
+                        conflict.merged.conflicts.join(", ")
+                    ));
+                    ui.horizontal(|ui| {
+                        if ui.button("Keep mine").clicked {
+                            resolved = Some((idx, Some(conflict.local.clone())));
+                        }
+                        if ui.button("Keep theirs").clicked {
+                            resolved = Some((idx, None));
+                        }
+                        if ui.button("Merge").clicked {
+                            resolved = Some((idx, Some(conflict.merged.item.clone())));
+                        }
+                    });
+                });
+            }
+        });
+
+        if let Some((idx, item)) = resolved {
+            let conflict = app.conflicts.remove(idx);
+            let item = item.map(|mut item| {
+                item.creation_date = conflict.remote.creation_date;
+                item
+            });
+            app.inv.resolve_conflict(conflict.id, conflict.remote, item);
+            out.sync_server = true;
+        }
     }
 }
@@ -0,0 +1,473 @@
+//! Append-only record of every change made to the server's inventory.
+//!
+//! The log file starts with [`MAGIC`], followed by a sequence of entries. Each entry is a
+//! big-endian `u32` length, then the `DataVersion` the entry was written in, then the bincode
+//! encoded [`AuditEntry`]. Entries are only ever appended, so the file is read back for queries.
+//! Where each item's entries start is kept in memory, so reading an item's history only reads
+//! those entries.
+//!
+//! Logs from before entries had a version are rewritten by [`AuditLog::upgrade`]. Their entries
+//! can only be told apart by trying each layout in turn. Entries from before pictures were stored
+//! apart from items have their pictures moved into the storage, so undoing them brings the
+//! pictures back. Entries from before items had a gallery get their picture as the only photo.
+
+use crate::inv::{Id, Item, PictureId};
+use crate::merge::changed_fields;
+use crate::save::{self, v4, v5};
+use crate::storage::Storage;
+use crate::DataVersion;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The first bytes of an audit log whose entries have a version.
+pub const MAGIC: &[u8; 4] = b"INVa";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Action {
+    InsertItem,
+    RemoveItem,
+    /// Put an item back the way it was before the entry with this number.
+    Undo(u64),
+    /// Moved an item out of the trash.
+    RestoreItem,
+    /// Deleted an item from the trash for good.
+    PurgeItem,
+}
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InsertItem => f.write_str("InsertItem"),
+            Self::RemoveItem => f.write_str("RemoveItem"),
+            Self::Undo(seq) => write!(f, "Undo #{seq}"),
+            Self::RestoreItem => f.write_str("RestoreItem"),
+            Self::PurgeItem => f.write_str("PurgeItem"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Number of the entry, counting from 1.
+    pub seq: u64,
+    pub time: SystemTime,
+    /// Name of the user who made the change.
+    pub user: String,
+    pub action: Action,
+    pub id: Id,
+    /// The item before the change, `None` if it didn't exist.
+    pub before: Option<Item>,
+    /// The item after the change, `None` if it was removed.
+    pub after: Option<Item>,
+}
+
+/// An `AuditEntry` as written before pictures were stored apart from items.
+#[derive(Deserialize)]
+struct AuditEntryV4 {
+    seq: u64,
+    time: SystemTime,
+    user: String,
+    action: Action,
+    id: Id,
+    before: Option<v4::Item>,
+    after: Option<v4::Item>,
+}
+impl AuditEntryV4 {
+    /// The entry in the current layout, with the pictures of its items encoded and added to `pictures`.
+    fn upgrade(self, pictures: &mut Vec<Vec<u8>>) -> AuditEntry {
+        AuditEntry {
+            seq: self.seq,
+            time: self.time,
+            user: self.user,
+            action: self.action,
+            id: self.id,
+            before: self.before.map(|item| item.upgrade(pictures).into()),
+            after: self.after.map(|item| item.upgrade(pictures).into()),
+        }
+    }
+}
+
+/// An `AuditEntry` as written before items had a gallery.
+#[derive(Deserialize)]
+struct AuditEntryV5 {
+    seq: u64,
+    time: SystemTime,
+    user: String,
+    action: Action,
+    id: Id,
+    before: Option<v5::Item>,
+    after: Option<v5::Item>,
+}
+impl From<AuditEntryV5> for AuditEntry {
+    fn from(old: AuditEntryV5) -> Self {
+        Self {
+            seq: old.seq,
+            time: old.time,
+            user: old.user,
+            action: old.action,
+            id: old.id,
+            before: old.before.map(Item::from),
+            after: old.after.map(Item::from),
+        }
+    }
+}
+
+/// One change to an item, as shown in its history.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ItemVersion {
+    /// Number of the audit log entry this comes from.
+    pub seq: u64,
+    pub time: SystemTime,
+    pub user: String,
+    pub action: Action,
+    /// Fields that differ from the version before. Empty if the item was created or removed.
+    pub changed: Vec<String>,
+    /// The item after the change, `None` if it was removed.
+    pub item: Option<Item>,
+}
+impl From<AuditEntry> for ItemVersion {
+    fn from(entry: AuditEntry) -> Self {
+        let changed = match (&entry.before, &entry.after) {
+            (Some(before), Some(after)) => changed_fields(before, after)
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            _ => vec![],
+        };
+        Self {
+            seq: entry.seq,
+            time: entry.time,
+            user: entry.user,
+            action: entry.action,
+            changed,
+            item: entry.after,
+        }
+    }
+}
+
+/// Which entries to list. Every field that is set has to match.
+#[derive(Default, Debug)]
+pub struct AuditQuery {
+    pub id: Option<Id>,
+    pub user: Option<String>,
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+}
+impl AuditQuery {
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        self.id.is_none_or(|id| id == entry.id)
+            && self.user.as_ref().is_none_or(|user| *user == entry.user)
+            && self.since.is_none_or(|since| entry.time >= since)
+            && self.until.is_none_or(|until| entry.time <= until)
+    }
+}
+
+/// The audit log of a server. Without a path nothing is recorded.
+#[derive(Default)]
+pub struct AuditLog {
+    pub path: Option<PathBuf>,
+    file: Option<File>,
+    next_seq: u64,
+    /// Length of the file, where the next entry will start.
+    len: u64,
+    /// Offsets of the entries about each item, oldest first.
+    offsets: HashMap<Id, Vec<u64>>,
+}
+impl AuditLog {
+    /// Opens the log at `path` for appending, creating it if needed.
+    /// A log from before entries had a version has to be upgraded first, see `upgrade`.
+    ///
+    /// An entry left half written by a crash is cut off, so new entries are appended after the
+    /// last complete one. A complete entry that can't be decoded is an error instead, as cutting
+    /// it off would lose it and every entry after it.
+    pub fn open(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(MAGIC)?;
+            file.sync_data()?;
+            file.seek(SeekFrom::Start(0))?;
+        }
+        let mut reader = BufReader::new(&mut file);
+        if !read_magic(&mut reader)? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("audit log {path:?} is from an older release and has to be upgraded"),
+            ));
+        }
+        let mut valid_len = MAGIC.len() as u64;
+        let mut last_seq = 0;
+        let mut offsets = HashMap::<Id, Vec<u64>>::new();
+        let at = |offset, err: std::io::Error| {
+            let msg = format!("audit log {path:?} at byte {offset} : {err}");
+            std::io::Error::new(err.kind(), msg)
+        };
+        while let Some((entry, len)) = read_entry(&mut reader).map_err(|err| at(valid_len, err))? {
+            offsets.entry(entry.id).or_default().push(valid_len);
+            valid_len += len;
+            last_seq = entry.seq;
+        }
+        if file.metadata()?.len() != valid_len {
+            log::warn!("Truncating incomplete entry at the end of audit log {path:?}");
+            file.set_len(valid_len)?;
+        }
+        Ok(Self {
+            path: Some(path),
+            file: Some(file),
+            next_seq: last_seq + 1,
+            len: valid_len,
+            offsets,
+        })
+    }
+
+    /// Rewrites the log at `path` with a version on each entry, if it is from before entries had one.
+    /// Pictures held in old entries are added to `storage`. Returns whether the log was upgraded.
+    /// It has to be done before `open`, which indexes the upgraded entries.
+    pub fn upgrade(path: &Path, storage: &dyn Storage) -> std::io::Result<bool> {
+        let mut reader = match File::open(path) {
+            Ok(file) => BufReader::new(file),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        let empty = reader.get_ref().metadata()?.len() == 0;
+        if empty || read_magic(&mut reader)? {
+            return Ok(false);
+        }
+        // Read again from the start, as there was no magic.
+        let mut reader = BufReader::new(File::open(path)?);
+        log::info!("Upgrading audit log {path:?}");
+        let mut upgraded = MAGIC.to_vec();
+        let mut offset = 0;
+        let mut pictures = vec![];
+        while let Some(bytes) = read_frame(&mut reader)? {
+            let entry = save::deserialize::<AuditEntry>(&bytes)
+                .or_else(|_| save::deserialize::<AuditEntryV5>(&bytes).map(AuditEntry::from))
+                .or_else(|_| {
+                    save::deserialize::<AuditEntryV4>(&bytes).map(|old| old.upgrade(&mut pictures))
+                })
+                .map_err(|err| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("audit log {path:?} at byte {offset} : undecodable entry : {err}"),
+                    )
+                })?;
+            for encoded in pictures.drain(..) {
+                storage.store_picture(&encoded)?;
+            }
+            upgraded.extend(frame(&bincode::serialize(&entry).unwrap()));
+            offset += 4 + bytes.len() as u64;
+        }
+        save::write_atomic(path, &upgraded)?;
+        Ok(true)
+    }
+
+    /// Rewrites every entry with `f`, for when a change to the inv changes what old entries mean.
+    pub fn rewrite(&mut self, mut f: impl FnMut(&mut AuditEntry)) -> std::io::Result<()> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        let mut rewritten = MAGIC.to_vec();
+        let mut offsets = HashMap::<Id, Vec<u64>>::new();
+        self.for_each(|mut entry| {
+            f(&mut entry);
+            let offset = rewritten.len() as u64;
+            offsets.entry(entry.id).or_default().push(offset);
+            rewritten.extend(frame(&bincode::serialize(&entry).unwrap()));
+        })?;
+        save::write_atomic(&path, &rewritten)?;
+        // The file was replaced, so appending has to go to the new one.
+        self.file = Some(OpenOptions::new().append(true).open(&path)?);
+        self.len = rewritten.len() as u64;
+        self.offsets = offsets;
+        Ok(())
+    }
+
+    /// Appends an entry, returning its number.
+    pub fn record(
+        &mut self,
+        user: &str,
+        action: Action,
+        id: Id,
+        before: Option<&Item>,
+        after: Option<&Item>,
+    ) -> std::io::Result<u64> {
+        let Some(file) = &mut self.file else {
+            return Ok(0);
+        };
+        // Same layout as `AuditEntry`, but borrowing the items so they don't have to be copied.
+        #[derive(Serialize)]
+        struct EntryRef<'a> {
+            seq: u64,
+            time: SystemTime,
+            user: &'a str,
+            action: Action,
+            id: Id,
+            before: Option<&'a Item>,
+            after: Option<&'a Item>,
+        }
+        let seq = self.next_seq;
+        let bytes = bincode::serialize(&EntryRef {
+            seq,
+            time: SystemTime::now(),
+            user,
+            action,
+            id,
+            before,
+            after,
+        })
+        .unwrap();
+        let framed = frame(&bytes);
+        file.write_all(&framed)?;
+        file.sync_data()?;
+        self.offsets.entry(id).or_default().push(self.len);
+        self.len += framed.len() as u64;
+        self.next_seq += 1;
+        Ok(seq)
+    }
+
+    /// Every entry matching `query`, oldest first.
+    pub fn query(&self, query: &AuditQuery) -> std::io::Result<Vec<AuditEntry>> {
+        if let Some(id) = query.id {
+            let mut entries = self.entries_of(id)?;
+            entries.retain(|entry| query.matches(entry));
+            return Ok(entries);
+        }
+        let mut entries = vec![];
+        self.for_each(|entry| {
+            if query.matches(&entry) {
+                entries.push(entry);
+            }
+        })?;
+        Ok(entries)
+    }
+
+    /// Every version of the item `id`, oldest first.
+    pub fn history(&self, id: Id) -> std::io::Result<Vec<ItemVersion>> {
+        let entries = self.entries_of(id)?;
+        Ok(entries.into_iter().map(ItemVersion::from).collect())
+    }
+
+    /// Every entry about the item `id`, oldest first, read through the index.
+    fn entries_of(&self, id: Id) -> std::io::Result<Vec<AuditEntry>> {
+        let (Some(path), Some(offsets)) = (&self.path, self.offsets.get(&id)) else {
+            return Ok(vec![]);
+        };
+        let mut file = File::open(path)?;
+        let mut entries = vec![];
+        for &offset in offsets {
+            file.seek(SeekFrom::Start(offset))?;
+            match read_entry(&mut BufReader::new(&mut file))? {
+                Some((entry, _)) => entries.push(entry),
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!("audit log {path:?} ends before the entry at byte {offset}"),
+                    ))
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The entry numbered `seq`, if there is one.
+    pub fn get(&self, seq: u64) -> std::io::Result<Option<AuditEntry>> {
+        let mut found = None;
+        self.for_each(|entry| {
+            if entry.seq == seq {
+                found = Some(entry);
+            }
+        })?;
+        Ok(found)
+    }
+
+    /// Every picture used by an item in any entry, so undoing a change can bring its pictures back.
+    pub fn pictures(&self) -> std::io::Result<HashSet<PictureId>> {
+        let mut pictures = HashSet::new();
+        self.for_each(|entry| {
+            for item in entry.before.iter().chain(&entry.after) {
+                pictures.extend(item.gallery.pictures().copied());
+            }
+        })?;
+        Ok(pictures)
+    }
+
+    fn for_each(&self, mut f: impl FnMut(AuditEntry)) -> std::io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut reader = BufReader::new(File::open(path)?);
+        read_magic(&mut reader)?;
+        while let Some((entry, _)) = read_entry(&mut reader)? {
+            f(entry);
+        }
+        Ok(())
+    }
+}
+
+/// Reads the start of a log, returning whether it is `MAGIC`.
+fn read_magic<R: Read>(reader: &mut R) -> std::io::Result<bool> {
+    let mut magic = [0u8; 4];
+    match reader.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == *MAGIC),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// An encoded entry as written to the log, with its length and the data version it is in.
+fn frame(encoded: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(encoded.len() + 5);
+    buf.extend_from_slice(&(encoded.len() as u32 + 1).to_be_bytes());
+    buf.push(DataVersion::CURRENT.0);
+    buf.extend_from_slice(encoded);
+    buf
+}
+
+/// Reads the bytes of the next entry, after its length.
+/// Returns `None` at the end of the log, or at an entry that was never finished.
+fn read_frame<R: Read>(reader: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+    let mut len_bytes = [0u8; 4];
+    match reader.read_exact(&mut len_bytes) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_be_bytes(len_bytes);
+    let mut bytes = vec![];
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len as usize {
+        return Ok(None);
+    }
+    Ok(Some(bytes))
+}
+
+/// Reads the next entry and how many bytes it took up.
+/// Returns `None` at the end of the log, or at an entry that was never finished.
+/// Fails on a complete entry that can't be decoded.
+fn read_entry<R: Read>(reader: &mut R) -> std::io::Result<Option<(AuditEntry, u64)>> {
+    let Some(bytes) = read_frame(reader)? else {
+        return Ok(None);
+    };
+    // Entries in a layout older than the current one get an arm here when the layout changes.
+    let entry = match bytes.split_first() {
+        Some((&version, encoded)) if version == DataVersion::CURRENT.0 => {
+            save::deserialize::<AuditEntry>(encoded).map_err(|err| err.to_string())
+        }
+        Some((version, _)) => Err(format!("unknown data version {version}")),
+        None => Err(String::from("no data version")),
+    };
+    match entry {
+        Ok(entry) => Ok(Some((entry, 4 + bytes.len() as u64))),
+        Err(err) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("undecodable entry of {} bytes : {err}", bytes.len()),
+        )),
+    }
+}
@@ -0,0 +1,267 @@
+//! User accounts on the server, and what each of them is allowed to do.
+
+use crate::CmdCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Rounds of SHA-256 applied to a password, to make guessing from a stolen accounts file slow.
+const HASH_ROUNDS: u32 = 100_000;
+/// How many logins are remembered, so each one only pays for `HASH_ROUNDS` once.
+const MAX_REMEMBERED: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum Role {
+    /// Can download the inv, but not change it.
+    ReadOnly = 0,
+    /// Can add, edit and remove items.
+    Editor = 1,
+    /// Can also create backups on the server, and empty the trash.
+    Admin = 2,
+}
+impl Role {
+    pub const ALL: [Self; 3] = [Self::ReadOnly, Self::Editor, Self::Admin];
+
+    pub fn from_u8(v: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|role| *role as u8 == v)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ReadOnly => "read-only",
+            Self::Editor => "editor",
+            Self::Admin => "admin",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|role| role.as_str() == s)
+    }
+
+    /// Whether a client with this role may send `cmd`.
+    pub fn allows(self, cmd: CmdCode) -> bool {
+        match cmd {
+            CmdCode::InsertItem
+            | CmdCode::RemoveItem
+            | CmdCode::ApplyBatch
+            | CmdCode::RestoreItem
+            | CmdCode::PutPicture => self >= Self::Editor,
+            CmdCode::CreateServerBackup | CmdCode::PurgeItem => self >= Self::Admin,
+            _ => true,
+        }
+    }
+}
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub role: Role,
+    salt: [u8; 16],
+    password_hash: [u8; 32],
+    /// Hashes of the tokens that can be used instead of the password.
+    token_hashes: Vec<[u8; 32]>,
+}
+impl Account {
+    fn new(password: &str, role: Role) -> Self {
+        let mut account = Self {
+            role,
+            salt: [0; 16],
+            password_hash: [0; 32],
+            token_hashes: vec![],
+        };
+        account.set_password(password);
+        account
+    }
+
+    fn set_password(&mut self, password: &str) {
+        getrandom::getrandom(&mut self.salt).expect("no source of randomness");
+        self.password_hash = hash_password(&self.salt, password);
+    }
+
+    fn check(&self, secret: &str) -> bool {
+        let token_hash: [u8; 32] = Sha256::digest(secret.as_bytes()).into();
+        let token_ok = self.token_hashes.iter().any(|h| eq(h, &token_hash));
+        let password_ok = eq(&self.password_hash, &hash_password(&self.salt, secret));
+        token_ok | password_ok
+    }
+
+    pub fn token_count(&self) -> usize {
+        self.token_hashes.len()
+    }
+}
+
+#[derive(Debug)]
+pub enum AccountErr {
+    Exists(String),
+    NotFound(String),
+    InvalidName,
+}
+impl std::fmt::Display for AccountErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exists(name) => write!(f, "user {name:?} already exists"),
+            Self::NotFound(name) => write!(f, "no user called {name:?}"),
+            Self::InvalidName => f.write_str("user names can't be empty or contain spaces"),
+        }
+    }
+}
+impl std::error::Error for AccountErr {}
+
+/// Every account that can log in to the server, by user name.
+#[derive(Default)]
+pub struct Accounts {
+    /// Where the accounts are saved after each change, if anywhere.
+    pub path: Option<PathBuf>,
+    users: BTreeMap<String, Account>,
+    /// Secrets that have been checked, by user name, each hashed once with the account's salt.
+    /// Forgotten whenever an account changes.
+    remembered: Mutex<HashSet<(String, [u8; 32])>>,
+}
+impl Accounts {
+    /// Reads the accounts saved at `path`. A missing file gives no accounts.
+    pub fn load(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let users = match std::fs::read(&path) {
+            Ok(bytes) => bincode::deserialize(&bytes).map_err(std::io::Error::other)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            path: Some(path),
+            users,
+            ..Default::default()
+        })
+    }
+
+    /// Writes the accounts to `path`, if there is one.
+    pub fn save(&self) -> std::io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let tmp_path = crate::save::with_suffix(path, ".tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&bincode::serialize(&self.users).unwrap())?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&tmp_path, path)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Account)> {
+        self.users
+            .iter()
+            .map(|(name, account)| (name.as_str(), account))
+    }
+
+    /// Returns the role of `name` if `secret` is its password or one of its tokens.
+    /// Clients like the HTTP API log in on every request, so secrets that were right are remembered.
+    pub fn authenticate(&self, name: &str, secret: &str) -> Option<Role> {
+        let account = self.users.get(name)?;
+        let key: [u8; 32] = Sha256::new()
+            .chain_update(account.salt)
+            .chain_update(secret.as_bytes())
+            .finalize()
+            .into();
+        let key = (name.to_owned(), key);
+        if self.remembered.lock().unwrap().contains(&key) {
+            return Some(account.role);
+        }
+        if !account.check(secret) {
+            return None;
+        }
+        let mut remembered = self.remembered.lock().unwrap();
+        if remembered.len() >= MAX_REMEMBERED {
+            remembered.clear();
+        }
+        remembered.insert(key);
+        Some(account.role)
+    }
+
+    pub fn add(&mut self, name: &str, password: &str, role: Role) -> Result<(), AccountErr> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(AccountErr::InvalidName);
+        }
+        if self.users.contains_key(name) {
+            return Err(AccountErr::Exists(name.into()));
+        }
+        self.users.insert(name.into(), Account::new(password, role));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), AccountErr> {
+        self.remembered.get_mut().unwrap().clear();
+        self.users
+            .remove(name)
+            .map(drop)
+            .ok_or_else(|| AccountErr::NotFound(name.into()))
+    }
+
+    pub fn set_password(&mut self, name: &str, password: &str) -> Result<(), AccountErr> {
+        self.get_mut(name)?.set_password(password);
+        Ok(())
+    }
+
+    pub fn set_role(&mut self, name: &str, role: Role) -> Result<(), AccountErr> {
+        self.get_mut(name)?.role = role;
+        Ok(())
+    }
+
+    /// Creates a new token for `name`. Only its hash is kept, so this is the one chance to see it.
+    pub fn create_token(&mut self, name: &str) -> Result<String, AccountErr> {
+        let account = self.get_mut(name)?;
+        let mut bytes = [0u8; 24];
+        getrandom::getrandom(&mut bytes).expect("no source of randomness");
+        let token: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+        account
+            .token_hashes
+            .push(Sha256::digest(token.as_bytes()).into());
+        Ok(token)
+    }
+
+    /// Makes every token of `name` stop working.
+    pub fn revoke_tokens(&mut self, name: &str) -> Result<(), AccountErr> {
+        self.get_mut(name)?.token_hashes.clear();
+        Ok(())
+    }
+
+    /// The account to change, forgetting every remembered login.
+    fn get_mut(&mut self, name: &str) -> Result<&mut Account, AccountErr> {
+        self.remembered.get_mut().unwrap().clear();
+        self.users
+            .get_mut(name)
+            .ok_or_else(|| AccountErr::NotFound(name.into()))
+    }
+}
+
+fn hash_password(salt: &[u8; 16], password: &str) -> [u8; 32] {
+    let mut hash: [u8; 32] = Sha256::new()
+        .chain_update(salt)
+        .chain_update(password.as_bytes())
+        .finalize()
+        .into();
+    for _ in 1..HASH_ROUNDS {
+        hash = Sha256::new()
+            .chain_update(hash)
+            .chain_update(salt)
+            .finalize()
+            .into();
+    }
+    hash
+}
+
+/// Compares without stopping at the first difference, so timing doesn't reveal how close a guess was.
+fn eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
@@ -0,0 +1,144 @@
+//! Timestamped snapshots of the server's inventory.
+
+use crate::inv::Inv;
+use crate::save;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const EXT: &str = "inv";
+
+/// Where backups are written, and how many of them are kept around.
+#[derive(Clone, Debug)]
+pub struct Backups {
+    pub dir: PathBuf,
+    /// At least 1, so the backup just written is never removed.
+    pub keep: usize,
+}
+impl Backups {
+    pub fn new(dir: impl Into<PathBuf>, keep: usize) -> Self {
+        Self {
+            dir: dir.into(),
+            keep: keep.max(1),
+        }
+    }
+
+    /// Writes a snapshot of `inv`, removes the oldest snapshots over `keep`,
+    /// and returns the name of the new snapshot.
+    pub fn create(&self, inv: &Inv) -> std::io::Result<String> {
+        std::fs::create_dir_all(&self.dir)?;
+
+        let stamp = utc_timestamp(SystemTime::now());
+        let mut name = format!("inv-{stamp}");
+        let mut n = 1;
+        while self.path(&name).exists() {
+            n += 1;
+            name = format!("inv-{stamp}-{n}");
+        }
+
+        save::write_atomic(&self.path(&name), &save::encode(inv))?;
+        self.prune()?;
+        Ok(name)
+    }
+
+    /// Names of all snapshots, least recently written first.
+    pub fn list(&self) -> std::io::Result<Vec<String>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let mut snapshots = vec![];
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == EXT) {
+                if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                    let modified = entry.metadata()?.modified()?;
+                    snapshots.push((modified, name.to_string()));
+                }
+            }
+        }
+        // Names are timestamps, but the clock may have been wrong or changed when they were taken.
+        snapshots.sort();
+        Ok(snapshots.into_iter().map(|(_, name)| name).collect())
+    }
+
+    pub fn load(&self, name: &str) -> std::io::Result<save::Decoded> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid backup name {name:?}"),
+            ));
+        }
+        let bytes = std::fs::read(self.path(name))?;
+        save::decode(&bytes).map_err(std::io::Error::other)
+    }
+
+    /// The file the backup called `name` is in.
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{name}.{EXT}"))
+    }
+
+    fn prune(&self) -> std::io::Result<()> {
+        let names = self.list()?;
+        let excess = names.len().saturating_sub(self.keep.max(1));
+        for name in &names[..excess] {
+            std::fs::remove_file(self.path(name))?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats `time` as `YYYYMMDD-HHMMSS` in UTC.
+pub fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    let (hour, min, sec) = (secs / 3600, secs / 60 % 60, secs % 60);
+    format!("{year:04}{month:02}{day:02}-{hour:02}{min:02}{sec:02}")
+}
+
+/// Parses a UTC time written like `utc_timestamp` does, or just the `YYYYMMDD` date.
+pub fn parse_utc_timestamp(s: &str) -> Option<SystemTime> {
+    let (date, time) = s.split_once('-').unwrap_or((s, "000000"));
+    if date.len() != 8 || time.len() != 6 || !s.chars().all(|c| c == '-' || c.is_ascii_digit()) {
+        return None;
+    }
+    let num = |s: &str, range: std::ops::Range<usize>| s[range].parse::<u32>().ok();
+    let (year, month, day) = (num(date, 0..4)?, num(date, 4..6)?, num(date, 6..8)?);
+    let (hour, min, sec) = (num(time, 0..2)?, num(time, 2..4)?, num(time, 4..6)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 59 {
+        return None;
+    }
+    let days = days_from_civil(year as i64, month, day);
+    let secs = days * 86400 + (hour * 3600 + min * 60 + sec) as i64;
+    Some(UNIX_EPOCH + std::time::Duration::from_secs(secs.try_into().ok()?))
+}
+
+// Howard Hinnant's `days_from_civil`: the inverse of `civil_from_days`.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// Howard Hinnant's `civil_from_days`: days since 1970-01-01 to a (year, month, day) date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
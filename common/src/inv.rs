@@ -1,35 +1,232 @@
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
 use std::time::SystemTime;
 
 /// Always stored as ARGB, 1 byte per channel.
+/// Only held decoded like this in memory, items refer to their pictures by `PictureId`.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Picture {
     pub data: Vec<u8>,
     pub size: [u32; 2],
 }
 
+/// A picture as encoded by `picture::encode`, identified by the SHA-256 hash of its bytes.
+/// The same id always refers to the same picture.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PictureId(pub [u8; 32]);
+impl PictureId {
+    pub fn of(encoded: &[u8]) -> Self {
+        Self(Sha256::digest(encoded).into())
+    }
+}
+impl std::fmt::Display for PictureId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+impl std::fmt::Debug for PictureId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PictureId({self})")
+    }
+}
+impl std::str::FromStr for PictureId {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(());
+        }
+        let mut id = [0; 32];
+        for (idx, byte) in id.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[idx * 2..idx * 2 + 2], 16).map_err(|_| ())?;
+        }
+        Ok(Self(id))
+    }
+}
+
+/// One of an item's photos.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Photo {
+    pub picture: PictureId,
+    /// What the photo shows, like "label" or "damage". Empty for none.
+    pub caption: String,
+}
+impl Photo {
+    pub fn new(picture: PictureId) -> Self {
+        Self {
+            picture,
+            caption: String::new(),
+        }
+    }
+}
+
+/// An item's photos, in the order they are shown, with one of them chosen as the cover.
+/// The cover stands for the item wherever only one picture fits, like in lists.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Gallery {
+    photos: Vec<Photo>,
+    /// Index of the cover in `photos`. Kept on the same photo as others are moved or removed.
+    cover: usize,
+}
+impl Gallery {
+    pub fn photos(&self) -> &[Photo] {
+        &self.photos
+    }
+
+    pub fn len(&self) -> usize {
+        self.photos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photos.is_empty()
+    }
+
+    pub fn pictures(&self) -> impl Iterator<Item = &PictureId> {
+        self.photos.iter().map(|photo| &photo.picture)
+    }
+
+    /// The cover photo, unless there are no photos.
+    pub fn cover(&self) -> Option<&Photo> {
+        self.photos.get(self.cover).or(self.photos.first())
+    }
+
+    pub fn cover_idx(&self) -> usize {
+        self.cover.min(self.photos.len().saturating_sub(1))
+    }
+
+    pub fn set_cover(&mut self, idx: usize) {
+        if idx < self.photos.len() {
+            self.cover = idx;
+        }
+    }
+
+    pub fn set_caption(&mut self, idx: usize, caption: impl Into<String>) {
+        if let Some(photo) = self.photos.get_mut(idx) {
+            photo.caption = caption.into();
+        }
+    }
+
+    /// Adds a photo after the others. The first photo becomes the cover.
+    pub fn push(&mut self, photo: Photo) {
+        self.photos.push(photo);
+    }
+
+    /// Removes the photo at `idx`. Removing the cover makes the photo after it the cover.
+    pub fn remove(&mut self, idx: usize) -> Option<Photo> {
+        if idx >= self.photos.len() {
+            return None;
+        }
+        let photo = self.photos.remove(idx);
+        if idx < self.cover || self.cover >= self.photos.len() {
+            self.cover = self.cover.saturating_sub(1);
+        }
+        Some(photo)
+    }
+
+    /// Moves the photo at `from` to `to`, shifting the ones in between.
+    pub fn move_photo(&mut self, from: usize, to: usize) {
+        if from >= self.photos.len() || to >= self.photos.len() {
+            return;
+        }
+        let photo = self.photos.remove(from);
+        self.photos.insert(to, photo);
+        let cover = self.cover_idx();
+        self.cover = if cover == from {
+            to
+        } else if from < cover && cover <= to {
+            cover - 1
+        } else if to <= cover && cover < from {
+            cover + 1
+        } else {
+            cover
+        };
+    }
+}
+impl From<Option<PictureId>> for Gallery {
+    fn from(picture: Option<PictureId>) -> Self {
+        Self {
+            photos: picture.map(Photo::new).into_iter().collect(),
+            cover: 0,
+        }
+    }
+}
+
 // x100 ($5.46 = Usd(546))
 #[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Usd(pub u32);
 impl std::fmt::Display for Usd {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let upper = self.0 / 100;
-        let lower = self.0 % 100;
-        std::fmt::Display::fmt(&upper, f)?;
-        f.write_char('.')?;
-        std::fmt::Display::fmt(&lower, f)?;
-        Ok(())
+        fmt_cents(self.0 as u64, f)
+    }
+}
+
+/// A sum of `Usd`, which can be more than a single `Usd` holds.
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct UsdTotal(pub u64);
+impl std::fmt::Display for UsdTotal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_cents(self.0, f)
+    }
+}
+
+fn fmt_cents(cents: u64, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let upper = cents / 100;
+    let lower = cents % 100;
+    std::fmt::Display::fmt(&upper, f)?;
+    f.write_char('.')?;
+    if lower < 10 {
+        f.write_char('0')?;
     }
+    std::fmt::Display::fmt(&lower, f)?;
+    Ok(())
 }
 impl std::str::FromStr for Usd {
-    type Err = std::num::ParseFloatError;
+    type Err = ParseUsdErr;
+    /// Parses dollars like `5.46`, `5.4` or `5`, exactly, without going through a float.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let float: f32 = s.parse()?;
-        Ok(Self((float * 100.0) as u32))
+        if s.starts_with('-') {
+            return Err(ParseUsdErr::Negative);
+        }
+        let (dollars, cents) = s.split_once('.').unwrap_or((s, ""));
+        let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+        if (dollars.is_empty() && cents.is_empty()) || !is_digits(dollars) || !is_digits(cents) {
+            return Err(ParseUsdErr::Invalid);
+        }
+        let (cents, fraction) = cents.split_at(cents.len().min(2));
+        if fraction.bytes().any(|b| b != b'0') {
+            return Err(ParseUsdErr::FractionOfCent);
+        }
+        let cents = (cents.bytes().chain(std::iter::repeat(b'0')).take(2))
+            .fold(0, |cents, digit| cents * 10 + (digit - b'0') as u32);
+        // Only digits are left, so the only way this can fail is by being too big.
+        let dollars: u32 = match dollars {
+            "" => 0,
+            dollars => dollars.parse().map_err(|_| ParseUsdErr::TooLarge)?,
+        };
+        let total = dollars.checked_mul(100).and_then(|d| d.checked_add(cents));
+        total.map(Self).ok_or(ParseUsdErr::TooLarge)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseUsdErr {
+    Invalid,
+    Negative,
+    FractionOfCent,
+    TooLarge,
+}
+impl std::fmt::Display for ParseUsdErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Invalid => "not an amount of dollars, like 5.46",
+            Self::Negative => "negative amount",
+            Self::FractionOfCent => "amount with a fraction of a cent",
+            Self::TooLarge => "amount too large",
+        })
     }
 }
+impl std::error::Error for ParseUsdErr {}
 
 /// Corresponds to one of the platforms in Inv::platform_names
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
@@ -70,7 +267,7 @@ impl Listings {
     }
 
     pub fn total_sold(&self) -> u32 {
-        self.0.iter().flatten().map(|l| l.sold).sum()
+        (self.0.iter().flatten()).fold(0, |sold, l| sold.saturating_add(l.sold))
     }
 
     pub fn contains_platform(&self, platform: Platform) -> bool {
@@ -80,6 +277,13 @@ impl Listings {
     pub fn add_listing(&mut self, platform: Platform) {
         self.0[platform.as_idx() as usize] = Some(Listing::default());
     }
+
+    /// Drops the listing on `platform`, moving the listings on the platforms after it down a place.
+    pub fn remove_platform(&mut self, platform: Platform) {
+        let idx = platform.as_idx() as usize;
+        self.0[idx] = None;
+        self.0[idx..].rotate_left(1);
+    }
 }
 impl std::ops::Index<Platform> for Listings {
     type Output = Option<Listing>;
@@ -119,9 +323,12 @@ impl<'a> IntoIterator for &'a Listings {
 pub struct Item {
     // Inventory properties
     pub creation_date: SystemTime,
+    /// The `Inv::revision` this item was last written at.
+    /// An edit is only accepted if it was based on the latest revision.
+    pub revision: u64,
     pub location: String,
     pub listings: Listings,
-    pub picture: Option<Picture>,
+    pub gallery: Gallery,
 
     // Item details
     pub name: String,
@@ -143,9 +350,10 @@ impl std::clone::Clone for Item {
     fn clone(&self) -> Self {
         Self {
             creation_date: SystemTime::now(),
+            revision: self.revision,
             location: self.location.clone(),
             listings: self.listings.clone(),
-            picture: self.picture.clone(),
+            gallery: self.gallery.clone(),
             name: self.name.clone(),
             desc: self.desc.clone(),
             count: self.count,
@@ -162,13 +370,32 @@ impl std::clone::Clone for Item {
     }
 }
 impl Item {
+    /// An exact copy. Unlike `clone`, this keeps the creation date.
+    pub fn copy(&self) -> Self {
+        Self {
+            creation_date: self.creation_date,
+            ..self.clone()
+        }
+    }
+
+    /// Whether any of the text fields, or the model or serial number, contain `text`.
+    /// `text` must be lowercase, as the fields are compared in lowercase.
+    pub fn contains_text(&self, text: &str) -> bool {
+        let numbers = [self.model_no.to_string(), self.serial_no.to_string()];
+        let fields = [
+            &self.name,
+            &self.desc,
+            &self.location,
+            &self.brand,
+            &self.color,
+            &self.condition,
+        ];
+        let contains = |field: &String| field.to_lowercase().contains(text);
+        fields.into_iter().any(contains) || numbers.iter().any(contains)
+    }
+
     pub fn sold_count(&self) -> u32 {
-        self.listings
-            .0
-            .iter()
-            .flatten()
-            .map(|listing| listing.sold)
-            .sum()
+        self.listings.total_sold()
     }
 }
 impl Default for Item {
@@ -176,9 +403,10 @@ impl Default for Item {
         Self {
             // Inventory properties
             creation_date: SystemTime::now(),
+            revision: 0,
             location: String::new(),
             listings: Listings::default(),
-            picture: None,
+            gallery: Gallery::default(),
 
             // Item details
             name: String::new(),
@@ -202,9 +430,111 @@ impl Default for Item {
 #[derive(Default, Debug, Serialize, Deserialize)]
 pub struct Inv {
     pub platform_names: Vec<String>,
+    /// Bumped every time an item is inserted or removed.
+    pub revision: u64,
     pub items: HashMap<Id, Item>,
+    /// Removed items, with the revision they were removed at, so clients syncing later find out.
+    /// Old ones are dropped by `prune_removed`.
+    pub removed: HashMap<Id, u64>,
+    /// Removed items that can still be restored.
+    pub trash: HashMap<Id, Trashed>,
 }
 impl Inv {
+    /// Stores `item` at a new revision of the inv, and returns that revision.
+    /// A copy of the item in the trash is dropped, since it's back.
+    pub fn write_item(&mut self, id: Id, mut item: Item) -> u64 {
+        self.revision += 1;
+        item.revision = self.revision;
+        self.removed.remove(&id);
+        self.trash.remove(&id);
+        self.items.insert(id, item);
+        self.revision
+    }
+
+    /// Removes an item at a new revision of the inv, so clients syncing later find out about it.
+    pub fn remove_item(&mut self, id: Id) -> Option<Item> {
+        let item = self.items.remove(&id)?;
+        self.revision += 1;
+        self.removed.insert(id, self.revision);
+        Some(item)
+    }
+
+    /// Forgets the items removed before `revision`, apart from the last one removed.
+    /// Clients that synced before any of them are sent everything instead of a delta.
+    pub fn prune_removed(&mut self, revision: u64) {
+        let last = self.removed.values().max().copied();
+        self.removed
+            .retain(|_, removed| *removed >= revision || Some(*removed) == last);
+    }
+
+    /// Removes an item like `remove_item`, but keeps it in the trash.
+    pub fn trash_item(&mut self, id: Id, deleted_by: &str) -> Option<&Trashed> {
+        let item = self.remove_item(id)?;
+        let trashed = Trashed {
+            item,
+            deleted_at: SystemTime::now(),
+            deleted_by: deleted_by.to_owned(),
+        };
+        self.trash.insert(id, trashed);
+        self.trash.get(&id)
+    }
+
+    /// Moves an item out of the trash, returning the revision it was restored at.
+    pub fn restore_item(&mut self, id: Id) -> Option<u64> {
+        if self.items.contains_key(&id) {
+            return None;
+        }
+        let trashed = self.trash.remove(&id)?;
+        Some(self.write_item(id, trashed.item))
+    }
+
+    /// The pictures of every item, including those in the trash. A picture can be listed more than once.
+    pub fn pictures(&self) -> impl Iterator<Item = &PictureId> {
+        let trashed = self.trash.values().map(|trashed| &trashed.item);
+        (self.items.values().chain(trashed)).flat_map(|item| item.gallery.pictures())
+    }
+
+    /// Ids of trashed items deleted before `cutoff`.
+    pub fn trashed_before(&self, cutoff: SystemTime) -> Vec<Id> {
+        self.trash
+            .iter()
+            .filter(|(_, trashed)| trashed.deleted_at < cutoff)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Everything that changed after `revision`.
+    /// If `revision` is 0 or newer than ours, the client's copy is unrelated and gets everything.
+    pub fn changes_since(&self, revision: u64) -> InvDelta {
+        // Removed items are pruned oldest first, so any that were pruned were removed before the
+        // oldest one left. A client that synced before then may not know about them.
+        let oldest_removed = self.removed.values().min().copied().unwrap_or(0);
+        let full = revision == 0 || revision > self.revision || revision + 1 < oldest_removed;
+        let changed = self
+            .items
+            .iter()
+            .filter(|(_, item)| full || item.revision > revision)
+            .map(|(id, item)| {
+                let mut copy = item.clone();
+                copy.creation_date = item.creation_date;
+                (*id, copy)
+            })
+            .collect();
+        let removed = self
+            .removed
+            .iter()
+            .filter(|(_, rev)| !full && **rev > revision)
+            .map(|(id, _)| *id)
+            .collect();
+        InvDelta {
+            revision: self.revision,
+            full,
+            platform_names: self.platform_names.clone(),
+            changed,
+            removed,
+        }
+    }
+
     pub fn platforms(&self) -> impl Iterator<Item = (Platform, &str)> {
         self.platform_names
             .iter()
@@ -216,6 +546,52 @@ impl Inv {
         self.platform_names[platform.as_idx() as usize].as_str()
     }
 
+    pub fn find_platform(&self, name: &str) -> Option<Platform> {
+        self.platforms()
+            .find(|(_, n)| *n == name)
+            .map(|(platform, _)| platform)
+    }
+
+    /// Removes `platform`, moving the listings on the platforms after it down a place.
+    /// Returns the items that had to be rewritten for that, as they were before.
+    /// Fails with the number of items still listed on it (counting the trash) if there are any.
+    pub fn remove_platform(&mut self, platform: Platform) -> Result<Vec<(Id, Item)>, usize> {
+        let idx = platform.as_idx() as usize;
+        let in_use = self.platform_uses(platform);
+        if in_use > 0 {
+            return Err(in_use);
+        }
+        self.platform_names.remove(idx);
+        for trashed in self.trash.values_mut() {
+            trashed.item.listings.remove_platform(platform);
+        }
+        let moved: Vec<Id> = (self.items.iter())
+            .filter(|(_, item)| item.listings.0[idx..].iter().any(Option::is_some))
+            .map(|(id, _)| *id)
+            .collect();
+        let mut before = Vec::with_capacity(moved.len());
+        for id in moved {
+            let item = &self.items[&id];
+            let mut after = item.copy();
+            after.listings.remove_platform(platform);
+            before.push((id, item.copy()));
+            self.write_item(id, after);
+        }
+        Ok(before)
+    }
+
+    /// How many items are listed on `platform`, counting the trash.
+    pub fn platform_uses(&self, platform: Platform) -> usize {
+        (self.items.values())
+            .chain(self.trash.values().map(|trashed| &trashed.item))
+            .filter(|item| item.listings.contains_platform(platform))
+            .count()
+    }
+
+    pub fn stats(&self) -> InvStats {
+        InvStats::of(self.items.values())
+    }
+
     pub fn all_locations(&self) -> impl Iterator<Item = &str> {
         let mut items = self
             .items
@@ -231,6 +607,62 @@ impl Inv {
     }
 }
 
+/// Totals over a set of items.
+/// Summed in 64 bits, which saturate rather than overflow on absurd counts and costs.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct InvStats {
+    /// Number of items, not counting how many of each there are.
+    pub listings: usize,
+    pub unsold_count: u64,
+    pub sold_count: u64,
+    pub unsold_cost: UsdTotal,
+    pub sold_cost: UsdTotal,
+}
+impl InvStats {
+    pub fn of<'a>(items: impl IntoIterator<Item = &'a Item>) -> Self {
+        let mut stats = Self::default();
+        for item in items {
+            let sold = item.sold_count() as u64;
+            let unsold = (item.count as u64).saturating_sub(sold);
+            let cost = item.est_cost.0 as u64;
+            stats.listings += 1;
+            stats.unsold_count = stats.unsold_count.saturating_add(unsold);
+            stats.sold_count = stats.sold_count.saturating_add(sold);
+            stats.unsold_cost.0 = (stats.unsold_cost.0).saturating_add(cost * unsold);
+            stats.sold_cost.0 = (stats.sold_cost.0).saturating_add(cost * sold);
+        }
+        stats
+    }
+
+    pub fn total_count(&self) -> u64 {
+        self.unsold_count.saturating_add(self.sold_count)
+    }
+
+    pub fn total_cost(&self) -> UsdTotal {
+        UsdTotal(self.unsold_cost.0.saturating_add(self.sold_cost.0))
+    }
+}
+
+/// An item in the trash, with when and by whom it was removed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Trashed {
+    pub item: Item,
+    pub deleted_at: SystemTime,
+    pub deleted_by: String,
+}
+
+/// The part of an `Inv` that changed after some revision.
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct InvDelta {
+    /// The revision this brings the client up to.
+    pub revision: u64,
+    /// If set, `changed` holds every item and the client should drop any others.
+    pub full: bool,
+    pub platform_names: Vec<String>,
+    pub changed: Vec<(Id, Item)>,
+    pub removed: Vec<Id>,
+}
+
 #[derive(Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
 pub struct Id(pub u32);
 impl Id {
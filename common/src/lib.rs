@@ -1,16 +1,52 @@
+pub mod audit;
+pub mod auth;
+pub mod backup;
 pub mod inv;
+pub mod local;
+pub mod merge;
+pub mod picture;
+pub mod save;
+pub mod spreadsheet;
+pub mod storage;
+pub mod tls;
 
-use inv::{Id, Inv, Item};
-use std::collections::HashMap;
+use audit::{Action, AuditLog, ItemVersion};
+use auth::{Accounts, Role};
+use backup::Backups;
+use inv::{Id, Inv, InvDelta, Item, Picture, PictureId, Platform, Trashed};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use storage::Storage;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct DataVersion(pub u8);
+impl DataVersion {
+    /// Layout of `inv::Inv` as written by this release.
+    pub const CURRENT: Self = Self(6);
+}
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Release(pub u8, pub u8, pub u8);
 impl Release {
-    pub const CURRENT: Self = Self(0, 0, 1);
+    pub const CURRENT: Self = Self(0, 0, 11);
+    /// The oldest release the server still talks to, the first with a gallery of photos per item.
+    ///
+    /// Items are sent in the layout of the current `DataVersion`, which older releases can't
+    /// decode, so they are turned away with a message naming the release to update to. An updated
+    /// app upgrades its local save when it starts, so edits made while offline are still uploaded.
+    ///
+    /// Translating items to an older layout instead wouldn't be safe. 0.0.7 started framing
+    /// requests, which older releases can't read at all. Releases before 0.0.9 send pictures inside
+    /// items, and before 0.0.11 an item had a single picture, so an edit from an older app would
+    /// drop every photo it didn't know about.
+    pub const OLDEST_SUPPORTED: Self = Self(0, 0, 11);
+
     pub fn as_bytes(self) -> [u8; 3] {
         [self.0, self.1, self.2]
     }
@@ -18,11 +54,29 @@ impl Release {
         Self(a, b, c)
     }
 
+    /// Whether this release speaks the same protocol as ours, and sends items in our layout.
+    ///
+    /// The data version alone isn't enough, 0.0.2 changed every response while keeping the
+    /// layout of 0.0.1.
+    pub fn is_compatible(self) -> bool {
+        self >= Self::OLDEST_SUPPORTED && self.data_version() == Self::CURRENT.data_version()
+    }
+
+    /// Layout of the inv saved and sent by this release.
     pub fn data_version(self) -> Option<DataVersion> {
         match self {
             Self(0, 0, 0) => None,
-            Self(0, 0, 1) => Some(DataVersion(0)),
-            Self(0, 0, 2) => Some(DataVersion(0)),
+            Self(0, 0, 1) => Some(DataVersion(1)),
+            Self(0, 0, 2) => Some(DataVersion(1)),
+            Self(0, 0, 3) => Some(DataVersion(2)),
+            Self(0, 0, 4) => Some(DataVersion(3)),
+            Self(0, 0, 5) => Some(DataVersion(3)),
+            Self(0, 0, 6) => Some(DataVersion(4)),
+            Self(0, 0, 7) => Some(DataVersion(4)),
+            Self(0, 0, 8) => Some(DataVersion(4)),
+            Self(0, 0, 9) => Some(DataVersion(5)),
+            Self(0, 0, 10) => Some(DataVersion(5)),
+            Self(0, 0, 11) => Some(DataVersion(6)),
             _ => None,
         }
     }
@@ -47,9 +101,29 @@ pub enum CmdCode {
     RemoveItem = 4,
     GetServerClients = 5,
     CreateServerBackup = 6,
+    GetChanges = 7,
+    /// Applies a list of `BatchOp`s all at once.
+    ApplyBatch = 8,
     ConnectionSuccessfull = 10,
     OperationSuccessfull = 11,
     CmdResponseRecieved = 12,
+    OperationFailed = 13,
+    Conflict = 14,
+    /// A request failed. Holds an `ErrCode` and a message.
+    Error = 16,
+    ItemInserted = 20,
+    ItemRemoved = 21,
+    ServerShutdown = 22,
+    GetTrash = 30,
+    RestoreItem = 31,
+    PurgeItem = 32,
+    GetItemHistory = 33,
+    /// Gets an encoded picture by its `PictureId`.
+    GetPicture = 34,
+    /// Stores an encoded picture, answering with its `PictureId`.
+    PutPicture = 35,
+    /// Gets the encoded thumbnail of a picture by its `PictureId`.
+    GetThumbnail = 36,
 }
 impl CmdCode {
     pub fn from_u8(v: u8) -> Option<Self> {
@@ -60,9 +134,24 @@ impl CmdCode {
             4 => Some(Self::RemoveItem),
             5 => Some(Self::GetServerClients),
             6 => Some(Self::CreateServerBackup),
+            7 => Some(Self::GetChanges),
+            8 => Some(Self::ApplyBatch),
             10 => Some(Self::ConnectionSuccessfull),
             11 => Some(Self::OperationSuccessfull),
             12 => Some(Self::CmdResponseRecieved),
+            13 => Some(Self::OperationFailed),
+            14 => Some(Self::Conflict),
+            16 => Some(Self::Error),
+            20 => Some(Self::ItemInserted),
+            21 => Some(Self::ItemRemoved),
+            22 => Some(Self::ServerShutdown),
+            30 => Some(Self::GetTrash),
+            31 => Some(Self::RestoreItem),
+            32 => Some(Self::PurgeItem),
+            33 => Some(Self::GetItemHistory),
+            34 => Some(Self::GetPicture),
+            35 => Some(Self::PutPicture),
+            36 => Some(Self::GetThumbnail),
             _ => None,
         }
     }
@@ -72,116 +161,1417 @@ pub fn expect_code<T: std::io::Read>(io: &mut T, code: CmdCode) -> std::io::Resu
     let mut code_buf = [0u8];
     io.read_exact(&mut code_buf)?;
     if code_buf[0] != code as u8 {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Unexpected code recieved",
-        ));
+        return Err(std::io::Error::other("Unexpected code recieved"));
     }
     Ok(())
 }
 pub fn send_code<T: std::io::Write>(io: &mut T, code: CmdCode) -> std::io::Result<()> {
     io.write_all(&[code as u8])
 }
+pub fn read_code<T: std::io::Read>(io: &mut T) -> std::io::Result<CmdCode> {
+    let mut code_buf = [0u8];
+    io.read_exact(&mut code_buf)?;
+    CmdCode::from_u8(code_buf[0]).ok_or_else(|| std::io::Error::other("Unknown code recieved"))
+}
+
+pub fn send_str<T: std::io::Write>(io: &mut T, s: &str) -> std::io::Result<()> {
+    io.write_all(&(s.len() as u32).to_be_bytes())?;
+    io.write_all(s.as_bytes())
+}
+pub fn read_str<T: std::io::Read>(io: &mut T) -> std::io::Result<String> {
+    read_str_max(io, u32::MAX)
+}
+/// Reads a string like `read_str`, but fails without reading it if it is longer than `max_len` bytes.
+pub fn read_str_max<T: std::io::Read>(io: &mut T, max_len: u32) -> std::io::Result<String> {
+    let mut len_buf = [0u8; 4];
+    io.read_exact(&mut len_buf)?;
+    let len = u32::from_be_bytes(len_buf);
+    if len > max_len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("String of {len} bytes is longer than {max_len}"),
+        ));
+    }
+
+    let mut buf = vec![0u8; len as usize];
+    io.read_exact(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+/// Longest user name or password accepted when logging in, read before anyone is authenticated.
+pub const MAX_CREDENTIAL_LEN: u32 = 1024;
+
+/// Requests bigger than this are refused without being read into memory.
+pub const MAX_REQUEST_LEN: u32 = 64 * 1024 * 1024;
+
+/// After the login handshake every message is a frame: `[code: u8][request id: u32][payload length: u32][payload]`.
+///
+/// A response has the id of the request it answers, so it can't be mistaken for the response to
+/// another one. Events answer nothing, so their id is 0 and clients number requests from 1.
+/// The length means a message can be skipped over whole, even one with an unknown code or a bad payload.
+#[derive(Debug)]
+pub struct Frame {
+    /// Kept as a byte, so a code we don't know yet can still be answered.
+    pub code: u8,
+    pub id: u32,
+    pub payload: Vec<u8>,
+}
+impl Frame {
+    pub fn new(code: CmdCode, id: u32, payload: Vec<u8>) -> Self {
+        Self {
+            code: code as u8,
+            id,
+            payload,
+        }
+    }
+
+    /// An `Error` response to the request `id`.
+    pub fn error(id: u32, err: ErrCode, msg: &str) -> Self {
+        let mut payload = vec![err as u8];
+        send_str(&mut payload, msg).unwrap();
+        Self::new(CmdCode::Error, id, payload)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(9 + self.payload.len());
+        bytes.push(self.code);
+        bytes.extend(self.id.to_be_bytes());
+        bytes.extend((self.payload.len() as u32).to_be_bytes());
+        bytes.extend(&self.payload);
+        bytes
+    }
+
+    /// Writes the frame in one piece.
+    pub fn write<T: Write>(&self, io: &mut T) -> std::io::Result<()> {
+        io.write_all(&self.encode())?;
+        io.flush()
+    }
+
+    /// Reads a frame, failing without reading the payload if it is longer than `MAX_REQUEST_LEN`.
+    pub fn read<T: Read>(io: &mut T) -> std::io::Result<Self> {
+        let header = FrameHeader::read(io)?;
+        if header.len > MAX_REQUEST_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Frame of {} bytes is longer than {MAX_REQUEST_LEN}",
+                    header.len
+                ),
+            ));
+        }
+        let mut payload = vec![0u8; header.len as usize];
+        io.read_exact(&mut payload)?;
+        Ok(Self {
+            code: header.code,
+            id: header.id,
+            payload,
+        })
+    }
+}
+
+/// The start of a `Frame`, for checking its length before reading the payload.
+pub struct FrameHeader {
+    pub code: u8,
+    pub id: u32,
+    pub len: u32,
+}
+impl FrameHeader {
+    pub fn read<T: Read>(io: &mut T) -> std::io::Result<Self> {
+        let mut buf = [0u8; 9];
+        io.read_exact(&mut buf)?;
+        Ok(Self {
+            code: buf[0],
+            id: u32::from_be_bytes(buf[1..5].try_into().unwrap()),
+            len: u32::from_be_bytes(buf[5..].try_into().unwrap()),
+        })
+    }
+}
+
+/// Why a request failed, sent at the start of an `Error` response.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum ErrCode {
+    /// The request's payload couldn't be read.
+    Malformed = 1,
+    /// The server doesn't know the command, maybe because it is an older release.
+    UnknownCommand = 2,
+    /// The request is longer than `MAX_REQUEST_LEN`.
+    TooLarge = 3,
+    /// Our account's role doesn't allow the command.
+    PermissionDenied = 4,
+    /// The command ran, but couldn't do what was asked. The message says why.
+    Failed = 5,
+}
+impl ErrCode {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Self::Malformed),
+            2 => Some(Self::UnknownCommand),
+            3 => Some(Self::TooLarge),
+            4 => Some(Self::PermissionDenied),
+            5 => Some(Self::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// Transports that can be checked for incoming data without blocking.
+pub trait Nonblocking {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>;
+}
+impl Nonblocking for std::net::TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        std::net::TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// A change made by another client, pushed by the server without being asked for.
+#[derive(Debug)]
+pub enum InvEvent {
+    ItemInserted(Id, Box<Item>),
+    ItemRemoved(Id),
+    /// The server is going away, and will close the connection right after.
+    ServerShutdown,
+}
+impl InvEvent {
+    pub fn code(&self) -> CmdCode {
+        match self {
+            Self::ItemInserted(..) => CmdCode::ItemInserted,
+            Self::ItemRemoved(..) => CmdCode::ItemRemoved,
+            Self::ServerShutdown => CmdCode::ServerShutdown,
+        }
+    }
+
+    /// The event as a frame, with the request id 0.
+    pub fn to_frame(&self) -> Frame {
+        let mut payload = vec![];
+        match self {
+            Self::ItemInserted(id, item) => {
+                payload.extend(id.0.to_be_bytes());
+                payload.extend(bincode::serialize(item).unwrap());
+            }
+            Self::ItemRemoved(id) => payload.extend(id.0.to_be_bytes()),
+            Self::ServerShutdown => {}
+        }
+        Frame::new(self.code(), 0, payload)
+    }
+
+    /// Returns `None` if `frame` is not an event, or one this release doesn't know about.
+    pub fn read(frame: &Frame) -> std::io::Result<Option<Self>> {
+        let mut payload = frame.payload.as_slice();
+        let mut id_bytes = [0u8; 4];
+        match CmdCode::from_u8(frame.code) {
+            Some(CmdCode::ItemInserted) => {
+                payload.read_exact(&mut id_bytes)?;
+                let item: Item = bincode::deserialize(payload).map_err(std::io::Error::other)?;
+                Ok(Some(Self::ItemInserted(
+                    Id(u32::from_be_bytes(id_bytes)),
+                    Box::new(item),
+                )))
+            }
+            Some(CmdCode::ItemRemoved) => {
+                payload.read_exact(&mut id_bytes)?;
+                Ok(Some(Self::ItemRemoved(Id(u32::from_be_bytes(id_bytes)))))
+            }
+            Some(CmdCode::ServerShutdown) => Ok(Some(Self::ServerShutdown)),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// One change in an `ApplyBatch` request.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// Like `InsertItem`, so the item's revision must be the one the edit was based on.
+    Insert(Id, Box<Item>),
+    Remove(Id),
+}
+impl BatchOp {
+    pub fn id(&self) -> Id {
+        match self {
+            Self::Insert(id, _) | Self::Remove(id) => *id,
+        }
+    }
+}
+
+/// What became of a `BatchOp`, at the same index as the op.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum BatchResult {
+    /// Stored at this revision.
+    Inserted(u64),
+    Removed,
+    /// There was no such item to remove. Doesn't stop the rest of the batch.
+    Missing,
+    /// The item was changed on the server since the edit was based on it. Holds the server's version.
+    Conflict(Box<Item>),
+    /// Left out because another op in the batch was rejected.
+    NotApplied,
+    /// The item refers to a picture the server doesn't have, which has to be uploaded first.
+    MissingPicture(PictureId),
+}
 
 pub type ClientId = u32;
 
-pub struct ServerHost<C> {
-    pub clients: HashMap<ClientId, (String, C)>,
-    pub inv: Inv,
+/// What the server knows about a connected client.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub id: ClientId,
+    pub name: String,
+    pub role: Role,
+    pub release: Release,
+    pub connected: SystemTime,
+}
+
+/// A logged in client, as the server sees it.
+pub struct Client {
+    pub info: ClientInfo,
+    /// Where the client connected from, if the socket could tell.
+    pub addr: Option<SocketAddr>,
+    /// Bytes waiting to be written to the client, in order, by its writer thread.
+    outbox: Sender<Vec<u8>>,
+}
+impl Client {
+    fn send(&self, bytes: Vec<u8>) {
+        // Only fails if the client is already on its way out.
+        _ = self.outbox.send(bytes);
+    }
+}
+
+/// How long a shutdown waits for clients to be sent what is still queued for them.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// When the server writes the inv to disk without being asked to.
+#[derive(Clone, Copy, Debug)]
+pub struct Autosave {
+    /// Save at least this often while there are unsaved changes.
+    pub interval: Duration,
+    /// Save as soon as this many changes have been made.
+    pub max_changes: u32,
+}
+impl Default for Autosave {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            max_changes: 20,
+        }
+    }
+}
+
+/// The server's state, shared by the threads that handle each client.
+///
+/// When more than one lock is needed they are taken in the order `inv`, `audit`, `clients`.
+/// Saving takes `inv` itself, so it must not be started while holding it.
+pub struct ServerHost {
+    pub clients: RwLock<HashMap<ClientId, Client>>,
+    pub inv: RwLock<Inv>,
+    pub storage: Option<Box<dyn Storage>>,
+    /// Where pictures are kept when there is no `storage`, for as long as the server runs.
+    pictures: Mutex<HashMap<PictureId, Vec<u8>>>,
+    /// Where thumbnails are kept when there is no `storage`.
+    thumbnails: Mutex<HashMap<PictureId, Vec<u8>>>,
+    pub autosave: Autosave,
+    pub backups: Option<Backups>,
+    pub accounts: RwLock<Accounts>,
+    pub audit: Mutex<AuditLog>,
+    /// How long removed items stay in the trash before they are purged.
+    pub trash_retention: Duration,
+    /// Further clients are turned away while this many are connected.
+    pub max_clients: Option<usize>,
+    unsaved_changes: AtomicU32,
+    /// Also held for the whole of a save, so two saves never write the same file at once.
+    last_save: Mutex<Instant>,
+    shutting_down: AtomicBool,
+    /// Held for reading while a command runs, so a shutdown can wait for them to finish.
+    commands: RwLock<()>,
+    /// Held for reading from checking that an item's pictures are stored until the item is,
+    /// so `collect_garbage` can't delete them in between.
+    picture_checks: RwLock<()>,
+    /// How many clients are being served, with a signal for when one goes away.
+    connections: (Mutex<usize>, Condvar),
 }
-impl<C: Read + Write> ServerHost<C> {
+impl ServerHost {
     pub fn new(inv: Inv) -> Self {
         let clients = Default::default();
-        Self { clients, inv }
+        Self {
+            clients,
+            inv: RwLock::new(inv),
+            storage: None,
+            pictures: Default::default(),
+            thumbnails: Default::default(),
+            autosave: Autosave::default(),
+            backups: None,
+            accounts: Default::default(),
+            audit: Default::default(),
+            trash_retention: Duration::from_secs(30 * 24 * 60 * 60),
+            max_clients: None,
+            unsaved_changes: AtomicU32::new(0),
+            last_save: Mutex::new(Instant::now()),
+            shutting_down: AtomicBool::new(false),
+            commands: RwLock::new(()),
+            picture_checks: RwLock::new(()),
+            connections: Default::default(),
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Stops taking commands, tells every client the server is going away and disconnects them,
+    /// then saves. Commands that are already running are finished first.
+    pub fn shutdown(&self) -> std::io::Result<()> {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        drop(self.commands.write().unwrap());
+
+        self.broadcast(None, InvEvent::ServerShutdown);
+        let clients = std::mem::take(&mut *self.clients.write().unwrap());
+        log::info!("Disconnecting {} clients", clients.len());
+        // Dropping the outboxes lets each writer thread send what's left, then close.
+        drop(clients);
+        let (connections, closed) = &self.connections;
+        let connections = connections.lock().unwrap();
+        let (connections, _) = closed
+            .wait_timeout_while(connections, SHUTDOWN_TIMEOUT, |n| *n > 0)
+            .unwrap();
+        if *connections > 0 {
+            log::warn!("Gave up waiting on {} clients to disconnect", *connections);
+        }
+        drop(connections);
+
+        self.save()
+    }
+
+    /// Runs `f`, unless the server is shutting down. A shutdown waits for it to finish before saving.
+    /// For commands that don't come in over a client connection.
+    pub fn run_command<T>(&self, f: impl FnOnce() -> T) -> Option<T> {
+        let _running = self.commands.read().unwrap();
+        if self.is_shutting_down() {
+            return None;
+        }
+        Some(f())
+    }
+
+    /// Writes the inv to `storage`, if there is one.
+    pub fn save(&self) -> std::io::Result<()> {
+        let Some(storage) = &self.storage else {
+            return Ok(());
+        };
+        let mut last_save = self.last_save.lock().unwrap();
+        // Changes made from here on may not make it into the save, so they still count after it.
+        let changes = self.unsaved_changes.load(Ordering::SeqCst);
+        storage.save(&self.inv)?;
+        log::info!("Saved inv to {:?}", storage.path());
+        self.unsaved_changes.fetch_sub(changes, Ordering::SeqCst);
+        *last_save = Instant::now();
+        Ok(())
+    }
+
+    /// Saves if there are changes and the `autosave` policy says they have waited long enough.
+    pub fn autosave(&self) {
+        let unsaved_changes = self.unsaved_changes.load(Ordering::SeqCst);
+        if unsaved_changes == 0 {
+            return;
+        }
+        if unsaved_changes < self.autosave.max_changes
+            && self.last_save.lock().unwrap().elapsed() < self.autosave.interval
+        {
+            return;
+        }
+        if let Err(err) = self.save() {
+            let path = self.storage.as_ref().map(|storage| storage.path());
+            log::error!("Failed to autosave inv to {path:?} : {err:?}");
+        }
+    }
+
+    /// Stores an encoded picture and a thumbnail of it, returning its id.
+    /// Fails if it isn't a picture `picture::decode` can read.
+    pub fn store_picture(&self, encoded: &[u8]) -> std::io::Result<PictureId> {
+        let pic = picture::decode(encoded).map_err(std::io::Error::other)?;
+        let id = match &self.storage {
+            Some(storage) => storage.store_picture(encoded)?,
+            None => {
+                let id = PictureId::of(encoded);
+                let mut pictures = self.pictures.lock().unwrap();
+                pictures.entry(id).or_insert_with(|| encoded.to_vec());
+                id
+            }
+        };
+        self.store_thumbnail(&id, &pic)?;
+        Ok(id)
+    }
+
+    /// Makes and stores the thumbnail of picture `id`, returning it encoded.
+    fn store_thumbnail(&self, id: &PictureId, pic: &Picture) -> std::io::Result<Vec<u8>> {
+        let encoded = picture::encode(&picture::thumbnail(pic))?;
+        match &self.storage {
+            Some(storage) => storage.store_thumbnail(id, &encoded)?,
+            None => {
+                let mut thumbnails = self.thumbnails.lock().unwrap();
+                thumbnails.entry(*id).or_insert_with(|| encoded.clone());
+            }
+        }
+        Ok(encoded)
+    }
+
+    /// The encoded thumbnail of picture `id`, if the server has the picture.
+    /// Pictures that were stored without one, when moved out of an older save, get it made here.
+    pub fn load_thumbnail(&self, id: &PictureId) -> std::io::Result<Option<Vec<u8>>> {
+        let stored = match &self.storage {
+            Some(storage) => storage.load_thumbnail(id)?,
+            None => self.thumbnails.lock().unwrap().get(id).cloned(),
+        };
+        if stored.is_some() {
+            return Ok(stored);
+        }
+        let Some(encoded) = self.load_picture(id)? else {
+            return Ok(None);
+        };
+        let pic = picture::decode(&encoded).map_err(std::io::Error::other)?;
+        self.store_thumbnail(id, &pic).map(Some)
+    }
+
+    /// The encoded picture `id`, if the server has it.
+    pub fn load_picture(&self, id: &PictureId) -> std::io::Result<Option<Vec<u8>>> {
+        match &self.storage {
+            Some(storage) => storage.load_picture(id),
+            None => Ok(self.pictures.lock().unwrap().get(id).cloned()),
+        }
+    }
+
+    pub fn has_picture(&self, id: &PictureId) -> std::io::Result<bool> {
+        match &self.storage {
+            Some(storage) => storage.has_picture(id),
+            None => Ok(self.pictures.lock().unwrap().contains_key(id)),
+        }
+    }
+
+    /// Deletes the pictures no item, trashed item, audit log entry or backup uses,
+    /// returning how many were deleted.
+    ///
+    /// A picture uploaded for an edit the client hasn't sent yet is deleted too. The server then
+    /// refuses the edit, and the client uploads the picture again.
+    pub fn collect_garbage(&self) -> std::io::Result<usize> {
+        let _checks = self.picture_checks.write().unwrap();
+        let inv = self.inv.write().unwrap();
+        let mut used: HashSet<PictureId> = inv.pictures().copied().collect();
+        used.extend(self.audit.lock().unwrap().pictures()?);
+        if let Some(backups) = &self.backups {
+            for name in backups.list()? {
+                used.extend(backups.load(&name)?.inv.pictures());
+            }
+        }
+
+        let stored = match &self.storage {
+            Some(storage) => storage.picture_ids()?,
+            None => self.pictures.lock().unwrap().keys().copied().collect(),
+        };
+        let unused: Vec<_> = stored.into_iter().filter(|id| !used.contains(id)).collect();
+        for id in &unused {
+            match &self.storage {
+                Some(storage) => storage.delete_picture(id)?,
+                None => {
+                    self.pictures.lock().unwrap().remove(id);
+                    self.thumbnails.lock().unwrap().remove(id);
+                }
+            }
+        }
+        drop(inv);
+        log::info!("Deleted {} unused pictures", unused.len());
+        Ok(unused.len())
+    }
+
+    /// Writes a snapshot of the inv to the backup directory, returning the backup's name.
+    /// Pictures stay where they are stored, and `collect_garbage` keeps the ones backups use.
+    pub fn create_backup(&self) -> std::io::Result<String> {
+        let Some(backups) = &self.backups else {
+            return Err(std::io::Error::other(
+                "Backups are not enabled on this server",
+            ));
+        };
+        let name = backups.create(&self.inv.read().unwrap())?;
+        log::info!("Created backup {name:?}");
+        Ok(name)
+    }
+
+    /// Replaces the inv with the backup called `name`, returning the file it was read from.
+    /// The current inv is backed up first, so a restore can itself be undone.
+    pub fn restore_backup(&self, name: &str) -> std::io::Result<std::path::PathBuf> {
+        let Some(backups) = &self.backups else {
+            return Err(std::io::Error::other(
+                "Backups are not enabled on this server",
+            ));
+        };
+        let save::Decoded {
+            inv: mut restored,
+            pictures,
+        } = backups.load(name)?;
+        // Backups from before pictures were stored separately still hold their pictures.
+        for encoded in pictures {
+            self.store_picture(&encoded)?;
+        }
+        self.create_backup()?;
+
+        let mut inv = self.inv.write().unwrap();
+        // Revisions must keep moving forward, or clients would think they are already up to date.
+        let revision = inv.revision.max(restored.revision) + 1;
+        for id in inv.items.keys() {
+            if !restored.items.contains_key(id) {
+                restored.removed.insert(*id, revision);
+            }
+        }
+        for item in restored.items.values_mut() {
+            item.revision = revision;
+        }
+        restored.revision = revision;
+        *inv = restored;
+        drop(inv);
+        self.mark_changed();
+        let path = backups.path(name);
+        log::info!("Restored backup {}", path.display());
+        Ok(path)
+    }
+
+    /// Puts an item back the way it was before the change numbered `seq` in the audit log.
+    ///
+    /// Refuses if the item has changed again since, so later changes aren't silently lost.
+    pub fn undo_change(&self, seq: u64, user: &str) -> std::io::Result<()> {
+        let Some(entry) = self.audit.lock().unwrap().get(seq)? else {
+            return Err(std::io::Error::other(format!("No change numbered {seq}")));
+        };
+        let id = entry.id;
+        let mut inv = self.inv.write().unwrap();
+        let current = inv.items.get(&id);
+        let unchanged = match (current, &entry.after) {
+            (Some(current), Some(after)) => current.revision == after.revision,
+            (None, None) => true,
+            _ => false,
+        };
+        if !unchanged {
+            return Err(std::io::Error::other(format!(
+                "Item {:x} has changed since, undo the later changes first",
+                id.0
+            )));
+        }
+
+        let before = current.map(Item::copy);
+        let after = match entry.before {
+            Some(item) => {
+                let revision = inv.write_item(id, item.copy());
+                let mut after = item;
+                after.revision = revision;
+                self.broadcast(None, InvEvent::ItemInserted(id, Box::new(after.copy())));
+                Some(after)
+            }
+            None => {
+                inv.trash_item(id, user);
+                self.broadcast(None, InvEvent::ItemRemoved(id));
+                None
+            }
+        };
+        self.record(user, Action::Undo(seq), id, before.as_ref(), after.as_ref());
+        drop(inv);
+        self.mark_changed();
+        log::info!("Undid change #{seq} to item {:x}", id.0);
+        Ok(())
+    }
+
+    /// Writes `item`, returning the revision it was written at. Every client except `from` is told about it.
+    ///
+    /// `item.revision` is the revision the edit was based on. If someone else has written the item since,
+    /// nothing is written and their version is returned to merge with instead.
+    /// `written` is called with the new revision before anyone else can hear about the write.
+    pub fn insert_item(
+        &self,
+        id: Id,
+        item: Item,
+        user: &str,
+        from: Option<ClientId>,
+        written: impl FnOnce(u64),
+    ) -> Result<u64, Box<Item>> {
+        let mut inv = self.inv.write().unwrap();
+        let current = inv.items.get(&id);
+        if let Some(current) = current.filter(|c| c.revision != item.revision) {
+            log::info!(
+                "Rejected edit of item {:x} from {user} based on revision {} (current: {})",
+                id.0,
+                item.revision,
+                current.revision
+            );
+            return Err(Box::new(current.copy()));
+        }
+        let before = current.map(Item::copy);
+        let mut copy = item.copy();
+        copy.revision = inv.write_item(id, item);
+        let revision = copy.revision;
+        written(revision);
+        self.record(user, Action::InsertItem, id, before.as_ref(), Some(&copy));
+        self.broadcast(from, InvEvent::ItemInserted(id, Box::new(copy)));
+        drop(inv);
+        self.mark_changed();
+        Ok(revision)
+    }
+
+    /// Applies every op in `ops` under one lock, so no other change lands in between.
+    /// If any insert conflicts or refers to a picture the server doesn't have, none of the ops are
+    /// applied, and only those get a result saying why.
+    /// `written` is called with the results before other clients are told, like in `insert_item`.
+    ///
+    /// Each item may only appear once in a batch, as ops are checked against the inv before any of them run.
+    pub fn apply_batch(
+        &self,
+        ops: Vec<BatchOp>,
+        user: &str,
+        from: Option<ClientId>,
+        written: impl FnOnce(&[BatchResult]),
+    ) -> std::io::Result<Vec<BatchResult>> {
+        let mut inv = self.inv.write().unwrap();
+        let mut rejected = Vec::with_capacity(ops.len());
+        for op in &ops {
+            let BatchOp::Insert(id, item) = op else {
+                rejected.push(None);
+                continue;
+            };
+            let current = inv.items.get(id);
+            if let Some(current) = current.filter(|current| current.revision != item.revision) {
+                rejected.push(Some(BatchResult::Conflict(Box::new(current.copy()))));
+                continue;
+            }
+            let mut missing = None;
+            for picture in item.gallery.pictures() {
+                if !self.has_picture(picture)? {
+                    missing = Some(BatchResult::MissingPicture(*picture));
+                    break;
+                }
+            }
+            rejected.push(missing);
+        }
+        if rejected.iter().any(Option::is_some) {
+            let results: Vec<_> = (rejected.into_iter())
+                .map(|rejected| rejected.unwrap_or(BatchResult::NotApplied))
+                .collect();
+            log::info!(
+                "Rejected batch of {} changes from {user}, as {} of them conflict or use missing pictures",
+                results.len(),
+                (results.iter())
+                    .filter(|r| !matches!(r, BatchResult::NotApplied))
+                    .count()
+            );
+            written(&results);
+            return Ok(results);
+        }
+
+        let mut results = Vec::with_capacity(ops.len());
+        let mut events = Vec::with_capacity(ops.len());
+        for op in ops {
+            match op {
+                BatchOp::Insert(id, item) => {
+                    let before = inv.items.get(&id).map(Item::copy);
+                    let mut copy = item.copy();
+                    copy.revision = inv.write_item(id, *item);
+                    self.record(user, Action::InsertItem, id, before.as_ref(), Some(&copy));
+                    results.push(BatchResult::Inserted(copy.revision));
+                    events.push(InvEvent::ItemInserted(id, Box::new(copy)));
+                }
+                BatchOp::Remove(id) => match inv.trash_item(id, user).map(|t| t.item.copy()) {
+                    Some(removed) => {
+                        self.record(user, Action::RemoveItem, id, Some(&removed), None);
+                        results.push(BatchResult::Removed);
+                        events.push(InvEvent::ItemRemoved(id));
+                    }
+                    None => results.push(BatchResult::Missing),
+                },
+            }
+        }
+        written(&results);
+        let changes = events.len() as u32;
+        for event in events {
+            self.broadcast(from, event);
+        }
+        drop(inv);
+        log::info!("Applied batch of {} changes from {user}", results.len());
+        if changes > 0 {
+            self.unsaved_changes.fetch_add(changes, Ordering::SeqCst);
+            self.autosave();
+        }
+        Ok(results)
+    }
+
+    /// Moves an item to the trash. Every client except `from` is told about it.
+    /// `removed` is called once it is gone, before other clients are told, like in `insert_item`.
+    /// Returns false if there is no such item.
+    pub fn remove_item(
+        &self,
+        id: Id,
+        user: &str,
+        from: Option<ClientId>,
+        removed: impl FnOnce(),
+    ) -> bool {
+        let mut inv = self.inv.write().unwrap();
+        let Some(trashed) = inv.trash_item(id, user).map(|t| t.item.copy()) else {
+            return false;
+        };
+        removed();
+        self.record(user, Action::RemoveItem, id, Some(&trashed), None);
+        self.broadcast(from, InvEvent::ItemRemoved(id));
+        drop(inv);
+        self.mark_changed();
+        true
+    }
+
+    /// Adds a platform items can be listed on.
+    /// Connected clients pick up platform changes the next time they sync.
+    pub fn add_platform(&self, name: &str) -> std::io::Result<Platform> {
+        let mut inv = self.inv.write().unwrap();
+        check_platform_name(&inv, name)?;
+        let Some(platform) = Platform::from_idx(inv.platform_names.len() as u8) else {
+            return Err(std::io::Error::other(
+                "There can't be more than 8 platforms",
+            ));
+        };
+        inv.platform_names.push(name.to_owned());
+        drop(inv);
+        self.mark_changed();
+        log::info!("Added platform {name:?}");
+        Ok(platform)
+    }
+
+    pub fn rename_platform(&self, name: &str, new_name: &str) -> std::io::Result<()> {
+        let mut inv = self.inv.write().unwrap();
+        let Some(platform) = inv.find_platform(name) else {
+            return Err(std::io::Error::other(format!(
+                "No platform called {name:?}"
+            )));
+        };
+        check_platform_name(&inv, new_name)?;
+        inv.platform_names[platform.as_idx() as usize] = new_name.to_owned();
+        drop(inv);
+        self.mark_changed();
+        log::info!("Renamed platform {name:?} to {new_name:?}");
+        Ok(())
+    }
+
+    /// Removes a platform no item is listed on.
+    /// Items listed on the platforms after it are rewritten to keep their listings, as `user`.
+    pub fn remove_platform(&self, name: &str, user: &str) -> std::io::Result<()> {
+        let mut inv = self.inv.write().unwrap();
+        let Some(platform) = inv.find_platform(name) else {
+            return Err(std::io::Error::other(format!(
+                "No platform called {name:?}"
+            )));
+        };
+        let in_use = inv.platform_uses(platform);
+        if in_use > 0 {
+            return Err(std::io::Error::other(format!(
+                "{in_use} items (counting the trash) are still listed on {name:?}"
+            )));
+        }
+        // Listings are kept by the place of their platform, so the ones in the audit log are moved
+        // along with the inv's, or old versions of items would show them on the wrong platform.
+        self.audit.lock().unwrap().rewrite(|entry| {
+            for item in entry.before.iter_mut().chain(&mut entry.after) {
+                item.listings.remove_platform(platform);
+            }
+        })?;
+        let moved = inv.remove_platform(platform).unwrap();
+        for (id, mut before) in moved {
+            before.listings.remove_platform(platform);
+            let after = inv.items[&id].copy();
+            self.record(user, Action::InsertItem, id, Some(&before), Some(&after));
+            self.broadcast(None, InvEvent::ItemInserted(id, Box::new(after)));
+        }
+        drop(inv);
+        self.mark_changed();
+        log::info!("Removed platform {name:?}");
+        Ok(())
+    }
+
+    /// Moves an item out of the trash, returning the revision it was restored at.
+    /// Every client except `from` is told about it.
+    pub fn restore_trashed(
+        &self,
+        id: Id,
+        user: &str,
+        from: Option<ClientId>,
+    ) -> std::io::Result<u64> {
+        let mut inv = self.inv.write().unwrap();
+        let Some(revision) = inv.restore_item(id) else {
+            return Err(std::io::Error::other(format!(
+                "Item {:x} is not in the trash",
+                id.0
+            )));
+        };
+        let item = inv.items[&id].copy();
+        self.record(user, Action::RestoreItem, id, None, Some(&item));
+        self.broadcast(from, InvEvent::ItemInserted(id, Box::new(item)));
+        drop(inv);
+        self.mark_changed();
+        log::info!("Restored item {:x} from the trash", id.0);
+        Ok(revision)
+    }
+
+    /// Deletes an item from the trash for good.
+    pub fn purge_trashed(&self, id: Id, user: &str) -> std::io::Result<()> {
+        let mut inv = self.inv.write().unwrap();
+        let Some(trashed) = inv.trash.remove(&id) else {
+            return Err(std::io::Error::other(format!(
+                "Item {:x} is not in the trash",
+                id.0
+            )));
+        };
+        self.record(user, Action::PurgeItem, id, Some(&trashed.item), None);
+        drop(inv);
+        self.mark_changed();
+        log::info!("Purged item {:x} from the trash", id.0);
+        Ok(())
+    }
+
+    /// Purges items that have been in the trash for longer than `trash_retention`.
+    ///
+    /// Their removal, and every one before it, is forgotten as well. A client that hasn't synced
+    /// for that long is sent the whole inv instead.
+    pub fn purge_expired_trash(&self) {
+        let Some(cutoff) = SystemTime::now().checked_sub(self.trash_retention) else {
+            return;
+        };
+        let (expired, last_removed) = {
+            let inv = self.inv.read().unwrap();
+            let expired = inv.trashed_before(cutoff);
+            let last_removed = expired.iter().filter_map(|id| inv.removed.get(id)).max();
+            (expired, last_removed.copied())
+        };
+        for id in expired {
+            _ = self.purge_trashed(id, "server");
+        }
+        if let Some(revision) = last_removed {
+            self.inv.write().unwrap().prune_removed(revision + 1);
+            self.mark_changed();
+        }
+    }
+
+    /// Call while still holding the `inv` write lock, so entries are in the order the changes were made.
+    fn record(
+        &self,
+        user: &str,
+        action: Action,
+        id: Id,
+        before: Option<&Item>,
+        after: Option<&Item>,
+    ) {
+        let result = self
+            .audit
+            .lock()
+            .unwrap()
+            .record(user, action, id, before, after);
+        if let Err(err) = result {
+            log::error!(
+                "Failed to write {action} of item {:x} to the audit log : {err:?}",
+                id.0
+            );
+        }
+    }
+
+    /// Pushes `event` to every client except the one that caused it.
+    fn broadcast(&self, from: Option<ClientId>, event: InvEvent) {
+        let bytes = event.to_frame().encode();
+        for client in self.clients.read().unwrap().values() {
+            if Some(client.info.id) != from {
+                client.send(bytes.clone());
+            }
+        }
+    }
+
+    /// Queues `bytes` to be written to the client `id`.
+    fn send_to(&self, id: ClientId, bytes: Vec<u8>) {
+        if let Some(client) = self.clients.read().unwrap().get(&id) {
+            client.send(bytes);
+        }
+    }
+
+    /// Must not be called while holding `inv`, as it may save.
+    fn mark_changed(&self) {
+        self.unsaved_changes.fetch_add(1, Ordering::SeqCst);
+        self.autosave();
+    }
+
+    /// Logs in a newly connected client and handles its commands until it disconnects.
+    /// Blocks for as long as the client is connected, so each client gets a thread of its own.
+    pub fn serve_connection(&self, mut stream: tls::Stream) {
+        let addr = stream.tcp().peer_addr().ok();
+        let (id, outbox) = match self.connect_client(&mut stream, addr) {
+            Ok(client) => client,
+            Err(err) => return log::warn!("Client failed to connect : {err:?}"),
+        };
+        match stream.split() {
+            Ok((reader, writer)) => self.serve_client(id, outbox, reader, writer),
+            Err(err) => {
+                log::error!("Failed to split connection to client : {err:?}");
+                self.disconnect_client(id);
+            }
+        }
     }
 
-    pub fn connect_client(&mut self, mut io: C) -> std::io::Result<ClientId> {
+    /// Runs the login handshake on `io`.
+    /// Returns the new client's id, and the receiving end of its outbox, which holds the reply.
+    fn connect_client<T: Read + Write>(
+        &self,
+        io: &mut T,
+        addr: Option<SocketAddr>,
+    ) -> std::io::Result<(ClientId, Receiver<Vec<u8>>)> {
         let release = {
             let mut buf = [0u8; 3];
             io.read_exact(&mut buf)?;
             Release::from_bytes(buf)
         };
-        let name = {
-            let mut len_buf = [0u8; 4];
-            io.read_exact(&mut len_buf)?;
-            let len = u32::from_be_bytes(len_buf);
+        if release < Release::OLDEST_SUPPORTED {
+            send_code(io, CmdCode::OperationFailed)?;
+            let msg = format!(
+                "This release is too old to connect, please update to {} or later",
+                Release::OLDEST_SUPPORTED
+            );
+            send_str(io, &msg)?;
+            return Err(std::io::Error::other(format!(
+                "Client release {release} is too old to connect"
+            )));
+        }
+        let name = read_str_max(io, MAX_CREDENTIAL_LEN)?;
+        let secret = read_str_max(io, MAX_CREDENTIAL_LEN)?;
+        let role = self.accounts.read().unwrap().authenticate(&name, &secret);
+        let Some(role) = role else {
+            send_code(io, CmdCode::OperationFailed)?;
+            send_str(io, "Invalid user name or password")?;
+            return Err(std::io::Error::other(format!(
+                "Failed login attempt as {name:?}"
+            )));
+        };
+        let id = fastrand::u32(..);
+        let info = ClientInfo {
+            id,
+            name: name.clone(),
+            role,
+            release,
+            connected: SystemTime::now(),
+        };
+        let (outbox, outbox_rx) = std::sync::mpsc::channel();
+        let mut clients = self.clients.write().unwrap();
+        // Checked under the lock, so a shutdown can't miss a client that connects at the same time.
+        if self.is_shutting_down() {
+            drop(clients);
+            send_code(io, CmdCode::OperationFailed)?;
+            send_str(io, "The server is shutting down")?;
+            return Err(std::io::Error::other("Refused client, shutting down"));
+        }
+        if self.max_clients.is_some_and(|max| clients.len() >= max) {
+            drop(clients);
+            send_code(io, CmdCode::OperationFailed)?;
+            send_str(io, "The server is full, try again later")?;
+            return Err(std::io::Error::other(format!(
+                "Refused client ({name}), already serving the most clients allowed"
+            )));
+        }
+        // Queued before the client is added, so it goes out ahead of any event.
+        let mut reply = vec![CmdCode::ConnectionSuccessfull as u8, role as u8];
+        reply.extend(Release::CURRENT.as_bytes());
+        _ = outbox.send(reply);
+        clients.insert(id, Client { info, addr, outbox });
+        drop(clients);
+        log::info!("Successfully connected client ({name}) as {role} {release:?} {id:?}");
+        Ok((id, outbox_rx))
+    }
 
-            let mut buf = vec![0u8; len as usize];
-            io.read_exact(&mut buf)?;
-            String::from_utf8_lossy(&buf).to_string()
+    /// Forgets the client `id`. Its writer thread stops once the outbox is empty,
+    /// which closes the connection.
+    pub fn disconnect_client(&self, id: ClientId) -> Option<ClientInfo> {
+        let client = self.clients.write().unwrap().remove(&id)?;
+        log::info!("Client disconnected {:?}", client.info.name);
+        Some(client.info)
+    }
+
+    /// Reads commands from `reader` until the client disconnects or is kicked.
+    /// Everything queued for the client is written to `writer` from a thread of its own,
+    /// so a client that is slow to read doesn't hold up the ones pushing events to it.
+    fn serve_client<R: Read, W: Write + Send + 'static>(
+        &self,
+        id: ClientId,
+        outbox: Receiver<Vec<u8>>,
+        reader: R,
+        mut writer: W,
+    ) {
+        *self.connections.0.lock().unwrap() += 1;
+        let writer_thread = std::thread::spawn(move || {
+            for bytes in outbox {
+                if let Err(err) = writer.write_all(&bytes).and_then(|_| writer.flush()) {
+                    log::warn!("Failed to write to client : {err:?}");
+                    break;
+                }
+            }
+        });
+        let mut reader = std::io::BufReader::new(reader);
+        while let Ok(header) = FrameHeader::read(&mut reader) {
+            let request = if header.len > MAX_REQUEST_LEN {
+                // Skipped rather than read, but the connection can carry on after it.
+                let skipped = std::io::copy(
+                    &mut (&mut reader).take(header.len as u64),
+                    &mut std::io::sink(),
+                );
+                if skipped.is_err() {
+                    break;
+                }
+                Err(header)
+            } else {
+                let mut payload = vec![0u8; header.len as usize];
+                if reader.read_exact(&mut payload).is_err() {
+                    break;
+                }
+                Ok(Frame {
+                    code: header.code,
+                    id: header.id,
+                    payload,
+                })
+            };
+            let _running = self.commands.read().unwrap();
+            if self.is_shutting_down() {
+                break;
+            }
+            match request {
+                Ok(request) => self.handle_client_cmd(id, request),
+                Err(header) => {
+                    log::warn!(
+                        "Client {id} sent a request of {} bytes, refused it",
+                        header.len
+                    );
+                    let msg = format!("Requests can't be longer than {MAX_REQUEST_LEN} bytes");
+                    self.send_to(
+                        id,
+                        Frame::error(header.id, ErrCode::TooLarge, &msg).encode(),
+                    );
+                }
+            }
+        }
+        self.disconnect_client(id);
+        _ = writer_thread.join();
+        let (connections, closed) = &self.connections;
+        *connections.lock().unwrap() -= 1;
+        closed.notify_all();
+    }
+
+    /// Runs the command in `request` and queues the response in the client's outbox.
+    /// A request that fails, or can't be read, is answered with an `Error` response.
+    pub fn handle_client_cmd(&self, client_id: ClientId, request: Frame) {
+        let Some((name, role)) = self
+            .clients
+            .read()
+            .unwrap()
+            .get(&client_id)
+            .map(|c| (c.info.name.clone(), c.info.role))
+        else {
+            return log::warn!("Got a command from client {client_id}, who is not connected");
         };
-        send_code(&mut io, CmdCode::ConnectionSuccessfull)?;
+        let result = match CmdCode::from_u8(request.code) {
+            Some(cmd) if !role.allows(cmd) => {
+                log::warn!("Denied {cmd:?} to client ({name}), who is {role}");
+                Err(CmdErr(
+                    ErrCode::PermissionDenied,
+                    format!("{name} is {role}, which can't run {cmd:?}"),
+                ))
+            }
+            Some(cmd) => {
+                log::debug!("Recieved command from client {name:?} : {cmd:?}");
+                self.run_client_cmd(client_id, &name, cmd, request.id, &request.payload)
+            }
+            None => Err(CmdErr(
+                ErrCode::UnknownCommand,
+                format!("Unknown command {}", request.code),
+            )),
+        };
+        let response = match result {
+            Ok(Some(response)) => response,
+            // Already queued.
+            Ok(None) => return,
+            Err(CmdErr(code, msg)) => {
+                log::warn!(
+                    "Request {} ({}) from client ({name}) failed : {code:?} {msg}",
+                    request.id,
+                    request.code
+                );
+                Frame::error(request.id, code, &msg)
+            }
+        };
+        self.send_to(client_id, response.encode());
+        log::debug!("Finished response to command");
+    }
 
-        let id = fastrand::u32(..);
-        self.clients.insert(id, (name.clone(), io));
-        println!("Successfully connected client ({name}) {release:?} {id:?}");
-        Ok(id)
+    /// Refuses items that refer to a picture the server doesn't have, which a client has to upload first.
+    fn check_pictures<'a>(&self, items: impl IntoIterator<Item = &'a Item>) -> Result<(), CmdErr> {
+        for id in items.into_iter().flat_map(|item| item.gallery.pictures()) {
+            if !self.has_picture(id)? {
+                return Err(CmdErr(
+                    ErrCode::Failed,
+                    format!("Picture {id} hasn't been uploaded"),
+                ));
+            }
+        }
+        Ok(())
     }
 
-    pub fn handle_client_cmd(&mut self, id: ClientId, cmd: CmdCode) -> std::io::Result<()> {
-        let (name, io) = self.clients.get_mut(&id).unwrap();
-        println!("Recieved command from client {name:?} : {cmd:?}");
-        match cmd {
-            CmdCode::GetRelease => io.write_all(&Release::CURRENT.as_bytes())?,
+    /// Runs `cmd`, with its arguments in `payload`.
+    /// Returns the response to request `request_id`, or `None` if it has already been queued.
+    fn run_client_cmd(
+        &self,
+        client_id: ClientId,
+        name: &str,
+        cmd: CmdCode,
+        request_id: u32,
+        mut payload: &[u8],
+    ) -> Result<Option<Frame>, CmdErr> {
+        let io = &mut payload;
+        let mut out = vec![];
+        let code = match cmd {
+            CmdCode::GetRelease => {
+                out.write_all(&Release::CURRENT.as_bytes())?;
+                CmdCode::OperationSuccessfull
+            }
             CmdCode::GetInv => {
-                let bytes = bincode::serialize(&self.inv).unwrap();
-                let len = bytes.len() as u32;
-                io.write_all(&len.to_be_bytes())?;
-                io.write_all(&bytes)?;
+                out = bincode::serialize(&*self.inv.read().unwrap()).unwrap();
+                CmdCode::OperationSuccessfull
             }
             CmdCode::InsertItem => {
-                let mut id_bytes = [0u8; 4];
-                io.read_exact(&mut id_bytes)?;
-                let id = u32::from_be_bytes(id_bytes);
-
-                let mut size_bytes = [0u8; 4];
-                io.read_exact(&mut size_bytes)?;
-                let size = u32::from_be_bytes(size_bytes);
-
-                let mut item_bytes = vec![0u8; size as usize];
-                io.read_exact(&mut item_bytes)?;
-
-                let Ok(item) = bincode::deserialize(&item_bytes) else {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("Item data recieved from client ({name:?}) is invalid"),
-                    ));
-                };
-                self.inv.items.insert(Id(id), item);
-                send_code(io, CmdCode::OperationSuccessfull)?;
+                let id = Id(read_u32(io)?);
+                let item = bincode::deserialize::<Item>(io).map_err(|err| {
+                    CmdErr(ErrCode::Malformed, format!("Invalid item data : {err}"))
+                })?;
+                let checks = self.picture_checks.read().unwrap();
+                self.check_pictures([&item])?;
+
+                let result = self.insert_item(id, item, name, Some(client_id), |revision| {
+                    let payload = revision.to_be_bytes().to_vec();
+                    let response = Frame::new(CmdCode::OperationSuccessfull, request_id, payload);
+                    // Queued before the lock is released, so the client hears about its own write
+                    // before any later change to the same item.
+                    self.send_to(client_id, response.encode());
+                });
+                drop(checks);
+                match result {
+                    Ok(_) => return Ok(None),
+                    Err(current) => {
+                        out = bincode::serialize(&current).unwrap();
+                        CmdCode::Conflict
+                    }
+                }
             }
             CmdCode::RemoveItem => {
-                let mut id_bytes = [0u8; 4];
-                io.read_exact(&mut id_bytes)?;
-                let id = u32::from_be_bytes(id_bytes);
+                let id = Id(read_u32(io)?);
 
-                if !self.inv.items.contains_key(&Id(id)) {
-                    eprintln!("Client ({name}) tried to remove item with id {id:x}, but it does not exist.");
+                let removed = self.remove_item(id, name, Some(client_id), || {
+                    let response = Frame::new(CmdCode::OperationSuccessfull, request_id, vec![]);
+                    // Queued before the lock is released, like the response to `InsertItem`.
+                    self.send_to(client_id, response.encode());
+                });
+                if !removed {
+                    log::warn!(
+                        "Client ({name}) tried to remove item with id {:x}, but it does not exist.",
+                        id.0
+                    );
+                    return Err(CmdErr(ErrCode::Failed, format!("No item {:x}", id.0)));
                 }
-                self.inv.items.remove(&Id(id));
+                return Ok(None);
+            }
+            CmdCode::ApplyBatch => {
+                let ops: Vec<BatchOp> = bincode::deserialize(io).map_err(|err| {
+                    CmdErr(ErrCode::Malformed, format!("Invalid batch data : {err}"))
+                })?;
+                let mut ids = HashSet::with_capacity(ops.len());
+                if let Some(op) = ops.iter().find(|op| !ids.insert(op.id())) {
+                    let msg = format!("Item {:x} appears more than once in the batch", op.id().0);
+                    return Err(CmdErr(ErrCode::Malformed, msg));
+                }
+                self.apply_batch(ops, name, Some(client_id), |results| {
+                    let payload = bincode::serialize(results).unwrap();
+                    let response = Frame::new(CmdCode::OperationSuccessfull, request_id, payload);
+                    // Queued before the lock is released, like the response to `InsertItem`.
+                    self.send_to(client_id, response.encode());
+                })?;
+                return Ok(None);
+            }
+            CmdCode::GetChanges => {
+                let mut rev_bytes = [0u8; 8];
+                io.read_exact(&mut rev_bytes)?;
+                let since = u64::from_be_bytes(rev_bytes);
 
-                send_code(io, CmdCode::OperationSuccessfull)?;
+                let delta = self.inv.read().unwrap().changes_since(since);
+                out = bincode::serialize(&delta).unwrap();
+                CmdCode::OperationSuccessfull
             }
             CmdCode::GetServerClients => {
-                send_code(io, CmdCode::OperationSuccessfull)?;
+                let infos: Vec<ClientInfo> = self
+                    .clients
+                    .read()
+                    .unwrap()
+                    .values()
+                    .map(|c| c.info.clone())
+                    .collect();
+                out = bincode::serialize(&infos).unwrap();
+                CmdCode::OperationSuccessfull
             }
             CmdCode::CreateServerBackup => {
-                send_code(io, CmdCode::OperationSuccessfull)?;
+                let backup_name = self.create_backup().map_err(|err| {
+                    log::error!("Failed to create backup for client ({name}) : {err:?}");
+                    CmdErr(ErrCode::Failed, err.to_string())
+                })?;
+                send_str(&mut out, &backup_name)?;
+                CmdCode::OperationSuccessfull
+            }
+            CmdCode::GetTrash => {
+                let inv = self.inv.read().unwrap();
+                let trash: Vec<(&Id, &Trashed)> = inv.trash.iter().collect();
+                out = bincode::serialize(&trash).unwrap();
+                CmdCode::OperationSuccessfull
+            }
+            CmdCode::RestoreItem => {
+                let id = Id(read_u32(io)?);
+                let revision = self.restore_trashed(id, name, Some(client_id))?;
+                out.write_all(&revision.to_be_bytes())?;
+                CmdCode::OperationSuccessfull
+            }
+            CmdCode::PurgeItem => {
+                let id = Id(read_u32(io)?);
+                self.purge_trashed(id, name)?;
+                CmdCode::OperationSuccessfull
+            }
+            CmdCode::GetItemHistory => {
+                let id = Id(read_u32(io)?);
+                let history = self.audit.lock().unwrap().history(id).map_err(|err| {
+                    log::error!("Failed to read history of item {:x} : {err:?}", id.0);
+                    CmdErr(
+                        ErrCode::Failed,
+                        String::from("Failed to read the item's history"),
+                    )
+                })?;
+                out = bincode::serialize(&history).unwrap();
+                CmdCode::OperationSuccessfull
+            }
+            CmdCode::GetPicture => {
+                let mut id = [0u8; 32];
+                io.read_exact(&mut id)?;
+                let id = PictureId(id);
+                out = self
+                    .load_picture(&id)
+                    .map_err(|err| {
+                        log::error!("Failed to load picture {id} : {err:?}");
+                        CmdErr(ErrCode::Failed, String::from("Failed to load the picture"))
+                    })?
+                    .ok_or_else(|| CmdErr(ErrCode::Failed, format!("No picture {id}")))?;
+                CmdCode::OperationSuccessfull
+            }
+            CmdCode::GetThumbnail => {
+                let mut id = [0u8; 32];
+                io.read_exact(&mut id)?;
+                let id = PictureId(id);
+                out = self
+                    .load_thumbnail(&id)
+                    .map_err(|err| {
+                        log::error!("Failed to load thumbnail of picture {id} : {err:?}");
+                        CmdErr(
+                            ErrCode::Failed,
+                            String::from("Failed to load the thumbnail"),
+                        )
+                    })?
+                    .ok_or_else(|| CmdErr(ErrCode::Failed, format!("No picture {id}")))?;
+                CmdCode::OperationSuccessfull
+            }
+            CmdCode::PutPicture => {
+                let id = self.store_picture(payload).map_err(|err| {
+                    log::warn!("Failed to store picture from client ({name}) : {err:?}");
+                    CmdErr(
+                        ErrCode::Failed,
+                        format!("Failed to store the picture : {err}"),
+                    )
+                })?;
+                log::debug!("Stored picture {id} from client ({name})");
+                out.write_all(&id.0)?;
+                CmdCode::OperationSuccessfull
             }
             code => {
-                eprintln!("Recieved unexpected command code from client ({name}) : {code:?}")
+                return Err(CmdErr(
+                    ErrCode::UnknownCommand,
+                    format!("{code:?} is not a command"),
+                ))
             }
+        };
+        Ok(Some(Frame::new(code, request_id, out)))
+    }
+}
+
+/// A command that failed, with what to tell the client about it.
+struct CmdErr(ErrCode, String);
+/// Reading the arguments of a command only fails if there are too few of them.
+/// Errors from `ServerHost` methods are failures of the command itself.
+impl From<std::io::Error> for CmdErr {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => {
+                Self(ErrCode::Malformed, String::from("Request is too short"))
+            }
+            _ => Self(ErrCode::Failed, err.to_string()),
         }
-        println!("Finished response to command");
-        Ok(())
     }
 }
 
+fn read_u32<T: Read>(io: &mut T) -> std::io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    io.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn check_platform_name(inv: &Inv, name: &str) -> std::io::Result<()> {
+    if name.trim().is_empty() {
+        return Err(std::io::Error::other("Platform names can't be empty"));
+    }
+    if inv.find_platform(name).is_some() {
+        return Err(std::io::Error::other(format!(
+            "There is already a platform called {name:?}"
+        )));
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 pub enum ServerErr {
     TimedOut,
     OtherIo(std::io::Error),
     IncompatibleRelease(Release),
+    OperationFailed(String),
+    /// Our account's role doesn't allow the command.
+    PermissionDenied,
+    /// The item was changed on the server since our copy was last synced. Holds the server's version.
+    Conflict(Box<Item>),
+    /// The server couldn't read our request.
+    MalformedRequest(String),
+    /// The server doesn't know the command, likely because it runs an older release.
+    UnknownCommand(String),
+    /// The request was longer than the server accepts.
+    RequestTooLarge(String),
+    /// The server answered with something other than a response to our request.
+    UnexpectedResponse(u8),
+}
+impl ServerErr {
+    /// The error in the payload of an `Error` response.
+    fn from_error_payload(payload: &[u8]) -> Self {
+        let Some((&code, mut msg)) = payload.split_first() else {
+            return std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into();
+        };
+        let msg = match read_str(&mut msg) {
+            Ok(msg) => msg,
+            Err(err) => return err.into(),
+        };
+        match ErrCode::from_u8(code) {
+            Some(ErrCode::Malformed) => Self::MalformedRequest(msg),
+            Some(ErrCode::UnknownCommand) => Self::UnknownCommand(msg),
+            Some(ErrCode::TooLarge) => Self::RequestTooLarge(msg),
+            Some(ErrCode::PermissionDenied) => Self::PermissionDenied,
+            // Codes added by later releases are still failures we can show the message of.
+            Some(ErrCode::Failed) | None => Self::OperationFailed(msg),
+        }
+    }
 }
 impl From<std::io::Error> for ServerErr {
     fn from(err: std::io::Error) -> ServerErr {
@@ -205,6 +1595,17 @@ impl std::fmt::Display for ServerErr {
             Self::OtherIo(err) => {
                 std::fmt::Display::fmt(&err.kind(), f)?;
             }
+            Self::OperationFailed(msg) => f.write_str(msg)?,
+            Self::Conflict(_) => f.write_str("Item was changed by someone else")?,
+            Self::PermissionDenied => f.write_str("Your account is not allowed to do that")?,
+            Self::MalformedRequest(msg) => {
+                write!(f, "The server couldn't read the request : {msg}")?
+            }
+            Self::UnknownCommand(msg) => write!(f, "The server doesn't support that : {msg}")?,
+            Self::RequestTooLarge(msg) => f.write_str(msg)?,
+            Self::UnexpectedResponse(code) => {
+                write!(f, "Unexpected response from the server ({code})")?
+            }
         }
         Ok(())
     }
@@ -212,75 +1613,309 @@ impl std::fmt::Display for ServerErr {
 
 pub struct ServerConn<T> {
     io: T,
+    role: Role,
+    /// Events that arrived while we were waiting on a response.
+    events: VecDeque<InvEvent>,
+    /// The id of the next request. 0 is left for events.
+    next_request_id: u32,
+    /// Set once reading or writing a frame failed part way, say on a timeout.
+    /// Where the next frame starts is unknown from then on, so the connection can't be used again.
+    lost: bool,
 }
 impl<T: Read + Write> ServerConn<T> {
-    pub fn connect(mut io: T, name: &str) -> Result<Self, ServerErr> {
-        eprintln!("ServerConn::connect running");
+    /// Logs in as the user `name`. `secret` is either its password or one of its tokens.
+    pub fn connect(mut io: T, name: &str, secret: &str) -> Result<Self, ServerErr> {
+        log::debug!("ServerConn::connect running");
         io.write_all(&Release::CURRENT.as_bytes())?;
-        io.write_all(&(name.len() as u32).to_be_bytes())?;
-        io.write_all(name.as_bytes())?;
+        send_str(&mut io, name)?;
+        send_str(&mut io, secret)?;
 
-        expect_code(&mut io, CmdCode::ConnectionSuccessfull)?;
-        eprintln!("ServerConn::connect finished");
-        Ok(Self { io })
+        match read_code(&mut io)? {
+            CmdCode::ConnectionSuccessfull => {}
+            CmdCode::OperationFailed => return Err(ServerErr::OperationFailed(read_str(&mut io)?)),
+            code => return Err(ServerErr::UnexpectedResponse(code as u8)),
+        }
+        let mut buf = [0u8; 4];
+        io.read_exact(&mut buf)?;
+        let role =
+            Role::from_u8(buf[0]).ok_or_else(|| std::io::Error::other("Unknown role recieved"))?;
+        let release = Release::from_bytes(buf[1..].try_into().unwrap());
+        if !release.is_compatible() {
+            return Err(ServerErr::IncompatibleRelease(release));
+        }
+        log::debug!("ServerConn::connect finished");
+        Ok(Self {
+            io,
+            role,
+            events: VecDeque::new(),
+            next_request_id: 1,
+            lost: false,
+        })
     }
 
-    pub fn get_release(&mut self) -> Result<Release, ServerErr> {
-        send_code(&mut self.io, CmdCode::GetRelease)?;
+    /// What the server lets us do.
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Whether the connection failed in the middle of a frame. Every request fails from then on,
+    /// and it has to be replaced with a new connection.
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+
+    fn check_lost(&self) -> Result<(), ServerErr> {
+        match self.lost {
+            true => Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "The connection to the server was lost",
+            )
+            .into()),
+            false => Ok(()),
+        }
+    }
+
+    /// Writes a frame, giving up on the connection if that fails.
+    fn write_frame(&mut self, frame: Frame) -> Result<(), ServerErr> {
+        self.check_lost()?;
+        let written = frame.write(&mut self.io);
+        self.lost = written.is_err();
+        Ok(written?)
+    }
+
+    /// Reads the next frame, giving up on the connection if that fails.
+    fn read_frame(&mut self) -> Result<Frame, ServerErr> {
+        self.check_lost()?;
+        let frame = Frame::read(&mut self.io);
+        self.lost = frame.is_err();
+        Ok(frame?)
+    }
+
+    /// Sends `cmd`, and waits for the response to it. Events that arrive first are queued.
+    ///
+    /// Responses to earlier requests we stopped waiting on, say after a timeout, are skipped.
+    /// An `Error` response is returned as the matching `ServerErr`.
+    fn request(&mut self, cmd: CmdCode, payload: Vec<u8>) -> Result<Frame, ServerErr> {
+        let id = self.next_request_id;
+        self.next_request_id = id.checked_add(1).unwrap_or(1);
+        self.write_frame(Frame::new(cmd, id, payload))?;
+        loop {
+            let frame = self.read_frame()?;
+            if frame.id == 0 {
+                self.events.extend(InvEvent::read(&frame)?);
+                continue;
+            }
+            if frame.id != id {
+                continue;
+            }
+            return match CmdCode::from_u8(frame.code) {
+                Some(CmdCode::Error) => Err(ServerErr::from_error_payload(&frame.payload)),
+                _ => Ok(frame),
+            };
+        }
+    }
+
+    /// Sends `cmd`, and returns the payload of its `OperationSuccessfull` response.
+    fn request_ok(&mut self, cmd: CmdCode, payload: Vec<u8>) -> Result<Vec<u8>, ServerErr> {
+        let response = self.request(cmd, payload)?;
+        match CmdCode::from_u8(response.code) {
+            Some(CmdCode::OperationSuccessfull) => Ok(response.payload),
+            _ => Err(ServerErr::UnexpectedResponse(response.code)),
+        }
+    }
 
+    /// Takes the events that have already been recieved, without waiting for more.
+    pub fn take_events(&mut self) -> impl Iterator<Item = InvEvent> + '_ {
+        self.events.drain(..)
+    }
+
+    /// Waits for the next event from the server.
+    pub fn recv_event(&mut self) -> Result<InvEvent, ServerErr> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(event);
+        }
+        loop {
+            let frame = self.read_frame()?;
+            if frame.id != 0 {
+                continue;
+            }
+            if let Some(event) = InvEvent::read(&frame)? {
+                return Ok(event);
+            }
+        }
+    }
+
+    pub fn get_release(&mut self) -> Result<Release, ServerErr> {
+        let payload = self.request_ok(CmdCode::GetRelease, vec![])?;
         let mut buf = [0u8; 3];
-        self.io.read_exact(&mut buf)?;
-        Ok(Release(buf[0], buf[1], buf[2]))
+        payload.as_slice().read_exact(&mut buf)?;
+        Ok(Release::from_bytes(buf))
     }
 
     pub fn get_inv(&mut self) -> Result<Inv, ServerErr> {
         // Check inv version first
         let release = self.get_release()?;
-        if release.data_version() != Release::CURRENT.data_version() {
+        if !release.is_compatible() {
             return Err(ServerErr::IncompatibleRelease(release));
         }
 
-        eprintln!("ServerConn::get_inv running");
-        send_code(&mut self.io, CmdCode::GetInv)?;
-        eprintln!("Sent code");
-
-        let mut len_bytes = [0u8; 4];
-        self.io.read_exact(&mut len_bytes)?;
-        let len = u32::from_be_bytes(len_bytes);
-        eprintln!("Recieved len value : {len}");
-
-        let mut inv_bytes = vec![0u8; len as usize];
-        self.io.read_exact(&mut inv_bytes)?;
-        eprintln!("Recieved inv bytes");
-        let inv: Inv = bincode::deserialize(&inv_bytes)
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
-        eprintln!("ServerConn::get_inv finished");
+        log::debug!("ServerConn::get_inv running");
+        let payload = self.request_ok(CmdCode::GetInv, vec![])?;
+        log::debug!("Recieved inv bytes : {}", payload.len());
+        let inv: Inv = bincode::deserialize(&payload).map_err(std::io::Error::other)?;
+        log::debug!("ServerConn::get_inv finished");
         Ok(inv)
     }
 
-    pub fn insert_item(&mut self, id: Id, item: &Item) -> std::io::Result<()> {
-        eprintln!("ServerConn::insert_item running");
-        let item_bytes = bincode::serialize(item).unwrap();
+    /// Gets everything that changed on the server after `revision`.
+    pub fn get_changes(&mut self, revision: u64) -> Result<InvDelta, ServerErr> {
+        let release = self.get_release()?;
+        if !release.is_compatible() {
+            return Err(ServerErr::IncompatibleRelease(release));
+        }
 
-        send_code(&mut self.io, CmdCode::InsertItem)?;
+        let payload = self.request_ok(CmdCode::GetChanges, revision.to_be_bytes().to_vec())?;
+        let delta = bincode::deserialize(&payload).map_err(std::io::Error::other)?;
+        Ok(delta)
+    }
 
-        self.io.write_all(&id.0.to_be_bytes())?;
-        self.io
-            .write_all(&(item_bytes.len() as u32).to_be_bytes())?;
-        self.io.write_all(&item_bytes)?;
+    /// Uploads `item`, returning the revision the server stored it at.
+    ///
+    /// `item.revision` must be the revision our edit was based on,
+    /// otherwise this fails with `ServerErr::Conflict`.
+    pub fn insert_item(&mut self, id: Id, item: &Item) -> Result<u64, ServerErr> {
+        log::debug!("ServerConn::insert_item running");
+        let mut payload = id.0.to_be_bytes().to_vec();
+        payload.extend(bincode::serialize(item).unwrap());
 
-        expect_code(&mut self.io, CmdCode::OperationSuccessfull)?;
-        eprintln!("ServerConn::insert_item finished");
+        let response = self.request(CmdCode::InsertItem, payload)?;
+        match CmdCode::from_u8(response.code) {
+            Some(CmdCode::OperationSuccessfull) => {
+                let mut rev_bytes = [0u8; 8];
+                response.payload.as_slice().read_exact(&mut rev_bytes)?;
+                log::debug!("ServerConn::insert_item finished");
+                Ok(u64::from_be_bytes(rev_bytes))
+            }
+            Some(CmdCode::Conflict) => {
+                let current =
+                    bincode::deserialize(&response.payload).map_err(std::io::Error::other)?;
+                Err(ServerErr::Conflict(Box::new(current)))
+            }
+            _ => Err(ServerErr::UnexpectedResponse(response.code)),
+        }
+    }
+
+    /// Moves item `id` to the server's trash.
+    /// Fails with `ServerErr::OperationFailed` if the server has no such item.
+    pub fn remove_item(&mut self, id: Id) -> Result<(), ServerErr> {
+        log::debug!("ServerConn::remove_item running");
+        self.request_ok(CmdCode::RemoveItem, id.0.to_be_bytes().to_vec())?;
+        log::debug!("ServerConn::remove_item finished");
         Ok(())
     }
 
-    pub fn remove_item(&mut self, id: Id) -> std::io::Result<()> {
-        eprintln!("ServerConn::remove_item running");
-        send_code(&mut self.io, CmdCode::RemoveItem)?;
-        self.io.write_all(&id.0.to_be_bytes())?;
+    /// Applies `ops` on the server all at once, returning what became of each of them.
+    ///
+    /// If any insert conflicts or uses a picture the server doesn't have, nothing is applied: the
+    /// results hold the server's version of each conflicting item, the first missing picture of
+    /// each insert that uses one, and `BatchResult::NotApplied` for the rest.
+    pub fn apply_batch(&mut self, ops: &[BatchOp]) -> Result<Vec<BatchResult>, ServerErr> {
+        let payload = bincode::serialize(ops).unwrap();
+        let payload = self.request_ok(CmdCode::ApplyBatch, payload)?;
+        let results: Vec<BatchResult> =
+            bincode::deserialize(&payload).map_err(std::io::Error::other)?;
+        if results.len() != ops.len() {
+            return Err(std::io::Error::other("Wrong number of batch results recieved").into());
+        }
+        Ok(results)
+    }
+
+    /// Lists the items in the server's trash.
+    pub fn get_trash(&mut self) -> Result<Vec<(Id, Trashed)>, ServerErr> {
+        let payload = self.request_ok(CmdCode::GetTrash, vec![])?;
+        let trash = bincode::deserialize(&payload).map_err(std::io::Error::other)?;
+        Ok(trash)
+    }
+
+    /// Moves an item out of the server's trash, returning the revision it was restored at.
+    pub fn restore_item(&mut self, id: Id) -> Result<u64, ServerErr> {
+        let payload = self.request_ok(CmdCode::RestoreItem, id.0.to_be_bytes().to_vec())?;
+        let mut rev_bytes = [0u8; 8];
+        payload.as_slice().read_exact(&mut rev_bytes)?;
+        Ok(u64::from_be_bytes(rev_bytes))
+    }
 
-        expect_code(&mut self.io, CmdCode::OperationSuccessfull)?;
-        eprintln!("ServerConn::remove_item finished");
+    /// Deletes an item from the server's trash for good.
+    pub fn purge_item(&mut self, id: Id) -> Result<(), ServerErr> {
+        self.request_ok(CmdCode::PurgeItem, id.0.to_be_bytes().to_vec())?;
         Ok(())
     }
+
+    /// Every recorded version of an item, oldest first.
+    pub fn get_item_history(&mut self, id: Id) -> Result<Vec<ItemVersion>, ServerErr> {
+        let payload = self.request_ok(CmdCode::GetItemHistory, id.0.to_be_bytes().to_vec())?;
+        let history = bincode::deserialize(&payload).map_err(std::io::Error::other)?;
+        Ok(history)
+    }
+
+    /// Downloads the encoded picture `id`. Pictures never change, so this only needs doing once,
+    /// see `picture::PictureCache`.
+    pub fn get_picture(&mut self, id: &PictureId) -> Result<Vec<u8>, ServerErr> {
+        self.request_ok(CmdCode::GetPicture, id.0.to_vec())
+    }
+
+    /// Downloads the encoded thumbnail of picture `id`, at most `picture::THUMBNAIL_SIZE` pixels
+    /// on a side. Lists should use these, and only get the whole picture when it is looked at.
+    pub fn get_thumbnail(&mut self, id: &PictureId) -> Result<Vec<u8>, ServerErr> {
+        self.request_ok(CmdCode::GetThumbnail, id.0.to_vec())
+    }
+
+    /// Uploads an encoded picture, returning its id.
+    /// Items can only refer to pictures the server has, so this has to come before the item.
+    pub fn put_picture(&mut self, encoded: &[u8]) -> Result<PictureId, ServerErr> {
+        let payload = self.request_ok(CmdCode::PutPicture, encoded.to_vec())?;
+        let mut id = [0u8; 32];
+        payload.as_slice().read_exact(&mut id)?;
+        Ok(PictureId(id))
+    }
+
+    /// Asks the server to snapshot its inv, returning the name of the backup.
+    pub fn create_backup(&mut self) -> Result<String, ServerErr> {
+        let payload = self.request_ok(CmdCode::CreateServerBackup, vec![])?;
+        Ok(read_str(&mut payload.as_slice())?)
+    }
+
+    /// Lists every client connected to the server, including this one.
+    pub fn get_clients(&mut self) -> Result<Vec<ClientInfo>, ServerErr> {
+        let payload = self.request_ok(CmdCode::GetServerClients, vec![])?;
+        let clients = bincode::deserialize(&payload).map_err(std::io::Error::other)?;
+        Ok(clients)
+    }
+}
+
+impl<T: Read + Write + Nonblocking> ServerConn<T> {
+    /// Collects every event the server has pushed so far, without blocking.
+    pub fn poll_events(&mut self) -> Result<Vec<InvEvent>, ServerErr> {
+        self.check_lost()?;
+        let mut events: Vec<_> = self.events.drain(..).collect();
+        loop {
+            self.io.set_nonblocking(true)?;
+            let mut code_buf = [0u8];
+            let read = self.io.read(&mut code_buf);
+            self.io.set_nonblocking(false)?;
+            match read {
+                Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                Ok(_) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(events),
+                Err(err) => return Err(err.into()),
+            }
+            // The rest of the frame is on its way, so it's fine to block for it.
+            let frame = Frame::read(&mut code_buf.as_slice().chain(&mut self.io));
+            self.lost = frame.is_err();
+            let frame = frame?;
+            if frame.id == 0 {
+                events.extend(InvEvent::read(&frame)?);
+            }
+        }
+    }
 }
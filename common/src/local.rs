@@ -0,0 +1,345 @@
+//! The apps' copy of the inv, with the edits made to it that the server hasn't stored yet.
+
+use crate::inv::{Id, Inv, InvDelta, Item, PictureId};
+use crate::picture::{PictureCache, Size};
+use crate::{BatchOp, BatchResult, InvEvent, ServerConn, ServerErr};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct LocalInv {
+    pub(crate) modified_items: HashSet<Id>,
+    pub(crate) deleted_items: HashSet<Id>,
+    pub(crate) added_items: HashSet<Id>,
+    /// The last synced version of each item with unsynced edits, to merge against on a conflict.
+    pub(crate) base_items: HashMap<Id, Item>,
+    /// Pictures we took that the server doesn't have yet.
+    pub(crate) unsent_pictures: HashSet<PictureId>,
+
+    pub(crate) inv: Inv,
+}
+impl std::ops::Deref for LocalInv {
+    type Target = Inv;
+    fn deref(&self) -> &Inv {
+        &self.inv
+    }
+}
+impl std::ops::DerefMut for LocalInv {
+    fn deref_mut(&mut self) -> &mut Inv {
+        &mut self.inv
+    }
+}
+
+/// What came of uploading our edits.
+#[derive(Default)]
+pub struct Upload {
+    /// The server's version of each item it rejected because someone else changed it first.
+    /// Our edits of these are still pending.
+    pub conflicts: Vec<(Id, Item)>,
+    /// Our account isn't allowed to change the inv, so our edits were dropped.
+    pub denied: bool,
+    /// Photos taken out of our edits, as neither we nor the server have their pictures.
+    pub dropped_photos: Vec<(Id, PictureId)>,
+}
+
+impl LocalInv {
+    /// Our edits as a batch, each item once. They stay pending until the server has answered for them.
+    pub fn pending_ops(&self) -> Vec<BatchOp> {
+        let changed: HashSet<Id> = self
+            .added_items
+            .union(&self.modified_items)
+            .copied()
+            .collect();
+        let inserts = (changed.into_iter()).filter_map(|id| {
+            Some(BatchOp::Insert(
+                id,
+                Box::new(self.inv.items.get(&id)?.copy()),
+            ))
+        });
+        let removes = self.deleted_items.iter().map(|id| BatchOp::Remove(*id));
+        inserts.chain(removes).collect()
+    }
+
+    pub fn has_pending_changes(&self) -> bool {
+        !(self.added_items.is_empty()
+            && self.modified_items.is_empty()
+            && self.deleted_items.is_empty())
+    }
+
+    pub fn r#override(&mut self, inv: Inv) {
+        self.inv = inv;
+        self.modified_items.clear();
+        self.deleted_items.clear();
+        self.added_items.clear();
+        self.base_items.clear();
+    }
+
+    fn has_pending_change(&self, id: &Id) -> bool {
+        self.added_items.contains(id)
+            || self.modified_items.contains(id)
+            || self.deleted_items.contains(id)
+    }
+
+    /// Brings the inv up to date with the server, keeping any items we still have unsynced changes to.
+    pub fn apply_delta(&mut self, delta: InvDelta) {
+        if delta.full {
+            let pending: HashSet<Id> = self
+                .inv
+                .items
+                .keys()
+                .filter(|id| self.has_pending_change(id))
+                .copied()
+                .collect();
+            self.inv.items.retain(|id, _| pending.contains(id));
+        }
+        for (id, item) in delta.changed {
+            if !self.has_pending_change(&id) {
+                self.inv.items.insert(id, item);
+            }
+        }
+        for id in delta.removed {
+            if !self.has_pending_change(&id) {
+                self.inv.items.remove(&id);
+            }
+        }
+        self.inv.platform_names = delta.platform_names;
+        self.inv.revision = delta.revision;
+    }
+
+    /// Records that the server has stored our version of an item at `revision`.
+    pub fn mark_synced(&mut self, id: Id, revision: u64) {
+        if let Some(item) = self.inv.items.get_mut(&id) {
+            item.revision = revision;
+        }
+        self.added_items.remove(&id);
+        self.modified_items.remove(&id);
+        self.base_items.remove(&id);
+    }
+
+    /// Records that the server no longer has an item we removed.
+    pub fn mark_removed(&mut self, id: Id) {
+        self.deleted_items.remove(&id);
+    }
+
+    /// Drops our pending edits and what we know of the server's state, so the next download
+    /// replaces every item.
+    pub fn discard_changes(&mut self) {
+        self.added_items.clear();
+        self.modified_items.clear();
+        self.deleted_items.clear();
+        self.base_items.clear();
+        self.inv.revision = 0;
+    }
+
+    /// The last synced version of an item we have edited.
+    pub fn base_item(&self, id: &Id) -> Option<&Item> {
+        self.base_items.get(id)
+    }
+
+    /// Queues an item to be uploaded again on the next sync.
+    pub fn requeue(&mut self, id: Id) {
+        if self.inv.items.contains_key(&id) {
+            self.modified_items.insert(id);
+        }
+    }
+
+    /// Settles a conflict with the server's version `remote`, either by accepting it (`None`)
+    /// or by replacing it with `resolved`, which is uploaded on the next sync.
+    pub fn resolve_conflict(&mut self, id: Id, remote: Item, resolved: Option<Item>) {
+        self.added_items.remove(&id);
+        match resolved {
+            None => {
+                self.modified_items.remove(&id);
+                self.base_items.remove(&id);
+                self.inv.items.insert(id, remote);
+            }
+            Some(mut item) => {
+                item.revision = remote.revision;
+                self.modified_items.insert(id);
+                self.inv.items.insert(id, item);
+                self.base_items.insert(id, remote);
+            }
+        }
+    }
+
+    /// Applies a change pushed by the server, unless we have an unsynced change of our own to
+    /// the same item, which will be uploaded on the next sync.
+    pub fn apply_event(&mut self, event: InvEvent) {
+        let id = match &event {
+            InvEvent::ItemInserted(id, _) | InvEvent::ItemRemoved(id) => *id,
+            InvEvent::ServerShutdown => return,
+        };
+        if self.has_pending_change(&id) {
+            return;
+        }
+        match event {
+            InvEvent::ItemInserted(id, item) => _ = self.inv.items.insert(id, *item),
+            InvEvent::ItemRemoved(id) => _ = self.inv.items.remove(&id),
+            InvEvent::ServerShutdown => {}
+        }
+    }
+
+    /// Queues a picture we took to be uploaded before the items using it.
+    pub fn queue_picture(&mut self, id: PictureId) {
+        self.unsent_pictures.insert(id);
+    }
+
+    pub fn is_picture_unsent(&self, id: &PictureId) -> bool {
+        self.unsent_pictures.contains(id)
+    }
+
+    pub fn mark_picture_sent(&mut self, id: &PictureId) {
+        self.unsent_pictures.remove(id);
+    }
+
+    pub fn get_item(&self, id: &Id) -> Option<&Item> {
+        self.inv.items.get(id)
+    }
+
+    pub fn items(&self) -> impl Iterator<Item = (&Id, &Item)> {
+        self.inv.items.iter()
+    }
+    pub fn item_count(&self) -> usize {
+        self.inv.items.len()
+    }
+
+    pub fn insert_item(&mut self, id: Id, mut item: Item) {
+        match self.inv.items.get(&id) {
+            None => _ = self.added_items.insert(id),
+            Some(old) => {
+                // The edit is based on the revision we had, whatever the new item says.
+                item.revision = old.revision;
+                item.creation_date = old.creation_date;
+                if !self.added_items.contains(&id) {
+                    self.modified_items.insert(id);
+                    self.base_items.entry(id).or_insert_with(|| {
+                        let mut base = old.clone();
+                        base.creation_date = old.creation_date;
+                        base
+                    });
+                }
+            }
+        }
+        self.inv.items.insert(id, item);
+    }
+
+    pub fn remove_item(&mut self, id: &Id) {
+        self.inv.items.remove(id);
+        self.modified_items.remove(id);
+        self.base_items.remove(id);
+        if !self.added_items.remove(id) {
+            self.deleted_items.insert(*id);
+        }
+    }
+
+    /// Uploads our edits, and before them the pictures they use that the server doesn't have yet.
+    ///
+    /// An edit stays pending until the server has answered for it, so if the connection fails
+    /// everything not yet stored is uploaded again on the next sync.
+    /// An edit the server rejects is left out, and the rest is sent again without it.
+    pub fn upload_changes<S: Read + Write>(
+        &mut self,
+        server: &mut ServerConn<S>,
+        pictures: &PictureCache,
+    ) -> Result<Upload, ServerErr> {
+        let mut upload = Upload::default();
+        // Pictures the server turned out not to have, though we sent them before.
+        let mut resent = HashSet::new();
+        loop {
+            let mut ops = self.pending_ops();
+            ops.retain(|op| !upload.conflicts.iter().any(|(id, _)| *id == op.id()));
+            if ops.is_empty() {
+                break;
+            }
+            upload.denied = !self.upload_pictures(&ops, server, pictures)?;
+            if upload.denied {
+                break;
+            }
+            let results = match server.apply_batch(&ops) {
+                Ok(results) => results,
+                Err(ServerErr::PermissionDenied) => {
+                    upload.denied = true;
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
+            let mut rejected = false;
+            for (op, result) in ops.iter().zip(results) {
+                match result {
+                    BatchResult::Inserted(revision) => self.mark_synced(op.id(), revision),
+                    BatchResult::Removed | BatchResult::Missing => self.mark_removed(op.id()),
+                    BatchResult::Conflict(remote) => {
+                        upload.conflicts.push((op.id(), *remote));
+                        rejected = true;
+                    }
+                    BatchResult::MissingPicture(picture) => {
+                        if resent.insert(picture) && pictures.contains(&picture, Size::Full) {
+                            // The server lost it, say to a restored backup, so it is sent again.
+                            self.queue_picture(picture);
+                        } else {
+                            self.drop_photos(op.id(), &picture);
+                            upload.dropped_photos.push((op.id(), picture));
+                        }
+                        rejected = true;
+                    }
+                    BatchResult::NotApplied => {}
+                }
+            }
+            if !rejected {
+                break;
+            }
+        }
+        if upload.denied {
+            // Our edits will never be accepted, so put back what the server has instead.
+            self.discard_changes();
+        }
+        Ok(upload)
+    }
+
+    /// Uploads the pictures `ops` use that the server doesn't have yet, as it rejects items using them.
+    /// Returns false if our account isn't allowed to.
+    fn upload_pictures<S: Read + Write>(
+        &mut self,
+        ops: &[BatchOp],
+        server: &mut ServerConn<S>,
+        pictures: &PictureCache,
+    ) -> Result<bool, ServerErr> {
+        let unsent: Vec<PictureId> = (ops.iter())
+            .filter_map(|op| match op {
+                BatchOp::Insert(_, item) => Some(item.gallery.pictures()),
+                BatchOp::Remove(_) => None,
+            })
+            .flatten()
+            .copied()
+            .filter(|id| self.is_picture_unsent(id))
+            .collect();
+        for id in unsent {
+            let Some(encoded) = pictures.read(&id, Size::Full)? else {
+                log::warn!("Picture {id} is missing from the cache, so it can't be uploaded");
+                continue;
+            };
+            match server.put_picture(&encoded) {
+                Ok(_) => self.mark_picture_sent(&id),
+                Err(ServerErr::PermissionDenied) => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Takes the photos of `picture` out of our edit of item `id`.
+    fn drop_photos(&mut self, id: Id, picture: &PictureId) {
+        let Some(item) = self.inv.items.get_mut(&id) else {
+            return;
+        };
+        let ids: Vec<PictureId> = item.gallery.pictures().copied().collect();
+        for idx in (0..ids.len()).rev().filter(|idx| ids[*idx] == *picture) {
+            item.gallery.remove(idx);
+        }
+        log::warn!(
+            "Dropped picture {picture} from item {:x}, as nobody has it",
+            id.0
+        );
+    }
+}
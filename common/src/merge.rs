@@ -0,0 +1,160 @@
+//! Three-way merging of items edited on two devices at once.
+
+use crate::inv::{Item, Listing, Listings, Usd};
+
+pub struct Merged {
+    pub item: Item,
+    /// Fields that were changed differently on both sides. `item` has the local value for these.
+    pub conflicts: Vec<&'static str>,
+}
+
+/// Combines the `local` and `remote` edits of an item that both started from `base`.
+///
+/// A field changed on only one side takes that side's value.
+/// Sold counts changed on both sides add up, so sales recorded on two devices both survive.
+/// The result has `remote`'s revision, so it can be uploaded on top of it.
+pub fn merge_items(base: &Item, local: &Item, remote: &Item) -> Merged {
+    let mut conflicts = vec![];
+    let c = &mut conflicts;
+
+    let mut item = Item {
+        creation_date: remote.creation_date,
+        revision: remote.revision,
+        location: pick_field(
+            c,
+            "location",
+            &base.location,
+            &local.location,
+            &remote.location,
+        ),
+        listings: Listings::default(),
+        gallery: pick_field(c, "photos", &base.gallery, &local.gallery, &remote.gallery),
+        name: pick_field(c, "name", &base.name, &local.name, &remote.name),
+        desc: pick_field(c, "description", &base.desc, &local.desc, &remote.desc),
+        count: pick_field(c, "count", &base.count, &local.count, &remote.count),
+        est_cost: Usd(pick_field(
+            c,
+            "cost",
+            &base.est_cost.0,
+            &local.est_cost.0,
+            &remote.est_cost.0,
+        )),
+        condition: pick_field(
+            c,
+            "condition",
+            &base.condition,
+            &local.condition,
+            &remote.condition,
+        ),
+        color: pick_field(c, "color", &base.color, &local.color, &remote.color),
+        dimensions: pick_field(
+            c,
+            "dimensions",
+            &base.dimensions,
+            &local.dimensions,
+            &remote.dimensions,
+        ),
+        weight: pick_field(c, "weight", &base.weight, &local.weight, &remote.weight),
+        shipping_weight: pick_field(
+            c,
+            "shipping weight",
+            &base.shipping_weight,
+            &local.shipping_weight,
+            &remote.shipping_weight,
+        ),
+        model_no: pick_field(
+            c,
+            "model",
+            &base.model_no,
+            &local.model_no,
+            &remote.model_no,
+        ),
+        serial_no: pick_field(
+            c,
+            "serial",
+            &base.serial_no,
+            &local.serial_no,
+            &remote.serial_no,
+        ),
+        brand: pick_field(c, "brand", &base.brand, &local.brand, &remote.brand),
+    };
+
+    for idx in 0..item.listings.0.len() {
+        let (base, local, remote) = (
+            &base.listings.0[idx],
+            &local.listings.0[idx],
+            &remote.listings.0[idx],
+        );
+        item.listings.0[idx] = match (base, local, remote) {
+            (Some(base), Some(local), Some(remote)) => Some(Listing {
+                date: pick_field(
+                    &mut conflicts,
+                    "listing date",
+                    &base.date,
+                    &local.date,
+                    &remote.date,
+                ),
+                sold: merge_sold(base.sold, local.sold, remote.sold),
+            }),
+            _ => pick_field(&mut conflicts, "listings", base, local, remote),
+        };
+    }
+
+    Merged { item, conflicts }
+}
+
+/// Names of the fields that differ between two versions of an item.
+/// Uses the same names as `Merged::conflicts`.
+pub fn changed_fields(old: &Item, new: &Item) -> Vec<&'static str> {
+    let fields = [
+        ("location", old.location != new.location),
+        ("listings", old.listings != new.listings),
+        ("photos", old.gallery != new.gallery),
+        ("name", old.name != new.name),
+        ("description", old.desc != new.desc),
+        ("count", old.count != new.count),
+        ("cost", old.est_cost.0 != new.est_cost.0),
+        ("condition", old.condition != new.condition),
+        ("color", old.color != new.color),
+        ("dimensions", old.dimensions != new.dimensions),
+        ("weight", old.weight != new.weight),
+        (
+            "shipping weight",
+            old.shipping_weight != new.shipping_weight,
+        ),
+        ("model", old.model_no != new.model_no),
+        ("serial", old.serial_no != new.serial_no),
+        ("brand", old.brand != new.brand),
+    ];
+    fields
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// Sales recorded on each side since `base` added together. Sales taken back on one side cancel
+/// out sales on the other, down to 0.
+fn merge_sold(base: u32, local: u32, remote: u32) -> u32 {
+    let sold = local as i64 + remote as i64 - base as i64;
+    sold.clamp(0, u32::MAX as i64) as u32
+}
+
+fn pick_field<T: PartialEq + Clone>(
+    conflicts: &mut Vec<&'static str>,
+    name: &'static str,
+    base: &T,
+    local: &T,
+    remote: &T,
+) -> T {
+    if local == base || local == remote {
+        remote.clone()
+    } else if remote == base {
+        local.clone()
+    } else {
+        if !conflicts.contains(&name) {
+            conflicts.push(name);
+        }
+        local.clone()
+    }
+}
@@ -0,0 +1,438 @@
+//! Encoding pictures for storage, and the client's cache of pictures fetched from the server.
+//!
+//! Pictures are encoded once, when they are taken, and only ever passed around encoded.
+//! Each encoded picture is stored under its `PictureId`, so the same picture is never stored twice.
+//! A small thumbnail is stored next to it under the same id, for lists that don't need the whole picture.
+
+use crate::inv::{Picture, PictureId};
+use crate::{ServerConn, ServerErr};
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// JPEG quality pictures are encoded at. Photos barely look different from the originals at this.
+const JPEG_QUALITY: u8 = 85;
+
+/// How many decoded pictures a `PictureCache` keeps in memory.
+const MAX_DECODED: usize = 16;
+/// How many decoded thumbnails a `PictureCache` keeps in memory, enough for a few screens of a list.
+const MAX_DECODED_THUMBNAILS: usize = 256;
+
+/// Longest side of a picture that is decoded, in pixels. Enough for any phone camera, while a
+/// picture claiming more can't make whoever decodes it allocate gigabytes.
+pub const MAX_SIDE: u32 = 8192;
+
+/// Longest side of a thumbnail, in pixels. Enough for the app's 50 point list pictures at a scale of 3.
+pub const THUMBNAIL_SIZE: u32 = 160;
+
+/// Which version of a picture is wanted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Size {
+    Full,
+    Thumbnail,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    Jpeg,
+}
+impl Format {
+    /// Recognizes an encoded picture by its first bytes.
+    pub fn of(encoded: &[u8]) -> Option<Self> {
+        if encoded.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Self::Png)
+        } else if encoded.starts_with(&[0xff, 0xd8, 0xff]) {
+            Some(Self::Jpeg)
+        } else {
+            None
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeErr {
+    Unrecognized,
+    Png(png::DecodingError),
+    Jpeg(jpeg_decoder::Error),
+    Unsupported(&'static str),
+    /// Wider or higher than `MAX_SIDE`.
+    TooLarge([u32; 2]),
+}
+impl std::fmt::Display for DecodeErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unrecognized => f.write_str("not a PNG or JPEG picture"),
+            Self::Png(err) => write!(f, "invalid PNG : {err}"),
+            Self::Jpeg(err) => write!(f, "invalid JPEG : {err}"),
+            Self::Unsupported(what) => write!(f, "unsupported picture : {what}"),
+            Self::TooLarge([w, h]) => {
+                write!(
+                    f,
+                    "picture is {w}x{h}, more than {MAX_SIDE} pixels on a side"
+                )
+            }
+        }
+    }
+}
+impl std::error::Error for DecodeErr {}
+
+/// Encodes a picture as a JPEG. Fails if it is too big for one, or its data doesn't match its size.
+pub fn encode(pic: &Picture) -> std::io::Result<Vec<u8>> {
+    let [w, h] = pic.size;
+    if pic.data.len() as u64 != w as u64 * h as u64 * 4 {
+        return Err(std::io::Error::other(format!(
+            "{} bytes of data for a {w}x{h} picture",
+            pic.data.len()
+        )));
+    }
+    let (Ok(w), Ok(h)) = (u16::try_from(w), u16::try_from(h)) else {
+        return Err(std::io::Error::other(format!(
+            "{w}x{h} is too big for a JPEG"
+        )));
+    };
+    // JPEG has no alpha, the encoder skips the last channel of RGBA.
+    let rgba: Vec<u8> = (pic.data.chunks_exact(4))
+        .flat_map(|argb| [argb[1], argb[2], argb[3], argb[0]])
+        .collect();
+    let mut bytes = vec![];
+    jpeg_encoder::Encoder::new(&mut bytes, JPEG_QUALITY)
+        .encode(&rgba, w, h, jpeg_encoder::ColorType::Rgba)
+        .map_err(std::io::Error::other)?;
+    Ok(bytes)
+}
+
+/// Encodes a picture as a PNG. Pictures are stored as JPEGs, this is for handing them out to
+/// whatever expects a PNG.
+pub fn encode_png(pic: &Picture) -> std::io::Result<Vec<u8>> {
+    let [w, h] = pic.size;
+    if pic.data.len() as u64 != w as u64 * h as u64 * 4 {
+        return Err(std::io::Error::other(format!(
+            "{} bytes of data for a {w}x{h} picture",
+            pic.data.len()
+        )));
+    }
+    let rgba: Vec<u8> = (pic.data.chunks_exact(4))
+        .flat_map(|argb| [argb[1], argb[2], argb[3], argb[0]])
+        .collect();
+    let mut bytes = vec![];
+    let mut encoder = png::Encoder::new(&mut bytes, w, h);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(std::io::Error::other)?;
+    writer
+        .write_image_data(&rgba)
+        .map_err(std::io::Error::other)?;
+    writer.finish().map_err(std::io::Error::other)?;
+    Ok(bytes)
+}
+
+/// Refuses sizes over `MAX_SIDE`, before anything is allocated for the pixels.
+fn check_size(w: u32, h: u32) -> Result<(), DecodeErr> {
+    match w > MAX_SIDE || h > MAX_SIDE {
+        true => Err(DecodeErr::TooLarge([w, h])),
+        false => Ok(()),
+    }
+}
+
+/// Decodes a PNG or JPEG picture. Pictures over `MAX_SIDE` are refused without being decoded.
+pub fn decode(encoded: &[u8]) -> Result<Picture, DecodeErr> {
+    match Format::of(encoded) {
+        Some(Format::Png) => decode_png(encoded),
+        Some(Format::Jpeg) => decode_jpeg(encoded),
+        None => Err(DecodeErr::Unrecognized),
+    }
+}
+
+fn decode_png(encoded: &[u8]) -> Result<Picture, DecodeErr> {
+    let mut decoder = png::Decoder::new(encoded);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(DecodeErr::Png)?;
+    check_size(reader.info().width, reader.info().height)?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(DecodeErr::Png)?;
+    let pixels = &buf[..info.buffer_size()];
+    let data = match info.color_type {
+        png::ColorType::Rgba => (pixels.chunks_exact(4))
+            .flat_map(|rgba| [rgba[3], rgba[0], rgba[1], rgba[2]])
+            .collect(),
+        png::ColorType::Rgb => (pixels.chunks_exact(3))
+            .flat_map(|rgb| [255, rgb[0], rgb[1], rgb[2]])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => (pixels.chunks_exact(2))
+            .flat_map(|la| [la[1], la[0], la[0], la[0]])
+            .collect(),
+        png::ColorType::Grayscale => (pixels.iter()).flat_map(|l| [255, *l, *l, *l]).collect(),
+        png::ColorType::Indexed => return Err(DecodeErr::Unsupported("indexed PNG")),
+    };
+    Ok(Picture {
+        data,
+        size: [info.width, info.height],
+    })
+}
+
+fn decode_jpeg(encoded: &[u8]) -> Result<Picture, DecodeErr> {
+    let mut decoder = jpeg_decoder::Decoder::new(encoded);
+    decoder.read_info().map_err(DecodeErr::Jpeg)?;
+    if let Some(info) = decoder.info() {
+        check_size(info.width.into(), info.height.into())?;
+    }
+    let pixels = decoder.decode().map_err(DecodeErr::Jpeg)?;
+    let info = decoder
+        .info()
+        .ok_or(DecodeErr::Unsupported("JPEG without a frame"))?;
+    let data = match info.pixel_format {
+        jpeg_decoder::PixelFormat::RGB24 => (pixels.chunks_exact(3))
+            .flat_map(|rgb| [255, rgb[0], rgb[1], rgb[2]])
+            .collect(),
+        jpeg_decoder::PixelFormat::L8 => (pixels.iter()).flat_map(|l| [255, *l, *l, *l]).collect(),
+        jpeg_decoder::PixelFormat::L16 => (pixels.chunks_exact(2))
+            .flat_map(|l| [255, l[0], l[0], l[0]])
+            .collect(),
+        jpeg_decoder::PixelFormat::CMYK32 => return Err(DecodeErr::Unsupported("CMYK JPEG")),
+    };
+    Ok(Picture {
+        data,
+        size: [info.width as u32, info.height as u32],
+    })
+}
+
+/// Scales a picture down to fit in `THUMBNAIL_SIZE`, averaging the pixels each thumbnail pixel covers.
+/// Pictures that already fit are left as they are.
+pub fn thumbnail(pic: &Picture) -> Picture {
+    let [w, h] = pic.size;
+    let longest = w.max(h);
+    if longest <= THUMBNAIL_SIZE {
+        return pic.clone();
+    }
+    let scaled = |len: u32| (len as u64 * THUMBNAIL_SIZE as u64 / longest as u64).max(1) as u32;
+    let [tw, th] = [scaled(w), scaled(h)];
+    let mut data = Vec::with_capacity(tw as usize * th as usize * 4);
+    for ty in 0..th {
+        let ys = (ty * h / th)..((ty + 1) * h / th).max(ty * h / th + 1);
+        for tx in 0..tw {
+            let xs = (tx * w / tw)..((tx + 1) * w / tw).max(tx * w / tw + 1);
+            let mut sum = [0u64; 4];
+            for y in ys.clone() {
+                let row = (y * w) as usize * 4;
+                for x in xs.clone() {
+                    let px = &pic.data[row + x as usize * 4..][..4];
+                    for (total, channel) in sum.iter_mut().zip(px) {
+                        *total += *channel as u64;
+                    }
+                }
+            }
+            let count = ys.len() as u64 * xs.len() as u64;
+            data.extend(sum.map(|total| (total / count) as u8));
+        }
+    }
+    Picture {
+        data,
+        size: [tw, th],
+    }
+}
+
+/// The name of the file the `size` version of picture `id` is kept in, by `write_to_dir`.
+fn file_name(id: &PictureId, size: Size) -> String {
+    match size {
+        Size::Full => id.to_string(),
+        Size::Thumbnail => format!("{id}.thumb"),
+    }
+}
+
+/// Reads the `size` version of picture `id` stored in `dir` by `write_to_dir`.
+pub(crate) fn read_from_dir(
+    dir: &Path,
+    id: &PictureId,
+    size: Size,
+) -> std::io::Result<Option<Vec<u8>>> {
+    match std::fs::read(dir.join(file_name(id, size))) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+pub(crate) fn exists_in_dir(dir: &Path, id: &PictureId, size: Size) -> bool {
+    dir.join(file_name(id, size)).exists()
+}
+
+/// Deletes the `size` version of picture `id` from `dir`, if it is there.
+pub(crate) fn remove_from_dir(dir: &Path, id: &PictureId, size: Size) -> std::io::Result<()> {
+    match std::fs::remove_file(dir.join(file_name(id, size))) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Stores the encoded `size` version of picture `id` in `dir`, in a file named after the id.
+/// As the file's contents are fixed by its name, one that already exists is left alone.
+pub(crate) fn write_to_dir(
+    dir: &Path,
+    id: &PictureId,
+    size: Size,
+    encoded: &[u8],
+) -> std::io::Result<()> {
+    let name = file_name(id, size);
+    let path = dir.join(&name);
+    if path.exists() {
+        return Ok(());
+    }
+    std::fs::create_dir_all(dir)?;
+    // Written under another name first, so a crash never leaves a partial picture under the id.
+    let tmp_path = dir.join(format!("{name}.tmp"));
+    let mut file = std::fs::File::create(&tmp_path)?;
+    file.write_all(encoded)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp_path, &path)
+}
+
+/// Pictures a client has fetched from the server, or taken itself, kept on disk so each is only
+/// downloaded once. A picture never changes under the same id, so nothing in here goes stale.
+/// Thumbnails and full pictures are fetched separately, as lists only need thumbnails.
+pub struct PictureCache {
+    dir: PathBuf,
+    /// The last pictures that were asked for, decoded, most recent last.
+    decoded: Vec<((PictureId, Size), Picture)>,
+    /// Pictures the server doesn't have, or that failed to decode, so they aren't tried again on
+    /// every frame.
+    unavailable: HashSet<(PictureId, Size)>,
+}
+impl PictureCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            decoded: vec![],
+            unavailable: HashSet::new(),
+        }
+    }
+
+    pub fn contains(&self, id: &PictureId, size: Size) -> bool {
+        exists_in_dir(&self.dir, id, size)
+    }
+
+    /// The encoded picture `id`, if it is cached.
+    pub fn read(&self, id: &PictureId, size: Size) -> std::io::Result<Option<Vec<u8>>> {
+        read_from_dir(&self.dir, id, size)
+    }
+
+    /// Whether `id` needs fetching from the server : it isn't cached, and hasn't failed before.
+    pub fn is_missing(&self, id: &PictureId, size: Size) -> bool {
+        !self.unavailable.contains(&(*id, size)) && !self.contains(id, size)
+    }
+
+    /// Adds a picture we took, with its thumbnail, returning its id.
+    pub fn add(&mut self, pic: &Picture) -> std::io::Result<PictureId> {
+        self.store(&encode(pic)?, pic)
+    }
+
+    /// Adds an already encoded picture, with its thumbnail, returning its id.
+    /// It is kept as is, so it keeps the id the items using it refer to.
+    pub fn add_encoded(&mut self, encoded: &[u8]) -> std::io::Result<PictureId> {
+        let pic = decode(encoded).map_err(std::io::Error::other)?;
+        self.store(encoded, &pic)
+    }
+
+    fn store(&mut self, encoded: &[u8], pic: &Picture) -> std::io::Result<PictureId> {
+        let id = PictureId::of(encoded);
+        write_to_dir(&self.dir, &id, Size::Thumbnail, &encode(&thumbnail(pic))?)?;
+        write_to_dir(&self.dir, &id, Size::Full, encoded)?;
+        self.unavailable.remove(&(id, Size::Full));
+        self.unavailable.remove(&(id, Size::Thumbnail));
+        Ok(id)
+    }
+
+    /// Fetches the `size` version of `id` from the server unless it is already cached.
+    ///
+    /// If the server can't give it to us, or sends something that isn't it, it is marked
+    /// unavailable so it isn't asked for again. A failed connection is left for the next try.
+    pub fn fetch<S: Read + Write>(
+        &mut self,
+        id: &PictureId,
+        size: Size,
+        server: &mut ServerConn<S>,
+    ) -> Result<(), ServerErr> {
+        if !self.is_missing(id, size) {
+            return Ok(());
+        }
+        let fetched = match size {
+            Size::Full => server.get_picture(id),
+            Size::Thumbnail => server.get_thumbnail(id),
+        };
+        let encoded = match fetched {
+            Ok(encoded) => encoded,
+            Err(err @ (ServerErr::TimedOut | ServerErr::OtherIo(_))) => return Err(err),
+            Err(err) => {
+                self.unavailable.insert((*id, size));
+                return Err(err);
+            }
+        };
+        // The file is never replaced once written, so a wrong one would stay for good.
+        // Thumbnails aren't named after their own hash, so they can only be checked by decoding.
+        let valid = match size {
+            Size::Full => PictureId::of(&encoded) == *id,
+            Size::Thumbnail => decode(&encoded).is_ok(),
+        };
+        if !valid {
+            self.unavailable.insert((*id, size));
+            return Err(ServerErr::OperationFailed(format!(
+                "The server sent something other than picture {id}"
+            )));
+        }
+        Ok(write_to_dir(&self.dir, id, size, &encoded)?)
+    }
+
+    /// The decoded `size` version of picture `id`, if it is cached. Missing pictures are left for `fetch`.
+    /// Logs and returns `None` if it can't be read or decoded.
+    pub fn get(&mut self, id: &PictureId, size: Size) -> Option<&Picture> {
+        let key = (*id, size);
+        if let Some(idx) = self.decoded.iter().position(|(k, _)| *k == key) {
+            let entry = self.decoded.remove(idx);
+            self.decoded.push(entry);
+            return self.decoded.last().map(|(_, pic)| pic);
+        }
+        if self.unavailable.contains(&key) {
+            return None;
+        }
+        let encoded = match self.read(id, size) {
+            Ok(Some(encoded)) => encoded,
+            Ok(None) => return None,
+            Err(err) => {
+                log::warn!("Failed to read cached picture {id} : {err:?}");
+                self.unavailable.insert(key);
+                return None;
+            }
+        };
+        match decode(&encoded) {
+            Ok(pic) => {
+                let max = match size {
+                    Size::Full => MAX_DECODED,
+                    Size::Thumbnail => MAX_DECODED_THUMBNAILS,
+                };
+                let mut same_size = (self.decoded.iter().enumerate())
+                    .filter(|(_, ((_, s), _))| *s == size)
+                    .map(|(idx, _)| idx);
+                if let Some(oldest) = same_size.next() {
+                    if same_size.count() + 1 >= max {
+                        self.decoded.remove(oldest);
+                    }
+                }
+                self.decoded.push((key, pic));
+                self.decoded.last().map(|(_, pic)| pic)
+            }
+            Err(err) => {
+                log::warn!("Failed to decode picture {id} : {err}");
+                self.unavailable.insert(key);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! On-disk format of the server's inventory file, and of the apps' local copy of it.
+//!
+//! A save file is [`MAGIC`], one byte of [`DataVersion`], then the bincode encoded `Inv` of that
+//! version. Older versions are upgraded to the current `Inv` one step at a time.
+//! Files written before the header existed are recognized by trying each headerless layout.
+//! The apps' file is the same, but holds a [`LocalInv`] wrapping the `Inv` of that version.
+//!
+//! Pictures aren't part of the file, items only refer to them by id. Versions before 5 held them
+//! inside their items, so they come out of `decode` separately for the caller to store.
+//! Version 5 items had a single picture, which becomes the only photo of their gallery.
+
+mod v0;
+mod v1;
+mod v2;
+mod v3;
+pub(crate) mod v4;
+pub(crate) mod v5;
+
+use crate::inv::Inv;
+use crate::local::LocalInv;
+use crate::DataVersion;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const MAGIC: [u8; 4] = *b"INVd";
+
+#[derive(Debug)]
+pub enum LoadErr {
+    UnsupportedVersion(DataVersion),
+    Corrupt(DataVersion, bincode::Error),
+    Unrecognized,
+}
+impl std::fmt::Display for LoadErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedVersion(DataVersion(v)) => {
+                write!(f, "data version {v} is newer than this release supports")
+            }
+            Self::Corrupt(DataVersion(v), err) => write!(f, "invalid data for version {v} : {err}"),
+            Self::Unrecognized => f.write_str("not an inventory file"),
+        }
+    }
+}
+impl std::error::Error for LoadErr {}
+
+/// A decoded save file.
+pub struct Decoded<T = Inv> {
+    pub inv: T,
+    /// Encoded pictures that were taken out of the items of an older version.
+    /// The items refer to them by id, so they have to be stored somewhere along with the inv.
+    pub pictures: Vec<Vec<u8>>,
+}
+impl<T> From<T> for Decoded<T> {
+    fn from(inv: T) -> Self {
+        Self {
+            inv,
+            pictures: vec![],
+        }
+    }
+}
+
+/// Same encoding as `bincode::serialize`, but refuses data with leftover bytes,
+/// which is what lets us tell the headerless layouts apart.
+pub(crate) fn deserialize<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> bincode::Result<T> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .deserialize(bytes)
+}
+
+/// Decodes a payload of `version` and upgrades it to the current layout.
+fn migrate(version: DataVersion, payload: &[u8]) -> Result<Decoded, LoadErr> {
+    let corrupt = |err| LoadErr::Corrupt(version, err);
+    let inv: v4::Inv = match version {
+        DataVersion(0) => {
+            let inv: v1::Inv = deserialize::<v0::Inv>(payload).map_err(corrupt)?.into();
+            v3::Inv::from(v2::Inv::from(inv)).into()
+        }
+        DataVersion(1) => {
+            let inv: v2::Inv = deserialize::<v1::Inv>(payload).map_err(corrupt)?.into();
+            v3::Inv::from(inv).into()
+        }
+        DataVersion(2) => {
+            let inv: v3::Inv = deserialize::<v2::Inv>(payload).map_err(corrupt)?.into();
+            inv.into()
+        }
+        DataVersion(3) => deserialize::<v3::Inv>(payload).map_err(corrupt)?.into(),
+        DataVersion(4) => deserialize(payload).map_err(corrupt)?,
+        DataVersion(5) => {
+            let inv: Inv = deserialize::<v5::Inv>(payload).map_err(corrupt)?.into();
+            return Ok(inv.into());
+        }
+        DataVersion(6) => return Ok(deserialize::<Inv>(payload).map_err(corrupt)?.into()),
+        version => return Err(LoadErr::UnsupportedVersion(version)),
+    };
+    let mut pictures = vec![];
+    let inv = inv.upgrade(&mut pictures).into();
+    Ok(Decoded { inv, pictures })
+}
+
+/// Decodes an apps' local inv with an inv of `version`, and upgrades it to the current layout.
+fn migrate_local(version: DataVersion, payload: &[u8]) -> Result<Decoded<LocalInv>, LoadErr> {
+    let corrupt = |err| LoadErr::Corrupt(version, err);
+    let local: v4::LocalInv<v4::Inv> = match version {
+        DataVersion(1) => {
+            let local: v4::LocalInv<v2::Inv> = deserialize::<v1::LocalInv>(payload)
+                .map_err(corrupt)?
+                .into();
+            local.map_inv(|inv| v3::Inv::from(inv).into())
+        }
+        DataVersion(2) => deserialize::<v4::LocalInv<v2::Inv>>(payload)
+            .map_err(corrupt)?
+            .map_inv(|inv| v3::Inv::from(inv).into()),
+        DataVersion(3) => deserialize::<v4::LocalInv<v3::Inv>>(payload)
+            .map_err(corrupt)?
+            .map_inv(v4::Inv::from),
+        DataVersion(4) => deserialize(payload).map_err(corrupt)?,
+        DataVersion(5) => {
+            let local: LocalInv = deserialize::<v5::LocalInv>(payload)
+                .map_err(corrupt)?
+                .into();
+            return Ok(local.into());
+        }
+        DataVersion(6) => return Ok(deserialize::<LocalInv>(payload).map_err(corrupt)?.into()),
+        version => return Err(LoadErr::UnsupportedVersion(version)),
+    };
+    let mut pictures = vec![];
+    let local = local.upgrade(&mut pictures).into();
+    Ok(Decoded {
+        inv: local,
+        pictures,
+    })
+}
+
+fn encode_current(value: &impl Serialize) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(DataVersion::CURRENT.0);
+    bincode::serialize_into(&mut bytes, value).unwrap();
+    bytes
+}
+
+pub fn encode(inv: &Inv) -> Vec<u8> {
+    encode_current(inv)
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Decoded, LoadErr> {
+    if let Some(rest) = bytes.strip_prefix(&MAGIC) {
+        let Some((version, payload)) = rest.split_first() else {
+            return Err(LoadErr::Unrecognized);
+        };
+        return migrate(DataVersion(*version), payload);
+    }
+    // No header: the file was written by a release that saved the bare `Inv`.
+    // Try the most recent headerless layout first.
+    for version in [DataVersion(1), DataVersion(0)] {
+        if let Ok(inv) = migrate(version, bytes) {
+            return Ok(inv);
+        }
+    }
+    Err(LoadErr::Unrecognized)
+}
+
+pub fn encode_local(local: &LocalInv) -> Vec<u8> {
+    encode_current(local)
+}
+
+pub fn decode_local(bytes: &[u8]) -> Result<Decoded<LocalInv>, LoadErr> {
+    if let Some(rest) = bytes.strip_prefix(&MAGIC) {
+        let Some((version, payload)) = rest.split_first() else {
+            return Err(LoadErr::Unrecognized);
+        };
+        return migrate_local(DataVersion(*version), payload);
+    }
+    // No header: the file was written by an app that saved the bare `LocalInv`,
+    // which it did up to the layout of version 5.
+    for version in (1..=5).rev().map(DataVersion) {
+        if let Ok(local) = migrate_local(version, bytes) {
+            return Ok(local);
+        }
+    }
+    Err(LoadErr::Unrecognized)
+}
+
+/// `path` with `suffix` added to the end of its file name.
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Writes an `encode`d inv to `path` without ever leaving a partially written file behind.
+///
+/// The data goes to `<path>.tmp` and is fsynced before being renamed over `path`.
+/// The file being replaced is kept as `<path>.bak`.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let tmp_path = with_suffix(path, ".tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+
+    if path.exists() {
+        let bak_path = with_suffix(path, ".bak");
+        _ = std::fs::remove_file(&bak_path);
+        // A hard link keeps `path` in place until the rename below replaces it.
+        if std::fs::hard_link(path, &bak_path).is_err() {
+            std::fs::copy(path, &bak_path)?;
+        }
+    }
+    std::fs::rename(&tmp_path, path)?;
+
+    // Make the rename itself durable. Not every platform can open a directory, so this is best effort.
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+    if let Ok(dir) = File::open(dir.unwrap_or(Path::new("."))) {
+        _ = dir.sync_all();
+    }
+    Ok(())
+}
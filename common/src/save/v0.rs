@@ -0,0 +1,143 @@
+//! The original inventory layout, used before the save file had a header.
+//! Only kept around so old save files can be upgraded.
+
+use super::v1;
+use crate::inv::{self, Listing, Listings, Platform};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+// x100 ($5.46 = Usd(546))
+#[derive(Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Usd(pub u32);
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Default, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum Condition {
+    #[default]
+    NewInBox,
+    FairInBox,
+    WornInBox,
+
+    NewNoBox,
+    FairNoBox,
+    WornNoBox,
+
+    NewDamagedBox,
+    FairDamagedBox,
+    WornDamagedBox,
+}
+impl Condition {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NewInBox => "New, in box",
+            Self::FairInBox => "Fair, in box",
+            Self::WornInBox => "Worn, in box",
+            Self::NewNoBox => "New, no box",
+            Self::FairNoBox => "Fair, no box",
+            Self::WornNoBox => "Worn, no box",
+            Self::NewDamagedBox => "New, damaged box",
+            Self::FairDamagedBox => "Fair, damaged box",
+            Self::WornDamagedBox => "Worn, damaged box",
+        }
+    }
+}
+
+// Each bit enabled for the corresponding platform in Inv::platform_names
+#[derive(Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Platforms(pub u8);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Item {
+    pub creation_date: SystemTime,
+    pub listed: Platforms,
+    pub sold: u32,
+    pub name: String,
+    pub desc: String,
+    pub count: u32,
+    pub condition: Condition,
+    pub est_cost: Usd,
+    pub dimensions: [f32; 3],
+    pub weight: f32,
+    pub color: String,
+    pub brand: String,
+    pub category: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Inv {
+    pub platform_names: Vec<String>,
+    pub items: HashMap<Id, Item>,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Id(pub u32);
+
+impl From<Item> for v1::Item {
+    fn from(old: Item) -> Self {
+        // Every listed platform gets a listing dated at the item's creation.
+        // The old layout only tracked one sold count, so it goes to the first listing.
+        let mut listings = Listings::default();
+        for idx in 0..8 {
+            if old.listed.0 & (1 << idx) != 0 {
+                listings.0[idx as usize] = Some(Listing {
+                    date: old.creation_date,
+                    sold: 0,
+                });
+            }
+        }
+        if old.sold > 0 {
+            let platform = (&listings)
+                .into_iter()
+                .map(|(p, _)| p)
+                .next()
+                .unwrap_or(Platform::from_idx(0).unwrap());
+            let listing = listings[platform].get_or_insert(Listing {
+                date: old.creation_date,
+                sold: 0,
+            });
+            listing.sold = old.sold;
+        }
+
+        let mut desc = old.desc;
+        if !old.category.is_empty() {
+            if !desc.is_empty() {
+                desc.push('\n');
+            }
+            desc.push_str("Category: ");
+            desc.push_str(&old.category);
+        }
+
+        Self {
+            creation_date: old.creation_date,
+            location: String::new(),
+            listings,
+            picture: None,
+            name: old.name,
+            desc,
+            count: old.count,
+            est_cost: inv::Usd(old.est_cost.0),
+            condition: old.condition.as_str().into(),
+            color: old.color,
+            dimensions: old.dimensions,
+            weight: old.weight,
+            shipping_weight: 0.0,
+            model_no: 0,
+            serial_no: 0,
+            brand: old.brand,
+        }
+    }
+}
+
+impl From<Inv> for v1::Inv {
+    fn from(old: Inv) -> Self {
+        Self {
+            platform_names: old.platform_names,
+            items: old
+                .items
+                .into_iter()
+                .map(|(id, item)| (inv::Id(id.0), item.into()))
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! Layout before items had revisions.
+
+use super::{v2, v4};
+use crate::inv::{self, Listings, Picture, Usd};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+#[derive(Serialize, Deserialize)]
+pub struct Item {
+    pub creation_date: SystemTime,
+    pub location: String,
+    pub listings: Listings,
+    pub picture: Option<Picture>,
+    pub name: String,
+    pub desc: String,
+    pub count: u32,
+    pub est_cost: Usd,
+    pub condition: String,
+    pub color: String,
+    pub dimensions: [f32; 3],
+    pub weight: f32,
+    pub shipping_weight: f32,
+    pub model_no: u64,
+    pub serial_no: u64,
+    pub brand: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Inv {
+    pub platform_names: Vec<String>,
+    pub items: HashMap<inv::Id, Item>,
+}
+
+/// The apps' local inv, before it kept the synced version of the items it edited.
+#[derive(Serialize, Deserialize)]
+pub struct LocalInv {
+    pub modified_items: HashSet<inv::Id>,
+    pub deleted_items: HashSet<inv::Id>,
+    pub added_items: HashSet<inv::Id>,
+    pub inv: Inv,
+}
+
+impl From<Item> for v4::Item {
+    fn from(old: Item) -> Self {
+        Self {
+            creation_date: old.creation_date,
+            revision: 0,
+            location: old.location,
+            listings: old.listings,
+            picture: old.picture,
+            name: old.name,
+            desc: old.desc,
+            count: old.count,
+            est_cost: old.est_cost,
+            condition: old.condition,
+            color: old.color,
+            dimensions: old.dimensions,
+            weight: old.weight,
+            shipping_weight: old.shipping_weight,
+            model_no: old.model_no,
+            serial_no: old.serial_no,
+            brand: old.brand,
+        }
+    }
+}
+
+impl From<Inv> for v2::Inv {
+    fn from(old: Inv) -> Self {
+        Self {
+            platform_names: old.platform_names,
+            revision: 0,
+            items: old
+                .items
+                .into_iter()
+                .map(|(id, item)| (id, item.into()))
+                .collect(),
+        }
+    }
+}
+
+impl From<LocalInv> for v4::LocalInv<v2::Inv> {
+    /// Edits made before the upgrade have nothing to merge against, like newly added items.
+    fn from(old: LocalInv) -> Self {
+        Self {
+            modified_items: old.modified_items,
+            deleted_items: old.deleted_items,
+            added_items: old.added_items,
+            base_items: HashMap::new(),
+            inv: old.inv.into(),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+//! Layout before the inv kept track of removed items.
+
+use super::v3;
+use super::v4::Item;
+use crate::inv::Id;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize)]
+pub struct Inv {
+    pub platform_names: Vec<String>,
+    pub revision: u64,
+    pub items: HashMap<Id, Item>,
+}
+
+impl From<Inv> for v3::Inv {
+    fn from(old: Inv) -> Self {
+        Self {
+            platform_names: old.platform_names,
+            revision: old.revision,
+            items: old.items,
+            removed: HashMap::new(),
+        }
+    }
+}
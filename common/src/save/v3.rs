@@ -0,0 +1,26 @@
+//! Layout before removed items were kept in the trash.
+
+use super::v4::{self, Item};
+use crate::inv::Id;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize)]
+pub struct Inv {
+    pub platform_names: Vec<String>,
+    pub revision: u64,
+    pub items: HashMap<Id, Item>,
+    pub removed: HashMap<Id, u64>,
+}
+
+impl From<Inv> for v4::Inv {
+    fn from(old: Inv) -> Self {
+        Self {
+            platform_names: old.platform_names,
+            revision: old.revision,
+            items: old.items,
+            removed: old.removed,
+            trash: HashMap::new(),
+        }
+    }
+}
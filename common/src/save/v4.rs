@@ -0,0 +1,161 @@
+//! Layout before pictures were stored apart from their items.
+
+use super::v5;
+use crate::inv::{Id, Listings, Picture, PictureId, Usd};
+use crate::picture;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+#[derive(Serialize, Deserialize)]
+pub struct Item {
+    pub creation_date: SystemTime,
+    pub revision: u64,
+    pub location: String,
+    pub listings: Listings,
+    pub picture: Option<Picture>,
+    pub name: String,
+    pub desc: String,
+    pub count: u32,
+    pub est_cost: Usd,
+    pub condition: String,
+    pub color: String,
+    pub dimensions: [f32; 3],
+    pub weight: f32,
+    pub shipping_weight: f32,
+    pub model_no: u64,
+    pub serial_no: u64,
+    pub brand: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Trashed {
+    pub item: Item,
+    pub deleted_at: SystemTime,
+    pub deleted_by: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Inv {
+    pub platform_names: Vec<String>,
+    pub revision: u64,
+    pub items: HashMap<Id, Item>,
+    pub removed: HashMap<Id, u64>,
+    pub trash: HashMap<Id, Trashed>,
+}
+
+/// The apps' local inv, which kept an `I` of this or an earlier layout.
+#[derive(Serialize, Deserialize)]
+pub struct LocalInv<I> {
+    pub modified_items: HashSet<Id>,
+    pub deleted_items: HashSet<Id>,
+    pub added_items: HashSet<Id>,
+    pub base_items: HashMap<Id, Item>,
+    pub inv: I,
+}
+
+impl Item {
+    /// The item in the next layout, with its picture encoded and added to `pictures`.
+    /// A picture that can't be encoded is dropped.
+    pub fn upgrade(self, pictures: &mut Vec<Vec<u8>>) -> v5::Item {
+        let picture = self
+            .picture
+            .as_ref()
+            .and_then(|pic| match picture::encode(pic) {
+                Ok(encoded) => {
+                    let id = PictureId::of(&encoded);
+                    pictures.push(encoded);
+                    Some(id)
+                }
+                Err(err) => {
+                    log::warn!("Dropped a picture that failed to encode : {err}");
+                    None
+                }
+            });
+        self.with_picture(picture)
+    }
+
+    /// The item in the next layout, referring to `picture` instead of holding its own.
+    pub fn with_picture(self, picture: Option<PictureId>) -> v5::Item {
+        v5::Item {
+            creation_date: self.creation_date,
+            revision: self.revision,
+            location: self.location,
+            listings: self.listings,
+            picture,
+            name: self.name,
+            desc: self.desc,
+            count: self.count,
+            est_cost: self.est_cost,
+            condition: self.condition,
+            color: self.color,
+            dimensions: self.dimensions,
+            weight: self.weight,
+            shipping_weight: self.shipping_weight,
+            model_no: self.model_no,
+            serial_no: self.serial_no,
+            brand: self.brand,
+        }
+    }
+}
+
+impl Trashed {
+    pub fn upgrade(self, pictures: &mut Vec<Vec<u8>>) -> v5::Trashed {
+        v5::Trashed {
+            item: self.item.upgrade(pictures),
+            deleted_at: self.deleted_at,
+            deleted_by: self.deleted_by,
+        }
+    }
+}
+
+impl Inv {
+    /// The inv in the next layout, with every picture encoded and added to `pictures`.
+    pub fn upgrade(self, pictures: &mut Vec<Vec<u8>>) -> v5::Inv {
+        v5::Inv {
+            platform_names: self.platform_names,
+            revision: self.revision,
+            items: (self.items.into_iter())
+                .map(|(id, item)| (id, item.upgrade(pictures)))
+                .collect(),
+            removed: self.removed,
+            trash: (self.trash.into_iter())
+                .map(|(id, trashed)| (id, trashed.upgrade(pictures)))
+                .collect(),
+        }
+    }
+}
+
+impl<I> LocalInv<I> {
+    /// The same local inv, with its inv converted by `f`.
+    pub fn map_inv<J>(self, f: impl FnOnce(I) -> J) -> LocalInv<J> {
+        LocalInv {
+            modified_items: self.modified_items,
+            deleted_items: self.deleted_items,
+            added_items: self.added_items,
+            base_items: self.base_items,
+            inv: f(self.inv),
+        }
+    }
+}
+
+impl LocalInv<Inv> {
+    /// The local inv in the next layout, with every picture encoded and added to `pictures`.
+    /// The pictures of edited items are queued for upload, as they were never sent on their own.
+    pub fn upgrade(self, pictures: &mut Vec<Vec<u8>>) -> v5::LocalInv {
+        let inv = self.inv.upgrade(pictures);
+        let unsent_pictures = (self.added_items.union(&self.modified_items))
+            .filter_map(|id| inv.items.get(id)?.picture)
+            .collect();
+        v5::LocalInv {
+            modified_items: self.modified_items,
+            deleted_items: self.deleted_items,
+            added_items: self.added_items,
+            base_items: (self.base_items.into_iter())
+                .map(|(id, item)| (id, item.upgrade(pictures)))
+                .collect(),
+            unsent_pictures,
+            inv,
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Layout before items had a gallery of photos, when each had at most one picture.
+
+use crate::inv::{self, Id, Listings, PictureId, Usd};
+use crate::local;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+#[derive(Serialize, Deserialize)]
+pub struct Item {
+    pub creation_date: SystemTime,
+    pub revision: u64,
+    pub location: String,
+    pub listings: Listings,
+    pub picture: Option<PictureId>,
+    pub name: String,
+    pub desc: String,
+    pub count: u32,
+    pub est_cost: Usd,
+    pub condition: String,
+    pub color: String,
+    pub dimensions: [f32; 3],
+    pub weight: f32,
+    pub shipping_weight: f32,
+    pub model_no: u64,
+    pub serial_no: u64,
+    pub brand: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Trashed {
+    pub item: Item,
+    pub deleted_at: SystemTime,
+    pub deleted_by: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Inv {
+    pub platform_names: Vec<String>,
+    pub revision: u64,
+    pub items: HashMap<Id, Item>,
+    pub removed: HashMap<Id, u64>,
+    pub trash: HashMap<Id, Trashed>,
+}
+
+/// The apps' local inv, which kept a list of the pictures it had yet to upload.
+#[derive(Serialize, Deserialize)]
+pub struct LocalInv {
+    pub modified_items: HashSet<Id>,
+    pub deleted_items: HashSet<Id>,
+    pub added_items: HashSet<Id>,
+    pub base_items: HashMap<Id, Item>,
+    pub unsent_pictures: HashSet<PictureId>,
+    pub inv: Inv,
+}
+
+impl From<Item> for inv::Item {
+    /// The picture becomes the only photo in the gallery, and so its cover.
+    fn from(old: Item) -> Self {
+        Self {
+            creation_date: old.creation_date,
+            revision: old.revision,
+            location: old.location,
+            listings: old.listings,
+            gallery: old.picture.into(),
+            name: old.name,
+            desc: old.desc,
+            count: old.count,
+            est_cost: old.est_cost,
+            condition: old.condition,
+            color: old.color,
+            dimensions: old.dimensions,
+            weight: old.weight,
+            shipping_weight: old.shipping_weight,
+            model_no: old.model_no,
+            serial_no: old.serial_no,
+            brand: old.brand,
+        }
+    }
+}
+
+impl From<Trashed> for inv::Trashed {
+    fn from(old: Trashed) -> Self {
+        Self {
+            item: old.item.into(),
+            deleted_at: old.deleted_at,
+            deleted_by: old.deleted_by,
+        }
+    }
+}
+
+impl From<Inv> for inv::Inv {
+    fn from(old: Inv) -> Self {
+        Self {
+            platform_names: old.platform_names,
+            revision: old.revision,
+            items: (old.items.into_iter())
+                .map(|(id, item)| (id, item.into()))
+                .collect(),
+            removed: old.removed,
+            trash: (old.trash.into_iter())
+                .map(|(id, trashed)| (id, trashed.into()))
+                .collect(),
+        }
+    }
+}
+
+impl From<LocalInv> for local::LocalInv {
+    fn from(old: LocalInv) -> Self {
+        Self {
+            modified_items: old.modified_items,
+            deleted_items: old.deleted_items,
+            added_items: old.added_items,
+            base_items: (old.base_items.into_iter())
+                .map(|(id, item)| (id, item.into()))
+                .collect(),
+            unsent_pictures: old.unsent_pictures,
+            inv: old.inv.into(),
+        }
+    }
+}
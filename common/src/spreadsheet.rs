@@ -0,0 +1,382 @@
+//! CSV export and import of the inv, for spreadsheets from suppliers and for the accounts.
+//!
+//! The first row names the columns, then every row is an item. There is a column for each of
+//! the item's fields, named like in `COLUMNS`, and one for each platform, named after it.
+//!
+//! - `id` is the item id in hex. Importing a row without one adds a new item.
+//! - `cost` is in dollars to the cent, like `5.46`.
+//! - `created` and listing dates are UTC, written like `backup::utc_timestamp` does.
+//! - `photos` has a line for each photo : the picture id, then its caption after a space.
+//!   The cover's line starts with a `*`.
+//! - A platform's column holds the listing date and the sold count, like `20240501-120000 3`,
+//!   or nothing if the item isn't listed there.
+//!
+//! Imports match columns by header, ignoring case, so they can come in any order and columns
+//! the inv doesn't know about are skipped. Fields without a column keep their current value.
+//! Empty cells clear text fields, and leave number and date fields and listings as they were,
+//! so an import never takes an item off a platform.
+//!
+//! Spreadsheets run cells starting with `=`, `+`, `-` or `@` as formulas, so exports put a `'` in
+//! front of those, and imports take it off again.
+//! Cells copied straight out of a spreadsheet are separated by tabs rather than commas, which
+//! imports take as well.
+
+use crate::backup::{parse_utc_timestamp, utc_timestamp};
+use crate::inv::{Gallery, Id, Inv, Item, Listing, Photo, Platform};
+use crate::merge::changed_fields;
+use crate::BatchOp;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+
+/// Headers of the item field columns, in the order they are exported.
+pub const COLUMNS: &[&str] = &[
+    "id",
+    "name",
+    "description",
+    "location",
+    "count",
+    "cost",
+    "condition",
+    "color",
+    "brand",
+    "model",
+    "serial",
+    "width",
+    "height",
+    "length",
+    "weight",
+    "shipping weight",
+    "created",
+    "photos",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Column {
+    /// Index into `COLUMNS`.
+    Field(usize),
+    Platform(Platform),
+}
+impl Column {
+    const ID: Self = Self::Field(0);
+
+    /// The column with the header `header`, if the inv has one.
+    fn find(inv: &Inv, header: &str) -> Option<Self> {
+        let header = header.trim();
+        if let Some(idx) = COLUMNS.iter().position(|c| c.eq_ignore_ascii_case(header)) {
+            return Some(Self::Field(idx));
+        }
+        (inv.platforms())
+            .find(|(_, name)| name.trim().eq_ignore_ascii_case(header))
+            .map(|(platform, _)| Self::Platform(platform))
+    }
+
+    fn get(self, id: Id, item: &Item) -> String {
+        let [width, height, length] = item.dimensions;
+        let field = match self {
+            Self::Platform(platform) => {
+                return match &item.listings[platform] {
+                    Some(listing) => format!("{} {}", utc_timestamp(listing.date), listing.sold),
+                    None => String::new(),
+                }
+            }
+            Self::Field(idx) => COLUMNS[idx],
+        };
+        match field {
+            "id" => format!("{:x}", id.0),
+            "name" => item.name.clone(),
+            "description" => item.desc.clone(),
+            "location" => item.location.clone(),
+            "count" => item.count.to_string(),
+            "cost" => item.est_cost.to_string(),
+            "condition" => item.condition.clone(),
+            "color" => item.color.clone(),
+            "brand" => item.brand.clone(),
+            "model" => item.model_no.to_string(),
+            "serial" => item.serial_no.to_string(),
+            "width" => width.to_string(),
+            "height" => height.to_string(),
+            "length" => length.to_string(),
+            "weight" => item.weight.to_string(),
+            "shipping weight" => item.shipping_weight.to_string(),
+            "created" => utc_timestamp(item.creation_date),
+            "photos" => {
+                let cover = item.gallery.cover_idx();
+                let lines: Vec<_> = (item.gallery.photos().iter().enumerate())
+                    .map(|(idx, photo)| {
+                        let star = if idx == cover { "*" } else { "" };
+                        match photo.caption.as_str() {
+                            "" => format!("{star}{}", photo.picture),
+                            caption => format!("{star}{} {caption}", photo.picture),
+                        }
+                    })
+                    .collect();
+                lines.join("\n")
+            }
+            _ => unreachable!("unknown column {field:?}"),
+        }
+    }
+
+    /// Sets the field of `item` in this column from a cell. Fails if the cell can't be parsed.
+    fn set(self, item: &mut Item, cell: &str) -> Result<(), ()> {
+        let field = match self {
+            Self::Platform(platform) => {
+                if let Some(listing) = parse_listing(cell)? {
+                    item.listings[platform] = Some(listing);
+                }
+                return Ok(());
+            }
+            Self::Field(idx) => COLUMNS[idx],
+        };
+        let text = |field: &mut String| *field = cell.to_owned();
+        let cell = cell.trim();
+        match field {
+            "name" => text(&mut item.name),
+            "description" => text(&mut item.desc),
+            "location" => text(&mut item.location),
+            "condition" => text(&mut item.condition),
+            "color" => text(&mut item.color),
+            "brand" => text(&mut item.brand),
+            "photos" => item.gallery = parse_photos(cell)?,
+            _ if cell.is_empty() => {}
+            "count" => item.count = cell.parse().map_err(drop)?,
+            "cost" => item.est_cost = cell.parse().map_err(drop)?,
+            "model" => item.model_no = cell.parse().map_err(drop)?,
+            "serial" => item.serial_no = cell.parse().map_err(drop)?,
+            "width" => item.dimensions[0] = cell.parse().map_err(drop)?,
+            "height" => item.dimensions[1] = cell.parse().map_err(drop)?,
+            "length" => item.dimensions[2] = cell.parse().map_err(drop)?,
+            "weight" => item.weight = cell.parse().map_err(drop)?,
+            "shipping weight" => item.shipping_weight = cell.parse().map_err(drop)?,
+            "created" => item.creation_date = parse_utc_timestamp(cell).ok_or(())?,
+            _ => unreachable!("unknown column {field:?}"),
+        }
+        Ok(())
+    }
+}
+
+/// Whether a spreadsheet would take `cell` for a formula, even with the `'`s `guard` puts in front.
+fn looks_like_formula(cell: &str) -> bool {
+    cell.trim_start_matches('\'')
+        .starts_with(['=', '+', '-', '@'])
+}
+
+/// `cell` as exported, with a `'` in front if it would be taken for a formula.
+fn guard(cell: String) -> String {
+    match looks_like_formula(&cell) {
+        true => format!("'{cell}"),
+        false => cell,
+    }
+}
+
+/// An imported `cell` with the `'` that `guard` put in front of it taken off.
+fn unguard(cell: &str) -> &str {
+    match cell.strip_prefix('\'') {
+        Some(rest) if looks_like_formula(rest) => rest,
+        _ => cell,
+    }
+}
+
+fn parse_listing(cell: &str) -> Result<Option<Listing>, ()> {
+    let mut parts = cell.split_whitespace();
+    let Some(date) = parts.next() else {
+        return Ok(None);
+    };
+    let date = parse_utc_timestamp(date).ok_or(())?;
+    let sold = match parts.next() {
+        Some(sold) => sold.parse().map_err(drop)?,
+        None => 0,
+    };
+    if parts.next().is_some() {
+        return Err(());
+    }
+    Ok(Some(Listing { date, sold }))
+}
+
+fn parse_photos(cell: &str) -> Result<Gallery, ()> {
+    let mut gallery = Gallery::default();
+    let mut cover = None;
+    for line in cell.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let (is_cover, line) = match line.strip_prefix('*') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let (picture, caption) = line.split_once(' ').unwrap_or((line, ""));
+        if is_cover {
+            cover = Some(gallery.len());
+        }
+        let mut photo = Photo::new(picture.parse()?);
+        photo.caption = caption.trim().to_owned();
+        gallery.push(photo);
+    }
+    if let Some(cover) = cover {
+        gallery.set_cover(cover);
+    }
+    Ok(gallery)
+}
+
+/// Writes every item in `inv` as CSV, ordered by id.
+pub fn export(inv: &Inv, out: impl Write) -> std::io::Result<()> {
+    let mut csv = csv::Writer::from_writer(out);
+    let platform_names = inv.platform_names.iter().map(String::as_str);
+    csv.write_record(COLUMNS.iter().copied().chain(platform_names))?;
+
+    let columns: Vec<_> = (0..COLUMNS.len())
+        .map(Column::Field)
+        .chain(
+            inv.platforms()
+                .map(|(platform, _)| Column::Platform(platform)),
+        )
+        .collect();
+    let mut items: Vec<_> = inv.items.iter().collect();
+    items.sort_by_key(|(id, _)| id.0);
+    for (id, item) in items {
+        csv.write_record(columns.iter().map(|column| guard(column.get(*id, item))))?;
+    }
+    csv.flush()?;
+    Ok(())
+}
+
+/// A cell that couldn't be imported.
+#[derive(Debug)]
+pub struct RowErr {
+    /// The row as numbered in a spreadsheet, where the headers are row 1.
+    pub row: usize,
+    /// The header of the cell's column.
+    pub column: String,
+    pub value: String,
+}
+impl std::fmt::Display for RowErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "row {} : invalid {} {:?}",
+            self.row, self.column, self.value
+        )
+    }
+}
+
+/// An item that an import adds or changes.
+pub struct ImportedItem {
+    pub row: usize,
+    pub id: Id,
+    /// Based on the current revision of the item, so it can be written as a `BatchOp::Insert`.
+    pub item: Item,
+    /// The fields that differ from the current item, named like `merge::changed_fields` does.
+    /// `None` for a new item.
+    pub changed: Option<Vec<&'static str>>,
+}
+
+/// What importing a CSV file would do. Nothing is written until it is turned into a batch.
+#[derive(Default)]
+pub struct Import {
+    pub items: Vec<ImportedItem>,
+    /// How many rows are the same as the item already in the inv.
+    pub unchanged: usize,
+    pub errors: Vec<RowErr>,
+    /// Headers that aren't a field or one of the inv's platforms.
+    pub ignored_columns: Vec<String>,
+}
+impl Import {
+    pub fn new_count(&self) -> usize {
+        self.items.iter().filter(|i| i.changed.is_none()).count()
+    }
+
+    pub fn changed_count(&self) -> usize {
+        self.items.iter().filter(|i| i.changed.is_some()).count()
+    }
+
+    /// The changes to apply to the inv, unless any row had an error, in which case nothing should be.
+    pub fn into_batch(self) -> Result<Vec<BatchOp>, Vec<RowErr>> {
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+        let ops = (self.items.into_iter())
+            .map(|imported| BatchOp::Insert(imported.id, Box::new(imported.item)))
+            .collect();
+        Ok(ops)
+    }
+}
+
+/// Reads CSV items to add to or change in `inv`, without changing it.
+/// Only fails if the CSV itself can't be read, problems with cells are in `Import::errors`.
+pub fn import(inv: &Inv, mut csv: impl Read) -> std::io::Result<Import> {
+    let mut bytes = vec![];
+    csv.read_to_end(&mut bytes)?;
+    let header_line = bytes.split(|b| *b == b'\n').next().unwrap_or_default();
+    let is_tsv = header_line.contains(&b'\t') && !header_line.contains(&b',');
+    let delimiter = if is_tsv { b'\t' } else { b',' };
+    let mut reader = (csv::ReaderBuilder::new())
+        .flexible(true)
+        .delimiter(delimiter)
+        .from_reader(bytes.as_slice());
+    let headers = reader.headers()?.clone();
+    let mut import = Import::default();
+    let mut columns: Vec<(usize, Column)> = vec![];
+    for (idx, header) in headers.iter().enumerate() {
+        match Column::find(inv, header) {
+            Some(column) if columns.iter().all(|(_, c)| *c != column) => {
+                columns.push((idx, column))
+            }
+            _ => import.ignored_columns.push(header.to_owned()),
+        }
+    }
+
+    let mut ids = HashSet::new();
+    for (idx, record) in reader.records().enumerate() {
+        let record = record?;
+        let row = idx + 2;
+        let cell = |idx: usize| record.get(idx).unwrap_or("");
+        let error = |idx: usize| RowErr {
+            row,
+            column: headers[idx].to_owned(),
+            value: cell(idx).to_owned(),
+        };
+
+        let id_cell = columns
+            .iter()
+            .find(|(_, c)| *c == Column::ID)
+            .map(|(idx, _)| *idx);
+        let id = match id_cell.map(|idx| (idx, cell(idx).trim())) {
+            Some((_, "")) | None => loop {
+                let id = Id::new();
+                if !inv.items.contains_key(&id) && !ids.contains(&id) {
+                    break id;
+                }
+            },
+            Some((idx, id)) => match u32::from_str_radix(id, 16) {
+                Ok(id) if !ids.contains(&Id(id)) => Id(id),
+                // The same item twice would be two changes to it in one batch.
+                _ => {
+                    import.errors.push(error(idx));
+                    continue;
+                }
+            },
+        };
+        ids.insert(id);
+
+        let current = inv.items.get(&id);
+        let mut item = current.map(Item::copy).unwrap_or_default();
+        let mut ok = true;
+        for (idx, column) in columns.iter().filter(|(_, c)| *c != Column::ID) {
+            if column.set(&mut item, unguard(cell(*idx))).is_err() {
+                import.errors.push(error(*idx));
+                ok = false;
+            }
+        }
+        if !ok {
+            continue;
+        }
+        let changed = current.map(|current| changed_fields(current, &item));
+        if changed.as_ref().is_some_and(Vec::is_empty) {
+            import.unchanged += 1;
+            continue;
+        }
+        import.items.push(ImportedItem {
+            row,
+            id,
+            item,
+            changed,
+        });
+    }
+    Ok(import)
+}
@@ -0,0 +1,170 @@
+//! Where the server keeps its inv between runs.
+//!
+//! [`FileStorage`] is the original single save file, rewritten whole on every save.
+//! [`SqliteStorage`] keeps each item in a row of its own, so a save only writes what changed.
+//!
+//! Both keep encoded pictures apart from the inv, under their `PictureId`, with their thumbnails.
+//! A picture is only deleted once nothing refers to it any more, not even the audit log or a
+//! backup, see `ServerHost::collect_garbage`.
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStorage;
+
+use crate::inv::{Inv, PictureId};
+use crate::picture::{self, Size};
+use crate::save;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+pub trait Storage: Send + Sync {
+    /// Where the inv is kept, for messages.
+    fn path(&self) -> &Path;
+
+    /// Reads the stored inv, or an empty one if nothing has been stored yet.
+    fn load(&self) -> std::io::Result<Inv>;
+
+    /// Stores `inv`, taking its lock for as long as it needs a consistent view.
+    fn save(&self, inv: &RwLock<Inv>) -> std::io::Result<()>;
+
+    /// Reads the encoded picture stored under `id`.
+    fn load_picture(&self, id: &PictureId) -> std::io::Result<Option<Vec<u8>>>;
+
+    /// Stores an encoded picture under its id, which is returned.
+    /// Storing a picture that is already there does nothing.
+    fn store_picture(&self, encoded: &[u8]) -> std::io::Result<PictureId>;
+
+    fn has_picture(&self, id: &PictureId) -> std::io::Result<bool>;
+
+    /// Reads the encoded thumbnail of picture `id`, if one has been stored.
+    fn load_thumbnail(&self, id: &PictureId) -> std::io::Result<Option<Vec<u8>>>;
+
+    /// Stores the encoded thumbnail of picture `id`. Storing one that is already there does nothing.
+    fn store_thumbnail(&self, id: &PictureId, encoded: &[u8]) -> std::io::Result<()>;
+
+    /// The id of every stored picture.
+    fn picture_ids(&self) -> std::io::Result<Vec<PictureId>>;
+
+    /// Deletes picture `id` and its thumbnail. Deleting one that isn't stored does nothing.
+    fn delete_picture(&self, id: &PictureId) -> std::io::Result<()>;
+}
+
+/// Copies the inv stored in `from` into `to`, which has to be empty, with the pictures and thumbnails
+/// its items refer to. Returns the copied inv.
+pub fn migrate(from: &dyn Storage, to: &dyn Storage) -> std::io::Result<Inv> {
+    let existing = to.load()?;
+    let empty = existing.revision == 0
+        && existing.items.is_empty()
+        && existing.trash.is_empty()
+        && existing.platform_names.is_empty();
+    if !empty {
+        return Err(std::io::Error::other(format!(
+            "{:?} already holds an inv, move it out of the way first",
+            to.path()
+        )));
+    }
+    let inv = from.load().map_err(|err| {
+        std::io::Error::new(
+            err.kind(),
+            format!("Failed to load inv from {:?} : {err}", from.path()),
+        )
+    })?;
+    let ids: HashSet<PictureId> = inv.pictures().copied().collect();
+    for id in &ids {
+        match from.load_picture(id)? {
+            Some(encoded) => _ = to.store_picture(&encoded)?,
+            None => log::warn!("Picture {id} is missing from {:?}", from.path()),
+        }
+        if let Some(thumbnail) = from.load_thumbnail(id)? {
+            to.store_thumbnail(id, &thumbnail)?;
+        }
+    }
+    let inv = RwLock::new(inv);
+    to.save(&inv)?;
+    Ok(inv.into_inner().unwrap())
+}
+
+/// The whole inv in one file, in the format of the `save` module.
+/// Pictures and their thumbnails are files of their own, in the `<path>.pictures` directory.
+pub struct FileStorage {
+    path: PathBuf,
+    pictures_dir: PathBuf,
+}
+impl FileStorage {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        Self {
+            pictures_dir: save::with_suffix(&path, ".pictures"),
+            path,
+        }
+    }
+}
+impl Storage for FileStorage {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn load(&self) -> std::io::Result<Inv> {
+        let bytes = match std::fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Inv::default()),
+            Err(err) => return Err(err),
+        };
+        let decoded = save::decode(&bytes).map_err(std::io::Error::other)?;
+        // The file stays as it is until the next save, so this is done again if the server stops before then.
+        for encoded in &decoded.pictures {
+            self.store_picture(encoded)?;
+        }
+        Ok(decoded.inv)
+    }
+
+    fn save(&self, inv: &RwLock<Inv>) -> std::io::Result<()> {
+        // Only encoding needs the lock, the file is written after it's released.
+        let bytes = save::encode(&inv.read().unwrap());
+        save::write_atomic(&self.path, &bytes)
+    }
+
+    fn load_picture(&self, id: &PictureId) -> std::io::Result<Option<Vec<u8>>> {
+        picture::read_from_dir(&self.pictures_dir, id, Size::Full)
+    }
+
+    fn store_picture(&self, encoded: &[u8]) -> std::io::Result<PictureId> {
+        let id = PictureId::of(encoded);
+        picture::write_to_dir(&self.pictures_dir, &id, Size::Full, encoded)?;
+        Ok(id)
+    }
+
+    fn has_picture(&self, id: &PictureId) -> std::io::Result<bool> {
+        Ok(picture::exists_in_dir(&self.pictures_dir, id, Size::Full))
+    }
+
+    fn load_thumbnail(&self, id: &PictureId) -> std::io::Result<Option<Vec<u8>>> {
+        picture::read_from_dir(&self.pictures_dir, id, Size::Thumbnail)
+    }
+
+    fn store_thumbnail(&self, id: &PictureId, encoded: &[u8]) -> std::io::Result<()> {
+        picture::write_to_dir(&self.pictures_dir, id, Size::Thumbnail, encoded)
+    }
+    fn picture_ids(&self) -> std::io::Result<Vec<PictureId>> {
+        let entries = match std::fs::read_dir(&self.pictures_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let mut ids = vec![];
+        for entry in entries {
+            // Thumbnails and unfinished writes have a suffix, so they don't parse as an id.
+            if let Some(id) = entry?.file_name().to_str().and_then(|n| n.parse().ok()) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    fn delete_picture(&self, id: &PictureId) -> std::io::Result<()> {
+        picture::remove_from_dir(&self.pictures_dir, id, Size::Thumbnail)?;
+        picture::remove_from_dir(&self.pictures_dir, id, Size::Full)
+    }
+}
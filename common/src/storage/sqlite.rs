@@ -0,0 +1,437 @@
+//! An SQLite database with a row per item, picture, thumbnail, removed item and trashed item.
+//!
+//! Items and trashed items are stored as bincode, and encoded pictures and thumbnails by the picture's id.
+//! A save writes the items changed since the last one. Pictures are written when they are stored.
+
+use super::Storage;
+use crate::inv::{Id, Inv, Item, Picture, PictureId, Trashed};
+use crate::save::{v4, v5};
+use crate::{picture, DataVersion};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+const META_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value NOT NULL);";
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS items (id INTEGER PRIMARY KEY, revision INTEGER NOT NULL, item BLOB NOT NULL);
+CREATE TABLE IF NOT EXISTS pictures (id BLOB PRIMARY KEY, data BLOB NOT NULL);
+CREATE TABLE IF NOT EXISTS thumbnails (id BLOB PRIMARY KEY, data BLOB NOT NULL);
+CREATE TABLE IF NOT EXISTS removed (id INTEGER PRIMARY KEY, revision INTEGER NOT NULL);
+CREATE TABLE IF NOT EXISTS trash (id INTEGER PRIMARY KEY, trashed BLOB NOT NULL);
+";
+
+fn sql_err(err: rusqlite::Error) -> std::io::Error {
+    std::io::Error::other(err)
+}
+
+fn bincode_err(err: bincode::Error) -> std::io::Error {
+    std::io::Error::other(err)
+}
+
+/// What the database held after the last load or save.
+struct Saved {
+    revision: u64,
+    platform_names: Vec<String>,
+}
+
+pub struct SqliteStorage {
+    path: PathBuf,
+    conn: Mutex<Connection>,
+    /// `None` until the first load or save, so the first save writes everything.
+    saved: Mutex<Option<Saved>>,
+}
+impl SqliteStorage {
+    /// Opens the database at `path`, creating it if it doesn't exist.
+    pub fn open(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let mut conn = Connection::open(&path).map_err(sql_err)?;
+        // Readers don't block the writer, and a crash mid-save leaves the last save intact.
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(sql_err)?;
+        conn.execute_batch(META_SCHEMA).map_err(sql_err)?;
+        if get_meta::<u8>(&conn, "data_version").map_err(sql_err)? == Some(4) {
+            log::info!("Moving the pictures in {path:?} out of their items");
+            migrate_v4(&mut conn)?;
+        }
+        if get_meta::<u8>(&conn, "data_version").map_err(sql_err)? == Some(5) {
+            log::info!("Moving the pictures in {path:?} into galleries");
+            migrate_v5(&mut conn)?;
+        }
+        conn.execute_batch(SCHEMA).map_err(sql_err)?;
+        Ok(Self {
+            path,
+            conn: Mutex::new(conn),
+            saved: Mutex::new(None),
+        })
+    }
+}
+
+fn get_meta<T: rusqlite::types::FromSql>(
+    conn: &Connection,
+    key: &str,
+) -> rusqlite::Result<Option<T>> {
+    conn.query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+        row.get(0)
+    })
+    .optional()
+}
+
+fn set_meta(tx: &Transaction, key: &str, value: impl rusqlite::ToSql) -> rusqlite::Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+        params![key, value],
+    )?;
+    Ok(())
+}
+
+fn write_item(tx: &Transaction, id: Id, item: &Item) -> std::io::Result<()> {
+    let bytes = bincode::serialize(item).map_err(bincode_err)?;
+    tx.execute(
+        "INSERT OR REPLACE INTO items (id, revision, item) VALUES (?1, ?2, ?3)",
+        params![id.0, item.revision, bytes],
+    )
+    .map_err(sql_err)?;
+    tx.execute("DELETE FROM removed WHERE id = ?1", [id.0])
+        .map_err(sql_err)?;
+    Ok(())
+}
+
+fn write_trashed(tx: &Transaction, id: Id, trashed: &Trashed) -> std::io::Result<()> {
+    let bytes = bincode::serialize(trashed).map_err(bincode_err)?;
+    tx.execute(
+        "INSERT OR REPLACE INTO trash (id, trashed) VALUES (?1, ?2)",
+        params![id.0, bytes],
+    )
+    .map_err(sql_err)?;
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn load(&self) -> std::io::Result<Inv> {
+        let conn = self.conn.lock().unwrap();
+        let mut saved = self.saved.lock().unwrap();
+
+        let version: Option<u8> = get_meta(&conn, "data_version").map_err(sql_err)?;
+        if let Some(version) = version.filter(|v| *v != DataVersion::CURRENT.0) {
+            return Err(std::io::Error::other(format!(
+                "{:?} holds data version {version}, which this release can't read",
+                self.path
+            )));
+        }
+        let mut inv = Inv {
+            revision: get_meta(&conn, "revision").map_err(sql_err)?.unwrap_or(0),
+            ..Default::default()
+        };
+        if let Some(bytes) = get_meta::<Vec<u8>>(&conn, "platform_names").map_err(sql_err)? {
+            inv.platform_names = bincode::deserialize(&bytes).map_err(bincode_err)?;
+        }
+
+        let mut stmt = conn
+            .prepare("SELECT id, item FROM items")
+            .map_err(sql_err)?;
+        let mut rows = stmt.query([]).map_err(sql_err)?;
+        while let Some(row) = rows.next().map_err(sql_err)? {
+            let bytes: Vec<u8> = row.get(1).map_err(sql_err)?;
+            let item = bincode::deserialize(&bytes).map_err(bincode_err)?;
+            inv.items.insert(Id(row.get(0).map_err(sql_err)?), item);
+        }
+
+        let mut stmt = conn
+            .prepare("SELECT id, revision FROM removed")
+            .map_err(sql_err)?;
+        let removed = stmt
+            .query_map([], |row| Ok((Id(row.get(0)?), row.get(1)?)))
+            .map_err(sql_err)?;
+        for entry in removed {
+            let (id, revision) = entry.map_err(sql_err)?;
+            inv.removed.insert(id, revision);
+        }
+
+        let mut stmt = conn
+            .prepare("SELECT id, trashed FROM trash")
+            .map_err(sql_err)?;
+        let mut rows = stmt.query([]).map_err(sql_err)?;
+        while let Some(row) = rows.next().map_err(sql_err)? {
+            let bytes: Vec<u8> = row.get(1).map_err(sql_err)?;
+            let trashed = bincode::deserialize(&bytes).map_err(bincode_err)?;
+            inv.trash.insert(Id(row.get(0).map_err(sql_err)?), trashed);
+        }
+
+        *saved = Some(Saved {
+            revision: inv.revision,
+            platform_names: inv.platform_names.clone(),
+        });
+        Ok(inv)
+    }
+
+    fn save(&self, inv: &RwLock<Inv>) -> std::io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let mut saved = self.saved.lock().unwrap();
+        let inv = inv.read().unwrap();
+        let tx = conn.transaction().map_err(sql_err)?;
+
+        // Everything is written if we don't know what the database holds, if it holds nothing yet,
+        // or if the inv was replaced by an unrelated one. Items from old releases can be at revision 0.
+        let since = match &*saved {
+            Some(saved) if saved.revision != 0 && saved.revision <= inv.revision => Some(saved),
+            _ => None,
+        };
+        if since.is_none() {
+            tx.execute_batch("DELETE FROM items; DELETE FROM removed; DELETE FROM trash;")
+                .map_err(sql_err)?;
+        }
+        let changed_since = |revision: u64| since.is_none_or(|saved| revision > saved.revision);
+
+        for (id, item) in &inv.items {
+            if !changed_since(item.revision) {
+                continue;
+            }
+            write_item(&tx, *id, item)?;
+        }
+        for (id, revision) in &inv.removed {
+            if !changed_since(*revision) {
+                continue;
+            }
+            tx.execute("DELETE FROM items WHERE id = ?1", [id.0])
+                .map_err(sql_err)?;
+            tx.execute(
+                "INSERT OR REPLACE INTO removed (id, revision) VALUES (?1, ?2)",
+                params![id.0, revision],
+            )
+            .map_err(sql_err)?;
+        }
+
+        // Removing a platform rewrites the listings of trashed items without a new revision.
+        let platforms_changed =
+            since.is_some_and(|saved| saved.platform_names != inv.platform_names);
+        let mut stmt = tx.prepare("SELECT id FROM trash").map_err(sql_err)?;
+        let stored: HashSet<Id> = stmt
+            .query_map([], |row| row.get(0).map(Id))
+            .map_err(sql_err)?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(sql_err)?;
+        drop(stmt);
+        for (id, trashed) in &inv.trash {
+            let trashed_since = inv.removed.get(id).is_some_and(|rev| changed_since(*rev));
+            if platforms_changed || trashed_since || !stored.contains(id) {
+                write_trashed(&tx, *id, trashed)?;
+            }
+        }
+        for id in stored.iter().filter(|id| !inv.trash.contains_key(id)) {
+            tx.execute("DELETE FROM trash WHERE id = ?1", [id.0])
+                .map_err(sql_err)?;
+        }
+
+        let platform_names = bincode::serialize(&inv.platform_names).map_err(bincode_err)?;
+        set_meta(&tx, "data_version", DataVersion::CURRENT.0).map_err(sql_err)?;
+        set_meta(&tx, "revision", inv.revision).map_err(sql_err)?;
+        set_meta(&tx, "platform_names", platform_names).map_err(sql_err)?;
+        tx.commit().map_err(sql_err)?;
+
+        *saved = Some(Saved {
+            revision: inv.revision,
+            platform_names: inv.platform_names.clone(),
+        });
+        Ok(())
+    }
+
+    fn load_picture(&self, id: &PictureId) -> std::io::Result<Option<Vec<u8>>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT data FROM pictures WHERE id = ?1", [id.0], |row| {
+            row.get(0)
+        })
+        .optional()
+        .map_err(sql_err)
+    }
+
+    fn store_picture(&self, encoded: &[u8]) -> std::io::Result<PictureId> {
+        let id = PictureId::of(encoded);
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR IGNORE INTO pictures (id, data) VALUES (?1, ?2)",
+            params![id.0, encoded],
+        )
+        .map_err(sql_err)?;
+        Ok(id)
+    }
+
+    fn has_picture(&self, id: &PictureId) -> std::io::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let found: Option<u8> = conn
+            .query_row("SELECT 1 FROM pictures WHERE id = ?1", [id.0], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(sql_err)?;
+        Ok(found.is_some())
+    }
+
+    fn load_thumbnail(&self, id: &PictureId) -> std::io::Result<Option<Vec<u8>>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT data FROM thumbnails WHERE id = ?1", [id.0], |row| {
+            row.get(0)
+        })
+        .optional()
+        .map_err(sql_err)
+    }
+
+    fn store_thumbnail(&self, id: &PictureId, encoded: &[u8]) -> std::io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR IGNORE INTO thumbnails (id, data) VALUES (?1, ?2)",
+            params![id.0, encoded],
+        )
+        .map_err(sql_err)?;
+        Ok(())
+    }
+    fn picture_ids(&self) -> std::io::Result<Vec<PictureId>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT id FROM pictures").map_err(sql_err)?;
+        let ids = stmt
+            .query_map([], |row| row.get(0).map(PictureId))
+            .map_err(sql_err)?;
+        ids.collect::<Result<_, _>>().map_err(sql_err)
+    }
+
+    fn delete_picture(&self, id: &PictureId) -> std::io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(sql_err)?;
+        tx.execute("DELETE FROM pictures WHERE id = ?1", [id.0])
+            .map_err(sql_err)?;
+        tx.execute("DELETE FROM thumbnails WHERE id = ?1", [id.0])
+            .map_err(sql_err)?;
+        tx.commit().map_err(sql_err)
+    }
+}
+
+/// Upgrades a database of data version 4, which kept the raw picture of each item in a row with
+/// the item's id, to keep encoded pictures by their own id.
+fn migrate_v4(conn: &mut Connection) -> std::io::Result<()> {
+    let tx = conn.transaction().map_err(sql_err)?;
+    tx.execute_batch(
+        "ALTER TABLE pictures RENAME TO pictures_v4;
+        CREATE TABLE pictures (id BLOB PRIMARY KEY, data BLOB NOT NULL);",
+    )
+    .map_err(sql_err)?;
+    let store = |encoded: &[u8]| -> std::io::Result<PictureId> {
+        let id = PictureId::of(encoded);
+        tx.execute(
+            "INSERT OR IGNORE INTO pictures (id, data) VALUES (?1, ?2)",
+            params![id.0, encoded],
+        )
+        .map_err(sql_err)?;
+        Ok(id)
+    };
+
+    // Items were stored without their picture, which reads the same in either layout.
+    let mut stmt =
+        (tx.prepare("SELECT id, width, height, data FROM pictures_v4")).map_err(sql_err)?;
+    let mut rows = stmt.query([]).map_err(sql_err)?;
+    while let Some(row) = rows.next().map_err(sql_err)? {
+        let id = Id(row.get(0).map_err(sql_err)?);
+        let pic = Picture {
+            size: [row.get(1).map_err(sql_err)?, row.get(2).map_err(sql_err)?],
+            data: row.get(3).map_err(sql_err)?,
+        };
+        let encoded = match picture::encode(&pic) {
+            Ok(encoded) => encoded,
+            Err(err) => {
+                log::warn!(
+                    "Dropped the picture of item {:x}, which failed to encode : {err}",
+                    id.0
+                );
+                continue;
+            }
+        };
+        let bytes: Option<Vec<u8>> =
+            (tx.query_row("SELECT item FROM items WHERE id = ?1", [id.0], |row| {
+                row.get(0)
+            }))
+            .optional()
+            .map_err(sql_err)?;
+        let Some(bytes) = bytes else {
+            continue;
+        };
+        let mut item: v5::Item = bincode::deserialize(&bytes).map_err(bincode_err)?;
+        item.picture = Some(store(&encoded)?);
+        let bytes = bincode::serialize(&item).map_err(bincode_err)?;
+        tx.execute(
+            "UPDATE items SET item = ?2 WHERE id = ?1",
+            params![id.0, bytes],
+        )
+        .map_err(sql_err)?;
+    }
+    drop(rows);
+    drop(stmt);
+
+    // Trashed items kept their picture inside them.
+    let mut stmt = tx
+        .prepare("SELECT id, trashed FROM trash")
+        .map_err(sql_err)?;
+    let mut rows = stmt.query([]).map_err(sql_err)?;
+    while let Some(row) = rows.next().map_err(sql_err)? {
+        let id = Id(row.get(0).map_err(sql_err)?);
+        let bytes: Vec<u8> = row.get(1).map_err(sql_err)?;
+        let old: v4::Trashed = bincode::deserialize(&bytes).map_err(bincode_err)?;
+        let mut pictures = vec![];
+        let trashed = old.upgrade(&mut pictures);
+        for encoded in &pictures {
+            store(encoded)?;
+        }
+        let bytes = bincode::serialize(&trashed).map_err(bincode_err)?;
+        tx.execute(
+            "UPDATE trash SET trashed = ?2 WHERE id = ?1",
+            params![id.0, bytes],
+        )
+        .map_err(sql_err)?;
+    }
+    drop(rows);
+    drop(stmt);
+
+    tx.execute_batch("DROP TABLE pictures_v4;")
+        .map_err(sql_err)?;
+    set_meta(&tx, "data_version", 5).map_err(sql_err)?;
+    tx.commit().map_err(sql_err)
+}
+
+/// Upgrades a database of data version 5, whose items had a single picture, to give each item a
+/// gallery with that picture as its only photo.
+fn migrate_v5(conn: &mut Connection) -> std::io::Result<()> {
+    let tx = conn.transaction().map_err(sql_err)?;
+    let mut stmt = tx.prepare("SELECT id, item FROM items").map_err(sql_err)?;
+    let mut rows = stmt.query([]).map_err(sql_err)?;
+    while let Some(row) = rows.next().map_err(sql_err)? {
+        let id: u32 = row.get(0).map_err(sql_err)?;
+        let bytes: Vec<u8> = row.get(1).map_err(sql_err)?;
+        let old: v5::Item = bincode::deserialize(&bytes).map_err(bincode_err)?;
+        let bytes = bincode::serialize(&Item::from(old)).map_err(bincode_err)?;
+        tx.execute(
+            "UPDATE items SET item = ?2 WHERE id = ?1",
+            params![id, bytes],
+        )
+        .map_err(sql_err)?;
+    }
+    drop(rows);
+    drop(stmt);
+
+    let mut stmt = tx
+        .prepare("SELECT id, trashed FROM trash")
+        .map_err(sql_err)?;
+    let mut rows = stmt.query([]).map_err(sql_err)?;
+    while let Some(row) = rows.next().map_err(sql_err)? {
+        let id = Id(row.get(0).map_err(sql_err)?);
+        let bytes: Vec<u8> = row.get(1).map_err(sql_err)?;
+        let old: v5::Trashed = bincode::deserialize(&bytes).map_err(bincode_err)?;
+        write_trashed(&tx, id, &Trashed::from(old))?;
+    }
+    drop(rows);
+    drop(stmt);
+
+    set_meta(&tx, "data_version", 6).map_err(sql_err)?;
+    tx.commit().map_err(sql_err)
+}
@@ -0,0 +1,328 @@
+//! Optional TLS for the connection between the apps and the server.
+//!
+//! There is no certificate authority involved. The server usually has a self-signed certificate,
+//! and clients pin its SHA-256 fingerprint instead.
+
+use crate::Nonblocking;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    ClientConnection, Connection, DigitallySignedStruct, ServerConfig, ServerConnection,
+    StreamOwned,
+};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long a client gets to finish the TLS handshake before the server gives up on it.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A connection that may or may not be encrypted.
+pub enum Stream {
+    Plain(TcpStream),
+    Server(Box<StreamOwned<ServerConnection, TcpStream>>),
+    Client(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+impl Stream {
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Self::Plain(tcp) => tcp,
+            Self::Server(tls) => tls.get_ref(),
+            Self::Client(tls) => tls.get_ref(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        !matches!(self, Self::Plain(_))
+    }
+
+    /// Splits the stream so one thread can read from it while another writes.
+    pub fn split(self) -> std::io::Result<(ReadHalf, WriteHalf)> {
+        let (tcp, tls) = match self {
+            Self::Plain(tcp) => (tcp, None),
+            Self::Server(tls) => {
+                let (conn, tcp) = tls.into_parts();
+                (tcp, Some(Connection::from(conn)))
+            }
+            Self::Client(tls) => {
+                let (conn, tcp) = tls.into_parts();
+                (tcp, Some(Connection::from(conn)))
+            }
+        };
+        let tls = tls.map(|conn| Arc::new(Mutex::new(conn)));
+        let read = ReadHalf {
+            tcp: tcp.try_clone()?,
+            tls: tls.clone(),
+        };
+        Ok((read, WriteHalf { tcp, tls }))
+    }
+}
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(tcp) => tcp.read(buf),
+            Self::Server(tls) => tls.read(buf),
+            Self::Client(tls) => tls.read(buf),
+        }
+    }
+}
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(tcp) => tcp.write(buf),
+            Self::Server(tls) => tls.write(buf),
+            Self::Client(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(tcp) => tcp.flush(),
+            Self::Server(tls) => tls.flush(),
+            Self::Client(tls) => tls.flush(),
+        }
+    }
+}
+impl Nonblocking for Stream {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        self.tcp().set_nonblocking(nonblocking)
+    }
+}
+
+/// The reading end of a [`Stream::split`].
+pub struct ReadHalf {
+    tcp: TcpStream,
+    tls: Option<Arc<Mutex<Connection>>>,
+}
+impl Read for ReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(tls) = &self.tls else {
+            return self.tcp.read(buf);
+        };
+        let mut raw = [0u8; 4096];
+        loop {
+            match tls.lock().unwrap().reader().read(buf) {
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            // Wait for the socket without holding the lock, so the writing end isn't held up.
+            let len = self.tcp.read(&mut raw)?;
+            let mut conn = tls.lock().unwrap();
+            let mut rest = &raw[..len];
+            loop {
+                conn.read_tls(&mut rest)?;
+                conn.process_new_packets().map_err(std::io::Error::other)?;
+                if rest.is_empty() {
+                    break;
+                }
+            }
+            // Alerts and key updates are answered right away.
+            while conn.wants_write() {
+                conn.write_tls(&mut self.tcp)?;
+            }
+        }
+    }
+}
+
+/// The writing end of a [`Stream::split`]. Dropping it closes the connection,
+/// which also wakes up whoever is blocked reading from the other end.
+pub struct WriteHalf {
+    tcp: TcpStream,
+    tls: Option<Arc<Mutex<Connection>>>,
+}
+impl Write for WriteHalf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some(tls) = &self.tls else {
+            return self.tcp.write(buf);
+        };
+        let mut conn = tls.lock().unwrap();
+        let len = conn.writer().write(buf)?;
+        while conn.wants_write() {
+            conn.write_tls(&mut self.tcp)?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.tcp.flush()
+    }
+}
+impl Drop for WriteHalf {
+    fn drop(&mut self) {
+        if let Some(tls) = &self.tls {
+            if let Ok(mut conn) = tls.lock() {
+                conn.send_close_notify();
+                _ = conn.write_tls(&mut self.tcp);
+            }
+        }
+        _ = self.tcp.shutdown(Shutdown::Both);
+    }
+}
+
+/// Hex encoded SHA-256 of a DER encoded certificate.
+pub fn fingerprint(cert_der: &[u8]) -> String {
+    Sha256::digest(cert_der)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Strips the separators and capitals people tend to copy fingerprints with.
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(char::is_ascii_hexdigit)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// A PEM encoded certificate and private key.
+pub struct SelfSigned {
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+/// Generates a certificate for `names` (host names or IP addresses) that signs itself.
+pub fn generate_self_signed(names: Vec<String>) -> std::io::Result<SelfSigned> {
+    let cert = rcgen::generate_simple_self_signed(names).map_err(std::io::Error::other)?;
+    Ok(SelfSigned {
+        cert_pem: cert.cert.pem(),
+        key_pem: cert.key_pair.serialize_pem(),
+    })
+}
+
+/// The server's TLS setup, and the fingerprint clients should pin.
+pub struct ServerTls {
+    pub config: Arc<ServerConfig>,
+    pub fingerprint: String,
+}
+impl ServerTls {
+    pub fn new(cert_pem: &[u8], key_pem: &[u8]) -> std::io::Result<Self> {
+        let certs: Vec<CertificateDer> =
+            rustls_pemfile::certs(&mut &cert_pem[..]).collect::<Result<_, _>>()?;
+        let Some(first) = certs.first() else {
+            return Err(std::io::Error::other("No certificate found"));
+        };
+        let fingerprint = fingerprint(first);
+        let key: PrivateKeyDer = rustls_pemfile::private_key(&mut &key_pem[..])?
+            .ok_or_else(|| std::io::Error::other("No private key found"))?;
+
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(std::io::Error::other)?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(std::io::Error::other)?;
+        Ok(Self {
+            config: Arc::new(config),
+            fingerprint,
+        })
+    }
+
+    /// Loads the PEM files at `cert_path` and `key_path`.
+    /// If neither exists yet, a self-signed certificate is generated and written there first.
+    pub fn load_or_generate(cert_path: &Path, key_path: &Path) -> std::io::Result<Self> {
+        if !cert_path.exists() && !key_path.exists() {
+            let generated = generate_self_signed(vec!["localhost".into()])?;
+            std::fs::write(cert_path, &generated.cert_pem)?;
+            std::fs::write(key_path, &generated.key_pem)?;
+            log::info!("Generated a self-signed certificate at {cert_path:?}");
+        }
+        Self::new(&std::fs::read(cert_path)?, &std::fs::read(key_path)?)
+    }
+
+    /// Runs the server side of the handshake on a newly accepted connection.
+    pub fn accept(&self, tcp: TcpStream) -> std::io::Result<Stream> {
+        let conn = ServerConnection::new(self.config.clone()).map_err(std::io::Error::other)?;
+        let tls = handshake(conn, tcp)?;
+        Ok(Stream::Server(Box::new(tls)))
+    }
+}
+
+/// Starts TLS on a connection to a server, which must present the certificate with `fingerprint`.
+pub fn connect(tcp: TcpStream, fingerprint: &str) -> std::io::Result<Stream> {
+    let provider = ring::default_provider();
+    let verifier = PinnedCert {
+        fingerprint: normalize_fingerprint(fingerprint),
+        algorithms: provider.signature_verification_algorithms,
+    };
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(provider))
+        .with_safe_default_protocol_versions()
+        .map_err(std::io::Error::other)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    // The name is only used for SNI; the pinned fingerprint is what identifies the server.
+    let name = ServerName::IpAddress(tcp.peer_addr()?.ip().into());
+    let conn =
+        ClientConnection::new(Arc::new(config), name.to_owned()).map_err(std::io::Error::other)?;
+    let tls = handshake(conn, tcp)?;
+    Ok(Stream::Client(Box::new(tls)))
+}
+
+/// Finishes the handshake up front, so a bad certificate is reported when connecting
+/// instead of on whatever happens to be sent first.
+fn handshake<C, S>(mut conn: C, mut tcp: TcpStream) -> std::io::Result<StreamOwned<C, TcpStream>>
+where
+    C: std::ops::DerefMut<Target = rustls::ConnectionCommon<S>>,
+    S: rustls::SideData,
+{
+    let timeout = tcp.read_timeout()?;
+    tcp.set_read_timeout(Some(timeout.unwrap_or(HANDSHAKE_TIMEOUT)))?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut tcp)?;
+    }
+    tcp.set_read_timeout(timeout)?;
+    Ok(StreamOwned::new(conn, tcp))
+}
+
+#[derive(Debug)]
+struct PinnedCert {
+    fingerprint: String,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "Server certificate {} does not match the pinned one",
+                fingerprint(end_entity)
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
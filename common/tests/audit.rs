@@ -0,0 +1,315 @@
+mod common;
+
+use common::test_path;
+use inv_common::audit::{self, Action, AuditLog, AuditQuery};
+use inv_common::backup::Backups;
+use inv_common::inv::{Gallery, Id, Inv, Item, Picture, PictureId, Trashed};
+use inv_common::storage::{FileStorage, Storage};
+use inv_common::{picture, ServerHost};
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+
+fn log_with_two_entries(path: &Path) {
+    let mut log = AuditLog::open(path).unwrap();
+    let item = Item {
+        name: "lamp".into(),
+        ..Default::default()
+    };
+    log.record("tester", Action::InsertItem, Id(1), None, Some(&item))
+        .unwrap();
+    log.record("tester", Action::RemoveItem, Id(1), Some(&item), None)
+        .unwrap();
+}
+
+fn append(path: &Path, bytes: &[u8]) {
+    let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+    file.write_all(bytes).unwrap();
+}
+
+#[test]
+fn half_written_entries_are_cut_off() {
+    let path = test_path("half-written");
+    log_with_two_entries(&path);
+    let len = std::fs::metadata(&path).unwrap().len();
+    // The length of an entry, but only part of it.
+    append(&path, &[0, 0, 0, 100, 1, 2, 3]);
+
+    let mut log = AuditLog::open(&path).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    let seq = log
+        .record("tester", Action::InsertItem, Id(2), None, None)
+        .unwrap();
+    assert_eq!(seq, 3);
+    assert_eq!(log.query(&AuditQuery::default()).unwrap().len(), 3);
+    _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn undecodable_entries_are_never_cut_off() {
+    let path = test_path("undecodable");
+    log_with_two_entries(&path);
+    // A whole entry, that isn't one.
+    append(&path, &[0, 0, 0, 3, 1, 2, 3]);
+    let len = std::fs::metadata(&path).unwrap().len();
+
+    let err = AuditLog::open(&path).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn new_logs_start_with_the_magic() {
+    let path = test_path("magic");
+    log_with_two_entries(&path);
+    assert!(std::fs::read(&path).unwrap().starts_with(audit::MAGIC));
+    _ = std::fs::remove_file(path);
+}
+
+/// An item as written before pictures were stored apart from items.
+#[derive(Serialize)]
+struct ItemV4 {
+    creation_date: SystemTime,
+    revision: u64,
+    location: String,
+    listings: inv_common::inv::Listings,
+    picture: Option<Picture>,
+    name: String,
+    desc: String,
+    count: u32,
+    est_cost: inv_common::inv::Usd,
+    condition: String,
+    color: String,
+    dimensions: [f32; 3],
+    weight: f32,
+    shipping_weight: f32,
+    model_no: u64,
+    serial_no: u64,
+    brand: String,
+}
+
+/// An entry as written before entries had a version.
+#[derive(Serialize)]
+struct EntryV4 {
+    seq: u64,
+    time: SystemTime,
+    user: String,
+    action: Action,
+    id: Id,
+    before: Option<ItemV4>,
+    after: Option<ItemV4>,
+}
+
+#[test]
+fn old_logs_are_upgraded_keeping_their_pictures() {
+    let path = test_path("old");
+    let storage_path = test_path("old-storage");
+    let lamp = ItemV4 {
+        creation_date: SystemTime::now(),
+        revision: 1,
+        location: String::new(),
+        listings: Default::default(),
+        picture: Some(Picture {
+            data: vec![255; 4 * 16 * 8],
+            size: [16, 8],
+        }),
+        name: "lamp".into(),
+        desc: String::new(),
+        count: 1,
+        est_cost: inv_common::inv::Usd(0),
+        condition: String::new(),
+        color: String::new(),
+        dimensions: [0.0; 3],
+        weight: 0.0,
+        shipping_weight: 0.0,
+        model_no: 0,
+        serial_no: 0,
+        brand: String::new(),
+    };
+    let entry = EntryV4 {
+        seq: 1,
+        time: SystemTime::now(),
+        user: "tester".into(),
+        action: Action::InsertItem,
+        id: Id(1),
+        before: None,
+        after: Some(lamp),
+    };
+    let encoded = bincode::serialize(&entry).unwrap();
+    let mut bytes = (encoded.len() as u32).to_be_bytes().to_vec();
+    bytes.extend(encoded);
+    std::fs::write(&path, bytes).unwrap();
+
+    let err = AuditLog::open(&path).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let storage = FileStorage::new(&storage_path);
+    assert!(AuditLog::upgrade(&path, &storage).unwrap());
+    assert!(!AuditLog::upgrade(&path, &storage).unwrap());
+
+    let mut log = AuditLog::open(&path).unwrap();
+    let entries = log.query(&AuditQuery::default()).unwrap();
+    assert_eq!(entries.len(), 1);
+    let after = entries[0].after.as_ref().unwrap();
+    assert_eq!(after.name, "lamp");
+    let id = after.gallery.cover().unwrap().picture;
+    let stored = storage.load_picture(&id).unwrap().unwrap();
+    assert_eq!(picture::decode(&stored).unwrap().size, [16, 8]);
+    // New entries go after the upgraded ones.
+    let seq = log
+        .record("tester", Action::RemoveItem, Id(1), Some(after), None)
+        .unwrap();
+    assert_eq!(seq, 2);
+    assert_eq!(log.history(Id(1)).unwrap().len(), 2);
+    _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn item_histories_stay_indexed() {
+    let path = test_path("index");
+    let mut log = AuditLog::open(&path).unwrap();
+    let item = |name: &str| Item {
+        name: name.into(),
+        ..Default::default()
+    };
+    for (id, name) in [(1, "lamp"), (2, "desk"), (1, "desk lamp")] {
+        log.record(
+            "tester",
+            Action::InsertItem,
+            Id(id),
+            None,
+            Some(&item(name)),
+        )
+        .unwrap();
+    }
+    let names = |log: &AuditLog, id| -> Vec<String> {
+        let query = AuditQuery {
+            id: Some(Id(id)),
+            ..Default::default()
+        };
+        let entries = log.query(&query).unwrap();
+        entries.into_iter().map(|e| e.after.unwrap().name).collect()
+    };
+    assert_eq!(names(&log, 1), ["lamp", "desk lamp"]);
+    assert_eq!(names(&log, 2), ["desk"]);
+    assert!(names(&log, 3).is_empty());
+
+    // Longer entries move every one after them.
+    log.rewrite(|entry| {
+        if let Some(after) = &mut entry.after {
+            after.name += " from the attic";
+        }
+    })
+    .unwrap();
+    log.record(
+        "tester",
+        Action::InsertItem,
+        Id(2),
+        None,
+        Some(&item("chair")),
+    )
+    .unwrap();
+    assert_eq!(names(&log, 2), ["desk from the attic", "chair"]);
+    assert_eq!(log.history(Id(1)).unwrap().len(), 2);
+
+    let log = AuditLog::open(&path).unwrap();
+    assert_eq!(
+        names(&log, 1),
+        ["lamp from the attic", "desk lamp from the attic"]
+    );
+    assert_eq!(names(&log, 2), ["desk from the attic", "chair"]);
+    _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn only_unused_pictures_are_collected() {
+    let path = test_path("gc");
+    let backups_dir = test_path("gc-backups");
+    let mut server = ServerHost::new(Inv::default());
+    server.storage = Some(Box::new(FileStorage::new(&path)));
+    server.backups = Some(Backups::new(&backups_dir, 5));
+    *server.audit.get_mut().unwrap() = AuditLog::open(test_path("gc-audit")).unwrap();
+    let [in_item, in_trash, in_log, in_backup, unused] = [1, 2, 3, 4, 5].map(|shade| {
+        let pic = Picture {
+            data: vec![shade; 4 * 3 * 2],
+            size: [3, 2],
+        };
+        server
+            .store_picture(&picture::encode(&pic).unwrap())
+            .unwrap()
+    });
+    let item = |picture: PictureId| Item {
+        gallery: Gallery::from(Some(picture)),
+        ..Default::default()
+    };
+
+    server
+        .inv
+        .write()
+        .unwrap()
+        .items
+        .insert(Id(1), item(in_backup));
+    server.create_backup().unwrap();
+    let mut inv = server.inv.write().unwrap();
+    inv.items.insert(Id(1), item(in_item));
+    let trashed = Trashed {
+        item: item(in_trash),
+        deleted_at: SystemTime::now(),
+        deleted_by: "tester".into(),
+    };
+    inv.trash.insert(Id(2), trashed);
+    drop(inv);
+    let old = item(in_log);
+    server
+        .audit
+        .lock()
+        .unwrap()
+        .record("tester", Action::RemoveItem, Id(3), Some(&old), None)
+        .unwrap();
+
+    assert_eq!(server.collect_garbage().unwrap(), 1);
+    assert!(!server.has_picture(&unused).unwrap());
+    for id in [in_item, in_trash, in_log, in_backup] {
+        assert!(server.has_picture(&id).unwrap());
+    }
+    assert_eq!(server.collect_garbage().unwrap(), 0);
+    _ = std::fs::remove_dir_all(backups_dir);
+}
+
+#[test]
+fn removing_a_platform_moves_the_listings_in_the_log() {
+    let mut server = ServerHost::new(Inv {
+        platform_names: vec!["Ebay".into(), "Etsy".into(), "Mercari".into()],
+        ..Default::default()
+    });
+    *server.audit.get_mut().unwrap() = AuditLog::open(test_path("platforms")).unwrap();
+    let mercari = server.inv.read().unwrap().find_platform("Mercari").unwrap();
+    let mut lamp = Item::default();
+    lamp.listings.add_listing(mercari);
+    server
+        .insert_item(Id(1), lamp, "tester", None, drop)
+        .unwrap();
+    let mut lamp = server.inv.read().unwrap().items[&Id(1)].copy();
+    lamp.name = "lamp".into();
+    server
+        .insert_item(Id(1), lamp, "tester", None, drop)
+        .unwrap();
+
+    server.remove_platform("Etsy", "tester").unwrap();
+    let mercari = server.inv.read().unwrap().find_platform("Mercari").unwrap();
+    let history = server.audit.lock().unwrap().history(Id(1)).unwrap();
+    assert_eq!(history.len(), 3);
+    for version in &history {
+        let listings = &version.item.as_ref().unwrap().listings;
+        assert!(listings.contains_platform(mercari));
+        assert_eq!(listings.count(), 1);
+    }
+    // Moving the listings changed nothing, and undoing it keeps them on the same platform.
+    assert!(history[2].changed.is_empty());
+    server.undo_change(history[2].seq, "tester").unwrap();
+    let lamp = server.inv.read().unwrap().items[&Id(1)].copy();
+    assert_eq!(lamp.name, "lamp");
+    assert!(lamp.listings.contains_platform(mercari));
+    assert_eq!(lamp.listings.count(), 1);
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use common::test_path;
+use inv_common::backup::Backups;
+use inv_common::inv::Inv;
+use inv_common::ServerHost;
+use std::fs::File;
+use std::time::{Duration, SystemTime};
+
+#[test]
+fn the_newest_backup_is_kept_even_with_keep_0() {
+    let backups = Backups::new(test_path("keep-0"), 0);
+    backups.create(&Inv::default()).unwrap();
+    let name = backups.create(&Inv::default()).unwrap();
+    assert_eq!(backups.list().unwrap(), [name]);
+}
+
+#[test]
+fn backups_are_pruned_by_when_they_were_written() {
+    let backups = Backups::new(test_path("prune-by-time"), 2);
+    let old = backups.create(&Inv::default()).unwrap();
+    let older = backups.create(&Inv::default()).unwrap();
+    // `older` was named while the clock was ahead, so its name sorts after `old`.
+    let now = SystemTime::now();
+    for (name, age) in [(&old, 60), (&older, 120)] {
+        let path = backups.dir.join(format!("{name}.inv"));
+        let file = File::options().write(true).open(path).unwrap();
+        file.set_modified(now - Duration::from_secs(age)).unwrap();
+    }
+    assert_eq!(backups.list().unwrap(), [older.as_str(), &old]);
+
+    let new = backups.create(&Inv::default()).unwrap();
+    assert_eq!(backups.list().unwrap(), [old, new]);
+}
+
+#[test]
+fn restoring_names_the_file_read() {
+    let mut server = ServerHost::new(Inv::default());
+    server.backups = Some(Backups::new(test_path("restore"), 5));
+    let name = server.create_backup().unwrap();
+    let path = server.restore_backup(&name).unwrap();
+    assert_eq!(path, server.backups.as_ref().unwrap().path(&name));
+    assert!(path.is_file());
+}
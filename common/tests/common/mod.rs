@@ -0,0 +1,71 @@
+//! Helpers shared by the integration tests. Each test file uses only some of them.
+#![allow(dead_code)]
+
+use inv_common::auth::Role;
+use inv_common::inv::{Inv, Item};
+use inv_common::tls::{ServerTls, Stream};
+use inv_common::{ServerConn, ServerHost};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// A path no other test uses, with nothing left there from an earlier run.
+pub fn test_path(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("inv-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    for suffix in ["", "-wal", "-shm"] {
+        _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+    }
+    _ = std::fs::remove_dir_all(&path);
+    _ = std::fs::remove_dir_all(format!("{}.pictures", path.display()));
+    path
+}
+
+/// Serves plain connections on a loopback port, a thread per client like the real server.
+/// The editor `tester` logs in with the password `hunter2`.
+pub fn spawn_server() -> (Arc<ServerHost>, u16) {
+    serve(|tcp| Some(Stream::Plain(tcp)))
+}
+
+/// Like `spawn_server`, but over TLS.
+pub fn spawn_tls_server(server_tls: Arc<ServerTls>) -> (Arc<ServerHost>, u16) {
+    serve(move |tcp| server_tls.accept(tcp).ok())
+}
+
+fn serve(accept: impl Fn(TcpStream) -> Option<Stream> + Send + 'static) -> (Arc<ServerHost>, u16) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = ServerHost::new(Inv::default());
+    server
+        .accounts
+        .write()
+        .unwrap()
+        .add("tester", "hunter2", Role::Editor)
+        .unwrap();
+    let server = Arc::new(server);
+    let host = server.clone();
+    std::thread::spawn(move || {
+        for tcp in listener.incoming() {
+            let Some(stream) = accept(tcp.unwrap()) else {
+                continue;
+            };
+            let server = server.clone();
+            std::thread::spawn(move || server.serve_connection(stream));
+        }
+    });
+    (host, port)
+}
+
+/// Logs in to the server on `port` as `tester`.
+pub fn connect(port: u16) -> ServerConn<TcpStream> {
+    let tcp = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    ServerConn::connect(tcp, "tester", "hunter2").unwrap()
+}
+
+pub fn item(name: &str) -> Item {
+    Item {
+        name: name.into(),
+        ..Default::default()
+    }
+}
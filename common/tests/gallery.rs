@@ -0,0 +1,66 @@
+use inv_common::inv::{Gallery, Photo, PictureId};
+
+fn gallery(count: u8) -> Gallery {
+    let mut gallery = Gallery::default();
+    for n in 0..count {
+        gallery.push(Photo::new(PictureId([n; 32])));
+    }
+    gallery
+}
+
+fn order(gallery: &Gallery) -> Vec<u8> {
+    gallery.pictures().map(|id| id.0[0]).collect()
+}
+
+fn cover(gallery: &Gallery) -> Option<u8> {
+    gallery.cover().map(|photo| photo.picture.0[0])
+}
+
+#[test]
+fn the_cover_stays_on_its_photo_when_others_move() {
+    let mut gallery = gallery(4);
+    assert_eq!(cover(&gallery), Some(0));
+    gallery.set_cover(2);
+
+    gallery.move_photo(0, 3);
+    assert_eq!(order(&gallery), [1, 2, 3, 0]);
+    assert_eq!(cover(&gallery), Some(2));
+    gallery.move_photo(1, 0);
+    assert_eq!(order(&gallery), [2, 1, 3, 0]);
+    assert_eq!(cover(&gallery), Some(2));
+    assert_eq!(gallery.cover_idx(), 0);
+    gallery.move_photo(3, 1);
+    assert_eq!(order(&gallery), [2, 0, 1, 3]);
+    assert_eq!(cover(&gallery), Some(2));
+    // Out of range moves do nothing.
+    gallery.move_photo(4, 0);
+    assert_eq!(order(&gallery), [2, 0, 1, 3]);
+}
+
+#[test]
+fn removing_the_cover_picks_the_next_photo() {
+    let mut gallery = gallery(3);
+    gallery.set_cover(1);
+    gallery.remove(0);
+    assert_eq!(cover(&gallery), Some(1));
+
+    gallery.remove(0);
+    assert_eq!(cover(&gallery), Some(2));
+    gallery.push(Photo::new(PictureId([3; 32])));
+    gallery.set_cover(1);
+    gallery.remove(1);
+    assert_eq!(cover(&gallery), Some(2));
+    gallery.remove(0);
+    assert_eq!(cover(&gallery), None);
+    assert!(gallery.remove(0).is_none());
+}
+
+#[test]
+fn captions_are_optional() {
+    let mut gallery = gallery(2);
+    gallery.set_caption(1, "label");
+    assert_eq!(gallery.photos()[0].caption, "");
+    assert_eq!(gallery.photos()[1].caption, "label");
+    assert_eq!(Gallery::from(None), Gallery::default());
+    assert_eq!(Gallery::from(Some(PictureId([7; 32]))).len(), 1);
+}
@@ -0,0 +1,99 @@
+mod common;
+
+use common::{connect, item, spawn_server};
+use inv_common::inv::Id;
+use inv_common::{InvEvent, ServerConn, ServerErr};
+use std::collections::HashSet;
+use std::net::TcpStream;
+use std::sync::{Arc, Barrier};
+
+const CLIENTS: u32 = 8;
+const ITEMS_PER_CLIENT: u32 = 10;
+
+/// Runs `f` on `CLIENTS` logged in connections at once, returning what each of them returned.
+fn run_clients<T: Send + 'static>(
+    port: u16,
+    f: impl Fn(u32, &mut ServerConn<TcpStream>) -> T + Send + Sync + 'static,
+) -> Vec<T> {
+    let f = Arc::new(f);
+    // Everyone logs in first, so the commands really are sent at the same time.
+    let barrier = Arc::new(Barrier::new(CLIENTS as usize));
+    let threads: Vec<_> = (0..CLIENTS)
+        .map(|client| {
+            let f = f.clone();
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                let mut conn = connect(port);
+                barrier.wait();
+                f(client, &mut conn)
+            })
+        })
+        .collect();
+    threads.into_iter().map(|t| t.join().unwrap()).collect()
+}
+
+#[test]
+fn concurrent_inserts() {
+    let (server, port) = spawn_server();
+    let mut observer = connect(port);
+    // Never finishes logging in. It must not hold anyone else up.
+    let mut stalled = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    std::io::Write::write_all(&mut stalled, &[0]).unwrap();
+
+    let revisions = run_clients(port, |client, conn| {
+        (0..ITEMS_PER_CLIENT)
+            .map(|n| {
+                let item = item(&format!("{client}-{n}"));
+                conn.insert_item(Id(client * 1000 + n), &item).unwrap()
+            })
+            .collect::<Vec<u64>>()
+    });
+
+    // Every write got a revision of its own.
+    let total = (CLIENTS * ITEMS_PER_CLIENT) as u64;
+    let mut revisions: Vec<u64> = revisions.into_iter().flatten().collect();
+    revisions.sort();
+    assert_eq!(revisions, (1..=total).collect::<Vec<_>>());
+    assert_eq!(server.inv.read().unwrap().revision, total);
+
+    // The observer heard about every one of them.
+    let mut seen = HashSet::new();
+    for _ in 0..total {
+        match observer.recv_event().unwrap() {
+            InvEvent::ItemInserted(id, _) => assert!(seen.insert(id)),
+            event => panic!("unexpected event {event:?}"),
+        }
+    }
+
+    let delta = observer.get_changes(0).unwrap();
+    assert_eq!(delta.changed.len() as u64, total);
+    for (id, item) in &delta.changed {
+        assert_eq!(item.name, format!("{}-{}", id.0 / 1000, id.0 % 1000));
+    }
+}
+
+#[test]
+fn contended_item() {
+    let (server, port) = spawn_server();
+
+    let conflicts = run_clients(port, |client, conn| {
+        let mut item = item(&client.to_string());
+        let mut conflicts = 0;
+        loop {
+            match conn.insert_item(Id(1), &item) {
+                Ok(_) => return conflicts,
+                Err(ServerErr::Conflict(current)) => {
+                    item.revision = current.revision;
+                    conflicts += 1;
+                }
+                Err(err) => panic!("insert failed : {err}"),
+            }
+        }
+    });
+
+    // Each client got its edit in exactly once, and no edit was based on a stale copy.
+    let inv = server.inv.read().unwrap();
+    assert_eq!(inv.revision, CLIENTS as u64);
+    assert_eq!(inv.items.len(), 1);
+    assert!(conflicts.iter().sum::<u32>() >= CLIENTS - 1);
+}
@@ -0,0 +1,315 @@
+mod common;
+
+use common::{item, spawn_server};
+use inv_common::inv::{Id, Listings, Photo, Picture, PictureId, Usd};
+use inv_common::local::LocalInv;
+use inv_common::picture::{self, PictureCache, Size};
+use inv_common::save::{self, LoadErr};
+use inv_common::{ServerConn, ServerErr};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// A connection whose reads start failing once `broken` is set, like one that drops while
+/// waiting on a response.
+struct Flaky {
+    tcp: TcpStream,
+    broken: Arc<AtomicBool>,
+}
+impl Read for Flaky {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.broken.load(Ordering::SeqCst) {
+            return Err(std::io::ErrorKind::ConnectionReset.into());
+        }
+        self.tcp.read(buf)
+    }
+}
+impl Write for Flaky {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.tcp.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.tcp.flush()
+    }
+}
+
+fn connect(port: u16) -> (ServerConn<Flaky>, Arc<AtomicBool>) {
+    let broken = Arc::new(AtomicBool::new(false));
+    let tcp = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let flaky = Flaky {
+        tcp,
+        broken: broken.clone(),
+    };
+    (
+        ServerConn::connect(flaky, "tester", "hunter2").unwrap(),
+        broken,
+    )
+}
+
+#[test]
+fn edits_stay_pending_when_the_connection_fails_mid_batch() {
+    let (host, port) = spawn_server();
+    let dir = std::env::temp_dir().join(format!("inv-local-test-{}", std::process::id()));
+    let pictures = PictureCache::new(dir);
+    let mut local = LocalInv::default();
+    local.insert_item(Id(1), item("lamp"));
+    local.insert_item(Id(2), item("chair"));
+
+    // The batch reaches the server, but the answer never comes back.
+    let (mut conn, broken) = connect(port);
+    broken.store(true, Ordering::SeqCst);
+    assert!(local.upload_changes(&mut conn, &pictures).is_err());
+    assert!(local.has_pending_changes());
+    assert_eq!(local.pending_ops().len(), 2);
+    // The server applies it on its own thread.
+    for _ in 0..100 {
+        if host.inv.read().unwrap().items.len() == 2 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert_eq!(host.inv.read().unwrap().items.len(), 2);
+
+    // On the next sync the server already has the items, so they come back as conflicts to merge.
+    let (mut conn, _) = connect(port);
+    let upload = local.upload_changes(&mut conn, &pictures).unwrap();
+    let mut conflicts: Vec<_> = upload.conflicts.iter().map(|(id, _)| id.0).collect();
+    conflicts.sort();
+    assert_eq!(conflicts, [1, 2]);
+    assert!(local.has_pending_changes());
+    for (id, remote) in upload.conflicts {
+        local.resolve_conflict(id, remote, None);
+    }
+    assert!(!local.has_pending_changes());
+}
+
+#[test]
+fn photos_nobody_has_are_dropped_from_rejected_edits() {
+    let (host, port) = spawn_server();
+    let dir = std::env::temp_dir().join(format!("inv-local-test-{}", std::process::id()));
+    let pictures = PictureCache::new(dir);
+    let mut local = LocalInv::default();
+    local.insert_item(Id(1), item("lamp"));
+    // A picture neither we nor the server have, as a bad import could leave behind.
+    let nowhere = PictureId::of(b"nowhere");
+    let mut chair = item("chair");
+    chair.gallery.push(Photo::new(nowhere));
+    local.insert_item(Id(2), chair);
+
+    // Only the chair is rejected, and the lamp goes through once it is left out.
+    let (mut conn, _) = connect(port);
+    let upload = local.upload_changes(&mut conn, &pictures).unwrap();
+    assert!(upload.conflicts.is_empty());
+    assert_eq!(upload.dropped_photos, [(Id(2), nowhere)]);
+    assert!(!local.has_pending_changes());
+    let inv = host.inv.read().unwrap();
+    assert_eq!(inv.items[&Id(1)].name, "lamp");
+    assert_eq!(inv.items[&Id(2)].name, "chair");
+    assert!(inv.items[&Id(2)].gallery.is_empty());
+    assert!(local.get_item(&Id(2)).unwrap().gallery.is_empty());
+    assert_eq!(
+        local.get_item(&Id(1)).unwrap().revision,
+        inv.items[&Id(1)].revision
+    );
+}
+
+#[test]
+fn pictures_the_server_lost_are_sent_again() {
+    let (host, port) = spawn_server();
+    let dir = std::env::temp_dir().join(format!("inv-local-resend-{}", std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+    let mut pictures = PictureCache::new(&dir);
+    let id = pictures
+        .add(&Picture {
+            data: [255, 9, 9, 9].repeat(16),
+            size: [4, 4],
+        })
+        .unwrap();
+    // We sent it before, but the server doesn't have it any more.
+    let mut local = LocalInv::default();
+    let mut lamp = item("lamp");
+    lamp.gallery.push(Photo::new(id));
+    local.insert_item(Id(1), lamp);
+
+    let (mut conn, _) = connect(port);
+    let upload = local.upload_changes(&mut conn, &pictures).unwrap();
+    assert!(upload.dropped_photos.is_empty());
+    assert!(!local.has_pending_changes());
+    assert!(host.has_picture(&id).unwrap());
+    let stored = &host.inv.read().unwrap().items[&Id(1)];
+    assert_eq!(stored.gallery.pictures().collect::<Vec<_>>(), [&id]);
+    _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn pictures_are_fetched_again_after_a_failed_connection() {
+    let (host, port) = spawn_server();
+    let dir = std::env::temp_dir().join(format!("inv-local-fetch-{}", std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+    let mut pictures = PictureCache::new(&dir);
+    let encoded = picture::encode(&Picture {
+        data: [255, 1, 2, 3].repeat(16),
+        size: [4, 4],
+    })
+    .unwrap();
+    let id = host.store_picture(&encoded).unwrap();
+
+    let (mut conn, broken) = connect(port);
+    broken.store(true, Ordering::SeqCst);
+    assert!(matches!(
+        pictures.fetch(&id, Size::Full, &mut conn),
+        Err(ServerErr::OtherIo(_))
+    ));
+    assert!(pictures.is_missing(&id, Size::Full));
+
+    let (mut conn, _) = connect(port);
+    pictures.fetch(&id, Size::Full, &mut conn).unwrap();
+    assert_eq!(pictures.read(&id, Size::Full).unwrap(), Some(encoded));
+
+    // One the server doesn't have isn't asked for again.
+    let unknown = PictureId::of(b"nowhere");
+    assert!(pictures
+        .fetch(&unknown, Size::Thumbnail, &mut conn)
+        .is_err());
+    assert!(!pictures.is_missing(&unknown, Size::Thumbnail));
+    _ = std::fs::remove_dir_all(dir);
+}
+
+/// An item as the app saved it in releases 0.0.6 to 0.0.8, holding its own picture.
+#[derive(Serialize)]
+struct OldItem {
+    creation_date: SystemTime,
+    revision: u64,
+    location: String,
+    listings: Listings,
+    picture: Option<Picture>,
+    name: String,
+    desc: String,
+    count: u32,
+    est_cost: Usd,
+    condition: String,
+    color: String,
+    dimensions: [f32; 3],
+    weight: f32,
+    shipping_weight: f32,
+    model_no: u64,
+    serial_no: u64,
+    brand: String,
+}
+
+#[derive(Serialize)]
+struct OldInv {
+    platform_names: Vec<String>,
+    revision: u64,
+    items: HashMap<Id, OldItem>,
+    removed: HashMap<Id, u64>,
+    trash: HashMap<Id, (OldItem, SystemTime, String)>,
+}
+
+/// The app's local inv in releases 0.0.6 to 0.0.8, saved without a header.
+#[derive(Serialize)]
+struct OldLocalInv {
+    modified_items: HashSet<Id>,
+    deleted_items: HashSet<Id>,
+    added_items: HashSet<Id>,
+    base_items: HashMap<Id, OldItem>,
+    inv: OldInv,
+}
+
+fn old_item(name: &str, shade: u8) -> OldItem {
+    OldItem {
+        creation_date: SystemTime::UNIX_EPOCH,
+        revision: 3,
+        location: String::new(),
+        listings: Listings::default(),
+        picture: Some(Picture {
+            data: [255, shade, shade, shade].repeat(16),
+            size: [4, 4],
+        }),
+        name: name.into(),
+        desc: String::new(),
+        count: 1,
+        est_cost: Usd(100),
+        condition: String::new(),
+        color: String::new(),
+        dimensions: [0.0; 3],
+        weight: 0.0,
+        shipping_weight: 0.0,
+        model_no: 0,
+        serial_no: 0,
+        brand: String::new(),
+    }
+}
+
+#[test]
+fn old_local_invs_are_upgraded_with_their_pictures() {
+    let old = OldLocalInv {
+        modified_items: HashSet::new(),
+        deleted_items: HashSet::new(),
+        added_items: HashSet::from([Id(1)]),
+        base_items: HashMap::new(),
+        inv: OldInv {
+            platform_names: vec!["Ebay".into()],
+            revision: 3,
+            items: HashMap::from([
+                (Id(1), old_item("lamp", 10)),
+                (Id(2), old_item("chair", 200)),
+            ]),
+            removed: HashMap::new(),
+            trash: HashMap::new(),
+        },
+    };
+    let decoded = save::decode_local(&bincode::serialize(&old).unwrap()).unwrap();
+    assert_eq!(decoded.pictures.len(), 2);
+    let local = decoded.inv;
+    assert_eq!(local.item_count(), 2);
+    assert_eq!(local.revision, 3);
+
+    // Each picture became the cover of its item's gallery, under the id of its encoding.
+    let dir = std::env::temp_dir().join(format!("inv-local-upgrade-{}", std::process::id()));
+    let mut pictures = PictureCache::new(&dir);
+    let ids: Vec<PictureId> = (decoded.pictures.iter())
+        .map(|encoded| pictures.add_encoded(encoded).unwrap())
+        .collect();
+    let lamp = local.get_item(&Id(1)).unwrap();
+    let chair = local.get_item(&Id(2)).unwrap();
+    for item in [lamp, chair] {
+        assert_eq!(item.gallery.len(), 1);
+        let cover = item.gallery.cover().unwrap().picture;
+        assert!(ids.contains(&cover));
+        assert!(pictures.contains(&cover, Size::Thumbnail));
+    }
+    // Only the picture of the unsynced lamp has to be uploaded, the server has the chair's.
+    assert!(local.is_picture_unsent(&lamp.gallery.cover().unwrap().picture));
+    assert!(!local.is_picture_unsent(&chair.gallery.cover().unwrap().picture));
+    assert_eq!(local.pending_ops().len(), 1);
+
+    // Saved again, it comes back as is.
+    let decoded = save::decode_local(&save::encode_local(&local)).unwrap();
+    assert!(decoded.pictures.is_empty());
+    assert_eq!(decoded.inv.item_count(), 2);
+    assert_eq!(decoded.inv.pending_ops().len(), 1);
+    assert_eq!(
+        decoded.inv.get_item(&Id(1)).unwrap().gallery,
+        local.get_item(&Id(1)).unwrap().gallery
+    );
+    _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn unreadable_local_invs_are_refused() {
+    assert!(matches!(
+        save::decode_local(b"not an inv"),
+        Err(LoadErr::Unrecognized)
+    ));
+    let mut newer = save::encode_local(&LocalInv::default());
+    newer[4] += 1;
+    assert!(matches!(
+        save::decode_local(&newer),
+        Err(LoadErr::UnsupportedVersion(_))
+    ));
+}
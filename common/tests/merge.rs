@@ -0,0 +1,87 @@
+mod common;
+
+use common::item;
+use inv_common::inv::{Item, Listing, Platform};
+use inv_common::merge::merge_items;
+use std::time::SystemTime;
+
+/// A copy of `base` listed on the first platform, with `sold` sales.
+fn sold(base: &Item, sold: u32) -> Item {
+    let mut item = base.copy();
+    item.listings[Platform::from_idx(0).unwrap()] = Some(Listing {
+        date: SystemTime::UNIX_EPOCH,
+        sold,
+    });
+    item
+}
+
+fn merged_sold(base: u32, local: u32, remote: u32) -> u32 {
+    let lamp = item("lamp");
+    let merged = merge_items(
+        &sold(&lamp, base),
+        &sold(&lamp, local),
+        &sold(&lamp, remote),
+    );
+    assert!(merged.conflicts.is_empty());
+    merged.item.listings[Platform::from_idx(0).unwrap()]
+        .as_ref()
+        .unwrap()
+        .sold
+}
+
+#[test]
+fn sales_on_both_sides_add_up() {
+    assert_eq!(merged_sold(2, 5, 3), 6);
+    assert_eq!(merged_sold(2, 2, 3), 3);
+    // A sale taken back on one side cancels one made on the other.
+    assert_eq!(merged_sold(2, 1, 3), 2);
+    assert_eq!(merged_sold(5, 0, 1), 0);
+}
+
+#[test]
+fn sales_past_the_limit_stop_at_it() {
+    assert_eq!(merged_sold(10, u32::MAX, 20), u32::MAX);
+    assert_eq!(merged_sold(0, u32::MAX, u32::MAX), u32::MAX);
+    assert_eq!(merged_sold(u32::MAX, u32::MAX, 0), 0);
+    assert_eq!(merged_sold(u32::MAX - 1, u32::MAX, u32::MAX), u32::MAX);
+}
+
+#[test]
+fn fields_changed_on_both_sides_conflict() {
+    let base = sold(&item("lamp"), 1);
+    let mut local = base.copy();
+    local.name = "desk lamp".into();
+    local.color = "red".into();
+    let mut remote = sold(&base, 4);
+    remote.name = "floor lamp".into();
+    remote.brand = "Acme".into();
+
+    let merged = merge_items(&base, &local, &remote);
+    assert_eq!(merged.conflicts, ["name"]);
+    // Conflicting fields keep the local value, the rest take whichever side changed.
+    assert_eq!(merged.item.name, "desk lamp");
+    assert_eq!(merged.item.color, "red");
+    assert_eq!(merged.item.brand, "Acme");
+    assert_eq!(merged.item.listings, remote.listings);
+}
+
+#[test]
+fn listing_dates_changed_on_both_sides_conflict() {
+    let base = sold(&item("lamp"), 1);
+    let platform = Platform::from_idx(0).unwrap();
+    let [mut local, mut remote] = [base.copy(), base.copy()];
+    for (item, secs, sold) in [(&mut local, 10, 2), (&mut remote, 20, 3)] {
+        let listing = item.listings[platform].as_mut().unwrap();
+        listing.date = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        listing.sold = sold;
+    }
+
+    let merged = merge_items(&base, &local, &remote);
+    assert_eq!(merged.conflicts, ["listing date"]);
+    let listing = merged.item.listings[platform].as_ref().unwrap();
+    assert_eq!(
+        listing.date,
+        local.listings[platform].as_ref().unwrap().date
+    );
+    assert_eq!(listing.sold, 4);
+}
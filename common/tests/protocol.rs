@@ -0,0 +1,344 @@
+mod common;
+
+use common::{connect, item, spawn_server};
+use inv_common::auth::Role;
+use inv_common::inv::{Id, Item, Photo, Picture, PictureId};
+use inv_common::{
+    picture, read_code, read_str, send_str, BatchOp, BatchResult, CmdCode, ErrCode, Frame,
+    InvEvent, Release, ServerConn, ServerErr, MAX_REQUEST_LEN,
+};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// Logs in by hand, so the test can write whatever bytes it likes afterwards.
+fn login(port: u16) -> TcpStream {
+    let mut tcp = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    tcp.write_all(&Release::CURRENT.as_bytes()).unwrap();
+    send_str(&mut tcp, "tester").unwrap();
+    send_str(&mut tcp, "hunter2").unwrap();
+    assert!(matches!(
+        read_code(&mut tcp).unwrap(),
+        CmdCode::ConnectionSuccessfull
+    ));
+    let mut role_and_release = [0u8; 4];
+    tcp.read_exact(&mut role_and_release).unwrap();
+    tcp
+}
+
+/// Reads the response to `id`, checking it is an `Error` with the code `err`.
+fn expect_error(tcp: &mut TcpStream, id: u32, err: ErrCode) {
+    let frame = Frame::read(tcp).unwrap();
+    assert_eq!(frame.id, id);
+    assert_eq!(frame.code, CmdCode::Error as u8);
+    assert_eq!(frame.payload[0], err as u8);
+}
+
+fn expect_release(tcp: &mut TcpStream, id: u32) {
+    Frame::new(CmdCode::GetRelease, id, vec![])
+        .write(tcp)
+        .unwrap();
+    let frame = Frame::read(tcp).unwrap();
+    assert_eq!(frame.id, id);
+    assert_eq!(frame.code, CmdCode::OperationSuccessfull as u8);
+    assert_eq!(frame.payload, Release::CURRENT.as_bytes());
+}
+
+#[test]
+fn bad_requests_get_errors_and_the_connection_carries_on() {
+    let (_, port) = spawn_server();
+    let mut tcp = login(port);
+
+    // A code no release uses, with a payload that has to be skipped.
+    Frame {
+        code: 200,
+        id: 1,
+        payload: vec![7; 100],
+    }
+    .write(&mut tcp)
+    .unwrap();
+    expect_error(&mut tcp, 1, ErrCode::UnknownCommand);
+    expect_release(&mut tcp, 2);
+
+    // Too short to hold an item id, let alone an item.
+    Frame::new(CmdCode::InsertItem, 3, vec![1, 2])
+        .write(&mut tcp)
+        .unwrap();
+    expect_error(&mut tcp, 3, ErrCode::Malformed);
+    // An id followed by bytes that aren't an item.
+    Frame::new(CmdCode::InsertItem, 4, vec![0, 0, 0, 1, 0xff, 0xff, 0xff])
+        .write(&mut tcp)
+        .unwrap();
+    expect_error(&mut tcp, 4, ErrCode::Malformed);
+    expect_release(&mut tcp, 5);
+
+    // Bigger than the server reads into memory. It is still sent, since the server skips over it.
+    let len = MAX_REQUEST_LEN + 1;
+    let mut header = vec![CmdCode::InsertItem as u8];
+    header.extend(6u32.to_be_bytes());
+    header.extend(len.to_be_bytes());
+    tcp.write_all(&header).unwrap();
+    let chunk = vec![0u8; 1024 * 1024];
+    let mut left = len as usize;
+    while left > 0 {
+        let n = left.min(chunk.len());
+        tcp.write_all(&chunk[..n]).unwrap();
+        left -= n;
+    }
+    expect_error(&mut tcp, 6, ErrCode::TooLarge);
+    expect_release(&mut tcp, 7);
+}
+
+#[test]
+fn error_responses_become_server_errors() {
+    let (_, port) = spawn_server();
+    let mut conn = connect(port);
+
+    // Editors can't make backups, and nothing is in the trash to restore.
+    assert!(matches!(
+        conn.create_backup(),
+        Err(ServerErr::PermissionDenied)
+    ));
+    assert!(matches!(
+        conn.restore_item(Id(1)),
+        Err(ServerErr::OperationFailed(_))
+    ));
+    assert_eq!(conn.get_release().unwrap(), Release::CURRENT);
+}
+
+#[test]
+fn removing_a_missing_item_fails() {
+    let (_, port) = spawn_server();
+    let mut conn = connect(port);
+
+    conn.insert_item(Id(1), &item("lamp")).unwrap();
+    conn.remove_item(Id(1)).unwrap();
+    match conn.remove_item(Id(1)) {
+        Err(ServerErr::OperationFailed(msg)) => assert_eq!(msg, "No item 1"),
+        result => panic!("removed a missing item : {result:?}"),
+    }
+    assert_eq!(conn.get_release().unwrap(), Release::CURRENT);
+}
+
+#[test]
+fn batches_apply_all_or_nothing() {
+    let (_, port) = spawn_server();
+    let mut conn = connect(port);
+    let mut other = connect(port);
+
+    let results = conn
+        .apply_batch(&[
+            BatchOp::Insert(Id(1), item("lamp").into()),
+            BatchOp::Insert(Id(2), item("chair").into()),
+            BatchOp::Remove(Id(3)),
+        ])
+        .unwrap();
+    let [BatchResult::Inserted(lamp_rev), BatchResult::Inserted(_), BatchResult::Missing] =
+        results[..]
+    else {
+        panic!("unexpected results : {results:?}");
+    };
+    let desk_lamp = || {
+        let item = Item {
+            revision: lamp_rev,
+            ..item("desk lamp")
+        };
+        Box::new(item)
+    };
+    for _ in 0..2 {
+        assert!(matches!(
+            other.recv_event().unwrap(),
+            InvEvent::ItemInserted(..)
+        ));
+    }
+
+    // An item can only be changed once per batch.
+    let err = conn
+        .apply_batch(&[BatchOp::Insert(Id(1), desk_lamp()), BatchOp::Remove(Id(1))])
+        .unwrap_err();
+    assert!(matches!(err, ServerErr::MalformedRequest(_)));
+
+    // The chair's edit is based on a revision the server has moved past, so nothing is applied.
+    let results = conn
+        .apply_batch(&[
+            BatchOp::Insert(Id(1), desk_lamp()),
+            BatchOp::Insert(Id(2), item("stool").into()),
+            BatchOp::Remove(Id(1)),
+        ])
+        .unwrap_err();
+    assert!(matches!(results, ServerErr::MalformedRequest(_)));
+    let results = conn
+        .apply_batch(&[
+            BatchOp::Insert(Id(1), desk_lamp()),
+            BatchOp::Insert(Id(2), item("stool").into()),
+        ])
+        .unwrap();
+    assert!(matches!(results[0], BatchResult::NotApplied));
+    let BatchResult::Conflict(current) = &results[1] else {
+        panic!("unexpected results : {results:?}");
+    };
+    assert_eq!(current.name, "chair");
+    let inv = conn.get_inv().unwrap();
+    assert_eq!(inv.items[&Id(1)].name, "lamp");
+
+    let results = conn
+        .apply_batch(&[BatchOp::Insert(Id(1), desk_lamp()), BatchOp::Remove(Id(2))])
+        .unwrap();
+    assert!(matches!(
+        results[..],
+        [BatchResult::Inserted(_), BatchResult::Removed]
+    ));
+    let inv = conn.get_inv().unwrap();
+    assert_eq!(inv.items[&Id(1)].name, "desk lamp");
+    assert!(!inv.items.contains_key(&Id(2)));
+}
+
+#[test]
+fn pictures_are_uploaded_before_the_items_using_them() {
+    let (_, port) = spawn_server();
+    let mut conn = connect(port);
+
+    let pic = Picture {
+        data: vec![200; 4 * 8 * 8],
+        size: [8, 8],
+    };
+    let encoded = picture::encode(&pic).unwrap();
+    let mut lamp: Box<Item> = item("lamp").into();
+    lamp.gallery.push(Photo::new(PictureId::of(&encoded)));
+    assert!(matches!(
+        conn.insert_item(Id(1), &lamp),
+        Err(ServerErr::OperationFailed(_))
+    ));
+    // In a batch only the insert using it is rejected, and nothing is applied.
+    let results = conn
+        .apply_batch(&[
+            BatchOp::Insert(Id(1), lamp.clone()),
+            BatchOp::Insert(Id(2), item("chair").into()),
+        ])
+        .unwrap();
+    assert!(
+        matches!(results[..], [BatchResult::MissingPicture(id), BatchResult::NotApplied] if id == PictureId::of(&encoded))
+    );
+
+    let id = conn.put_picture(&encoded).unwrap();
+    assert_eq!(Some(&id), lamp.gallery.pictures().next());
+    conn.insert_item(Id(1), &lamp).unwrap();
+    assert_eq!(conn.get_picture(&id).unwrap(), encoded);
+
+    // Only pictures the server can decode are stored.
+    assert!(matches!(
+        conn.put_picture(b"not a picture"),
+        Err(ServerErr::OperationFailed(_))
+    ));
+    assert!(matches!(
+        conn.get_picture(&PictureId::of(b"not a picture")),
+        Err(ServerErr::OperationFailed(_))
+    ));
+}
+
+#[test]
+fn thumbnails_are_made_when_pictures_are_stored() {
+    let (_, port) = spawn_server();
+    let mut conn = connect(port);
+
+    let pic = Picture {
+        data: vec![120; 4 * 640 * 480],
+        size: [640, 480],
+    };
+    let id = conn.put_picture(&picture::encode(&pic).unwrap()).unwrap();
+    let thumbnail = picture::decode(&conn.get_thumbnail(&id).unwrap()).unwrap();
+    assert_eq!(
+        thumbnail.size,
+        [picture::THUMBNAIL_SIZE, picture::THUMBNAIL_SIZE * 3 / 4]
+    );
+    assert!(matches!(
+        conn.get_thumbnail(&PictureId::of(b"not a picture")),
+        Err(ServerErr::OperationFailed(_))
+    ));
+}
+
+#[test]
+fn long_credentials_are_refused_unread() {
+    let (_, port) = spawn_server();
+    let mut tcp = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    tcp.write_all(&Release::CURRENT.as_bytes()).unwrap();
+    // A user name of 4 GiB, which never comes.
+    tcp.write_all(&u32::MAX.to_be_bytes()).unwrap();
+    let mut rest = vec![];
+    tcp.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    let mut tcp = login(port);
+    expect_release(&mut tcp, 1);
+}
+
+/// A server that logs a client in by hand, then answers its first request with `response`
+/// and waits for the client to hang up.
+fn spawn_fake_server(response: Vec<u8>) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        let (mut tcp, _) = listener.accept().unwrap();
+        let mut release = [0u8; 3];
+        tcp.read_exact(&mut release).unwrap();
+        read_str(&mut tcp).unwrap();
+        read_str(&mut tcp).unwrap();
+        tcp.write_all(&[CmdCode::ConnectionSuccessfull as u8, Role::Editor as u8])
+            .unwrap();
+        tcp.write_all(&Release::CURRENT.as_bytes()).unwrap();
+        Frame::read(&mut tcp).unwrap();
+        tcp.write_all(&response).unwrap();
+        _ = tcp.read_to_end(&mut vec![]);
+    });
+    port
+}
+
+#[test]
+fn responses_over_the_limit_are_refused_unread() {
+    let mut header = vec![CmdCode::OperationSuccessfull as u8];
+    header.extend(1u32.to_be_bytes());
+    header.extend(u32::MAX.to_be_bytes());
+    let port = spawn_fake_server(header);
+    let mut conn = connect(port);
+
+    match conn.get_release() {
+        Err(ServerErr::OtherIo(err)) => assert_eq!(err.kind(), std::io::ErrorKind::InvalidData),
+        result => panic!("read a response over the limit : {result:?}"),
+    }
+    assert!(conn.is_lost());
+}
+
+#[test]
+fn connections_are_lost_after_a_timeout_mid_frame() {
+    // The header of a response, without its payload.
+    let mut header = vec![CmdCode::OperationSuccessfull as u8];
+    header.extend(1u32.to_be_bytes());
+    header.extend(3u32.to_be_bytes());
+    let port = spawn_fake_server(header);
+    let tcp = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    tcp.set_read_timeout(Some(std::time::Duration::from_millis(200)))
+        .unwrap();
+    let mut conn = ServerConn::connect(tcp, "tester", "hunter2").unwrap();
+
+    assert!(conn.get_release().is_err());
+    assert!(conn.is_lost());
+    // Whatever comes next can't be told apart from the rest of the last frame, so nothing is read.
+    match conn.get_release() {
+        Err(ServerErr::OtherIo(err)) => assert_eq!(err.kind(), std::io::ErrorKind::NotConnected),
+        result => panic!("used a lost connection : {result:?}"),
+    }
+}
+
+#[test]
+fn releases_need_our_protocol_and_layout() {
+    assert!(Release::CURRENT.is_compatible());
+    // Same layout as 0.0.1, but answers differently.
+    assert_eq!(
+        Release(0, 0, 2).data_version(),
+        Release(0, 0, 1).data_version()
+    );
+    assert!(!Release(0, 0, 1).is_compatible());
+    assert!(!Release(0, 0, 2).is_compatible());
+    // A release we don't know might have changed anything.
+    let Release(major, minor, patch) = Release::CURRENT;
+    assert!(!Release(major, minor, patch + 1).is_compatible());
+}
@@ -0,0 +1,226 @@
+use inv_common::backup::parse_utc_timestamp;
+use inv_common::inv::{Id, Inv, Item, Listing, ParseUsdErr, Photo, PictureId, Platform, Usd};
+use inv_common::spreadsheet::{self, export};
+use inv_common::BatchOp;
+
+fn inv_with_items() -> Inv {
+    let mut inv = Inv {
+        platform_names: vec!["Ebay".into(), "Mercari".into()],
+        ..Default::default()
+    };
+    let mut lamp = Item {
+        name: "lamp".into(),
+        desc: "Brass, \"vintage\", with a shade\nand a bulb".into(),
+        count: 2,
+        est_cost: Usd(546),
+        dimensions: [10.0, 40.5, 10.0],
+        model_no: 1234,
+        creation_date: parse_utc_timestamp("20240102-030405").unwrap(),
+        ..Default::default()
+    };
+    lamp.listings[Platform::from_idx(1).unwrap()] = Some(Listing {
+        date: parse_utc_timestamp("20240501-120000").unwrap(),
+        sold: 1,
+    });
+    lamp.gallery.push(Photo::new(PictureId::of(b"front")));
+    lamp.gallery.push(Photo::new(PictureId::of(b"label")));
+    lamp.gallery.set_caption(1, "label");
+    lamp.gallery.set_cover(1);
+    inv.write_item(Id(0x1a), lamp);
+    inv.write_item(
+        Id(0x2b),
+        Item {
+            name: "chair".into(),
+            ..Default::default()
+        },
+    );
+    inv
+}
+
+#[test]
+fn exported_items_import_unchanged() {
+    let inv = inv_with_items();
+    let mut csv = vec![];
+    export(&inv, &mut csv).unwrap();
+    let text = String::from_utf8(csv.clone()).unwrap();
+    assert!(text.starts_with("id,name,description,"));
+    assert!(text.lines().next().unwrap().ends_with(",Ebay,Mercari"));
+    assert!(text.contains(",20240501-120000 1\n"));
+
+    let import = spreadsheet::import(&inv, csv.as_slice()).unwrap();
+    assert!(import.errors.is_empty(), "{:?}", import.errors);
+    assert!(import.ignored_columns.is_empty());
+    assert!(import.items.is_empty());
+    assert_eq!(import.unchanged, 2);
+
+    // Into an empty inv, every item is new and comes out the same.
+    let empty = Inv {
+        platform_names: inv.platform_names.clone(),
+        ..Default::default()
+    };
+    let import = spreadsheet::import(&empty, csv.as_slice()).unwrap();
+    assert_eq!(import.new_count(), 2);
+    let ops = import.into_batch().unwrap();
+    let Some(BatchOp::Insert(_, lamp)) = ops.iter().find(|op| op.id() == Id(0x1a)) else {
+        panic!("lamp missing from {ops:?}");
+    };
+    let original = &inv.items[&Id(0x1a)];
+    assert_eq!(lamp.desc, original.desc);
+    assert_eq!(lamp.est_cost.0, 546);
+    assert_eq!(lamp.gallery, original.gallery);
+    assert_eq!(lamp.listings, original.listings);
+    assert_eq!(lamp.creation_date, original.creation_date);
+    assert_eq!(lamp.revision, 0);
+}
+
+#[test]
+fn columns_are_matched_by_header() {
+    let inv = inv_with_items();
+    let csv = "\
+Price,COST,Name,ID,ebay,Count
+9.99,3.50,desk,,20240601,4
+1.00,6.00,,1a,,
+";
+    let import = spreadsheet::import(&inv, csv.as_bytes()).unwrap();
+    assert!(import.errors.is_empty(), "{:?}", import.errors);
+    assert_eq!(import.ignored_columns, ["Price"]);
+    assert_eq!(import.new_count(), 1);
+    assert_eq!(import.changed_count(), 1);
+
+    let desk = import.items.iter().find(|i| i.changed.is_none()).unwrap();
+    assert_eq!(desk.row, 2);
+    assert_eq!(desk.item.name, "desk");
+    assert_eq!(desk.item.est_cost.0, 350);
+    let ebay = desk.item.listings[Platform::from_idx(0).unwrap()].unwrap();
+    assert_eq!(ebay.date, parse_utc_timestamp("20240601").unwrap());
+    assert_eq!(ebay.sold, 0);
+
+    // Fields without a column, and number fields left empty, keep what the lamp had.
+    let lamp = import.items.iter().find(|i| i.id == Id(0x1a)).unwrap();
+    assert_eq!(lamp.row, 3);
+    assert_eq!(lamp.changed.as_deref(), Some(&["name", "cost"][..]));
+    assert_eq!(lamp.item.name, "");
+    assert_eq!(lamp.item.count, 2);
+    assert_eq!(lamp.item.desc, inv.items[&Id(0x1a)].desc);
+    assert_eq!(lamp.item.revision, inv.items[&Id(0x1a)].revision);
+}
+
+#[test]
+fn cells_pasted_from_a_spreadsheet_are_split_on_tabs() {
+    let csv = "name\tcost\tlocation\nlamp, brass\t5.46\tshelf 2\n";
+    let import = spreadsheet::import(&Inv::default(), csv.as_bytes()).unwrap();
+    assert!(import.errors.is_empty(), "{:?}", import.errors);
+    let lamp = &import.items[0].item;
+    assert_eq!(lamp.name, "lamp, brass");
+    assert_eq!(lamp.est_cost.0, 546);
+    assert_eq!(lamp.location, "shelf 2");
+}
+
+#[test]
+fn bad_cells_are_reported_by_row() {
+    let inv = inv_with_items();
+    let csv = "\
+id,name,count,weight,Mercari,photos
+,fine,1,2.5,,
+,bad count,many,2.5,,
+,bad weight and listing,1,heavy,yesterday,
+zz,bad id,1,1,,
+1a,first,1,1,,
+1a,twice,1,1,,
+,bad photo,1,1,,*not-a-picture
+";
+    let import = spreadsheet::import(&inv, csv.as_bytes()).unwrap();
+    let errors: Vec<_> = (import.errors.iter())
+        .map(|err| (err.row, err.column.as_str()))
+        .collect();
+    assert_eq!(
+        errors,
+        [
+            (3, "count"),
+            (4, "weight"),
+            (4, "Mercari"),
+            (5, "id"),
+            (7, "id"),
+            (8, "photos")
+        ]
+    );
+    assert_eq!(
+        import.errors[0].to_string(),
+        "row 3 : invalid count \"many\""
+    );
+    assert_eq!(import.new_count(), 1);
+    assert_eq!(import.changed_count(), 1);
+    assert_eq!(import.into_batch().unwrap_err().len(), 6);
+}
+
+#[test]
+fn costs_are_read_to_the_cent() {
+    let cents = |s: &str| s.parse::<Usd>().map(|usd| usd.0);
+    assert_eq!(cents("5.46"), Ok(546));
+    assert_eq!(cents("5.4"), Ok(540));
+    assert_eq!(cents("5"), Ok(500));
+    assert_eq!(cents(".5"), Ok(50));
+    assert_eq!(cents("0.290"), Ok(29));
+    assert_eq!(cents("42949672.95"), Ok(u32::MAX));
+    assert_eq!(cents("42949672.96"), Err(ParseUsdErr::TooLarge));
+    assert_eq!(cents("99999999999"), Err(ParseUsdErr::TooLarge));
+    assert_eq!(cents("-1"), Err(ParseUsdErr::Negative));
+    assert_eq!(cents("1.005"), Err(ParseUsdErr::FractionOfCent));
+    for invalid in ["", ".", "inf", "NaN", "1e3", "+1", "1.2.3", "$5"] {
+        assert_eq!(cents(invalid), Err(ParseUsdErr::Invalid), "{invalid:?}");
+    }
+
+    let csv = "name,cost\nfine,19.99\nnegative,-1\ninfinite,inf\nhuge,1e10\nprecise,0.001\n";
+    let import = spreadsheet::import(&Inv::default(), csv.as_bytes()).unwrap();
+    let rows: Vec<_> = import.errors.iter().map(|err| err.row).collect();
+    assert_eq!(rows, [3, 4, 5, 6]);
+    assert_eq!(import.items.len(), 1);
+    assert_eq!(import.items[0].item.est_cost.0, 1999);
+}
+
+#[test]
+fn formulas_are_exported_as_text() {
+    let mut inv = Inv::default();
+    let names = ["=1+1", "+cmd", "-2", "@SUM(A1)", "'=quoted", "don't", "'"];
+    for (idx, name) in names.into_iter().enumerate() {
+        let item = Item {
+            name: name.into(),
+            weight: -1.5,
+            ..Default::default()
+        };
+        inv.write_item(Id(idx as u32), item);
+    }
+    let mut csv = vec![];
+    export(&inv, &mut csv).unwrap();
+    let text = String::from_utf8(csv.clone()).unwrap();
+    for guarded in ["'=1+1", "'+cmd", "'-2", "'@SUM(A1)", "''=quoted", "'-1.5"] {
+        assert!(
+            text.contains(&format!(",{guarded},")),
+            "{guarded} in {text}"
+        );
+    }
+    assert!(text.contains(",don't,"));
+
+    let import = spreadsheet::import(&inv, csv.as_slice()).unwrap();
+    assert!(import.errors.is_empty(), "{:?}", import.errors);
+    assert!(import.items.is_empty());
+    assert_eq!(import.unchanged, names.len());
+}
+
+#[test]
+fn empty_platform_cells_keep_listings() {
+    let inv = inv_with_items();
+    let csv = "id,mercari,ebay\n1a,,20240601 2\n";
+    let import = spreadsheet::import(&inv, csv.as_bytes()).unwrap();
+    assert!(import.errors.is_empty(), "{:?}", import.errors);
+    let lamp = &import.items[0].item;
+    let mercari = Platform::from_idx(1).unwrap();
+    assert_eq!(
+        lamp.listings[mercari],
+        inv.items[&Id(0x1a)].listings[mercari]
+    );
+    assert_eq!(
+        lamp.listings[Platform::from_idx(0).unwrap()].unwrap().sold,
+        2
+    );
+}
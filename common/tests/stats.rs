@@ -0,0 +1,70 @@
+use inv_common::inv::{InvStats, Item, Listing, Listings, Usd};
+use std::time::SystemTime;
+
+#[test]
+fn huge_totals_dont_overflow() {
+    let mut listings = Listings::default();
+    let listing = Listing {
+        date: SystemTime::UNIX_EPOCH,
+        sold: u32::MAX,
+    };
+    listings.0[0] = Some(listing);
+    listings.0[1] = Some(listing);
+    let item = Item {
+        count: u32::MAX,
+        est_cost: Usd(u32::MAX),
+        listings,
+        ..Default::default()
+    };
+    let unsold = Item {
+        count: u32::MAX,
+        est_cost: Usd(u32::MAX),
+        ..Default::default()
+    };
+    let stats = InvStats::of([&item, &unsold, &unsold]);
+    let max = u32::MAX as u64;
+    assert_eq!(item.sold_count(), u32::MAX);
+    assert_eq!(stats.sold_count, max);
+    assert_eq!(stats.unsold_count, 2 * max);
+    assert_eq!(stats.total_count(), 3 * max);
+    assert_eq!(stats.sold_cost.0, max * max);
+    // Past what even 64 bits hold, costs stop at the largest total.
+    assert_eq!(stats.unsold_cost.0, u64::MAX);
+    assert_eq!(stats.total_cost().0, u64::MAX);
+    assert_eq!(
+        InvStats::of([&item]).total_cost().to_string(),
+        "184467440651196170.25"
+    );
+}
+
+#[test]
+fn totals_past_32_bits_are_exact() {
+    let mut listings = Listings::default();
+    listings.0[3] = Some(Listing {
+        date: SystemTime::UNIX_EPOCH,
+        sold: 1_000,
+    });
+    let item = Item {
+        count: 50_000,
+        est_cost: Usd(99_999),
+        listings,
+        ..Default::default()
+    };
+    let items = vec![item; 100_000];
+    let stats = InvStats::of(&items);
+    assert_eq!(stats.listings, 100_000);
+    assert_eq!(stats.sold_count, 100_000_000);
+    assert_eq!(stats.unsold_count, 4_900_000_000);
+    assert_eq!(stats.total_count(), 5_000_000_000);
+    assert_eq!(stats.sold_cost.0, 9_999_900_000_000);
+    assert_eq!(stats.unsold_cost.0, 489_995_100_000_000);
+    assert_eq!(stats.total_cost().to_string(), "4999950000000.00");
+}
+
+#[test]
+fn cents_always_have_two_digits() {
+    assert_eq!(Usd(1205).to_string(), "12.05");
+    assert_eq!(Usd(1250).to_string(), "12.50");
+    assert_eq!(Usd(7).to_string(), "0.07");
+    assert_eq!(Usd(0).to_string(), "0.00");
+}
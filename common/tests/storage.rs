@@ -0,0 +1,387 @@
+mod common;
+
+use common::test_path;
+use inv_common::inv::{Id, Inv, Item, Picture, PictureId};
+#[cfg(feature = "sqlite")]
+use inv_common::storage::{self, SqliteStorage};
+use inv_common::storage::{FileStorage, Storage};
+use inv_common::{picture, save, ServerHost};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+fn cover(item: &Item) -> Option<PictureId> {
+    item.gallery.cover().map(|photo| photo.picture)
+}
+
+fn encoded_picture(shade: u8) -> Vec<u8> {
+    let pic = Picture {
+        data: vec![shade; 4 * 3 * 2],
+        size: [3, 2],
+    };
+    picture::encode(&pic).unwrap()
+}
+
+fn inv_with_items(storage: &dyn Storage) -> Inv {
+    let mut inv = Inv {
+        platform_names: vec!["Ebay".into(), "Mercari".into()],
+        ..Default::default()
+    };
+    for (id, name) in [(1, "lamp"), (2, "chair"), (3, "desk")] {
+        let picture = (id != 3).then(|| {
+            let encoded = encoded_picture(id as u8 * 10);
+            storage.store_picture(&encoded).unwrap()
+        });
+        let item = Item {
+            name: name.into(),
+            gallery: picture.into(),
+            ..Default::default()
+        };
+        inv.write_item(Id(id), item);
+    }
+    inv.trash_item(Id(2), "tester");
+    inv
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn pictures_are_stored_by_id() {
+    let path = test_path("pictures.db");
+    let storage = SqliteStorage::open(&path).unwrap();
+    let encoded = encoded_picture(10);
+    let id = storage.store_picture(&encoded).unwrap();
+    assert_eq!(id, PictureId::of(&encoded));
+    // Storing the same picture again changes nothing.
+    assert_eq!(storage.store_picture(&encoded).unwrap(), id);
+    storage
+        .save(&RwLock::new(inv_with_items(&storage)))
+        .unwrap();
+
+    let storage = SqliteStorage::open(&path).unwrap();
+    let inv = storage.load().unwrap();
+    assert_eq!(inv.platform_names, ["Ebay", "Mercari"]);
+    assert_eq!(inv.items.len(), 2);
+    assert_eq!(inv.items[&Id(1)].name, "lamp");
+    assert_eq!(cover(&inv.items[&Id(1)]), Some(id));
+    assert!(inv.items[&Id(3)].gallery.is_empty());
+    assert!(inv.removed.contains_key(&Id(2)));
+    assert!(cover(&inv.trash[&Id(2)].item).is_some());
+
+    assert_eq!(storage.load_picture(&id).unwrap(), Some(encoded));
+    let decoded = picture::decode(&storage.load_picture(&id).unwrap().unwrap()).unwrap();
+    assert_eq!(decoded.size, [3, 2]);
+    let missing = PictureId::of(b"not stored");
+    assert!(!storage.has_picture(&missing).unwrap());
+    assert_eq!(storage.load_picture(&missing).unwrap(), None);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn saves_write_changed_items() {
+    let path = test_path("incremental.db");
+    let storage = SqliteStorage::open(&path).unwrap();
+    storage
+        .save(&RwLock::new(inv_with_items(&storage)))
+        .unwrap();
+
+    let storage = SqliteStorage::open(&path).unwrap();
+    let inv = storage.load().unwrap();
+    let mut server = ServerHost::new(inv);
+    server.storage = Some(Box::new(storage));
+
+    let new_picture = server.store_picture(&encoded_picture(90)).unwrap();
+    let mut lamp = server.inv.read().unwrap().items[&Id(1)].copy();
+    lamp.name = "desk lamp".into();
+    lamp.gallery.push(inv_common::inv::Photo::new(new_picture));
+    lamp.gallery.set_cover(1);
+    server
+        .insert_item(Id(1), lamp, "tester", None, drop)
+        .unwrap();
+    // The chair comes back from the trash, and the desk goes in.
+    server.restore_trashed(Id(2), "tester", None).unwrap();
+    assert!(server.remove_item(Id(3), "tester", None, || {}));
+    server.save().unwrap();
+
+    let storage = SqliteStorage::open(&path).unwrap();
+    let inv = storage.load().unwrap();
+    assert_eq!(inv.items[&Id(1)].name, "desk lamp");
+    assert_eq!(cover(&inv.items[&Id(1)]), Some(new_picture));
+    assert_eq!(inv.items[&Id(1)].gallery.len(), 2);
+    assert!(storage.has_picture(&new_picture).unwrap());
+    assert!(cover(&inv.items[&Id(2)]).is_some());
+    assert!(!inv.items.contains_key(&Id(3)));
+    assert!(inv.trash.contains_key(&Id(3)));
+    assert!(!inv.trash.contains_key(&Id(2)));
+    assert_eq!(inv.revision, server.inv.read().unwrap().revision);
+}
+
+#[test]
+fn file_storage_keeps_pictures_beside_the_save_file() {
+    let path = test_path("inv.data");
+    let storage = FileStorage::new(&path);
+    let inv = inv_with_items(&storage);
+    let id = cover(&inv.items[&Id(1)]).unwrap();
+    storage.save(&RwLock::new(inv)).unwrap();
+
+    let storage = FileStorage::new(&path);
+    let inv = storage.load().unwrap();
+    assert_eq!(cover(&inv.items[&Id(1)]), Some(id));
+    assert_eq!(
+        storage.load_picture(&id).unwrap(),
+        Some(encoded_picture(10))
+    );
+    let pictures_dir = save::with_suffix(&path, ".pictures");
+    assert!(pictures_dir.join(id.to_string()).exists());
+    // Only the id is in the save file.
+    let decoded = save::decode(&std::fs::read(&path).unwrap()).unwrap();
+    assert!(decoded.pictures.is_empty());
+}
+
+#[test]
+fn pictures_are_only_read_when_asked_for() {
+    let path = test_path("on-demand.data");
+    let storage = FileStorage::new(&path);
+    let inv = inv_with_items(&storage);
+    let id = cover(&inv.items[&Id(1)]).unwrap();
+    storage.save(&RwLock::new(inv)).unwrap();
+
+    let mut server = ServerHost::new(storage.load().unwrap());
+    server.storage = Some(Box::new(storage));
+    assert_eq!(server.load_picture(&id).unwrap(), Some(encoded_picture(10)));
+    // Nothing was kept in memory, so a picture gone from the storage is gone for the server too.
+    std::fs::remove_dir_all(save::with_suffix(&path, ".pictures")).unwrap();
+    assert_eq!(server.load_picture(&id).unwrap(), None);
+    assert_eq!(cover(&server.inv.read().unwrap().items[&Id(1)]), Some(id));
+}
+
+/// An item as saved before it had a gallery, with a picture of type `P` : the picture itself in
+/// data version 4, and a `PictureId` in version 5.
+#[derive(Serialize)]
+struct OldItem<P> {
+    creation_date: SystemTime,
+    revision: u64,
+    location: String,
+    listings: inv_common::inv::Listings,
+    picture: Option<P>,
+    name: String,
+    desc: String,
+    count: u32,
+    est_cost: inv_common::inv::Usd,
+    condition: String,
+    color: String,
+    dimensions: [f32; 3],
+    weight: f32,
+    shipping_weight: f32,
+    model_no: u64,
+    serial_no: u64,
+    brand: String,
+}
+
+#[derive(Serialize)]
+struct InvV4 {
+    platform_names: Vec<String>,
+    revision: u64,
+    items: HashMap<Id, OldItem<Picture>>,
+    removed: HashMap<Id, u64>,
+    trash: HashMap<Id, ()>,
+}
+
+#[cfg(feature = "sqlite")]
+#[derive(Serialize)]
+struct TrashedV5 {
+    item: OldItem<PictureId>,
+    deleted_at: SystemTime,
+    deleted_by: String,
+}
+
+fn old_item<P>(name: &str, picture: Option<P>) -> OldItem<P> {
+    OldItem {
+        creation_date: SystemTime::now(),
+        revision: 1,
+        location: String::new(),
+        listings: Default::default(),
+        picture,
+        name: name.into(),
+        desc: String::new(),
+        count: 1,
+        est_cost: inv_common::inv::Usd(0),
+        condition: String::new(),
+        color: String::new(),
+        dimensions: [0.0; 3],
+        weight: 0.0,
+        shipping_weight: 0.0,
+        model_no: 0,
+        serial_no: 0,
+        brand: String::new(),
+    }
+}
+
+#[test]
+fn pictures_move_out_of_old_save_files() {
+    let item = |picture| old_item("lamp", picture);
+    let pic = Picture {
+        data: vec![255; 4 * 16 * 8],
+        size: [16, 8],
+    };
+    let old = InvV4 {
+        platform_names: vec![],
+        revision: 2,
+        items: HashMap::from([(Id(1), item(Some(pic))), (Id(2), item(None))]),
+        removed: HashMap::new(),
+        trash: HashMap::new(),
+    };
+    let mut bytes = save::MAGIC.to_vec();
+    bytes.push(4);
+    bincode::serialize_into(&mut bytes, &old).unwrap();
+
+    let path = test_path("old.data");
+    std::fs::write(&path, &bytes).unwrap();
+    let storage = FileStorage::new(&path);
+    let inv = storage.load().unwrap();
+    let id = cover(&inv.items[&Id(1)]).unwrap();
+    assert!(inv.items[&Id(2)].gallery.is_empty());
+    let stored = storage.load_picture(&id).unwrap().unwrap();
+    assert_eq!(picture::Format::of(&stored), Some(picture::Format::Jpeg));
+    assert_eq!(picture::decode(&stored).unwrap().size, [16, 8]);
+
+    // Moved pictures get their thumbnail the first time it is asked for.
+    assert_eq!(storage.load_thumbnail(&id).unwrap(), None);
+    let mut server = ServerHost::new(inv);
+    server.storage = Some(Box::new(storage));
+    let thumbnail = server.load_thumbnail(&id).unwrap().unwrap();
+    assert_eq!(picture::decode(&thumbnail).unwrap().size, [16, 8]);
+    let storage = server.storage.as_ref().unwrap();
+    assert_eq!(storage.load_thumbnail(&id).unwrap(), Some(thumbnail));
+}
+
+#[test]
+fn thumbnails_fit_in_thumbnail_size() {
+    let pic = Picture {
+        data: (0..4 * 1000 * 10).map(|i| (i % 256) as u8).collect(),
+        size: [1000, 10],
+    };
+    let thumbnail = picture::thumbnail(&pic);
+    assert_eq!(thumbnail.size, [picture::THUMBNAIL_SIZE, 1]);
+    assert_eq!(thumbnail.data.len(), picture::THUMBNAIL_SIZE as usize * 4);
+    let small = Picture {
+        data: vec![7; 4 * 3 * 2],
+        size: [3, 2],
+    };
+    assert_eq!(picture::thumbnail(&small).size, [3, 2]);
+}
+
+#[test]
+fn pngs_keep_every_pixel() {
+    let pic = Picture {
+        data: (0..4 * 5 * 3).map(|i| (i * 7 % 256) as u8).collect(),
+        size: [5, 3],
+    };
+    let png = picture::encode_png(&pic).unwrap();
+    assert_eq!(picture::Format::of(&png), Some(picture::Format::Png));
+    assert_eq!(picture::decode(&png).unwrap(), pic);
+}
+
+/// The CRC at the end of a PNG chunk, over its type and data.
+fn png_crc(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[test]
+fn huge_pictures_are_refused_before_decoding() {
+    let pic = Picture {
+        data: vec![128; 4 * 4 * 4],
+        size: [4, 4],
+    };
+    // A JPEG whose frame header claims 65535x65535.
+    let mut jpeg = picture::encode(&pic).unwrap();
+    let sof = (jpeg.windows(2)).position(|m| m == [0xff, 0xc0]).unwrap();
+    jpeg[sof + 5..sof + 9].copy_from_slice(&[0xff; 4]);
+    // A PNG whose header claims the same, with its CRC fixed up to match.
+    let mut png = picture::encode_png(&pic).unwrap();
+    png[16..24].copy_from_slice(&[0, 0, 0xff, 0xff, 0, 0, 0xff, 0xff]);
+    let crc = png_crc(&png[12..29]);
+    png[29..33].copy_from_slice(&crc.to_be_bytes());
+
+    let server = ServerHost::new(Inv::default());
+    for encoded in [jpeg, png] {
+        let err = picture::decode(&encoded).unwrap_err();
+        assert!(matches!(err, picture::DecodeErr::TooLarge([65535, 65535])));
+        assert!(server.store_picture(&encoded).is_err());
+    }
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn pictures_become_galleries_in_old_databases() {
+    let path = test_path("v5.db");
+    let storage = SqliteStorage::open(&path).unwrap();
+    let id = storage.store_picture(&encoded_picture(40)).unwrap();
+    let mut inv = Inv::default();
+    inv.write_item(Id(1), Item::default());
+    inv.write_item(Id(3), Item::default());
+    storage.save(&RwLock::new(inv)).unwrap();
+    drop(storage);
+
+    // Put the rows back the way data version 5 wrote them.
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    for (item_id, item) in [(1, old_item("lamp", Some(id))), (3, old_item("desk", None))] {
+        conn.execute(
+            "UPDATE items SET item = ?2 WHERE id = ?1",
+            rusqlite::params![item_id, bincode::serialize(&item).unwrap()],
+        )
+        .unwrap();
+    }
+    let trashed = TrashedV5 {
+        item: old_item("chair", Some(id)),
+        deleted_at: SystemTime::now(),
+        deleted_by: "tester".into(),
+    };
+    conn.execute(
+        "INSERT INTO trash (id, trashed) VALUES (2, ?1)",
+        [bincode::serialize(&trashed).unwrap()],
+    )
+    .unwrap();
+    conn.execute("UPDATE meta SET value = 5 WHERE key = 'data_version'", [])
+        .unwrap();
+    drop(conn);
+
+    let storage = SqliteStorage::open(&path).unwrap();
+    let inv = storage.load().unwrap();
+    assert_eq!(inv.items[&Id(1)].name, "lamp");
+    assert_eq!(cover(&inv.items[&Id(1)]), Some(id));
+    assert_eq!(inv.items[&Id(1)].gallery.len(), 1);
+    assert!(inv.items[&Id(3)].gallery.is_empty());
+    assert_eq!(inv.trash[&Id(2)].item.name, "chair");
+    assert_eq!(cover(&inv.trash[&Id(2)].item), Some(id));
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn migrating_copies_every_picture() {
+    let from = FileStorage::new(test_path("migrate-from.data"));
+    let inv = inv_with_items(&from);
+    let lamp = cover(&inv.items[&Id(1)]).unwrap();
+    let chair = cover(&inv.trash[&Id(2)].item).unwrap();
+    from.store_thumbnail(&lamp, &encoded_picture(11)).unwrap();
+    from.save(&RwLock::new(inv)).unwrap();
+
+    let to = SqliteStorage::open(test_path("migrate-to.db")).unwrap();
+    storage::migrate(&from, &to).unwrap();
+    let inv = to.load().unwrap();
+    assert_eq!(inv.items[&Id(1)].name, "lamp");
+    assert_eq!(inv.trash[&Id(2)].item.name, "chair");
+    assert_eq!(to.load_picture(&lamp).unwrap(), Some(encoded_picture(10)));
+    assert_eq!(to.load_picture(&chair).unwrap(), Some(encoded_picture(20)));
+    assert_eq!(to.load_thumbnail(&lamp).unwrap(), Some(encoded_picture(11)));
+
+    // The inv is only ever copied into empty storage.
+    assert!(storage::migrate(&from, &to).is_err());
+}
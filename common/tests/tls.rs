@@ -0,0 +1,58 @@
+mod common;
+
+use common::{item, spawn_tls_server};
+use inv_common::inv::Id;
+use inv_common::tls::{self, ServerTls, Stream};
+use inv_common::{Release, ServerConn};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+fn self_signed() -> ServerTls {
+    let generated = tls::generate_self_signed(vec!["localhost".into()]).unwrap();
+    ServerTls::new(generated.cert_pem.as_bytes(), generated.key_pem.as_bytes()).unwrap()
+}
+
+fn connect_tcp(port: u16) -> TcpStream {
+    TcpStream::connect(("127.0.0.1", port)).unwrap()
+}
+
+#[test]
+fn pinned_self_signed_cert() {
+    let server_tls = Arc::new(self_signed());
+    let (_, port) = spawn_tls_server(server_tls.clone());
+
+    let stream = tls::connect(connect_tcp(port), &server_tls.fingerprint).unwrap();
+    assert!(stream.is_encrypted());
+    let mut conn = ServerConn::connect(stream, "tester", "hunter2").unwrap();
+    assert_eq!(conn.get_release().unwrap(), Release::CURRENT);
+
+    conn.insert_item(Id(7), &item("Lamp")).unwrap();
+    let delta = conn.get_changes(0).unwrap();
+    assert_eq!(delta.changed.len(), 1);
+    assert_eq!(delta.changed[0].1.name, "Lamp");
+}
+
+#[test]
+fn rejects_other_cert() {
+    let server_tls = Arc::new(self_signed());
+    let (_, port) = spawn_tls_server(server_tls);
+
+    let other = self_signed();
+    assert!(tls::connect(connect_tcp(port), &other.fingerprint).is_err());
+}
+
+#[test]
+fn fingerprint_ignores_formatting() {
+    let server_tls = Arc::new(self_signed());
+    let (_, port) = spawn_tls_server(server_tls.clone());
+
+    let pinned: Vec<String> = server_tls
+        .fingerprint
+        .to_uppercase()
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| String::from_utf8(pair.to_vec()).unwrap())
+        .collect();
+    let stream = tls::connect(connect_tcp(port), &pinned.join(":")).unwrap();
+    assert!(matches!(stream, Stream::Client(_)));
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use common::item;
+use inv_common::inv::{Id, Inv};
+use inv_common::ServerHost;
+use std::time::Duration;
+
+#[test]
+fn clients_behind_pruned_removals_get_everything() {
+    let mut inv = Inv::default();
+    for id in 1..=4 {
+        inv.write_item(Id(id), item("lamp"));
+    }
+    inv.remove_item(Id(1));
+    inv.remove_item(Id(2));
+    let after_two = inv.revision;
+    inv.remove_item(Id(3));
+
+    let delta = inv.changes_since(after_two);
+    assert!(!delta.full);
+    assert_eq!(delta.removed, [Id(3)]);
+
+    inv.prune_removed(after_two + 1);
+    assert_eq!(inv.removed.len(), 1);
+    assert!(!inv.changes_since(after_two).full);
+    assert!(inv.changes_since(after_two - 1).full);
+    assert_eq!(inv.changes_since(after_two - 1).changed.len(), 1);
+
+    // The last removal is kept, so clients that are up to date still get deltas.
+    inv.prune_removed(u64::MAX);
+    assert_eq!(inv.removed.keys().collect::<Vec<_>>(), [&Id(3)]);
+    let delta = inv.changes_since(inv.revision);
+    assert!(!delta.full);
+    assert!(delta.changed.is_empty());
+}
+
+#[test]
+fn purging_the_trash_prunes_removals() {
+    let mut server = ServerHost::new(Inv::default());
+    server.trash_retention = Duration::ZERO;
+    for id in 1..=3 {
+        server
+            .insert_item(Id(id), item("lamp"), "tester", None, |_| {})
+            .unwrap();
+    }
+    let before = server.inv.read().unwrap().revision;
+    assert!(server.remove_item(Id(1), "tester", None, || {}));
+    assert!(server.remove_item(Id(2), "tester", None, || {}));
+
+    server.purge_expired_trash();
+    let inv = server.inv.read().unwrap();
+    assert!(inv.trash.is_empty());
+    assert_eq!(inv.removed.keys().collect::<Vec<_>>(), [&Id(2)]);
+    assert!(inv.changes_since(before).full);
+    assert!(!inv.changes_since(inv.revision).full);
+}
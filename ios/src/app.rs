@@ -79,9 +79,14 @@ impl App {
             match change {
                 InvChange::AddedItem(id) | InvChange::ModifiedItem(id) => {
                     let item = self.inv.get_item(&id).unwrap();
-                    server.insert_item(id, item)?
+                    let revision = server
+                        .insert_item(id, item)
+                        .map_err(|err| std::io::Error::other(err.to_string()))?;
+                    self.inv.get_item_mut(&id).unwrap().revision = revision;
                 }
-                InvChange::DeletedItem(id) => server.remove_item(id)?,
+                InvChange::DeletedItem(id) => server
+                    .remove_item(id)
+                    .map_err(|err| std::io::Error::other(err.to_string()))?,
             };
         }
 
@@ -62,11 +62,20 @@ impl LocalInv {
         self.inv.items.len()
     }
 
-    pub fn insert_item(&mut self, id: Id, item: Item) {
-        if !self.inv.items.contains_key(&id) {
-            self.added_items.insert(id);
-        } else if !self.added_items.contains(&id) {
-            self.modified_items.insert(id);
+    pub fn get_item_mut(&mut self, id: &Id) -> Option<&mut Item> {
+        self.inv.items.get_mut(id)
+    }
+
+    pub fn insert_item(&mut self, id: Id, mut item: Item) {
+        match self.inv.items.get(&id) {
+            None => _ = self.added_items.insert(id),
+            Some(old) => {
+                // The edit is based on the revision we had, whatever the new item says.
+                item.revision = old.revision;
+                if !self.added_items.contains(&id) {
+                    self.modified_items.insert(id);
+                }
+            }
         }
         self.inv.items.insert(id, item);
     }
@@ -0,0 +1,296 @@
+//! Command line flags and the optional config file, merged into the settings the server runs with.
+
+use clap::{Parser, ValueEnum};
+use inv_common::{save, Autosave};
+use log::LevelFilter;
+use serde_derive::Deserialize;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The port the apps try by default.
+const DEFAULT_PORT: u16 = 25552;
+
+/// How the inv is kept in the data dir.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageKind {
+    /// One file, inv.data, rewritten whole on every save
+    #[default]
+    File,
+    /// An SQLite database, inv.db, that only writes what changed
+    Sqlite,
+}
+
+/// Server for the inventory apps.
+///
+/// Every option can also be set in a TOML config file, see `inv-server.example.toml`.
+/// Options given on the command line take precedence over the file.
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Args {
+    /// TOML file to read options from
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on [default: ::]
+    #[arg(long, value_name = "ADDR")]
+    pub bind: Option<IpAddr>,
+    /// Port to listen on [default: 25552]
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// Also serve the HTTP API on this port [default: off]
+    #[arg(long, value_name = "PORT")]
+    pub http_port: Option<u16>,
+    /// Directory holding the inv, accounts and audit log [default: .]
+    #[arg(short, long, value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
+    /// Save file of the `file` storage. The accounts and audit log are kept next to it, whichever
+    /// storage is used [default: <DATA_DIR>/inv.data]
+    #[arg(long, value_name = "FILE")]
+    pub save_path: Option<PathBuf>,
+    /// How the inv is stored [default: file]
+    #[arg(long, value_enum)]
+    pub storage: Option<StorageKind>,
+    /// Copy the inv from an old save file into the storage, then exit
+    #[arg(long, value_name = "FILE")]
+    pub migrate_from: Option<PathBuf>,
+    /// Save at least this often while there are unsaved changes [default: 60]
+    #[arg(long, value_name = "SECS")]
+    pub autosave_interval: Option<u64>,
+    /// Save as soon as this many changes have been made [default: 20]
+    #[arg(long, value_name = "N")]
+    pub autosave_changes: Option<u32>,
+    /// Where backups are kept [default: <SAVE_PATH>.backups if an older server left backups
+    /// there, otherwise <DATA_DIR>/backups]
+    #[arg(long, value_name = "DIR")]
+    pub backup_dir: Option<PathBuf>,
+    /// How many backups to keep before deleting the oldest [default: 10]
+    #[arg(long, value_name = "N")]
+    pub backups_keep: Option<usize>,
+    /// How long removed items stay in the trash [default: 30]
+    #[arg(long, value_name = "DAYS")]
+    pub trash_retention_days: Option<u64>,
+    /// TLS certificate (PEM). If neither it nor the key exist, a self-signed one is generated
+    #[arg(long, value_name = "FILE")]
+    pub tls_cert: Option<PathBuf>,
+    /// TLS private key (PEM)
+    #[arg(long, value_name = "FILE")]
+    pub tls_key: Option<PathBuf>,
+    /// Most clients that can be connected at once [default: no limit]
+    #[arg(long, value_name = "N")]
+    pub max_clients: Option<usize>,
+    /// One of off, error, warn, info, debug or trace [default: info]
+    #[arg(long, value_name = "LEVEL", value_parser = parse_log_level)]
+    pub log_level: Option<LevelFilter>,
+}
+
+fn parse_log_level(s: &str) -> Result<LevelFilter, String> {
+    s.parse().map_err(|_| {
+        format!("unknown log level {s:?}, expected off, error, warn, info, debug or trace")
+    })
+}
+
+/// Layout of the config file. Everything is optional.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct FileConfig {
+    bind: Option<IpAddr>,
+    port: Option<u16>,
+    data_dir: Option<PathBuf>,
+    save_path: Option<PathBuf>,
+    storage: Option<StorageKind>,
+    max_clients: Option<usize>,
+    log_level: Option<String>,
+    trash_retention_days: Option<u64>,
+    autosave: FileAutosave,
+    backups: FileBackups,
+    tls: FileTls,
+    http: FileHttp,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct FileAutosave {
+    interval: Option<u64>,
+    changes: Option<u32>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct FileBackups {
+    dir: Option<PathBuf>,
+    keep: Option<usize>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct FileTls {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct FileHttp {
+    port: Option<u16>,
+}
+
+impl FileConfig {
+    fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("failed to read config file {path:?} : {err}"))?;
+        let mut file: Self =
+            toml::from_str(&text).map_err(|err| format!("invalid config file {path:?} : {err}"))?;
+
+        // Relative paths in the file are relative to the file, not to wherever the server is started.
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for path in [
+            &mut file.data_dir,
+            &mut file.save_path,
+            &mut file.backups.dir,
+            &mut file.tls.cert,
+            &mut file.tls.key,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = dir.join(&*path);
+        }
+        Ok(file)
+    }
+}
+
+/// Everything the server needs to start, after defaults are filled in.
+#[derive(Debug)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Port for the HTTP API, if it is enabled.
+    pub http_port: Option<u16>,
+    pub data_dir: PathBuf,
+    /// Where the `file` storage keeps the inv. The accounts and audit log sit next to it,
+    /// whichever storage is used.
+    pub save_path: PathBuf,
+    pub storage: StorageKind,
+    /// Save file to copy into the storage instead of starting the server.
+    pub migrate_from: Option<PathBuf>,
+    pub autosave: Autosave,
+    pub backup_dir: PathBuf,
+    pub backups_keep: usize,
+    pub trash_retention: Duration,
+    /// Certificate and key paths, if TLS is enabled.
+    pub tls: Option<(PathBuf, PathBuf)>,
+    pub max_clients: Option<usize>,
+    pub log_level: LevelFilter,
+}
+impl Config {
+    /// Merges `args` over the config file they point to, if any, and checks the result.
+    pub fn load(args: Args) -> Result<Self, String> {
+        let file = match &args.config {
+            Some(path) => FileConfig::load(path)?,
+            None => FileConfig::default(),
+        };
+
+        let log_level = match (args.log_level, &file.log_level) {
+            (Some(level), _) => level,
+            (None, Some(level)) => parse_log_level(level)?,
+            (None, None) => LevelFilter::Info,
+        };
+        let data_dir = args.data_dir.or(file.data_dir).unwrap_or(".".into());
+        let save_path = args
+            .save_path
+            .or(file.save_path)
+            .unwrap_or_else(|| data_dir.join("inv.data"));
+        // Servers before the config file kept their backups next to the save file.
+        let old_backup_dir = save::with_suffix(&save_path, ".backups");
+        let backup_dir =
+            args.backup_dir
+                .or(file.backups.dir)
+                .unwrap_or_else(|| match old_backup_dir.is_dir() {
+                    true => old_backup_dir,
+                    false => data_dir.join("backups"),
+                });
+        let defaults = Autosave::default();
+        let autosave = Autosave {
+            interval: match args.autosave_interval.or(file.autosave.interval) {
+                Some(secs) => Duration::from_secs(secs),
+                None => defaults.interval,
+            },
+            max_changes: args
+                .autosave_changes
+                .or(file.autosave.changes)
+                .unwrap_or(defaults.max_changes),
+        };
+        let tls = match (
+            args.tls_cert.or(file.tls.cert),
+            args.tls_key.or(file.tls.key),
+        ) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => return Err("the TLS certificate and key have to be given together".into()),
+        };
+        let retention_days = args
+            .trash_retention_days
+            .or(file.trash_retention_days)
+            .unwrap_or(30);
+        let trash_retention = retention_days
+            .checked_mul(24 * 60 * 60)
+            .map(Duration::from_secs)
+            .ok_or("the trash retention is too long")?;
+
+        let config = Self {
+            bind: args
+                .bind
+                .or(file.bind)
+                .unwrap_or(Ipv6Addr::UNSPECIFIED.into()),
+            port: args.port.or(file.port).unwrap_or(DEFAULT_PORT),
+            http_port: args.http_port.or(file.http.port),
+            data_dir,
+            save_path,
+            storage: args.storage.or(file.storage).unwrap_or_default(),
+            migrate_from: args.migrate_from,
+            autosave,
+            backup_dir,
+            backups_keep: args.backups_keep.or(file.backups.keep).unwrap_or(10),
+            trash_retention,
+            tls,
+            max_clients: args.max_clients.or(file.max_clients),
+            log_level,
+        };
+        config.check()?;
+        Ok(config)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.autosave.interval.is_zero() {
+            return Err("the autosave interval has to be at least 1 second".into());
+        }
+        if self.autosave.max_changes == 0 {
+            return Err("autosave has to wait for at least 1 change".into());
+        }
+        if self.backups_keep == 0 {
+            return Err("at least 1 backup has to be kept".into());
+        }
+        if self.http_port == Some(self.port) {
+            return Err("the HTTP API needs a port of its own".into());
+        }
+        if self.max_clients == Some(0) {
+            return Err("max clients has to be at least 1".into());
+        }
+        if self.data_dir.is_file() {
+            return Err(format!("data dir {:?} is a file", self.data_dir));
+        }
+        if self.migrate_from.as_ref() == Some(&self.storage_path()) {
+            return Err("can't migrate the storage into itself".into());
+        }
+        Ok(())
+    }
+
+    /// Where the inv is stored.
+    pub fn storage_path(&self) -> PathBuf {
+        match self.storage {
+            StorageKind::File => self.save_path.clone(),
+            StorageKind::Sqlite => self.data_dir.join("inv.db"),
+        }
+    }
+}
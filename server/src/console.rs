@@ -0,0 +1,603 @@
+//! The admin console, read from stdin while the server runs.
+
+use inv_common::audit::AuditQuery;
+use inv_common::auth::{AccountErr, Accounts, Role};
+use inv_common::backup::{parse_utc_timestamp, utc_timestamp, Backups};
+use inv_common::inv::{Id, Item};
+use inv_common::{save, spreadsheet, BatchResult, ServerHost};
+
+use std::io::Write;
+use std::path::Path;
+use std::sync::mpsc::Sender;
+
+/// Usage and description of every command, as listed by `help`.
+const COMMANDS: &[(&str, &str)] = &[
+    ("help", "Show this list"),
+    ("stop", "Save and shut the server down"),
+    ("save", "Save the inv now"),
+    (
+        "export <path>",
+        "Write a copy of the inv, without pictures, to another file",
+    ),
+    ("exportCsv <path>", "Write every item to a CSV file"),
+    (
+        "importCsv <path> [commit]",
+        "Check what importing items from a CSV file would change, and with `commit` do it",
+    ),
+    ("stats", "Show totals over the inv"),
+    ("countItems", "Show how many items there are"),
+    ("ids", "List every item id"),
+    ("show <item id>", "Show everything about an item"),
+    ("find <text>", "List items whose text fields contain <text>"),
+    ("rm <item id>", "Move an item to the trash"),
+    ("trash", "List the items in the trash"),
+    ("untrash <item id>", "Restore an item from the trash"),
+    ("purge <item id>", "Delete an item from the trash for good"),
+    ("platforms", "List the platforms"),
+    ("platforms add <name>", "Add a platform"),
+    ("platforms rename <name> <new name>", "Rename a platform"),
+    (
+        "platforms remove <name>",
+        "Remove a platform nothing is listed on",
+    ),
+    ("countClients", "Show how many clients are connected"),
+    ("clients", "List the connected clients"),
+    (
+        "kick <client id | user>",
+        "Disconnect a client, or every client logged in as a user",
+    ),
+    ("backup", "Back the inv up now"),
+    ("backups", "List the backups"),
+    ("restore <name>", "Replace the inv with a backup"),
+    (
+        "gc",
+        "Delete the pictures no item, audit log entry or backup uses",
+    ),
+    (
+        "audit [id <item id>] [user <name>] [since <time>] [until <time>]",
+        "Search the audit log, times are UTC as YYYYMMDD or YYYYMMDD-HHMMSS",
+    ),
+    ("undo <change number>", "Revert a change from the audit log"),
+    ("users", "List the accounts"),
+    (
+        "useradd <name> <role> <password>",
+        "Add an account, the role is read-only, editor or admin",
+    ),
+    (
+        "userdel <name>",
+        "Remove an account and disconnect its clients",
+    ),
+    ("passwd <name> <password>", "Change an account's password"),
+    ("role <name> <role>", "Change an account's role"),
+    ("token <name>", "Create a login token for an account"),
+    ("revoke <name>", "Revoke every token of an account"),
+];
+
+fn print_help() {
+    println!("Arguments containing spaces can be put in double quotes. Item ids are hex.");
+    for (usage, desc) in COMMANDS {
+        println!("  {usage}");
+        println!("      {desc}");
+    }
+}
+
+/// Prints the usage of every form of `cmd`.
+fn print_usage(cmd: &str) {
+    for (usage, _) in COMMANDS {
+        if usage.split(' ').next() == Some(cmd) {
+            eprintln!("usage : {usage}");
+        }
+    }
+}
+
+/// Splits a command line on whitespace, keeping anything in double quotes together.
+fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let mut args = vec![];
+    let mut arg: Option<String> = None;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                arg.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => args.extend(arg.take()),
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted {
+        return Err(String::from("unclosed quote"));
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+fn save_inv(server: &ServerHost) {
+    if let Err(err) = server.save() {
+        let path = server.storage.as_ref().map(|storage| storage.path());
+        log::error!("Failed to save inv to {path:?} : {err:?}");
+    }
+}
+
+/// Saves the accounts after a change made by `result`, and reports how it went.
+fn finish_account_change(accounts: &Accounts, result: Result<(), AccountErr>) {
+    if let Err(err) = result {
+        eprintln!("{err}");
+        return;
+    }
+    match accounts.save() {
+        Ok(()) => println!("Saved accounts"),
+        Err(err) => eprintln!("Failed to save accounts to {:?} : {err}", accounts.path),
+    }
+}
+
+fn users_cmd(server: &ServerHost, cmd: &str, args: &[&str]) {
+    let mut accounts = server.accounts.write().unwrap();
+    let result = match (cmd, args) {
+        ("users", []) => {
+            for (name, account) in accounts.iter() {
+                println!(
+                    "{name} ({}, {} tokens)",
+                    account.role,
+                    account.token_count()
+                );
+            }
+            return;
+        }
+        ("useradd", [name, role, password]) => match Role::parse(role) {
+            Some(role) => accounts.add(name, password, role),
+            None => return eprintln!("unknown role : {role:?} (read-only, editor or admin)"),
+        },
+        ("userdel", [name]) => {
+            let result = accounts.remove(name);
+            // Whoever is logged in as them shouldn't be able to keep going.
+            server
+                .clients
+                .write()
+                .unwrap()
+                .retain(|_, c| c.info.name != *name);
+            result
+        }
+        ("passwd", [name, password]) => accounts.set_password(name, password),
+        ("role", [name, role]) => match Role::parse(role) {
+            Some(role) => {
+                let result = accounts.set_role(name, role);
+                for client in server.clients.write().unwrap().values_mut() {
+                    if client.info.name == *name {
+                        client.info.role = role;
+                    }
+                }
+                result
+            }
+            None => return eprintln!("unknown role : {role:?} (read-only, editor or admin)"),
+        },
+        ("token", [name]) => accounts.create_token(name).map(|token| {
+            println!("New token for {name} (it won't be shown again) : {token}");
+        }),
+        ("revoke", [name]) => accounts.revoke_tokens(name),
+        (cmd, _) => return print_usage(cmd),
+    };
+    finish_account_change(&accounts, result);
+}
+
+/// Item ids are shown in hex, so that's how they are typed in too.
+fn parse_id(s: &str) -> Option<Id> {
+    u32::from_str_radix(s, 16).ok().map(Id)
+}
+
+/// Runs `f` on the item id in `arg`, or says it isn't one.
+fn with_id(arg: &str, f: impl FnOnce(Id)) {
+    match parse_id(arg) {
+        Some(id) => f(id),
+        None => eprintln!("invalid item id : {arg:?}"),
+    }
+}
+
+fn parse_audit_query(args: &[&str]) -> Result<AuditQuery, String> {
+    let mut query = AuditQuery::default();
+    let mut args = args.iter();
+    while let Some(key) = args.next() {
+        let Some(value) = args.next() else {
+            return Err(format!("missing value for {key:?}"));
+        };
+        let time = || parse_utc_timestamp(value).ok_or(format!("invalid time : {value:?}"));
+        match *key {
+            "id" => match parse_id(value) {
+                Some(id) => query.id = Some(id),
+                None => return Err(format!("invalid item id : {value:?}")),
+            },
+            "user" => query.user = Some(value.to_string()),
+            "since" => query.since = Some(time()?),
+            "until" => query.until = Some(time()?),
+            key => return Err(format!("unknown filter : {key:?}")),
+        }
+    }
+    Ok(query)
+}
+
+fn audit_cmd(server: &ServerHost, args: &[&str]) {
+    let query = match parse_audit_query(args) {
+        Ok(query) => query,
+        Err(err) => {
+            eprintln!("{err}");
+            return print_usage("audit");
+        }
+    };
+    let entries = match server.audit.lock().unwrap().query(&query) {
+        Ok(entries) => entries,
+        Err(err) => return eprintln!("Failed to read audit log : {err}"),
+    };
+    for entry in &entries {
+        let name = |item: &Option<Item>| match item {
+            Some(item) => format!("{:?}", item.name),
+            None => String::from("(none)"),
+        };
+        println!(
+            "#{} {} {} {} item {:x} : {} -> {}",
+            entry.seq,
+            utc_timestamp(entry.time),
+            entry.user,
+            entry.action,
+            entry.id.0,
+            name(&entry.before),
+            name(&entry.after),
+        );
+    }
+    println!("{} changes", entries.len());
+}
+
+fn show_item(server: &ServerHost, id: Id) {
+    let inv = server.inv.read().unwrap();
+    let Some(item) = inv.items.get(&id) else {
+        return eprintln!("No item {:x}", id.0);
+    };
+    let [w, h, d] = item.dimensions;
+    println!("Item {:x} {:?}", id.0, item.name);
+    println!("  description : {:?}", item.desc);
+    println!("  location : {:?}", item.location);
+    println!("  count : {} ({} sold)", item.count, item.sold_count());
+    println!("  estimated cost : {}", item.est_cost);
+    println!("  condition : {:?}", item.condition);
+    println!("  color : {:?}", item.color);
+    println!("  brand : {:?}", item.brand);
+    println!("  model no : {}", item.model_no);
+    println!("  serial no : {}", item.serial_no);
+    println!("  dimensions : {w} x {h} x {d}");
+    println!(
+        "  weight : {} (shipping {})",
+        item.weight, item.shipping_weight
+    );
+    for (platform, listing) in &item.listings {
+        println!(
+            "  listed on {} since {} ({} sold)",
+            inv.get_platform_name(platform),
+            utc_timestamp(listing.date),
+            listing.sold
+        );
+    }
+    if item.gallery.is_empty() {
+        println!("  photos : none");
+    }
+    for (idx, photo) in item.gallery.photos().iter().enumerate() {
+        let cover = if idx == item.gallery.cover_idx() {
+            ", cover"
+        } else {
+            ""
+        };
+        match photo.caption.as_str() {
+            "" => println!("  photo {} : {}{cover}", idx + 1, photo.picture),
+            caption => println!("  photo {} : {} ({caption}{cover})", idx + 1, photo.picture),
+        }
+    }
+    println!("  created : {}", utc_timestamp(item.creation_date));
+    println!("  revision : {}", item.revision);
+}
+
+fn find_items(server: &ServerHost, text: &str) {
+    let text = text.to_lowercase();
+    let inv = server.inv.read().unwrap();
+    let mut found: Vec<_> = (inv.items.iter())
+        .filter(|(_, item)| item.contains_text(&text))
+        .collect();
+    found.sort_by_key(|(id, _)| id.0);
+    for (id, item) in &found {
+        println!("{:x} {:?} ({})", id.0, item.name, item.location);
+    }
+    println!("{} items", found.len());
+}
+
+fn print_stats(server: &ServerHost) {
+    let inv = server.inv.read().unwrap();
+    let stats = inv.stats();
+    println!("Listings : {}", stats.listings);
+    println!("Items : {} ({})", stats.total_count(), stats.total_cost());
+    println!("Unsold : {} ({})", stats.unsold_count, stats.unsold_cost);
+    println!("Sold : {} ({})", stats.sold_count, stats.sold_cost);
+    println!("In the trash : {}", inv.trash.len());
+    println!("Platforms : {}", inv.platform_names.len());
+    println!("Revision : {}", inv.revision);
+    println!("Clients : {}", server.clients.read().unwrap().len());
+}
+
+fn platforms_cmd(server: &ServerHost, args: &[&str]) {
+    let result = match args {
+        [] => {
+            for (_, name) in server.inv.read().unwrap().platforms() {
+                println!("{name}");
+            }
+            return;
+        }
+        ["add", name] => server.add_platform(name).map(drop),
+        ["rename", name, new_name] => server.rename_platform(name, new_name),
+        ["remove", name] => server.remove_platform(name, "console"),
+        _ => return print_usage("platforms"),
+    };
+    if let Err(err) = result {
+        eprintln!("{err}");
+    }
+}
+
+fn list_clients(server: &ServerHost) {
+    let clients = server.clients.read().unwrap();
+    let mut clients: Vec<_> = clients.values().collect();
+    clients.sort_by_key(|c| c.info.connected);
+    for client in clients {
+        let info = &client.info;
+        let addr = match client.addr {
+            Some(addr) => addr.to_string(),
+            None => String::from("unknown address"),
+        };
+        println!(
+            "{} {} ({}) from {addr}, release {}, connected {}",
+            info.id,
+            info.name,
+            info.role,
+            info.release,
+            utc_timestamp(info.connected)
+        );
+    }
+}
+
+/// Disconnects the client with the id `target`, or else every client logged in as `target`.
+fn kick(server: &ServerHost, target: &str) {
+    let ids: Vec<_> = (server.clients.read().unwrap().values())
+        .filter(|c| c.info.id.to_string() == target || c.info.name == target)
+        .map(|c| c.info.id)
+        .collect();
+    if ids.is_empty() {
+        return eprintln!("No client or user {target:?} is connected");
+    }
+    for id in ids {
+        if let Some(info) = server.disconnect_client(id) {
+            println!("Kicked {} ({})", info.id, info.name);
+        }
+    }
+}
+
+fn export(server: &ServerHost, path: &str) {
+    let bytes = save::encode(&server.inv.read().unwrap());
+    match save::write_atomic(Path::new(path), &bytes) {
+        Ok(()) => println!("Exported inv to {path:?}"),
+        Err(err) => eprintln!("Failed to export inv to {path:?} : {err}"),
+    }
+}
+
+fn export_csv(server: &ServerHost, path: &str) {
+    let mut csv = vec![];
+    let result = spreadsheet::export(&server.inv.read().unwrap(), &mut csv)
+        .and_then(|()| save::write_atomic(Path::new(path), &csv));
+    match result {
+        Ok(()) => println!("Exported items to {path:?}"),
+        Err(err) => eprintln!("Failed to export items to {path:?} : {err}"),
+    }
+}
+
+/// Shows what importing `path` would change, and unless it's a dry run, changes it.
+fn import_csv(server: &ServerHost, path: &str, dry_run: bool) {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) => return eprintln!("Failed to open {path:?} : {err}"),
+    };
+    let import = match spreadsheet::import(&server.inv.read().unwrap(), file) {
+        Ok(import) => import,
+        Err(err) => return eprintln!("Failed to read {path:?} : {err}"),
+    };
+    for header in &import.ignored_columns {
+        println!("Ignored column {header:?}");
+    }
+    for imported in &import.items {
+        match &imported.changed {
+            Some(fields) => println!(
+                "row {} : change item {:x} {:?} ({})",
+                imported.row,
+                imported.id.0,
+                imported.item.name,
+                fields.join(", ")
+            ),
+            None => println!("row {} : add item {:?}", imported.row, imported.item.name),
+        }
+        for picture in imported.item.gallery.pictures() {
+            if !server.has_picture(picture).unwrap_or(false) {
+                eprintln!("row {} : picture {picture} isn't stored", imported.row);
+            }
+        }
+    }
+    for err in &import.errors {
+        eprintln!("{err}");
+    }
+    println!(
+        "{} new items, {} changed, {} unchanged, {} errors",
+        import.new_count(),
+        import.changed_count(),
+        import.unchanged,
+        import.errors.len()
+    );
+    if dry_run {
+        return println!("Nothing was imported, run `importCsv {path:?} commit` to do it");
+    }
+
+    let missing_picture = (import.items.iter())
+        .flat_map(|imported| imported.item.gallery.pictures())
+        .any(|picture| !server.has_picture(picture).unwrap_or(false));
+    let ops = match import.into_batch() {
+        Ok(_) if missing_picture => {
+            return eprintln!("Nothing was imported, as pictures are missing")
+        }
+        Ok(ops) => ops,
+        Err(_) => return eprintln!("Nothing was imported, fix the errors first"),
+    };
+    let results = match server.apply_batch(ops, "console", None, |_| {}) {
+        Ok(results) => results,
+        Err(err) => return eprintln!("Nothing was imported : {err}"),
+    };
+    if results
+        .iter()
+        .any(|r| matches!(r, BatchResult::Conflict(_) | BatchResult::MissingPicture(_)))
+    {
+        return eprintln!(
+            "Nothing was imported, as items or pictures were changed meanwhile. Try again"
+        );
+    }
+    println!("Imported {} items", results.len());
+}
+
+/// Runs one command line. Returns false once the server should stop.
+fn run_cmd(server: &ServerHost, line: &str) -> bool {
+    let args = match split_args(line) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}");
+            return true;
+        }
+    };
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let Some((&cmd, args)) = args.split_first() else {
+        return true;
+    };
+    // Like a client's, a command either finishes before a shutdown saves, or doesn't run at all.
+    match server.run_command(|| dispatch(server, cmd, args)) {
+        Some(keep_running) => keep_running,
+        None => {
+            eprintln!("The server is shutting down");
+            true
+        }
+    }
+}
+
+/// Runs the command `cmd`. Returns false once the server should stop.
+fn dispatch(server: &ServerHost, cmd: &str, args: &[&str]) -> bool {
+    match (cmd, args) {
+        ("help", _) => print_help(),
+        ("stop", []) => return false,
+        ("save", []) => save_inv(server),
+        ("export", [path]) => export(server, path),
+        ("exportCsv", [path]) => export_csv(server, path),
+        ("importCsv", [path]) => import_csv(server, path, true),
+        ("importCsv", [path, "commit"]) => import_csv(server, path, false),
+        ("stats", []) => print_stats(server),
+        ("countItems", []) => {
+            println!("{}", server.inv.read().unwrap().items.len());
+        }
+        ("ids", []) => {
+            let inv = server.inv.read().unwrap();
+            let item_ids: Vec<_> = inv.items.keys().map(|id| id.0).collect();
+            println!("Item IDs: {item_ids:?}");
+        }
+        ("show", [id]) => with_id(id, |id| show_item(server, id)),
+        ("find", [_, ..]) => find_items(server, &args.join(" ")),
+        ("rm", [id]) => with_id(id, |id| {
+            match server.remove_item(id, "console", None, || {}) {
+                true => println!("Moved item {:x} to the trash", id.0),
+                false => eprintln!("No item {:x}", id.0),
+            }
+        }),
+        ("trash", []) => {
+            let inv = server.inv.read().unwrap();
+            let mut trash: Vec<_> = inv.trash.iter().collect();
+            trash.sort_by_key(|(_, trashed)| trashed.deleted_at);
+            for (id, trashed) in trash {
+                println!(
+                    "{:x} {:?} removed {} by {}",
+                    id.0,
+                    trashed.item.name,
+                    utc_timestamp(trashed.deleted_at),
+                    trashed.deleted_by
+                );
+            }
+        }
+        ("untrash" | "purge", [id]) => with_id(id, |id| {
+            let result = match cmd {
+                "untrash" => server.restore_trashed(id, "console", None).map(drop),
+                _ => server.purge_trashed(id, "console"),
+            };
+            if let Err(err) = result {
+                eprintln!("{err}");
+            }
+        }),
+        ("platforms", _) => platforms_cmd(server, args),
+        ("countClients", []) => {
+            println!("{}", server.clients.read().unwrap().len());
+        }
+        ("clients", []) => list_clients(server),
+        ("kick", [target]) => kick(server, target),
+        ("backup", []) => {
+            if let Err(err) = server.create_backup() {
+                eprintln!("Failed to create backup : {err}");
+            }
+        }
+        ("backups", []) => match server.backups.as_ref().map(Backups::list) {
+            Some(Ok(names)) => names.iter().for_each(|name| println!("{name}")),
+            Some(Err(err)) => eprintln!("Failed to list backups : {err}"),
+            None => eprintln!("Backups are not enabled on this server"),
+        },
+        ("restore", [name]) => match server.restore_backup(name) {
+            Ok(path) => println!("Restored {}", path.display()),
+            Err(err) => eprintln!("Failed to restore backup {name:?} : {err}"),
+        },
+        ("gc", []) => match server.collect_garbage() {
+            Ok(deleted) => println!("Deleted {deleted} pictures"),
+            Err(err) => eprintln!("Failed to delete unused pictures : {err}"),
+        },
+        ("audit", _) => audit_cmd(server, args),
+        ("undo", [seq]) => match seq.parse() {
+            Ok(seq) => {
+                if let Err(err) = server.undo_change(seq, "console") {
+                    eprintln!("Failed to undo change #{seq} : {err}");
+                }
+            }
+            Err(_) => eprintln!("invalid change number : {seq:?}"),
+        },
+        ("users" | "useradd" | "userdel" | "passwd" | "role" | "token" | "revoke", _) => {
+            users_cmd(server, cmd, args)
+        }
+        (cmd, _)
+            if COMMANDS
+                .iter()
+                .any(|(usage, _)| usage.split(' ').next() == Some(cmd)) =>
+        {
+            print_usage(cmd)
+        }
+        (cmd, _) => eprintln!("unknown command : {cmd:?}, type `help` to list them"),
+    }
+    true
+}
+
+/// Reads commands until `stop`, which is then passed on through `stop`.
+/// If stdin is closed, for example when running as a service, the server keeps running without a console.
+pub fn run(server: &ServerHost, stop: Sender<()>) {
+    let stdin = std::io::stdin();
+    loop {
+        print!("> ");
+        _ = std::io::stdout().flush();
+        let mut line = String::new();
+        match stdin.read_line(&mut line) {
+            Ok(0) => return log::info!("Console input closed, stop the server with a signal"),
+            Ok(_) => {}
+            Err(err) => return log::error!("Failed to read console input : {err}"),
+        }
+        if !run_cmd(server, &line) {
+            _ = stop.send(());
+            return;
+        }
+    }
+}
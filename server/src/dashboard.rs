@@ -0,0 +1,154 @@
+//! A read-only web page listing the inv, served at `/` next to the HTTP API.
+//! It is rendered here in full, so any browser on the network can use it without scripts.
+
+use inv_common::inv::{Id, Inv, Item};
+use std::fmt::Write;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 1em auto; max-width: 70em; padding: 0 1em; }
+.stats { display: flex; flex-wrap: wrap; gap: 0.5em 2em; }
+form { margin: 1em 0; }
+input { font-size: 1em; padding: 0.3em; width: 20em; max-width: 70%; }
+table { border-collapse: collapse; width: 100%; }
+th, td { border-bottom: 1px solid #ccc; padding: 0.4em; text-align: left; vertical-align: top; }
+td.num { text-align: right; }
+img { max-width: 6em; max-height: 6em; }
+.muted { color: #777; }
+";
+
+/// Escapes `s` for use in HTML text and attribute values.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Decodes a form-encoded query string value, where spaces are `+` and other bytes may be `%XX`.
+fn decode_query_value(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = rest.get(..2).and_then(|hex| std::str::from_utf8(hex).ok());
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(decoded) => {
+                        bytes.push(decoded);
+                        rest = &rest[2..];
+                    }
+                    None => bytes.push(b'%'),
+                }
+            }
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// The value of `key` in the query string of `url`, if it has one.
+pub fn query_param(url: &str, key: &str) -> Option<String> {
+    let (_, query) = url.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| decode_query_value(value))
+}
+
+fn item_row(html: &mut String, inv: &Inv, id: Id, item: &Item) {
+    let has_picture = !item.gallery.is_empty();
+    let id = format!("{:x}", id.0);
+    _ = write!(html, "<tr><td>");
+    if has_picture {
+        _ = write!(
+            html,
+            "<a href=\"/items/{id}/picture\"><img src=\"/items/{id}/thumbnail\" alt=\"\" loading=\"lazy\"></a>"
+        );
+    }
+    let platforms: Vec<_> = (&item.listings)
+        .into_iter()
+        .map(|(platform, _)| escape(inv.get_platform_name(platform)))
+        .collect();
+    _ = writeln!(
+        html,
+        "</td><td>{}<br><span class=\"muted\">{id}</span></td><td>{}</td><td>{}</td>\
+        <td class=\"num\">{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td><td>{}</td></tr>",
+        escape(&item.name),
+        escape(&item.desc),
+        escape(&item.location),
+        item.count,
+        item.sold_count(),
+        item.est_cost,
+        platforms.join(", "),
+    );
+}
+
+/// The whole page, listing the items that contain `search`, or every item if it is empty.
+pub fn render(inv: &Inv, search: &str) -> String {
+    let stats = inv.stats();
+    let mut html = String::new();
+    _ = writeln!(
+        html,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+        <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+        <title>Inventory</title><style>{STYLE}</style></head><body>"
+    );
+    _ = writeln!(html, "<h1>Inventory</h1>");
+    _ = writeln!(
+        html,
+        "<div class=\"stats\"><span>Total listings: {}</span><span>Total items: {} ({})</span>\
+        <span>Unsold items: {} ({})</span><span>Sold items: {} ({})</span></div>",
+        stats.listings,
+        stats.total_count(),
+        stats.total_cost(),
+        stats.unsold_count,
+        stats.unsold_cost,
+        stats.sold_count,
+        stats.sold_cost,
+    );
+    _ = writeln!(
+        html,
+        "<form method=\"get\" action=\"/\"><input type=\"search\" name=\"q\" value=\"{}\" \
+        placeholder=\"Name, location, brand, serial no...\" autofocus> <button>Search</button></form>",
+        escape(search)
+    );
+
+    let text = search.trim().to_lowercase();
+    let mut items: Vec<_> = (inv.items.iter())
+        .filter(|(_, item)| item.contains_text(&text))
+        .collect();
+    items.sort_by_cached_key(|(id, item)| (item.name.to_lowercase(), id.0));
+    match text.is_empty() {
+        true => _ = writeln!(html, "<p>{} items</p>", items.len()),
+        false => {
+            _ = writeln!(
+                html,
+                "<p>{} items matching \"{}\" <a href=\"/\">Show all</a></p>",
+                items.len(),
+                escape(search.trim())
+            )
+        }
+    }
+
+    _ = writeln!(
+        html,
+        "<table><tr><th></th><th>Name</th><th>Description</th><th>Location</th>\
+        <th>Count</th><th>Sold</th><th>Est. cost</th><th>Listed on</th></tr>"
+    );
+    for (id, item) in items {
+        item_row(&mut html, inv, *id, item);
+    }
+    _ = writeln!(html, "</table></body></html>");
+    html
+}
@@ -0,0 +1,446 @@
+//! The HTTP API, for scripts and tools that would rather speak JSON than the binary protocol.
+//!
+//! Every request logs in with HTTP basic auth, using the same accounts and tokens as the apps,
+//! and is allowed what the account's role allows the matching command.
+//!
+//! - `GET /items` lists every item, `GET /items/{id}` gets one.
+//! - `PUT /items/{id}` writes an item. Its `revision` has to be the one the edit was based on
+//!   (0 for a new item), or the current item is sent back with `409 Conflict`.
+//! - `DELETE /items/{id}` moves an item to the trash.
+//! - `GET /items/{id}/picture` gets an item's cover photo as a PNG.
+//!   `GET /items/{id}/thumbnail` gets a small version of it.
+//! - `GET /items/{id}/photos/{n}` gets an item's `n`th photo as a PNG, counting from 0, and
+//!   `GET /items/{id}/photos/{n}/thumbnail` a small version of it.
+//! - `GET /platforms` lists the platform names, `GET /stats` gets totals over the inv.
+//! - `GET /` is the dashboard, a web page listing the items. `GET /?q=text` searches them.
+//!
+//! Item ids are hex, costs are in cents and times are in seconds since the Unix epoch.
+
+use crate::dashboard;
+use inv_common::auth::Role;
+use inv_common::inv::{Id, Inv, Item, Listing, Usd};
+use inv_common::picture::{self, Format, Size};
+use inv_common::{CmdCode, ServerHost};
+
+use base64::Engine;
+use serde_derive::{Deserialize, Serialize};
+use std::io::{Cursor, Read};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, Method, Request, Response};
+
+/// How many requests are handled at once.
+const WORKERS: usize = 4;
+/// Bodies are only ever a JSON item, so anything bigger is a mistake.
+const MAX_BODY_LEN: u64 = 1024 * 1024;
+
+type Reply = Response<Cursor<Vec<u8>>>;
+
+/// A request that can't be served, with the status to answer it with and why.
+struct Refusal(u16, String);
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field, value).unwrap()
+}
+
+fn json_reply(status: u16, value: &impl serde::Serialize) -> Reply {
+    Response::from_data(serde_json::to_vec(value).unwrap())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn refusal_reply(Refusal(status, msg): Refusal) -> Reply {
+    let reply = json_reply(status, &serde_json::json!({ "error": msg }));
+    match status {
+        401 => reply.with_header(header("WWW-Authenticate", "Basic realm=\"inv-server\"")),
+        _ => reply,
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn from_unix_secs(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+#[derive(Serialize)]
+struct JsonPhoto {
+    caption: String,
+    cover: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonListing {
+    platform: String,
+    /// Defaults to now.
+    listed: Option<u64>,
+    #[serde(default)]
+    sold: u32,
+}
+
+/// An item as the API sends and accepts it.
+/// Fields left out of a `PUT` get the same defaults as a new item in the app.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct JsonItem {
+    #[serde(skip_deserializing)]
+    id: String,
+    revision: u64,
+    name: String,
+    desc: String,
+    location: String,
+    count: u32,
+    est_cost: u32,
+    condition: String,
+    color: String,
+    brand: String,
+    model_no: u64,
+    serial_no: u64,
+    dimensions: [f32; 3],
+    weight: f32,
+    shipping_weight: f32,
+    listings: Vec<JsonListing>,
+    /// Left as it is if not given.
+    created: Option<u64>,
+    /// Photos can't be changed through the API, so these are only sent.
+    #[serde(skip_deserializing)]
+    photos: Vec<JsonPhoto>,
+    /// Whether `photos` isn't empty, as sent before items had a gallery.
+    #[serde(skip_deserializing)]
+    has_picture: bool,
+}
+impl Default for JsonItem {
+    fn default() -> Self {
+        let mut json = Self::new(Id(0), &Item::default(), &Inv::default());
+        // Left out, the item keeps its creation date.
+        json.created = None;
+        json
+    }
+}
+impl JsonItem {
+    fn new(id: Id, item: &Item, inv: &Inv) -> Self {
+        let listings = (&item.listings)
+            .into_iter()
+            .map(|(platform, listing)| JsonListing {
+                platform: inv.get_platform_name(platform).to_owned(),
+                listed: Some(unix_secs(listing.date)),
+                sold: listing.sold,
+            })
+            .collect();
+        Self {
+            id: format!("{:x}", id.0),
+            revision: item.revision,
+            name: item.name.clone(),
+            desc: item.desc.clone(),
+            location: item.location.clone(),
+            count: item.count,
+            est_cost: item.est_cost.0,
+            condition: item.condition.clone(),
+            color: item.color.clone(),
+            brand: item.brand.clone(),
+            model_no: item.model_no,
+            serial_no: item.serial_no,
+            dimensions: item.dimensions,
+            weight: item.weight,
+            shipping_weight: item.shipping_weight,
+            listings,
+            created: Some(unix_secs(item.creation_date)),
+            photos: (item.gallery.photos().iter().enumerate())
+                .map(|(idx, photo)| JsonPhoto {
+                    caption: photo.caption.clone(),
+                    cover: idx == item.gallery.cover_idx(),
+                })
+                .collect(),
+            has_picture: !item.gallery.is_empty(),
+        }
+    }
+
+    /// The item this describes. The photos, which aren't part of the JSON, are kept from `current`.
+    fn into_item(self, inv: &Inv, current: Option<&Item>) -> Result<Item, Refusal> {
+        let mut item = Item {
+            revision: self.revision,
+            location: self.location,
+            gallery: current.map(|item| item.gallery.clone()).unwrap_or_default(),
+            name: self.name,
+            desc: self.desc,
+            count: self.count,
+            est_cost: Usd(self.est_cost),
+            condition: self.condition,
+            color: self.color,
+            dimensions: self.dimensions,
+            weight: self.weight,
+            shipping_weight: self.shipping_weight,
+            model_no: self.model_no,
+            serial_no: self.serial_no,
+            brand: self.brand,
+            ..Default::default()
+        };
+        match (self.created, current) {
+            (Some(secs), _) => item.creation_date = from_unix_secs(secs),
+            (None, Some(current)) => item.creation_date = current.creation_date,
+            (None, None) => {}
+        }
+        for listing in self.listings {
+            let Some(platform) = inv.find_platform(&listing.platform) else {
+                return Err(Refusal(
+                    400,
+                    format!("No platform called {:?}", listing.platform),
+                ));
+            };
+            item.listings[platform] = Some(Listing {
+                date: listing.listed.map_or_else(SystemTime::now, from_unix_secs),
+                sold: listing.sold,
+            });
+        }
+        Ok(item)
+    }
+}
+
+/// Checks the request's basic auth, returning who sent it.
+fn authenticate(server: &ServerHost, request: &Request) -> Result<(String, Role), Refusal> {
+    let unauthorized = || Refusal(401, String::from("Log in with basic auth"));
+    let value = (request.headers().iter())
+        .find(|h| h.field.equiv("Authorization"))
+        .ok_or_else(unauthorized)?
+        .value
+        .as_str();
+    let credentials = (value.strip_prefix("Basic "))
+        .and_then(|encoded| {
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .ok()
+        })
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(unauthorized)?;
+    let (name, secret) = credentials.split_once(':').ok_or_else(unauthorized)?;
+    let Some(role) = server.accounts.read().unwrap().authenticate(name, secret) else {
+        log::warn!("Failed HTTP login attempt as {name:?}");
+        return Err(Refusal(401, String::from("Invalid user name or password")));
+    };
+    Ok((name.to_owned(), role))
+}
+
+fn parse_id(s: &str) -> Result<Id, Refusal> {
+    match u32::from_str_radix(s, 16) {
+        Ok(id) => Ok(Id(id)),
+        Err(_) => Err(Refusal(400, format!("Invalid item id : {s:?}"))),
+    }
+}
+
+fn no_item(id: Id) -> Refusal {
+    Refusal(404, format!("No item {:x}", id.0))
+}
+
+fn get_item(server: &ServerHost, id: Id) -> Result<Reply, Refusal> {
+    let inv = server.inv.read().unwrap();
+    let item = inv.items.get(&id).ok_or_else(|| no_item(id))?;
+    Ok(json_reply(200, &JsonItem::new(id, item, &inv)))
+}
+
+fn put_item(
+    server: &ServerHost,
+    id: Id,
+    user: &str,
+    request: &mut Request,
+) -> Result<Reply, Refusal> {
+    let mut body = String::new();
+    let read = request
+        .as_reader()
+        .take(MAX_BODY_LEN)
+        .read_to_string(&mut body);
+    if let Err(err) = read {
+        return Err(Refusal(400, format!("Failed to read body : {err}")));
+    }
+    let json: JsonItem =
+        serde_json::from_str(&body).map_err(|err| Refusal(400, format!("Invalid item : {err}")))?;
+    let item = {
+        let inv = server.inv.read().unwrap();
+        json.into_item(&inv, inv.items.get(&id))?
+    };
+    let mut written = item.copy();
+    let result = server.insert_item(id, item, user, None, drop);
+    let inv = server.inv.read().unwrap();
+    match result {
+        Ok(revision) => {
+            written.revision = revision;
+            Ok(json_reply(200, &JsonItem::new(id, &written, &inv)))
+        }
+        Err(current) => Ok(json_reply(409, &JsonItem::new(id, &current, &inv))),
+    }
+}
+
+/// Sends photo `photo` of item `id`, or its cover photo if `None`, as a PNG.
+/// Pictures are stored as JPEGs, but the API has always handed out PNGs.
+fn get_picture(
+    server: &ServerHost,
+    id: Id,
+    photo: Option<&str>,
+    size: Size,
+) -> Result<Reply, Refusal> {
+    let picture = {
+        let inv = server.inv.read().unwrap();
+        let item = inv.items.get(&id).ok_or_else(|| no_item(id))?;
+        let photo = match photo {
+            None => item.gallery.cover(),
+            Some(idx) => {
+                let idx: usize = idx
+                    .parse()
+                    .map_err(|_| Refusal(400, format!("Invalid photo number {idx:?}")))?;
+                item.gallery.photos().get(idx)
+            }
+        };
+        photo
+            .ok_or_else(|| Refusal(404, format!("Item {:x} has no such photo", id.0)))?
+            .picture
+    };
+    let loaded = match size {
+        Size::Full => server.load_picture(&picture),
+        Size::Thumbnail => server.load_thumbnail(&picture),
+    };
+    let encoded = match loaded {
+        Ok(Some(encoded)) => encoded,
+        Ok(None) => return Err(Refusal(404, format!("Picture {picture} is missing"))),
+        Err(err) => return Err(Refusal(500, format!("Failed to load picture : {err}"))),
+    };
+    let png = match Format::of(&encoded) {
+        Some(Format::Png) => encoded,
+        _ => picture::decode(&encoded)
+            .map_err(std::io::Error::other)
+            .and_then(|pic| picture::encode_png(&pic))
+            .map_err(|err| Refusal(500, format!("Failed to convert picture {picture} : {err}")))?,
+    };
+    Ok(Response::from_data(png).with_header(header("Content-Type", Format::Png.mime_type())))
+}
+
+fn handle(server: &ServerHost, request: &mut Request) -> Result<Reply, Refusal> {
+    let (user, role) = authenticate(server, request)?;
+    log::debug!("HTTP {} {} from {user}", request.method(), request.url());
+
+    let url = request.url().to_owned();
+    let path = url.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let method = request.method().clone();
+    let cmd = match (&method, segments.as_slice()) {
+        (Method::Get, _) => CmdCode::GetInv,
+        (Method::Put, ["items", _]) => CmdCode::InsertItem,
+        (Method::Delete, ["items", _]) => CmdCode::RemoveItem,
+        _ => return Err(Refusal(404, format!("No such endpoint : {method} {path}"))),
+    };
+    if !role.allows(cmd) {
+        log::warn!("Denied HTTP {method} {path} to {user}, who is {role}");
+        return Err(Refusal(403, format!("Permission denied, {user} is {role}")));
+    }
+
+    let reply = server.run_command(|| match (&method, segments.as_slice()) {
+        (Method::Get, [""]) => {
+            let search = dashboard::query_param(&url, "q").unwrap_or_default();
+            let html = dashboard::render(&server.inv.read().unwrap(), &search);
+            Ok(Response::from_string(html)
+                .with_header(header("Content-Type", "text/html; charset=utf-8")))
+        }
+        (Method::Get, ["items"]) => {
+            let inv = server.inv.read().unwrap();
+            let mut items: Vec<_> = inv.items.iter().collect();
+            items.sort_by_key(|(id, _)| id.0);
+            let items: Vec<_> = (items.into_iter())
+                .map(|(id, item)| JsonItem::new(*id, item, &inv))
+                .collect();
+            Ok(json_reply(200, &items))
+        }
+        (Method::Get, ["items", id]) => get_item(server, parse_id(id)?),
+        (Method::Get, ["items", id, "picture"]) => {
+            get_picture(server, parse_id(id)?, None, Size::Full)
+        }
+        (Method::Get, ["items", id, "thumbnail"]) => {
+            get_picture(server, parse_id(id)?, None, Size::Thumbnail)
+        }
+        (Method::Get, ["items", id, "photos", n]) => {
+            get_picture(server, parse_id(id)?, Some(n), Size::Full)
+        }
+        (Method::Get, ["items", id, "photos", n, "thumbnail"]) => {
+            get_picture(server, parse_id(id)?, Some(n), Size::Thumbnail)
+        }
+        (Method::Put, ["items", id]) => put_item(server, parse_id(id)?, &user, request),
+        (Method::Delete, ["items", id]) => {
+            let id = parse_id(id)?;
+            match server.remove_item(id, &user, None, || {}) {
+                true => Ok(Response::from_data(vec![]).with_status_code(204)),
+                false => Err(no_item(id)),
+            }
+        }
+        (Method::Get, ["platforms"]) => {
+            let inv = server.inv.read().unwrap();
+            let names: Vec<_> = inv.platforms().map(|(_, name)| name).collect();
+            Ok(json_reply(200, &names))
+        }
+        (Method::Get, ["stats"]) => Ok(json_reply(200, &server.inv.read().unwrap().stats())),
+        _ => Err(Refusal(404, format!("No such endpoint : {method} {path}"))),
+    });
+    reply.unwrap_or_else(|| Err(Refusal(503, String::from("The server is shutting down"))))
+}
+
+/// Serves the HTTP API from a few threads of its own.
+pub struct HttpApi {
+    http: Arc<tiny_http::Server>,
+    workers: Vec<JoinHandle<()>>,
+}
+impl HttpApi {
+    /// Starts listening on `addr`. With `tls`, a certificate and key, requests are served over HTTPS.
+    pub fn start(
+        server: Arc<ServerHost>,
+        addr: SocketAddr,
+        tls: Option<(&Path, &Path)>,
+    ) -> std::io::Result<Self> {
+        let http = match tls {
+            Some((cert_path, key_path)) => {
+                let config = tiny_http::SslConfig {
+                    certificate: std::fs::read(cert_path)?,
+                    private_key: std::fs::read(key_path)?,
+                };
+                tiny_http::Server::https(addr, config)
+            }
+            None => tiny_http::Server::http(addr),
+        };
+        let http = Arc::new(http.map_err(|err| {
+            std::io::Error::other(format!("Failed to serve the HTTP API on {addr} : {err}"))
+        })?);
+
+        let workers = (0..WORKERS)
+            .map(|_| {
+                let http = http.clone();
+                let server = server.clone();
+                std::thread::spawn(move || loop {
+                    let mut request = match http.recv() {
+                        Ok(request) => request,
+                        // Woken up by `stop`.
+                        Err(_) if server.is_shutting_down() => break,
+                        Err(err) => {
+                            log::warn!("HTTP connection failed : {err:?}");
+                            continue;
+                        }
+                    };
+                    let reply = handle(&server, &mut request).unwrap_or_else(refusal_reply);
+                    if let Err(err) = request.respond(reply) {
+                        log::warn!("Failed to answer HTTP request : {err:?}");
+                    }
+                })
+            })
+            .collect();
+        Ok(Self { http, workers })
+    }
+
+    /// Stops serving. Call once the server is shutting down.
+    pub fn stop(self) {
+        for _ in &self.workers {
+            self.http.unblock();
+        }
+        for worker in self.workers {
+            _ = worker.join();
+        }
+    }
+}
@@ -1,144 +1,243 @@
-use inv_common::{inv::Inv, CmdCode, ServerHost};
-
-use std::collections::HashSet;
-use std::io::{Read, Write};
-use std::net::{Ipv6Addr, SocketAddrV6, TcpListener, TcpStream};
-use std::sync::{Arc, RwLock};
-
-type Server = Arc<RwLock<ServerHost<TcpStream>>>;
-
-fn tui(save_path: &str, server: Server) {
-    loop {
-        print!("> ");
-        std::io::stdout().flush().unwrap();
-        let mut cmd = String::new();
-        std::io::stdin().read_line(&mut cmd).unwrap();
-        _ = cmd.pop();
-
-        println!("Recieved command...");
-        let mut server = server.write().unwrap();
-        let inv = &mut server.inv;
-        match cmd.as_str() {
-            "stop" => {
-                let inv_bytes = bincode::serialize(inv).unwrap();
-                match std::fs::write(save_path, inv_bytes) {
-                    Ok(_) => println!("Saved inv to {save_path:?}"),
-                    Err(err) => eprintln!("Failed to save inv to {save_path:?} : {err:?}"),
-                }
-                std::process::exit(0); // TODO properly shut down TcpListener
-            }
-            "save" => {
-                let inv = bincode::serialize(inv).unwrap();
-                match std::fs::write(save_path, inv) {
-                    Ok(_) => println!("Saved inv to {save_path:?}"),
-                    Err(err) => eprintln!("Failed to save inv to {save_path:?} : {err:?}"),
-                }
-            }
-            "countItems" => {
-                println!("{}", inv.items.len());
-            }
-            "countClients" => {
-                println!("{}", server.clients.len());
-            }
-            "ids" => {
-                let item_ids: Vec<_> = inv.items.keys().map(|id| id.0).collect();
-                println!("Item IDs: {item_ids:?}");
-            }
-            s => eprintln!("unknown command : {s:?}"),
-        }
+use inv_common::audit::AuditLog;
+use inv_common::auth::Accounts;
+use inv_common::storage::{self, FileStorage, SqliteStorage, Storage};
+use inv_common::tls::{ServerTls, Stream};
+use inv_common::{backup::Backups, save, ServerHost};
+
+use clap::Parser;
+use config::{Args, Config, StorageKind};
+use http::HttpApi;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+mod config;
+mod console;
+mod dashboard;
+mod http;
+
+/// Writes log records to the console, problems to stderr and everything else to stdout.
+struct ConsoleLogger;
+impl log::Log for ConsoleLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
     }
-}
 
-fn check_clients(server: &mut ServerHost<TcpStream>) {
-    let mut disconnect_clients = HashSet::new();
-    for id in server.clients.keys().cloned().collect::<Vec<_>>() {
-        let (name, stream) = server.clients.get_mut(&id).unwrap();
-        stream.set_nonblocking(true).unwrap();
-        let name = name.clone();
-        // get command from client
-        let mut cmd_buf = [0u8];
-        match stream.read(&mut cmd_buf) {
-            Ok(0) => {
-                println!("Client disconnected {name:?}");
-                disconnect_clients.insert(id);
-                continue;
-            }
-            Ok(1) => {}
-            Ok(_) => unreachable!(),
-            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
-            Err(err) => {
-                println!("Client disconnected {name:?} : {err:?}");
-                disconnect_clients.insert(id);
-                continue;
-            }
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
         }
-        stream.set_nonblocking(false).unwrap();
-        let Some(cmd) = CmdCode::from_u8(cmd_buf[0]) else {
-            println!("Client sent unknown command {name:?} : {}", cmd_buf[0]);
-            continue;
-        };
-        if let Err(err) = server.handle_client_cmd(id, cmd) {
-            eprintln!("Encountered error while dealing with client ({name}) : {err:?}");
+        match record.level() {
+            log::Level::Error | log::Level::Warn => eprintln!("{}", record.args()),
+            _ => println!("{}", record.args()),
         }
     }
-    for id in disconnect_clients {
-        _ = server.clients.remove(&id).unwrap();
-    }
+
+    fn flush(&self) {}
+}
+
+fn open_storage(config: &Config) -> std::io::Result<Box<dyn Storage>> {
+    let path = config.storage_path();
+    Ok(match config.storage {
+        StorageKind::File => Box::new(FileStorage::new(path)),
+        StorageKind::Sqlite => Box::new(SqliteStorage::open(&path).map_err(|err| {
+            std::io::Error::new(err.kind(), format!("Failed to open {path:?} : {err}"))
+        })?),
+    })
+}
+
+/// Copies the inv in the save file at `from`, with its pictures, into `storage`, which has to be empty.
+fn migrate(from: &Path, storage: &dyn Storage) -> std::io::Result<()> {
+    // A missing save file would load as an empty inv.
+    std::fs::metadata(from).map_err(|err| {
+        std::io::Error::new(err.kind(), format!("Failed to read {from:?} : {err}"))
+    })?;
+    let inv = storage::migrate(&FileStorage::new(from), storage)?;
+    log::info!(
+        "Copied {} items and {} trashed items from {from:?} to {:?}",
+        inv.items.len(),
+        inv.trash.len(),
+        storage.path()
+    );
+    Ok(())
+}
+
+/// Where connecting to wakes up a listener bound to `addr`.
+fn wake_addr(addr: SocketAddr) -> SocketAddr {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+        ip => ip,
+    };
+    SocketAddr::new(ip, addr.port())
 }
 
-fn main() -> std::io::Result<()> {
-    let mut args = std::env::args();
-    let _program_path = args.next().unwrap();
-    let port: u16 = args
-        .next()
-        .expect("Missing 1st arg: port number to bind to")
-        .parse()
-        .expect("Invalid 1st arg: port number not a valid int");
-    let save_path = args
-        .next()
-        .expect("Missing 2nd arg: path to the save file location");
-
-    let addr: Ipv6Addr = "::".parse().unwrap();
-    let listener = TcpListener::bind(SocketAddrV6::new(addr, port, 0, 0))?;
-
-    let mut inv = Inv::default();
-    if let Ok(bytes) = std::fs::read(&save_path) {
-        match bincode::deserialize(&bytes) {
-            Ok(new_inv) => inv = new_inv,
-            Err(err) => eprintln!("Failed to parse inv as Inv VER 1: {err:?}"),
+fn run(config: Config) -> std::io::Result<()> {
+    std::fs::create_dir_all(&config.data_dir).map_err(|err| {
+        std::io::Error::new(
+            err.kind(),
+            format!("Failed to create data dir {:?} : {err}", config.data_dir),
+        )
+    })?;
+    let storage = open_storage(&config)?;
+    if let Some(from) = &config.migrate_from {
+        return migrate(from, &*storage);
+    }
+
+    let tls = match &config.tls {
+        Some((cert_path, key_path)) => {
+            let tls = ServerTls::load_or_generate(cert_path, key_path).map_err(|err| {
+                std::io::Error::new(err.kind(), format!("Failed to set up TLS : {err}"))
+            })?;
+            log::info!("TLS enabled, certificate fingerprint : {}", tls.fingerprint);
+            Some(tls)
         }
+        None => {
+            log::warn!("TLS disabled, traffic is not encrypted");
+            None
+        }
+    };
+
+    // Refuse to start rather than running with an empty inv that would overwrite the stored one.
+    let inv = storage.load().map_err(|err| {
+        std::io::Error::other(format!(
+            "Failed to load inv from {:?} : {err}",
+            storage.path()
+        ))
+    })?;
+    log::info!("Loaded {} items from {:?}", inv.items.len(), storage.path());
+    let save_path = &config.save_path;
+    let accounts = Accounts::load(save::with_suffix(save_path, ".accounts"))?;
+    let audit_path = save::with_suffix(save_path, ".audit");
+    AuditLog::upgrade(&audit_path, &*storage)?;
+    let audit = AuditLog::open(audit_path)?;
+    if accounts.is_empty() {
+        log::warn!("There are no accounts yet, so nobody can log in. Add one with `useradd <name> admin <password>`");
     }
 
-    let server = Arc::new(RwLock::new(ServerHost::new(inv)));
+    let addr = SocketAddr::new(config.bind, config.port);
+    let listener = TcpListener::bind(addr).map_err(|err| {
+        std::io::Error::new(err.kind(), format!("Failed to listen on {addr} : {err}"))
+    })?;
+    log::info!("Listening on {addr}");
+
+    let mut server = ServerHost::new(inv);
+    server.storage = Some(storage);
+    server.autosave = config.autosave;
+    log::info!("Keeping backups in {:?}", config.backup_dir);
+    server.backups = Some(Backups::new(config.backup_dir, config.backups_keep));
+    server.accounts = accounts.into();
+    server.audit = audit.into();
+    server.trash_retention = config.trash_retention;
+    server.max_clients = config.max_clients;
+    let server = Arc::new(server);
+    let tls = tls.map(Arc::new);
 
-    let save_path_clone = save_path.clone();
+    let http = match config.http_port {
+        Some(port) => {
+            let addr = SocketAddr::new(config.bind, port);
+            let tls = (config.tls.as_ref()).map(|(cert, key)| (cert.as_path(), key.as_path()));
+            let http = HttpApi::start(server.clone(), addr, tls)?;
+            log::info!("Serving the HTTP API on {addr}");
+            Some(http)
+        }
+        None => None,
+    };
+
+    let (stop, stop_requested) = std::sync::mpsc::channel();
     let server0 = server.clone();
-    std::thread::spawn(move || tui(&save_path_clone, server0));
+    let stop0 = stop.clone();
+    std::thread::spawn(move || console::run(&server0, stop0));
 
+    // A second Ctrl-C gives up on shutting down cleanly.
+    let mut interrupted = false;
+    ctrlc::set_handler(move || {
+        if std::mem::replace(&mut interrupted, true) {
+            eprintln!("Exiting without saving");
+            std::process::exit(130);
+        }
+        log::info!("Recieved signal to stop");
+        _ = stop.send(());
+    })
+    .map_err(std::io::Error::other)?;
+
+    // Clients are handled as their commands come in, this only runs the time based policies.
     let server1 = server.clone();
     std::thread::spawn(move || loop {
-        check_clients(&mut server1.write().unwrap());
+        server1.purge_expired_trash();
+        server1.autosave();
         std::thread::sleep(std::time::Duration::from_millis(1000));
     });
 
     let server2 = server.clone();
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let mut server = server2.write().unwrap();
-                match server.connect_client(stream) {
-                    Ok(_) => {}
-                    Err(err) => eprintln!("Client failed to connect : {err:?}"),
+    let accept_thread = std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            if server2.is_shutting_down() {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    let server = server2.clone();
+                    let tls = tls.clone();
+                    std::thread::spawn(move || {
+                        let stream = match &tls {
+                            Some(tls) => match tls.accept(stream) {
+                                Ok(stream) => stream,
+                                Err(err) => {
+                                    return log::warn!("TLS handshake with client failed : {err:?}")
+                                }
+                            },
+                            None => Stream::Plain(stream),
+                        };
+                        server.serve_connection(stream);
+                    });
                 }
+                Err(e) => log::warn!("Connection to client failed {e:?}"),
             }
-            Err(e) => eprintln!("Connection to client failed {e:?}"),
+        }
+    });
+
+    _ = stop_requested.recv();
+    log::info!("Shutting down...");
+    let result = server.shutdown();
+    if let Some(http) = http {
+        http.stop();
+    }
+    // Wake the accept loop up, so it sees the server is shutting down.
+    if TcpStream::connect(wake_addr(addr)).is_ok() {
+        _ = accept_thread.join();
+    }
+    match result {
+        Ok(()) => {
+            log::info!("Shut down cleanly");
+            Ok(())
+        }
+        Err(err) => {
+            let path = server.storage.as_ref().map(|storage| storage.path());
+            Err(std::io::Error::other(format!(
+                "Failed to save inv to {path:?} : {err}"
+            )))
         }
     }
+}
+
+fn main() -> ExitCode {
+    let config = match Config::load(Args::parse()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("error: {err}");
+            return ExitCode::from(2);
+        }
+    };
+    log::set_logger(&ConsoleLogger).unwrap();
+    log::set_max_level(config.log_level);
 
-    let inv = bincode::serialize(&server.read().unwrap().inv).unwrap();
-    match std::fs::write(&save_path, inv) {
-        Ok(_) => println!("Saved inv to {save_path:?}"),
-        Err(err) => eprintln!("Failed to save inv to {save_path:?} : {err:?}"),
+    match run(config) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            log::error!("{err}");
+            ExitCode::FAILURE
+        }
     }
-    Ok(())
 }
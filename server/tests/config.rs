@@ -0,0 +1,98 @@
+#[path = "../src/config.rs"]
+mod config;
+
+use clap::Parser;
+use config::{Args, Config, StorageKind};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+fn load(args: &[&str]) -> Result<Config, String> {
+    let args =
+        Args::try_parse_from(["inv-server"].iter().chain(args)).map_err(|e| e.to_string())?;
+    Config::load(args)
+}
+
+/// An empty directory no other test uses.
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("inv-config-test-{name}-{}", std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn defaults() {
+    let config = load(&[]).unwrap();
+    assert_eq!(config.port, 25552);
+    assert_eq!(config.http_port, None);
+    assert!(config.bind.is_unspecified());
+    assert_eq!(config.data_dir, Path::new("."));
+    assert_eq!(config.save_path, Path::new("./inv.data"));
+    assert_eq!(config.storage, StorageKind::File);
+    assert_eq!(config.storage_path(), config.save_path);
+    assert_eq!(config.backup_dir, Path::new("./backups"));
+    assert_eq!(config.backups_keep, 10);
+    assert_eq!(
+        config.trash_retention,
+        Duration::from_secs(30 * 24 * 60 * 60)
+    );
+    assert_eq!(config.tls, None);
+    assert_eq!(config.max_clients, None);
+    assert_eq!(config.log_level, log::LevelFilter::Info);
+}
+
+#[test]
+fn flags_take_precedence_over_the_file() {
+    let dir = test_dir("file");
+    let file = dir.join("inv-server.toml");
+    let toml = "port = 1000\ndata-dir = \"data\"\nstorage = \"sqlite\"\nlog-level = \"debug\"\n\
+        [backups]\nkeep = 3\n[http]\nport = 1001\n";
+    std::fs::write(&file, toml).unwrap();
+
+    let config = load(&["--config", file.to_str().unwrap(), "-p", "2000"]).unwrap();
+    assert_eq!(config.port, 2000);
+    assert_eq!(config.http_port, Some(1001));
+    // Relative paths in the file are relative to it.
+    assert_eq!(config.data_dir, dir.join("data"));
+    assert_eq!(config.storage_path(), dir.join("data").join("inv.db"));
+    assert_eq!(config.save_path, dir.join("data").join("inv.data"));
+    assert_eq!(config.backups_keep, 3);
+    assert_eq!(config.log_level, log::LevelFilter::Debug);
+    _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn invalid_options_are_refused() {
+    assert!(load(&["--backups-keep", "0"]).is_err());
+    assert!(load(&["--autosave-interval", "0"]).is_err());
+    assert!(load(&["-p", "1000", "--http-port", "1000"]).is_err());
+    assert!(load(&["--tls-cert", "cert.pem"]).is_err());
+    assert!(load(&["--log-level", "loud"]).is_err());
+    assert!(load(&["--storage", "paper"]).is_err());
+
+    let dir = test_dir("unknown");
+    let file = dir.join("inv-server.toml");
+    std::fs::write(&file, "colour = \"blue\"\n").unwrap();
+    assert!(load(&["--config", file.to_str().unwrap()]).is_err());
+    _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn old_save_files_and_backups_are_used_where_they_are() {
+    let dir = test_dir("old");
+    let save_path = dir.join("my.inv");
+    let save_arg = save_path.to_str().unwrap();
+
+    let config = load(&["--save-path", save_arg]).unwrap();
+    assert_eq!(config.storage_path(), save_path);
+    assert_eq!(config.backup_dir, Path::new("./backups"));
+
+    // Backups an older server made next to the save file are still used.
+    let old_backups = dir.join("my.inv.backups");
+    std::fs::create_dir(&old_backups).unwrap();
+    let config = load(&["--save-path", save_arg]).unwrap();
+    assert_eq!(config.backup_dir, old_backups);
+    let config = load(&["--save-path", save_arg, "--backup-dir", "elsewhere"]).unwrap();
+    assert_eq!(config.backup_dir, Path::new("elsewhere"));
+    _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,38 @@
+#[path = "../src/dashboard.rs"]
+mod dashboard;
+
+use dashboard::{query_param, render};
+use inv_common::inv::{Id, Inv, Item};
+
+#[test]
+fn query_params_are_decoded() {
+    let url = "/?page=2&q=red+lamp%21&empty=&flag";
+    assert_eq!(query_param(url, "q").as_deref(), Some("red lamp!"));
+    assert_eq!(query_param(url, "page").as_deref(), Some("2"));
+    assert_eq!(query_param(url, "empty").as_deref(), Some(""));
+    assert_eq!(query_param(url, "flag").as_deref(), Some(""));
+    assert_eq!(query_param(url, "missing"), None);
+    assert_eq!(query_param("/", "q"), None);
+    // Bytes make up UTF-8 characters, and a bad escape is kept as it was.
+    assert_eq!(query_param("/?q=caf%C3%A9", "q").as_deref(), Some("café"));
+    assert_eq!(query_param("/?q=100%", "q").as_deref(), Some("100%"));
+    assert_eq!(query_param("/?q=%zz", "q").as_deref(), Some("%zz"));
+}
+
+#[test]
+fn text_is_escaped() {
+    let mut inv = Inv::default();
+    let item = Item {
+        name: "<b>Tom & Jerry's \"mug\"</b>".into(),
+        ..Default::default()
+    };
+    inv.write_item(Id(1), item);
+
+    let html = render(&inv, "");
+    assert!(html.contains("&lt;b&gt;Tom &amp; Jerry&#39;s &quot;mug&quot;&lt;/b&gt;"));
+    assert!(!html.contains("<b>Tom"));
+
+    let html = render(&inv, "\"><script>alert(1)</script>");
+    assert!(!html.contains("<script>"));
+    assert!(html.contains("&quot;&gt;&lt;script&gt;alert(1)&lt;/script&gt;"));
+}
@@ -0,0 +1,206 @@
+use base64::Engine;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// A server run from the built binary, with an admin `alice` and a read-only `bob`.
+/// It is stopped when dropped.
+struct Server {
+    child: Child,
+    console: ChildStdin,
+    http_port: u16,
+    data_dir: PathBuf,
+}
+impl Server {
+    fn start(name: &str) -> Self {
+        let data_dir =
+            std::env::temp_dir().join(format!("inv-http-test-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&data_dir);
+        let [port, http_port] = [free_port(), free_port()];
+        let mut child = Command::new(env!("CARGO_BIN_EXE_inv-server"))
+            .args(["--bind", "127.0.0.1", "--log-level", "warn"])
+            .args([
+                "-p",
+                &port.to_string(),
+                "--http-port",
+                &http_port.to_string(),
+            ])
+            .arg("-d")
+            .arg(&data_dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut console = child.stdin.take().unwrap();
+        console
+            .write_all(b"useradd alice admin secret\nuseradd bob read-only hunter2\n")
+            .unwrap();
+        let server = Self {
+            child,
+            console,
+            http_port,
+            data_dir,
+        };
+
+        // Ready once the accounts have been added.
+        let start = Instant::now();
+        while server
+            .request("GET", "/platforms", Some(("bob", "hunter2")), "")
+            .0
+            != 200
+        {
+            assert!(
+                start.elapsed() < Duration::from_secs(20),
+                "server didn't start"
+            );
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        server
+    }
+
+    /// Sends a request, returning the status and the body.
+    fn request(
+        &self,
+        method: &str,
+        path: &str,
+        login: Option<(&str, &str)>,
+        body: &str,
+    ) -> (u16, String) {
+        let Ok(mut stream) = TcpStream::connect(("127.0.0.1", self.http_port)) else {
+            return (0, String::new());
+        };
+        let mut request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+            body.len()
+        );
+        if let Some((name, secret)) = login {
+            let encoded =
+                base64::engine::general_purpose::STANDARD.encode(format!("{name}:{secret}"));
+            request += &format!("Authorization: Basic {encoded}\r\n");
+        }
+        request += "\r\n";
+        request += body;
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        if stream.read_to_string(&mut response).is_err() {
+            return (0, String::new());
+        }
+        let status = response
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .unwrap_or(0);
+        let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+        (status, body.to_owned())
+    }
+
+    fn as_alice(&self, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let (status, body) = self.request(method, path, Some(("alice", "secret")), body);
+        (status, serde_json::from_str(&body).unwrap_or(Value::Null))
+    }
+}
+impl Drop for Server {
+    fn drop(&mut self) {
+        _ = self.console.write_all(b"stop\n");
+        let start = Instant::now();
+        while let Ok(None) = self.child.try_wait() {
+            if start.elapsed() > Duration::from_secs(10) {
+                _ = self.child.kill();
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        _ = std::fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+#[test]
+fn items_round_trip() {
+    let server = Server::start("round-trip");
+    let lamp = json!({ "name": "lamp", "count": 2, "est_cost": 1250, "created": 1000 });
+    let (status, written) = server.as_alice("PUT", "/items/1a", &lamp.to_string());
+    assert_eq!(status, 200);
+    assert_eq!(written["id"], "1a");
+    let revision = written["revision"].as_u64().unwrap();
+    assert!(revision > 0);
+
+    let (status, read) = server.as_alice("GET", "/items/1a", "");
+    assert_eq!(status, 200);
+    assert_eq!(read, written);
+    assert_eq!(read["name"], "lamp");
+    assert_eq!(read["count"], 2);
+    assert_eq!(read["est_cost"], 1250);
+    assert_eq!(read["created"], 1000);
+    assert_eq!(read["photos"], json!([]));
+    assert_eq!(read["has_picture"], false);
+
+    // Leaving `created` out keeps the item's creation date.
+    let edit = json!({ "name": "desk lamp", "revision": revision });
+    let (status, edited) = server.as_alice("PUT", "/items/1a", &edit.to_string());
+    assert_eq!(status, 200);
+    assert_eq!(edited["name"], "desk lamp");
+    assert_eq!(edited["created"], 1000);
+    let (_, items) = server.as_alice("GET", "/items", "");
+    assert_eq!(items.as_array().unwrap().len(), 1);
+    assert_eq!(items[0]["name"], "desk lamp");
+
+    // An edit based on an old revision gets the current item back.
+    let (status, current) = server.as_alice("PUT", "/items/1a", &edit.to_string());
+    assert_eq!(status, 409);
+    assert_eq!(current["name"], "desk lamp");
+
+    assert_eq!(server.as_alice("DELETE", "/items/1a", "").0, 204);
+    assert_eq!(server.as_alice("GET", "/items/1a", "").0, 404);
+    assert_eq!(server.as_alice("DELETE", "/items/1a", "").0, 404);
+}
+
+#[test]
+fn requests_need_a_login_allowed_to_send_them() {
+    let mut server = Server::start("auth");
+    let lamp = json!({ "name": "lamp" }).to_string();
+    assert_eq!(server.request("GET", "/items", None, "").0, 401);
+    let wrong = Some(("alice", "not the password"));
+    assert_eq!(server.request("GET", "/items", wrong, "").0, 401);
+    let nobody = Some(("carol", "secret"));
+    assert_eq!(server.request("GET", "/items", nobody, "").0, 401);
+
+    let bob = Some(("bob", "hunter2"));
+    assert_eq!(server.request("GET", "/items", bob, "").0, 200);
+    assert_eq!(server.request("PUT", "/items/1", bob, &lamp).0, 403);
+    // Logging in again is remembered, but still checked.
+    assert_eq!(server.request("GET", "/items", bob, "").0, 200);
+    assert_eq!(
+        server
+            .request("GET", "/items", Some(("bob", "hunter3")), "")
+            .0,
+        401
+    );
+    assert_eq!(server.as_alice("PUT", "/items/1", &lamp).0, 200);
+    assert_eq!(server.as_alice("GET", "/items/1", "").1["name"], "lamp");
+
+    // A new password makes the old one stop working, even though it was remembered.
+    server.console.write_all(b"passwd bob hunter3\n").unwrap();
+    let start = Instant::now();
+    while server.request("GET", "/items", bob, "").0 != 401 {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "password didn't change"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(
+        server
+            .request("GET", "/items", Some(("bob", "hunter3")), "")
+            .0,
+        200
+    );
+}